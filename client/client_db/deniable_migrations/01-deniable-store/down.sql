@@ -0,0 +1,19 @@
+DROP TABLE DeniableGroups;
+
+DROP TABLE DeniableMessageStatus;
+
+DROP TABLE IncomingDeniableChunk;
+
+DROP TABLE DeniableMessageAwaitingEncryption;
+
+DROP TABLE DeniableIdentifierRequestsSent;
+
+DROP TABLE DeniableKeyRequestsSent;
+
+DROP TABLE DeniablePayload;
+
+DROP TABLE DeniableDeviceSessionStore;
+
+DROP TABLE DeniableDeviceIdentityKeyStore;
+
+DROP TABLE DeniableIdentityKeys;
@@ -0,0 +1,70 @@
+CREATE TABLE DeniableIdentityKeys (
+  id                  INTEGER PRIMARY KEY,
+  public_key          TEXT NOT NULL,
+  private_key         TEXT NOT NULL,
+  registration_id     UNSIGNED BIG INT NOT NULL
+);
+
+CREATE TABLE DeniableDeviceIdentityKeyStore (
+  id               INTEGER PRIMARY KEY,
+  address          TEXT NOT NULL UNIQUE,
+  identity_key     TEXT NOT NULL
+);
+
+CREATE TABLE DeniableDeviceSessionStore (
+  id              INTEGER PRIMARY KEY,
+  address         TEXT NOT NULL UNIQUE,
+  session_record  TEXT NOT NULL
+);
+
+CREATE TABLE DeniablePayload (
+  id                  INTEGER PRIMARY KEY,
+  content             BLOB NOT NULL,
+  chunk_count         INTEGER NOT NULL,
+  total_length        INTEGER NOT NULL DEFAULT 0,
+  expires_at          INTEGER,
+  message_timestamp   INTEGER
+);
+
+CREATE TABLE DeniableKeyRequestsSent (
+  id              INTEGER PRIMARY KEY,
+  service_id      TEXT NOT NULL UNIQUE,
+  alias           TEXT NOT NULL
+);
+
+CREATE TABLE DeniableIdentifierRequestsSent (
+  id              INTEGER PRIMARY KEY,
+  phone_number    TEXT NOT NULL UNIQUE,
+  alias           TEXT NOT NULL
+);
+
+CREATE TABLE DeniableMessageAwaitingEncryption (
+  id              INTEGER PRIMARY KEY,
+  message         TEXT NOT NULL,
+  alias           TEXT NOT NULL
+);
+
+CREATE TABLE IncomingDeniableChunk (
+  id              INTEGER PRIMARY KEY,
+  chunk           BLOB NOT NULL,
+  flags           INTEGER NOT NULL,
+  version         INTEGER NOT NULL DEFAULT 0,
+  stream_id       INTEGER NOT NULL DEFAULT 0,
+  sequence        INTEGER NOT NULL DEFAULT 0,
+  total_length    INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE DeniableMessageStatus (
+  id              INTEGER PRIMARY KEY,
+  service_id      TEXT NOT NULL,
+  timestamp       INTEGER NOT NULL,
+  status          INTEGER NOT NULL,
+  UNIQUE(service_id, timestamp)
+);
+
+CREATE TABLE DeniableGroups (
+  id          INTEGER PRIMARY KEY,
+  group_id    TEXT NOT NULL UNIQUE,
+  name        TEXT NOT NULL,
+  members     TEXT NOT NULL -- Comma separated service ids
+);
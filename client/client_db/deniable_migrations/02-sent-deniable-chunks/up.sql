@@ -0,0 +1,12 @@
+-- Chunks sent recently, kept to send them again when the server flags their streams
+CREATE TABLE SentDeniableChunk (
+  id              INTEGER PRIMARY KEY,
+  chunk           BLOB NOT NULL,
+  flags           INTEGER NOT NULL,
+  version         INTEGER NOT NULL,
+  stream_id       INTEGER NOT NULL,
+  sequence        INTEGER NOT NULL,
+  total_length    INTEGER NOT NULL,
+  retransmit      INTEGER NOT NULL DEFAULT 0,
+  UNIQUE(stream_id, sequence)
+);
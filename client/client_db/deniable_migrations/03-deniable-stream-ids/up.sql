@@ -0,0 +1,12 @@
+-- Stream id of the next outgoing deniable payload. Payload ids are used as stream ids,
+-- so they must not be reused once the payloads before them are sent.
+CREATE TABLE DeniableStreamCounter (
+  id      INTEGER PRIMARY KEY CHECK (id = 0),
+  next    INTEGER NOT NULL
+);
+
+INSERT INTO DeniableStreamCounter (id, next)
+SELECT 0, MAX(
+  COALESCE((SELECT MAX(id) FROM DeniablePayload), 0),
+  COALESCE((SELECT MAX(stream_id) FROM SentDeniableChunk), 0)
+) + 1;
@@ -0,0 +1,5 @@
+-- Sync messages for the other devices of the account, kept until there are sessions with them
+CREATE TABLE DeniableSyncAwaitingEncryption (
+  id              INTEGER PRIMARY KEY,
+  content         BLOB NOT NULL
+);
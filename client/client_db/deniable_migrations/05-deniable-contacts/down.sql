@@ -0,0 +1,3 @@
+DROP TABLE DeniableNicknames;
+
+DROP TABLE DeniableContacts;
@@ -0,0 +1,12 @@
+-- Deniable contacts and their nicknames, which must not show up in the regular database
+CREATE TABLE DeniableContacts (
+  id INTEGER PRIMARY KEY,
+  service_id TEXT NOT NULL UNIQUE,
+  device_ids TEXT NOT NULL
+);
+
+CREATE TABLE DeniableNicknames (
+  id INTEGER PRIMARY KEY,
+  name TEXT NOT NULL,
+  service_id TEXT NOT NULL UNIQUE
+);
@@ -0,0 +1,9 @@
+ALTER TABLE DeniablePayload DROP COLUMN total_length;
+
+ALTER TABLE IncomingDeniableChunk DROP COLUMN version;
+
+ALTER TABLE IncomingDeniableChunk DROP COLUMN stream_id;
+
+ALTER TABLE IncomingDeniableChunk DROP COLUMN sequence;
+
+ALTER TABLE IncomingDeniableChunk DROP COLUMN total_length;
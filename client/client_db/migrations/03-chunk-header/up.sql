@@ -0,0 +1,9 @@
+ALTER TABLE DeniablePayload ADD COLUMN total_length INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE IncomingDeniableChunk ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE IncomingDeniableChunk ADD COLUMN stream_id INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE IncomingDeniableChunk ADD COLUMN sequence INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE IncomingDeniableChunk ADD COLUMN total_length INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+CREATE TABLE DeniableMessageStatus (
+  id              INTEGER PRIMARY KEY,
+  service_id      TEXT NOT NULL,
+  timestamp       INTEGER NOT NULL,
+  status          INTEGER NOT NULL,
+  UNIQUE(service_id, timestamp)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE DeniablePayload DROP COLUMN message_timestamp;
+
+ALTER TABLE DeniablePayload DROP COLUMN expires_at;
@@ -0,0 +1,3 @@
+ALTER TABLE DeniablePayload ADD COLUMN expires_at INTEGER;
+
+ALTER TABLE DeniablePayload ADD COLUMN message_timestamp INTEGER;
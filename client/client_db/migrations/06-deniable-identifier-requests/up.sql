@@ -0,0 +1,5 @@
+CREATE TABLE DeniableIdentifierRequestsSent (
+  id              INTEGER PRIMARY KEY,
+  phone_number    TEXT NOT NULL UNIQUE,
+  alias           TEXT NOT NULL
+);
@@ -0,0 +1,6 @@
+CREATE TABLE DeniableIdentityKeys (
+  id                  INTEGER PRIMARY KEY,
+  public_key          TEXT NOT NULL,
+  private_key         TEXT NOT NULL,
+  registration_id     UNSIGNED BIG INT NOT NULL
+);
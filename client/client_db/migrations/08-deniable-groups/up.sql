@@ -0,0 +1,6 @@
+CREATE TABLE DeniableGroups (
+  id          INTEGER PRIMARY KEY,
+  group_id    TEXT NOT NULL UNIQUE,
+  name        TEXT NOT NULL,
+  members     TEXT NOT NULL -- Comma separated service ids
+);
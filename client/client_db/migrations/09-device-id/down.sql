@@ -0,0 +1 @@
+ALTER TABLE Identity DROP COLUMN device_id;
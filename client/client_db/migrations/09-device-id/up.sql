@@ -0,0 +1,2 @@
+-- Accounts registered before the device id was stored are primary devices
+ALTER TABLE Identity ADD COLUMN device_id UNSIGNED BIG INT NOT NULL DEFAULT 1;
@@ -1,15 +1,17 @@
 use crate::{
-    contact_manager::ContactManager,
+    contact_manager::{self, ContactManager},
     encryption::{encrypt, pad_message},
     errors::{
         DatabaseError, ProcessPreKeyBundleError, ReceiveMessageError, Result, SignalClientError,
     },
+    event::DeniableEvent,
     key_manager::KeyManager,
     server::{SignalServer, SignalServerAPI},
     storage::{
-        database::ClientDB,
+        database::{ClientDB, DeniableGroup, DeniableMessageStatus},
         device::Device,
         generic::{ProtocolStore, Storage},
+        vault::DeniableVault,
     },
 };
 use async_std::sync::Mutex;
@@ -17,33 +19,48 @@ use axum::http::StatusCode;
 use base64::{prelude::BASE64_STANDARD, Engine as _};
 use bincode::{deserialize, serialize};
 use common::{
-    deniable::chunk::Chunker,
+    attachment::{
+        cover_blob, decrypt_attachment, encrypt_attachment, sample_cover_size, EncryptedAttachment,
+        MAX_ATTACHMENT_SIZE, MAX_COVER_UPLOAD_SIZE,
+    },
+    deniable::{
+        chunk::{random_filler, Chunker},
+        counter::{retransmit_header, CarrierOrder},
+        q_value::QValueRange,
+    },
     envelope::ProcessedEnvelope,
     signalservice::{
+        attachment_pointer::AttachmentIdentifier,
         data_message::{contact::Name, Contact},
-        envelope, Content, DataMessage, Envelope,
+        envelope, receipt_message, sync_message, AttachmentPointer, Content, DataMessage, Envelope,
+        GroupContextV2, NullMessage, ReceiptMessage, SyncMessage,
     },
     web_api::{
-        AccountAttributes, DeniablePayload, DenimChunk, DenimMessage, DenimMessages, PreKeyRequest,
+        AccountAttributes, ChunkHeader, DeniableGroupMessage, DeniablePayload, DeniableSyncMessage,
+        DenimChunk, DenimMessage, DenimMessages, IdentifierRequest, PreKeyRequest, PreKeyResponse,
         RegistrationRequest, RegularPayload, SignalMessage,
     },
+    SignalError,
 };
 use core::str;
 use include_dir::{include_dir, Dir};
 use libsignal_core::{Aci, DeviceId, Pni, ProtocolAddress, ServiceId};
 use libsignal_protocol::{
-    process_prekey_bundle, CiphertextMessage, IdentityKeyPair, IdentityKeyStore, PreKeyBundle,
-    SessionStore,
+    create_sender_key_distribution_message, group_encrypt, process_prekey_bundle,
+    process_sender_key_distribution_message, CiphertextMessage, IdentityKeyPair, IdentityKeyStore,
+    PreKeyBundle, SenderKeyDistributionMessage, SenderKeyStore, SessionStore,
 };
 use prost::Message;
-use rand::{rngs::OsRng, Rng};
+use rand::{rngs::OsRng, seq::SliceRandom, Rng};
 use rusqlite::Connection;
 use rusqlite_migration::Migrations;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    path::Path,
     sync::{Arc, LazyLock},
 };
+use uuid::Uuid;
 
 pub struct Client<T: ClientDB, U: SignalServerAPI> {
     pub alias: String,
@@ -51,16 +68,30 @@ pub struct Client<T: ClientDB, U: SignalServerAPI> {
     #[allow(dead_code)]
     pub pni: Pni,
     contact_manager: ContactManager,
+    /// Contacts of deniable conversations, kept in the deniable store
+    deniable_contact_manager: ContactManager,
     server_api: U,
     #[allow(dead_code)]
     key_manager: KeyManager,
     pub storage: Storage<T>,
     pub chunker: Chunker,
+    deniable_ttl: Option<Duration>,
+    deniable_events: Vec<DeniableEvent>,
+    /// Deniable attachments waiting for a cover upload
+    deniable_uploads: VecDeque<DeniableUpload>,
+}
+
+/// Encrypted deniable attachment and the message it is sent with once uploaded
+struct DeniableUpload {
+    encrypted: EncryptedAttachment,
+    file_name: String,
+    alias: String,
 }
 
 const PROFILE_KEY_LENGTH: usize = 32;
 const MASTER_KEY_LENGTH: usize = 32;
 const PASSWORD_LENGTH: usize = 16;
+const NULL_MESSAGE_MAX_PADDING: usize = 140;
 
 static MIGRATIONS_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/client_db/migrations");
 static MIGRATIONS: LazyLock<Migrations<'static>> =
@@ -72,6 +103,7 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
         aci: Aci,
         pni: Pni,
         contact_manager: ContactManager,
+        deniable_contact_manager: ContactManager,
         server_api: U,
         key_manager: KeyManager,
         storage: Storage<T>,
@@ -82,10 +114,14 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
             aci,
             pni,
             contact_manager,
+            deniable_contact_manager,
             server_api,
             key_manager,
             storage,
             chunker,
+            deniable_ttl: None,
+            deniable_events: Vec::new(),
+            deniable_uploads: VecDeque::new(),
         }
     }
 
@@ -99,8 +135,22 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
         Ok(conn)
     }
 
+    /// Open the deniable store kept next to the database at `database_url`.
+    /// Without the deniable passphrase it is kept in memory only.
+    fn open_deniable_store(
+        database_url: &str,
+        deniable_passphrase: Option<&str>,
+    ) -> Result<DeniableVault> {
+        Ok(DeniableVault::open(
+            &Path::new(database_url).with_extension("vault"),
+            deniable_passphrase,
+        )
+        .map_err(DatabaseError::from)?)
+    }
+
     /// Register a new account with the server.
-    /// `phone_number` must be unique.
+    /// `phone_number` must be unique. Given a deniable passphrase, a new deniable store is created.
+    #[allow(clippy::too_many_arguments)]
     pub async fn register(
         name: &str,
         phone_number: String,
@@ -108,6 +158,8 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
         server_url: &str,
         cert_path: &Option<String>,
         alias: String,
+        q_value: Option<QValueRange>,
+        deniable_passphrase: Option<&str>,
     ) -> Result<Client<Device, SignalServer>> {
         let mut csprng = OsRng;
         let aci_registration_id = OsRng.gen_range(1..16383);
@@ -115,7 +167,19 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
         let aci_id_key_pair = IdentityKeyPair::generate(&mut csprng);
         let pni_id_key_pair = IdentityKeyPair::generate(&mut csprng);
         let conn = Client::<T, U>::connect_to_db(database_url).await?;
-        let device = Arc::new(Mutex::new(Device::new(conn)));
+        let vault = Client::<T, U>::open_deniable_store(database_url, deniable_passphrase)?;
+        let mut device = Device::new(conn, vault);
+        if deniable_passphrase.is_some() {
+            device
+                .create_deniable_store()
+                .await
+                .map_err(DatabaseError::from)?;
+        } else {
+            device
+                .move_legacy_deniable_tables()
+                .map_err(DatabaseError::from)?;
+        }
+        let device = Arc::new(Mutex::new(device));
         device
             .lock()
             .await
@@ -220,31 +284,60 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
 
         // println!("Connecting to {}...", server_url);
         let q_value = server_api
-            .connect(&aci.service_id_string(), &password, server_url, cert_path)
+            .connect(
+                &aci.service_id_string(),
+                &password,
+                server_url,
+                cert_path,
+                q_value,
+            )
             .await?
             .unwrap();
         // println!("Connected");
 
-        Ok(Client::new(
+        let mut client = Client::new(
             alias,
             aci,
             pni,
             contact_manager,
+            ContactManager::new(),
             server_api,
             key_manager,
             storage,
             Chunker::new(q_value),
-        ))
+        );
+
+        // Deniable keys are only made when the deniable store is kept
+        if device
+            .lock()
+            .await
+            .is_deniable_store_open()
+            .await
+            .map_err(DatabaseError::from)?
+        {
+            client.queue_deniable_key_upload().await?;
+        }
+        Ok(client)
     }
 
+    /// Log in to the account stored at `database_url` and open the deniable store with
+    /// `deniable_passphrase`. Given the duress passphrase instead, the deniable store is erased
+    /// and the client is logged in with only its regular conversations.
     pub async fn login(
         database_url: &str,
         cert_path: &Option<String>,
         server_url: &str,
         alias: String,
+        q_value: Option<QValueRange>,
+        deniable_passphrase: Option<&str>,
     ) -> Result<Client<Device, SignalServer>> {
         let conn = Client::<T, U>::connect_to_db(database_url).await?;
-        let device = Arc::new(Mutex::new(Device::new(conn)));
+        let vault = Client::<T, U>::open_deniable_store(database_url, deniable_passphrase)?;
+        let mut device = Device::new(conn, vault);
+        device
+            .move_legacy_deniable_tables()
+            .map_err(DatabaseError::from)?;
+        let device = Arc::new(Mutex::new(device));
         let contacts = device
             .lock()
             .await
@@ -258,6 +351,18 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
                 c
             })
             .map_err(DatabaseError::from)?;
+        let deniable_contacts = device
+            .lock()
+            .await
+            .load_deniable_contacts()
+            .await
+            .map(|contacts| {
+                contacts
+                    .into_iter()
+                    .map(|contact| (contact.service_id, contact))
+                    .collect()
+            })
+            .map_err(DatabaseError::from)?;
         let (one_time, signed, kyber) = device
             .lock()
             .await
@@ -281,10 +386,22 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
         let mut server_api = SignalServer::new(cert_path, server_url);
 
         let q_value = server_api
-            .connect(&aci.service_id_string(), &password, server_url, cert_path)
+            .connect(
+                &aci.service_id_string(),
+                &password,
+                server_url,
+                cert_path,
+                q_value,
+            )
             .await?;
 
-        server_api.create_auth_header(aci, password.clone(), 1.into());
+        let device_id = device
+            .lock()
+            .await
+            .get_device_id()
+            .await
+            .map_err(DatabaseError::from)?;
+        server_api.create_auth_header(aci, password.clone(), device_id);
 
         let aci = device
             .lock()
@@ -298,16 +415,69 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
             .get_pni()
             .await
             .map_err(DatabaseError::from)?;
-        Ok(Client::new(
+        let mut client = Client::new(
             alias,
             aci,
             pni,
             ContactManager::new_with_contacts(contacts),
+            ContactManager::new_with_contacts(deniable_contacts),
             server_api,
             KeyManager::new(signed + 1, kyber + 1, one_time + 1), // Adds 1 to prevent reusing key ids
             Storage::new(device.clone(), ProtocolStore::new(device.clone())),
             Chunker::new(q_value.unwrap()),
-        ))
+        );
+
+        // Accounts registered before deniable keys existed, or before the deniable store was
+        // unlocked, upload them on their next login with the deniable passphrase
+        let deniable_store_open = device
+            .lock()
+            .await
+            .is_deniable_store_open()
+            .await
+            .map_err(DatabaseError::from)?;
+        if deniable_store_open
+            && !device
+                .lock()
+                .await
+                .has_deniable_key_information()
+                .await
+                .map_err(DatabaseError::from)?
+        {
+            client.queue_deniable_key_upload().await?;
+        }
+        Ok(client)
+    }
+
+    /// Generate a deniable identity key and prekeys and queue them for upload over the
+    /// deniable channel, so deniable sessions are not tied to the regular identity key.
+    async fn queue_deniable_key_upload(&mut self) -> Result<()> {
+        let mut csprng = OsRng;
+        self.storage
+            .device
+            .lock()
+            .await
+            .insert_deniable_key_information(
+                IdentityKeyPair::generate(&mut csprng),
+                csprng.gen_range(1..16383),
+            )
+            .await
+            .map_err(DatabaseError::from)?;
+
+        let key_upload = self
+            .key_manager
+            .generate_deniable_key_bundle(&mut self.storage.protocol_store)
+            .await?;
+        let deniable_payload_serialized =
+            serialize(&DeniablePayload::KeyUpload(key_upload)).expect("Should serialize payload");
+
+        self.storage
+            .device
+            .lock()
+            .await
+            .store_deniable_payload(None, 0, deniable_payload_serialized)
+            .await
+            .map_err(DatabaseError::from)?;
+        Ok(())
     }
 
     pub async fn get_service_id_from_server(&mut self, phone_number: &str) -> Result<ServiceId> {
@@ -321,6 +491,28 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
     }
 
     pub async fn send_message(&mut self, message: &str, alias: &str) -> Result<()> {
+        self.send_message_with_attachments(message, vec![], alias)
+            .await
+    }
+
+    /// Send the attachment with its file name as the message body
+    pub async fn send_attachment(
+        &mut self,
+        attachment: &[u8],
+        file_name: &str,
+        alias: &str,
+    ) -> Result<()> {
+        let pointer = self.upload_attachment(attachment, file_name).await?;
+        self.send_message_with_attachments(file_name, vec![pointer], alias)
+            .await
+    }
+
+    async fn send_message_with_attachments(
+        &mut self,
+        message: &str,
+        attachments: Vec<AttachmentPointer>,
+        alias: &str,
+    ) -> Result<()> {
         let service_id = self
             .storage
             .device
@@ -351,7 +543,7 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
                     }])
                     .body_ranges(vec![])
                     .preview(vec![])
-                    .attachments(vec![])
+                    .attachments(attachments)
                     .build(),
             )
             .build();
@@ -367,6 +559,100 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
         )
         .await?;
 
+        let msgs = self.create_denim_messages(msgs, timestamp).await?;
+
+        let missing_chunks = match self.server_api.send_msg(&msgs, &service_id).await {
+            Ok(missing_chunks) => missing_chunks,
+            Err(_) => {
+                let device_ids = self.get_new_device_ids(&service_id).await?;
+                self.update_contact(alias, device_ids).await?;
+                self.server_api.send_msg(&msgs, &service_id).await?
+            }
+        };
+        self.flag_deniable_chunks(&missing_chunks).await
+    }
+
+    /// Send a cover message to a random contact with a regular session.
+    /// The regular payload is a [NullMessage] that the receiver drops, while the
+    /// chunks carry whatever deniable payloads are waiting to be sent.
+    /// If it can not be sent, its chunks are sent again with later messages.
+    pub async fn send_cover_message(&mut self) -> Result<()> {
+        let service_ids: Vec<ServiceId> = self
+            .contact_manager
+            .get_contacts()
+            .into_iter()
+            .map(|contact| contact.service_id)
+            .collect();
+        let mut recipients = Vec::new();
+        for service_id in service_ids {
+            if self.has_sessions(&service_id, false).await? {
+                recipients.push(service_id);
+            }
+        }
+        let Some(service_id) = recipients.choose(&mut OsRng).copied() else {
+            return Ok(());
+        };
+
+        let mut padding = vec![0; OsRng.gen_range(1..=NULL_MESSAGE_MAX_PADDING)];
+        OsRng.fill(padding.as_mut_slice());
+        let content = Content::builder()
+            .null_message(NullMessage {
+                padding: Some(padding),
+            })
+            .build();
+
+        let timestamp = SystemTime::now();
+
+        let msgs = encrypt(
+            &mut self.storage.protocol_store.identity_key_store,
+            &mut self.storage.protocol_store.session_store,
+            self.contact_manager.get_contact(&service_id)?,
+            pad_message(content.encode_to_vec().as_ref()).as_ref(),
+            timestamp,
+        )
+        .await?;
+
+        let msgs = self.create_denim_messages(msgs, timestamp).await?;
+        let missing_chunks = match self.server_api.send_msg(&msgs, &service_id).await {
+            Ok(missing_chunks) => missing_chunks,
+            Err(err) => {
+                // The chunks were taken off the deniable payloads when the messages were created
+                let carried: Vec<ChunkHeader> = msgs
+                    .messages
+                    .iter()
+                    .flat_map(|msg| &msg.chunks)
+                    .filter(|chunk| !chunk.is_dummy())
+                    .map(|chunk| chunk.header)
+                    .collect();
+                self.flag_deniable_chunks(&carried).await?;
+                return Err(err);
+            }
+        };
+        self.flag_deniable_chunks(&missing_chunks).await
+    }
+
+    /// Flag the kept deniable chunks the server is missing, to send them again
+    async fn flag_deniable_chunks(&self, headers: &[ChunkHeader]) -> Result<()> {
+        if headers.is_empty() {
+            return Ok(());
+        }
+        self.storage
+            .device
+            .lock()
+            .await
+            .flag_sent_deniable_chunks(headers)
+            .await
+            .map_err(DatabaseError::from)?;
+        Ok(())
+    }
+
+    async fn create_denim_messages(
+        &mut self,
+        msgs: HashMap<DeviceId, (u32, CiphertextMessage)>,
+        timestamp: SystemTime,
+    ) -> Result<DenimMessages> {
+        self.expire_deniable_payloads().await?;
+
         let mut denim_messages = Vec::new();
         for (id, msg) in msgs {
             let regular_payload = RegularPayload::SignalMessage(SignalMessage {
@@ -385,9 +671,7 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
                 content: BASE64_STANDARD.encode(msg.1.serialize()),
                 ..Default::default()
             });
-            let regular_payload_size = serialize(&regular_payload)
-                .expect("Should serialize payload")
-                .len() as f32;
+            let regular_payload_size = regular_payload.encoded_len() as f32;
             let chunks = self
                 .chunker
                 .create_chunks(
@@ -396,17 +680,31 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
                 )
                 .await
                 .expect("Should create chunks");
+            self.storage
+                .device
+                .lock()
+                .await
+                .store_sent_deniable_chunks(
+                    chunks
+                        .0
+                        .iter()
+                        .filter(|chunk| !chunk.is_dummy())
+                        .cloned()
+                        .collect(),
+                )
+                .await
+                .map_err(DatabaseError::from)?;
 
             denim_messages.push(DenimMessage {
                 regular_payload,
                 chunks: chunks.0,
                 counter: None,
                 q: None,
-                ballast: vec![0; chunks.1],
+                ballast: random_filler(chunks.1),
             });
         }
 
-        let msgs = DenimMessages {
+        Ok(DenimMessages {
             messages: denim_messages,
             online: true,
             urgent: false,
@@ -414,251 +712,1282 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
                 .duration_since(UNIX_EPOCH)
                 .expect("can get the time since epoch")
                 .as_secs(),
-        };
+        })
+    }
 
-        match self.server_api.send_msg(&msgs, &service_id).await {
-            Ok(_) => Ok(()),
-            Err(_) => {
-                let device_ids = self.get_new_device_ids(&service_id).await?;
-                self.update_contact(alias, device_ids).await?;
-                self.server_api.send_msg(&msgs, &service_id).await
+    pub async fn send_deniable_message(&mut self, message: &str, alias: &str) -> Result<()> {
+        self.send_deniable_message_with_attachments(message, vec![], alias)
+            .await
+    }
+
+    /// Queue the attachment to be sent with its file name as the message body through the
+    /// deniable channel. An upload right away would be an upload that no regular message
+    /// follows, so the blob waits for [Client::send_cover_upload] to take the place of a
+    /// cover upload, and only the pointer to it is sent in deniable chunks.
+    pub async fn send_deniable_attachment(
+        &mut self,
+        attachment: &[u8],
+        file_name: &str,
+        alias: &str,
+    ) -> Result<()> {
+        self.storage
+            .device
+            .lock()
+            .await
+            .get_service_id_by_deniable_nickname(alias)
+            .await
+            .map_err(DatabaseError::from)?;
+        self.deniable_uploads.push_back(DeniableUpload {
+            encrypted: Self::encrypt_for_upload(attachment)?,
+            file_name: file_name.to_owned(),
+            alias: alias.to_owned(),
+        });
+        Ok(())
+    }
+
+    /// Upload a cover attachment followed by a cover message, like a regular attachment is
+    /// followed by the message pointing to it. The next queued deniable attachment is uploaded
+    /// in place of the random blob, and its message is queued to go in deniable chunks.
+    pub async fn send_cover_upload(&mut self) -> Result<()> {
+        match self.deniable_uploads.pop_front() {
+            Some(upload) => {
+                let pointer = match self
+                    .upload_encrypted(upload.encrypted.clone(), &upload.file_name)
+                    .await
+                {
+                    Ok(pointer) => pointer,
+                    Err(err) => {
+                        self.deniable_uploads.push_front(upload);
+                        return Err(err);
+                    }
+                };
+                self.send_deniable_message_with_attachments(
+                    &upload.file_name,
+                    vec![pointer],
+                    &upload.alias,
+                )
+                .await?;
+            }
+            None => {
+                let size = sample_cover_size(MAX_COVER_UPLOAD_SIZE, &mut OsRng);
+                self.server_api
+                    .upload_attachment(cover_blob(size, &mut OsRng))
+                    .await?;
             }
         }
+        self.send_cover_message().await
     }
 
-    pub async fn send_deniable_message(&mut self, message: &str, alias: &str) -> Result<()> {
+    async fn send_deniable_message_with_attachments(
+        &mut self,
+        message: &str,
+        attachments: Vec<AttachmentPointer>,
+        alias: &str,
+    ) -> Result<()> {
         let service_id = self
             .storage
             .device
             .lock()
             .await
-            .get_service_id_by_nickname(alias)
+            .get_service_id_by_deniable_nickname(alias)
             .await
             .map_err(DatabaseError::from)?;
 
+        let timestamp = SystemTime::now();
+        let timestamp_millis = timestamp
+            .duration_since(UNIX_EPOCH)
+            .expect("can get the time since epoch")
+            .as_millis() as u64;
+
+        let data_message = DataMessage::builder()
+            .body(message.to_owned())
+            .timestamp(timestamp_millis)
+            .contact(vec![Contact {
+                name: Some(Name {
+                    given_name: None,
+                    family_name: None,
+                    prefix: None,
+                    suffix: None,
+                    middle_name: None,
+                    display_name: Some(self.alias.to_owned()),
+                }),
+                number: vec![],
+                email: vec![],
+                address: vec![],
+                avatar: None,
+                organization: None,
+            }])
+            .body_ranges(vec![])
+            .preview(vec![])
+            .attachments(attachments)
+            .build();
         let content = Content::builder()
-            .data_message(
-                DataMessage::builder()
-                    .body(message.to_owned())
-                    .contact(vec![Contact {
-                        name: Some(Name {
-                            given_name: None,
-                            family_name: None,
-                            prefix: None,
-                            suffix: None,
-                            middle_name: None,
-                            display_name: Some(self.alias.to_owned()),
-                        }),
-                        number: vec![],
-                        email: vec![],
-                        address: vec![],
-                        avatar: None,
-                        organization: None,
-                    }])
-                    .body_ranges(vec![])
-                    .preview(vec![])
-                    .attachments(vec![])
-                    .build(),
-            )
+            .data_message(data_message.clone())
             .build();
 
-        let timestamp = SystemTime::now();
-
         let msgs = encrypt(
             &mut self.storage.protocol_store.deniable_identity_key_store,
             &mut self.storage.protocol_store.deniable_store,
-            self.contact_manager.get_contact(&service_id)?,
+            self.deniable_contact_manager.get_contact(&service_id)?,
             pad_message(content.encode_to_vec().as_ref()).as_ref(),
             timestamp,
         )
         .await?;
 
-        for (id, msg) in msgs {
-            let deniable_payload = DeniablePayload::SignalMessage(SignalMessage {
-                r#type: match msg.1 {
-                    CiphertextMessage::SignalMessage(_) => envelope::Type::Ciphertext.into(),
-                    CiphertextMessage::SenderKeyMessage(_) => envelope::Type::KeyExchange.into(),
-                    CiphertextMessage::PreKeySignalMessage(_) => {
-                        envelope::Type::PrekeyBundle.into()
-                    }
-                    CiphertextMessage::PlaintextContent(_) => {
-                        envelope::Type::PlaintextContent.into()
-                    }
-                },
-                destination_device_id: id.into(),
-                destination_service_id: Some(service_id.service_id_string()),
-                destination_registration_id: msg.0,
-                content: BASE64_STANDARD.encode(msg.1.serialize()),
-            });
-            let deniable_payload_serialized =
-                serialize(&deniable_payload).expect("Should serialize payload");
+        self.queue_deniable_messages(msgs, &service_id, Some(timestamp_millis))
+            .await?;
+        self.storage
+            .device
+            .lock()
+            .await
+            .store_deniable_message_sent(service_id.service_id_string(), timestamp_millis)
+            .await
+            .map_err(DatabaseError::from)?;
+        self.queue_deniable_sync(&service_id, alias, timestamp_millis, data_message)
+            .await
+    }
+
+    /// Queue a transcript of a sent deniable message for the other devices of this account.
+    /// It also tells them about new deniable contacts. Until there are deniable sessions with
+    /// all of them, the transcript is kept and the deniable keys of this account are requested.
+    async fn queue_deniable_sync(
+        &mut self,
+        destination: &ServiceId,
+        destination_alias: &str,
+        timestamp: u64,
+        message: DataMessage,
+    ) -> Result<()> {
+        let content = Content::builder()
+            .sync_message(SyncMessage {
+                sent: Some(sync_message::Sent {
+                    destination: Some(destination_alias.to_owned()),
+                    destination_service_id: Some(destination.service_id_string()),
+                    timestamp: Some(timestamp),
+                    message: Some(message),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .build();
 
+        let Some(other_devices) = self.other_own_devices().await? else {
             self.storage
                 .device
                 .lock()
                 .await
-                .store_deniable_payload(None, 0, deniable_payload_serialized)
+                .store_sync_awaiting_encryption(content.encode_to_vec())
                 .await
                 .map_err(DatabaseError::from)?;
-        }
-        Ok(())
+            let own_service_id = ServiceId::from(self.aci);
+            return self
+                .request_deniable_keys(&own_service_id, own_service_id.service_id_string())
+                .await;
+        };
+        self.queue_deniable_sync_content(&content, &other_devices)
+            .await
     }
 
-    pub async fn has_message(&mut self) -> bool {
-        self.server_api.has_message().await
+    /// The other devices of this account, `None` unless there is a deniable session with each
+    async fn other_own_devices(&self) -> Result<Option<contact_manager::Contact>> {
+        let own_service_id = ServiceId::from(self.aci);
+        let Ok(own_contact) = self.deniable_contact_manager.get_contact(&own_service_id) else {
+            return Ok(None);
+        };
+        let own_device_id = self.local_device_id().await?;
+        let mut other_devices = contact_manager::Contact::new(own_service_id);
+        for device_id in own_contact.device_ids.clone() {
+            if device_id == own_device_id {
+                continue;
+            }
+            let address = ProtocolAddress::new(own_service_id.service_id_string(), device_id);
+            if self
+                .storage
+                .protocol_store
+                .deniable_store
+                .load_session(&address)
+                .await?
+                .is_none()
+            {
+                return Ok(None);
+            }
+            other_devices.device_ids.insert(device_id);
+        }
+        Ok(Some(other_devices))
     }
 
-    pub async fn receive_message(&mut self) -> Result<Vec<ProcessedEnvelope>> {
-        // I get Envelope from Server.
-        let request = self
-            .server_api
-            .get_message()
+    /// Encrypt a sync message for each of the other devices of this account and queue them
+    /// as one payload, the server only delivers it if it covers all of them
+    async fn queue_deniable_sync_content(
+        &mut self,
+        content: &Content,
+        other_devices: &contact_manager::Contact,
+    ) -> Result<()> {
+        if other_devices.device_ids.is_empty() {
+            return Ok(());
+        }
+        let msgs = encrypt(
+            &mut self.storage.protocol_store.deniable_identity_key_store,
+            &mut self.storage.protocol_store.deniable_store,
+            other_devices,
+            pad_message(content.encode_to_vec().as_ref()).as_ref(),
+            SystemTime::now(),
+        )
+        .await?;
+
+        let expires_at = self.deniable_expires_at();
+        let deniable_payload = DeniablePayload::SyncMessage(DeniableSyncMessage {
+            messages: msgs
+                .into_iter()
+                .map(|(id, msg)| {
+                    deniable_signal_message(id, msg, &other_devices.service_id, expires_at)
+                })
+                .collect(),
+            expires_at,
+        });
+        self.store_queued_deniable_payload(&deniable_payload, None)
             .await
-            .ok_or(ReceiveMessageError::NoMessageReceived)?;
-        let denim_msg: DenimMessage = deserialize(request.body()).unwrap();
-        self.chunker.set_q_value(
-            denim_msg
-                .q
-                .expect("q value should always be populated by server"),
-        );
-        let envelope = match denim_msg.regular_payload {
+    }
+
+    /// Set up sessions with the other devices of this account from the keys the server sent
+    /// and send the sync messages that waited for them. The devices in the keys replace the
+    /// ones known before.
+    async fn start_deniable_sync(&mut self, pre_key_response: PreKeyResponse) -> Result<()> {
+        let own_service_id = ServiceId::from(self.aci);
+        let own_device_id = self.local_device_id().await?;
+        let bundles: Vec<PreKeyBundle> = Vec::<PreKeyBundle>::try_from(pre_key_response)
+            .map_err(|err| SignalClientError::KeyError(err.to_string()))?
+            .into_iter()
+            .filter(|bundle| bundle.device_id().is_ok_and(|id| id != own_device_id))
+            .collect();
+        let device_ids = self
+            .initialize_sessions_from_bundle(&own_service_id, &bundles, true)
+            .await?;
+        if self
+            .deniable_contact_manager
+            .get_contact(&own_service_id)
+            .is_ok()
+        {
+            self.deniable_contact_manager
+                .remove_contact(&own_service_id)
+                .map_err(SignalClientError::ContactManagerError)?;
+        }
+        self.add_own_devices(device_ids).await?;
+
+        let contents = self
+            .storage
+            .device
+            .lock()
+            .await
+            .take_syncs_awaiting_encryption()
+            .await
+            .map_err(DatabaseError::from)?;
+        let Some(other_devices) = self.other_own_devices().await? else {
+            return Ok(());
+        };
+        for content in contents {
+            let Ok(content) = Content::decode(content.as_slice()) else {
+                continue;
+            };
+            self.queue_deniable_sync_content(&content, &other_devices)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Add devices to the deniable contact of this account, creating it if there is none yet
+    async fn add_own_devices(&mut self, device_ids: Vec<DeviceId>) -> Result<()> {
+        let own_service_id = ServiceId::from(self.aci);
+        if self
+            .deniable_contact_manager
+            .get_contact(&own_service_id)
+            .is_err()
+        {
+            self.deniable_contact_manager
+                .add_contact(&own_service_id)
+                .map_err(SignalClientError::ContactManagerError)?;
+        }
+        self.update_deniable_contact(&own_service_id, device_ids)
+            .await
+    }
+
+    /// Handle a sync message from another device of this account, others are ignored.
+    /// Transcripts of sent deniable messages are reported as events, and keys are requested
+    /// for deniable contacts we have no session with, so this device sets up its own sessions
+    /// with them.
+    async fn handle_deniable_sync(
+        &mut self,
+        source: &ProcessedEnvelope,
+        sync_message: SyncMessage,
+    ) -> Result<()> {
+        let own_service_id = ServiceId::from(self.aci);
+        if source.source_service_id != Some(own_service_id) {
+            return Ok(());
+        }
+        let device_id = source
+            .source_device
+            .ok_or(ReceiveMessageError::InvalidMessageContent)?;
+
+        // The sending device gets our own sync messages from now on
+        self.add_own_devices(vec![device_id]).await?;
+
+        let Some(sent) = sync_message.sent else {
+            return Ok(());
+        };
+        let Some(destination) = sent
+            .destination_service_id
+            .as_deref()
+            .and_then(ServiceId::parse_from_service_id_string)
+        else {
+            return Err(ReceiveMessageError::InvalidMessageContent)?;
+        };
+        let alias = sent
+            .destination
+            .unwrap_or_else(|| destination.service_id_string());
+        if let Some(body) = sent.message.and_then(|message| message.body) {
+            self.deniable_events.push(DeniableEvent::SyncedMessage {
+                destination: alias.clone(),
+                body,
+            });
+        }
+
+        if self.has_sessions(&destination, true).await? {
+            return Ok(());
+        }
+        self.request_deniable_keys(&destination, alias).await
+    }
+
+    /// Whether the devices of the contact `service_id` are known and there is a deniable
+    /// or regular session with each of them
+    async fn has_sessions(&self, service_id: &ServiceId, deniable: bool) -> Result<bool> {
+        let contact_manager = if deniable {
+            &self.deniable_contact_manager
+        } else {
+            &self.contact_manager
+        };
+        let Ok(contact) = contact_manager.get_contact(service_id) else {
+            return Ok(false);
+        };
+        if contact.device_ids.is_empty() {
+            return Ok(false);
+        }
+        let session_store: &dyn SessionStore = if deniable {
+            &self.storage.protocol_store.deniable_store
+        } else {
+            &self.storage.protocol_store.session_store
+        };
+        for device_id in contact.device_ids.clone() {
+            let address = ProtocolAddress::new(service_id.service_id_string(), device_id);
+            if session_store.load_session(&address).await?.is_none() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Request the deniable keys of `service_id` through the deniable channel, unless they
+    /// were requested already. The response is handled for `alias`.
+    async fn request_deniable_keys(&mut self, service_id: &ServiceId, alias: String) -> Result<()> {
+        if self
+            .storage
+            .device
+            .lock()
+            .await
+            .try_get_key_request_sent(service_id.service_id_string())
+            .await
+            .map_err(DatabaseError::from)?
+            .is_some()
+        {
+            return Ok(());
+        }
+        let deniable_payload = DeniablePayload::KeyRequest(PreKeyRequest {
+            service_id: service_id.service_id_string(),
+        });
+        self.store_queued_deniable_payload(&deniable_payload, None)
+            .await?;
+        self.storage
+            .device
+            .lock()
+            .await
+            .store_key_request_sent(service_id.service_id_string(), alias)
+            .await
+            .map_err(DatabaseError::from)?;
+        Ok(())
+    }
+
+    /// Id of this device of the account
+    async fn local_device_id(&self) -> Result<DeviceId> {
+        Ok(self
+            .storage
+            .device
+            .lock()
+            .await
+            .get_device_id()
+            .await
+            .map_err(DatabaseError::from)?)
+    }
+
+    /// Encrypt and upload an attachment, returning the pointer sent to the receiver.
+    /// Regular and deniable attachments are padded and uploaded alike.
+    async fn upload_attachment(
+        &mut self,
+        attachment: &[u8],
+        file_name: &str,
+    ) -> Result<AttachmentPointer> {
+        let encrypted = Self::encrypt_for_upload(attachment)?;
+        self.upload_encrypted(encrypted, file_name).await
+    }
+
+    fn encrypt_for_upload(attachment: &[u8]) -> Result<EncryptedAttachment> {
+        if attachment.len() > MAX_ATTACHMENT_SIZE {
+            return Err(SignalClientError::AttachmentError(format!(
+                "Attachments can be at most {MAX_ATTACHMENT_SIZE} bytes"
+            )));
+        }
+        Ok(encrypt_attachment(attachment, &mut OsRng))
+    }
+
+    async fn upload_encrypted(
+        &mut self,
+        encrypted: EncryptedAttachment,
+        file_name: &str,
+    ) -> Result<AttachmentPointer> {
+        let cdn_key = self.server_api.upload_attachment(encrypted.blob).await?;
+
+        Ok(AttachmentPointer {
+            attachment_identifier: Some(AttachmentIdentifier::CdnKey(cdn_key)),
+            key: Some(encrypted.key),
+            size: Some(encrypted.size as u32),
+            digest: Some(encrypted.digest),
+            file_name: Some(file_name.to_owned()),
+            upload_timestamp: Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("can get the time since epoch")
+                    .as_millis() as u64,
+            ),
+            ..Default::default()
+        })
+    }
+
+    /// Download and decrypt an attachment of a received message.
+    /// Attachments of regular and deniable messages are fetched alike.
+    pub async fn download_attachment(&self, pointer: &AttachmentPointer) -> Result<Vec<u8>> {
+        let Some(AttachmentIdentifier::CdnKey(cdn_key)) = &pointer.attachment_identifier else {
+            return Err(SignalClientError::AttachmentError(
+                "Attachment has no cdn key".to_owned(),
+            ));
+        };
+        let blob = self.server_api.download_attachment(cdn_key).await?;
+        Ok(decrypt_attachment(
+            &blob,
+            pointer.key(),
+            pointer.digest(),
+            pointer.size() as usize,
+        )?)
+    }
+
+    /// Create a deniable group with the deniable contacts `member_aliases` and send them
+    /// our sender key. The group is never known to the server.
+    pub async fn create_deniable_group(
+        &mut self,
+        name: &str,
+        member_aliases: &[&str],
+    ) -> Result<()> {
+        let mut members = Vec::new();
+        for alias in member_aliases {
+            let service_id = self
+                .storage
+                .device
+                .lock()
+                .await
+                .get_service_id_by_deniable_nickname(alias)
+                .await
+                .map_err(DatabaseError::from)?;
+            if !members.contains(&service_id) {
+                members.push(service_id);
+            }
+        }
+
+        let group = DeniableGroup {
+            group_id: Uuid::new_v4(),
+            name: name.to_owned(),
+            members,
+        };
+        self.storage
+            .device
+            .lock()
+            .await
+            .store_deniable_group(&group)
+            .await
+            .map_err(DatabaseError::from)?;
+        self.distribute_deniable_sender_key(&group).await
+    }
+
+    /// Send a message to every member of the deniable group `group_name`.
+    /// It is encrypted once with our sender key and expanded to the members by the server.
+    pub async fn send_deniable_group_message(
+        &mut self,
+        message: &str,
+        group_name: &str,
+    ) -> Result<()> {
+        let group = self
+            .storage
+            .device
+            .lock()
+            .await
+            .get_deniable_group_by_name(group_name)
+            .await
+            .map_err(DatabaseError::from)?
+            .ok_or(SignalClientError::DeniableGroupNotFound(
+                group_name.to_owned(),
+            ))?;
+
+        // Members that joined through another member have not received our sender key yet
+        let own_address = self.deniable_group_address();
+        if self
+            .storage
+            .protocol_store
+            .sender_key_store
+            .load_sender_key(&own_address, group.group_id)
+            .await?
+            .is_none()
+        {
+            self.distribute_deniable_sender_key(&group).await?;
+        }
+
+        let timestamp_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("can get the time since epoch")
+            .as_millis() as u64;
+        let content = Content::builder()
+            .data_message(
+                DataMessage::builder()
+                    .body(message.to_owned())
+                    .timestamp(timestamp_millis)
+                    .group_v2(GroupContextV2 {
+                        master_key: Some(group.group_id.as_bytes().to_vec()),
+                        revision: None,
+                        group_change: None,
+                    })
+                    .contact(vec![Contact {
+                        name: Some(Name {
+                            given_name: None,
+                            family_name: None,
+                            prefix: None,
+                            suffix: None,
+                            middle_name: None,
+                            display_name: Some(self.alias.to_owned()),
+                        }),
+                        number: vec![],
+                        email: vec![],
+                        address: vec![],
+                        avatar: None,
+                        organization: None,
+                    }])
+                    .body_ranges(vec![])
+                    .preview(vec![])
+                    .attachments(vec![])
+                    .build(),
+            )
+            .build();
+
+        let sender_key_message = group_encrypt(
+            &mut self.storage.protocol_store.sender_key_store,
+            &own_address,
+            group.group_id,
+            pad_message(content.encode_to_vec().as_ref()).as_ref(),
+            &mut OsRng,
+        )
+        .await?;
+
+        let deniable_payload = DeniablePayload::GroupMessage(DeniableGroupMessage {
+            members: group
+                .members
+                .iter()
+                .map(ServiceId::service_id_string)
+                .collect(),
+            content: BASE64_STANDARD.encode(sender_key_message.serialized()),
+            expires_at: self.deniable_expires_at(),
+        });
+        self.store_queued_deniable_payload(&deniable_payload, None)
+            .await
+    }
+
+    /// Send our sender key for `group` to each of its members through their deniable session,
+    /// together with the name and members of the group.
+    async fn distribute_deniable_sender_key(&mut self, group: &DeniableGroup) -> Result<()> {
+        let distribution = create_sender_key_distribution_message(
+            &self.deniable_group_address(),
+            group.group_id,
+            &mut self.storage.protocol_store.sender_key_store,
+            &mut OsRng,
+        )
+        .await?;
+
+        let members = std::iter::once(ServiceId::from(self.aci))
+            .chain(group.members.iter().copied())
+            .map(|member| member.service_id_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        let timestamp = SystemTime::now();
+        let content = Content::builder()
+            .sender_key_distribution_message(distribution.serialized().to_vec())
+            .data_message(
+                DataMessage::builder()
+                    .body(group.name.clone())
+                    .group_v2(GroupContextV2 {
+                        master_key: Some(group.group_id.as_bytes().to_vec()),
+                        revision: None,
+                        // Deniable groups have no group server, so the members are sent along
+                        group_change: Some(members.into_bytes()),
+                    })
+                    .contact(vec![])
+                    .body_ranges(vec![])
+                    .preview(vec![])
+                    .attachments(vec![])
+                    .build(),
+            )
+            .build();
+
+        for service_id in &group.members {
+            let msgs = match self.deniable_contact_manager.get_contact(service_id) {
+                Ok(contact) => {
+                    encrypt(
+                        &mut self.storage.protocol_store.deniable_identity_key_store,
+                        &mut self.storage.protocol_store.deniable_store,
+                        contact,
+                        pad_message(content.encode_to_vec().as_ref()).as_ref(),
+                        timestamp,
+                    )
+                    .await
+                }
+                Err(_) => Err(SignalClientError::NoSession),
+            };
+            // Members we share no deniable session with can not read our group messages
+            let msgs = match msgs {
+                Ok(msgs) => msgs,
+                Err(SignalClientError::NoSession) => {
+                    self.deniable_events.push(DeniableEvent::SkippedGroupMember(
+                        service_id.service_id_string(),
+                    ));
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            self.queue_deniable_messages(msgs, service_id, None).await?;
+        }
+        Ok(())
+    }
+
+    /// Store the sender key and the group sent by a member of a deniable group.
+    async fn handle_deniable_sender_key_distribution(
+        &mut self,
+        source: &ProcessedEnvelope,
+        distribution: &[u8],
+    ) -> Result<()> {
+        let sender = ProtocolAddress::new(
+            source
+                .source_service_id()
+                .map_err(SignalError::from)?
+                .service_id_string(),
+            source
+                .source_device
+                .ok_or(ReceiveMessageError::InvalidMessageContent)?,
+        );
+        let distribution = SenderKeyDistributionMessage::try_from(distribution)?;
+        process_sender_key_distribution_message(
+            &sender,
+            &distribution,
+            &mut self.storage.protocol_store.sender_key_store,
+        )
+        .await?;
+
+        let Some(data_message) = source
+            .content
+            .as_ref()
+            .and_then(|content| content.data_message.as_ref())
+        else {
+            return Ok(());
+        };
+        let (Some(name), Some(members)) = (
+            data_message.body.clone(),
+            data_message
+                .group_v2
+                .as_ref()
+                .and_then(|group| group.group_change.as_deref())
+                .and_then(|members| str::from_utf8(members).ok()),
+        ) else {
+            return Ok(());
+        };
+        let own_service_id = ServiceId::from(self.aci);
+        let group = DeniableGroup {
+            group_id: distribution.distribution_id()?,
+            name,
+            members: members
+                .split(',')
+                .filter_map(ServiceId::parse_from_service_id_string)
+                .filter(|member| *member != own_service_id)
+                .collect(),
+        };
+        self.storage
+            .device
+            .lock()
+            .await
+            .store_deniable_group(&group)
+            .await
+            .map_err(DatabaseError::from)?;
+        Ok(())
+    }
+
+    /// Name of the deniable group a received message was sent to, `None` for direct messages
+    pub async fn get_deniable_group_name(
+        &self,
+        message: &ProcessedEnvelope,
+    ) -> Result<Option<String>> {
+        let Some(group_id) = message
+            .content
+            .as_ref()
+            .and_then(|content| content.data_message.as_ref())
+            .and_then(|data_message| data_message.group_v2.as_ref())
+            .and_then(|group| group.master_key.as_deref())
+            .and_then(|master_key| Uuid::from_slice(master_key).ok())
+        else {
+            return Ok(None);
+        };
+        Ok(self
+            .storage
+            .device
+            .lock()
+            .await
+            .get_deniable_group(group_id)
+            .await
+            .map_err(DatabaseError::from)?
+            .map(|group| group.name))
+    }
+
+    /// Our address in deniable groups, only the primary device takes part in them
+    fn deniable_group_address(&self) -> ProtocolAddress {
+        ProtocolAddress::new(self.aci.service_id_string(), 1.into())
+    }
+
+    /// Queue a read receipt for a deniable message, sent through the deniable channel.
+    pub async fn send_deniable_read_receipt(&mut self, message: &ProcessedEnvelope) -> Result<()> {
+        self.send_deniable_receipt(message, receipt_message::Type::Read)
+            .await
+    }
+
+    /// Queue a receipt for a deniable message to the device that sent it.
+    /// Messages without a data message timestamp, like receipts, are not acknowledged,
+    /// and neither are group messages, as the sender may share no session with us.
+    async fn send_deniable_receipt(
+        &mut self,
+        message: &ProcessedEnvelope,
+        r#type: receipt_message::Type,
+    ) -> Result<()> {
+        let Some(timestamp) = message
+            .content
+            .as_ref()
+            .and_then(|content| content.data_message.as_ref())
+            .filter(|data_message| data_message.group_v2.is_none())
+            .and_then(|data_message| data_message.timestamp)
+        else {
+            return Ok(());
+        };
+        let service_id = message.source_service_id().map_err(SignalError::from)?;
+        let device_id = message
+            .source_device
+            .ok_or(ReceiveMessageError::InvalidMessageContent)?;
+
+        let content = Content::builder()
+            .receipt_message(ReceiptMessage {
+                r#type: Some(r#type.into()),
+                timestamp: vec![timestamp],
+            })
+            .build();
+
+        let msgs = encrypt(
+            &mut self.storage.protocol_store.deniable_identity_key_store,
+            &mut self.storage.protocol_store.deniable_store,
+            &contact_manager::Contact {
+                service_id,
+                device_ids: HashSet::from([device_id]),
+            },
+            pad_message(content.encode_to_vec().as_ref()).as_ref(),
+            SystemTime::now(),
+        )
+        .await?;
+
+        self.queue_deniable_messages(msgs, &service_id, None).await
+    }
+
+    /// Update the delivery state of our deniable messages acknowledged by `receipt`.
+    async fn handle_deniable_receipt(
+        &mut self,
+        source: &ProcessedEnvelope,
+        receipt: ReceiptMessage,
+    ) -> Result<()> {
+        let status = match receipt.r#type() {
+            receipt_message::Type::Delivery => DeniableMessageStatus::Delivered,
+            receipt_message::Type::Read => DeniableMessageStatus::Read,
+            receipt_message::Type::Viewed => return Ok(()),
+        };
+        let service_id = source.source_service_id().map_err(SignalError::from)?;
+        for timestamp in receipt.timestamp {
+            self.storage
+                .device
+                .lock()
+                .await
+                .update_deniable_message_status(service_id.service_id_string(), timestamp, status)
+                .await
+                .map_err(DatabaseError::from)?;
+        }
+        Ok(())
+    }
+
+    /// Create a new deniable store under the deniable passphrase given on login, replacing the
+    /// store kept before. Until then nothing is written for a passphrase that opened no store.
+    pub async fn create_deniable_store(&mut self) -> Result<()> {
+        self.storage
+            .device
+            .lock()
+            .await
+            .create_deniable_store()
+            .await
+            .map_err(DatabaseError::from)?;
+        let has_deniable_keys = self
+            .storage
+            .device
+            .lock()
+            .await
+            .has_deniable_key_information()
+            .await
+            .map_err(DatabaseError::from)?;
+        if !has_deniable_keys {
+            self.queue_deniable_key_upload().await?;
+        }
+        Ok(())
+    }
+
+    /// Set the passphrase that erases the deniable store when it is given on login
+    /// in place of the deniable passphrase
+    pub async fn set_duress_passphrase(&mut self, passphrase: &str) -> Result<()> {
+        self.storage
+            .device
+            .lock()
+            .await
+            .set_deniable_duress_passphrase(passphrase)
+            .await
+            .map_err(DatabaseError::from)?;
+        Ok(())
+    }
+
+    /// Time after which deniable messages that have not started sending are dropped,
+    /// `None` keeps them until they are sent
+    pub fn set_deniable_ttl(&mut self, ttl: Option<Duration>) {
+        self.deniable_ttl = ttl;
+    }
+
+    /// Take the deniable events that happened since they were last taken
+    pub fn take_deniable_events(&mut self) -> Vec<DeniableEvent> {
+        std::mem::take(&mut self.deniable_events)
+    }
+
+    /// Drop expired deniable payloads and mark the messages they belong to as failed.
+    async fn expire_deniable_payloads(&mut self) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("can get the time since epoch")
+            .as_secs();
+        let expired = self
+            .storage
+            .device
+            .lock()
+            .await
+            .remove_expired_deniable_payloads(now)
+            .await
+            .map_err(DatabaseError::from)?;
+
+        for (payload, message_timestamp) in expired {
+            let Some(timestamp) = message_timestamp else {
+                continue;
+            };
+            let Ok(DeniablePayload::SignalMessage(message)) = deserialize(&payload) else {
+                continue;
+            };
+            let Some(service_id) = message.destination_service_id else {
+                continue;
+            };
+            self.storage
+                .device
+                .lock()
+                .await
+                .fail_deniable_message(service_id, timestamp)
+                .await
+                .map_err(DatabaseError::from)?;
+        }
+        Ok(())
+    }
+
+    /// Store encrypted messages as deniable payloads waiting to be chunked.
+    /// `message_timestamp` links the payloads to the delivery state of a sent message.
+    async fn queue_deniable_messages(
+        &mut self,
+        msgs: HashMap<DeviceId, (u32, CiphertextMessage)>,
+        service_id: &ServiceId,
+        message_timestamp: Option<u64>,
+    ) -> Result<()> {
+        let expires_at = self.deniable_expires_at();
+        for (id, msg) in msgs {
+            let deniable_payload = DeniablePayload::SignalMessage(deniable_signal_message(
+                id, msg, service_id, expires_at,
+            ));
+            self.store_queued_deniable_payload(&deniable_payload, message_timestamp)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Time at which deniable messages queued now expire, `None` if they never do
+    fn deniable_expires_at(&self) -> Option<u64> {
+        self.deniable_ttl.map(|ttl| {
+            (SystemTime::now() + ttl)
+                .duration_since(UNIX_EPOCH)
+                .expect("can get the time since epoch")
+                .as_secs()
+        })
+    }
+
+    /// Store a message payload waiting to be chunked, as expiring if it has an expiry.
+    async fn store_queued_deniable_payload(
+        &self,
+        deniable_payload: &DeniablePayload,
+        message_timestamp: Option<u64>,
+    ) -> Result<()> {
+        let expires_at = match deniable_payload {
+            DeniablePayload::SignalMessage(message) => message.expires_at,
+            DeniablePayload::GroupMessage(message) => message.expires_at,
+            DeniablePayload::SyncMessage(message) => message.expires_at,
+            _ => None,
+        };
+        let deniable_payload_serialized =
+            serialize(deniable_payload).expect("Should serialize payload");
+
+        let device = self.storage.device.lock().await;
+        let stored = match expires_at {
+            Some(expires_at) => {
+                device
+                    .store_expiring_deniable_payload(
+                        deniable_payload_serialized,
+                        expires_at,
+                        message_timestamp,
+                    )
+                    .await
+            }
+            None => {
+                device
+                    .store_deniable_payload(None, 0, deniable_payload_serialized)
+                    .await
+            }
+        };
+        stored.map_err(DatabaseError::from)?;
+        Ok(())
+    }
+
+    pub async fn has_message(&mut self) -> bool {
+        self.server_api.has_message().await
+    }
+
+    /// Receive the next message from the server.
+    /// Returns the regular message, if it is not cover traffic, and any deniable messages.
+    pub async fn receive_message(
+        &mut self,
+    ) -> Result<(Option<ProcessedEnvelope>, Vec<ProcessedEnvelope>)> {
+        // I get Envelope from Server.
+        let request = self
+            .server_api
+            .get_message()
+            .await
+            .ok_or(ReceiveMessageError::NoMessageReceived)?;
+        let denim_msg = DenimMessage::decode(request.body())
+            .map_err(|_| ReceiveMessageError::DenimMessageDecodeError)?;
+        let carrier = self.server_api.observe_carrier(denim_msg.counter);
+        // The server must stick to the negotiated q value and fill the space it gives.
+        // The regular message was already taken off the server, so only the chunks are dropped.
+        let size_checked = self.chunker.check_message_size(&denim_msg);
+        if let Err(err) = &size_checked {
+            self.deniable_events
+                .push(DeniableEvent::OversizedMessage(*err));
+        }
+        // A repeated denim message was handled when it first arrived
+        if carrier == CarrierOrder::Duplicate {
+            self.server_api
+                .send_response(request, StatusCode::OK, vec![])
+                .await?;
+
+            return Ok((None, Vec::new()));
+        }
+        let envelope = match denim_msg.regular_payload {
             RegularPayload::Envelope(e) => e,
             _ => {
                 self.server_api
-                    .send_response(request, StatusCode::INTERNAL_SERVER_ERROR)
+                    .send_response(request, StatusCode::INTERNAL_SERVER_ERROR, vec![])
                     .await?;
 
                 return Err(ReceiveMessageError::EnvelopeDecodeError)?;
             }
         };
-        let mut processed = vec![
-            Envelope::decrypt(
-                envelope,
-                &mut self.storage.protocol_store.session_store,
-                &mut self.storage.protocol_store.identity_key_store,
-                &mut self.storage.protocol_store.pre_key_store,
-                &mut self.storage.protocol_store.signed_pre_key_store,
-                &mut self.storage.protocol_store.kyber_pre_key_store,
-                &mut OsRng,
+        // Cover envelopes from the server only exist to carry chunks
+        let regular = if envelope.r#type() == envelope::Type::Unknown {
+            None
+        } else {
+            Some(
+                Envelope::decrypt(
+                    envelope,
+                    &mut self.storage.protocol_store.session_store,
+                    &mut self.storage.protocol_store.identity_key_store,
+                    &mut self.storage.protocol_store.pre_key_store,
+                    &mut self.storage.protocol_store.signed_pre_key_store,
+                    &mut self.storage.protocol_store.kyber_pre_key_store,
+                    &mut OsRng,
+                )
+                .await?,
             )
-            .await?,
-        ];
-
-        let _ = self.server_api.send_response(request, StatusCode::OK).await;
+        };
+        // Null messages are cover traffic from other clients
+        let regular = regular.filter(|processed| {
+            processed
+                .content()
+                .is_ok_and(|content| content.null_message.is_none())
+        });
 
         let chunks: Vec<DenimChunk> = denim_msg
             .chunks
             .into_iter()
-            .filter(|chunk| !chunk.is_dummy())
+            .filter(|chunk| size_checked.is_ok() && !chunk.is_dummy())
             .collect();
-        if !chunks.is_empty() {
-            let deniable_payloads = self.handle_incoming_chunks(chunks).await?;
-            for deniable_payload in deniable_payloads {
-                match deniable_payload {
-                    DeniablePayload::Envelope(envelope) => {
-                        processed.push(
-                            Envelope::decrypt(
-                                envelope,
-                                &mut self.storage.protocol_store.deniable_store,
-                                &mut self.storage.protocol_store.deniable_identity_key_store,
-                                &mut self.storage.protocol_store.pre_key_store,
-                                &mut self.storage.protocol_store.signed_pre_key_store,
-                                &mut self.storage.protocol_store.kyber_pre_key_store,
-                                &mut OsRng,
-                            )
-                            .await?,
-                        );
-                    }
-                    DeniablePayload::KeyResponse(pre_key_response) => {
-                        let service_id =
-                            ServiceId::parse_from_service_id_string(pre_key_response.service_id())
-                                .expect("Should be service id");
-                        let bundles: Vec<PreKeyBundle> =
-                            Vec::<PreKeyBundle>::try_from(pre_key_response)
-                                .map_err(|err| SignalClientError::KeyError(err.to_string()))?;
-                        let device_ids = self
-                            .initialize_sessions_from_bundle(&service_id, &bundles, true)
-                            .await?;
-                        let alias = self
-                            .storage
-                            .device
-                            .lock()
-                            .await
-                            .try_get_key_request_sent(service_id.service_id_string())
+        let missing = carrier.missing_before(denim_msg.counter);
+        let deniable_store_open = self
+            .storage
+            .device
+            .lock()
+            .await
+            .is_deniable_store_open()
+            .await
+            .map_err(DatabaseError::from)?;
+        // Chunks would be lost with a store that is not kept, so they are rejected and the
+        // server sends them again later. The regular message is delivered either way.
+        if !deniable_store_open {
+            let status = if chunks.is_empty() {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            let _ = self.server_api.send_response(request, status, vec![]).await;
+            return Ok((regular, Vec::new()));
+        }
+        let received = if chunks.is_empty() {
+            Ok(Vec::new())
+        } else {
+            self.handle_incoming_chunks(chunks).await
+        };
+        // Chunks are only acknowledged once they are written to the deniable store
+        let flushed = self
+            .storage
+            .device
+            .lock()
+            .await
+            .flush_deniable_store()
+            .await
+            .map_err(DatabaseError::from);
+
+        // The server sends the chunks of the missing denim messages again
+        let (status, headers) = match (&flushed, missing) {
+            (Err(_), _) => (StatusCode::INTERNAL_SERVER_ERROR, vec![]),
+            (Ok(()), Some(missing)) => (StatusCode::OK, vec![retransmit_header(&[missing])]),
+            (Ok(()), None) => (StatusCode::OK, vec![]),
+        };
+        let _ = self
+            .server_api
+            .send_response(request, status, headers)
+            .await;
+
+        flushed?;
+        let deniable_payloads = received?;
+        let processed = self.handle_deniable_payloads(deniable_payloads).await?;
+
+        // The final message is stored within a DataMessage inside a Content.
+        Ok((regular, processed))
+    }
+
+    /// Handle reassembled deniable payloads, returns the envelopes with messages for the user
+    async fn handle_deniable_payloads(
+        &mut self,
+        deniable_payloads: Vec<DeniablePayload>,
+    ) -> Result<Vec<ProcessedEnvelope>> {
+        let mut processed = Vec::new();
+        for deniable_payload in deniable_payloads {
+            match deniable_payload {
+                DeniablePayload::Envelope(envelope) => {
+                    // Group messages are encrypted with the sender key of their source
+                    let decrypted = if envelope.r#type() == envelope::Type::KeyExchange {
+                        envelope
+                            .decrypt_sender_key(&mut self.storage.protocol_store.sender_key_store)
                             .await
-                            .map_err(DatabaseError::from)?
-                            .expect("Should contain key request");
-                        self.add_contact(&alias, &service_id, Some(device_ids))
+                    } else {
+                        Envelope::decrypt(
+                            envelope,
+                            &mut self.storage.protocol_store.deniable_store,
+                            &mut self.storage.protocol_store.deniable_identity_key_store,
+                            &mut self.storage.protocol_store.pre_key_store,
+                            &mut self.storage.protocol_store.signed_pre_key_store,
+                            &mut self.storage.protocol_store.kyber_pre_key_store,
+                            &mut OsRng,
+                        )
+                        .await
+                    };
+                    // The payloads are already taken off the deniable store, so one that can
+                    // not be decrypted must not cost the ones after it
+                    let envelope = match decrypted {
+                        Ok(envelope) => envelope,
+                        Err(err) => {
+                            self.deniable_events
+                                .push(DeniableEvent::UndecryptableEnvelope(err));
+                            continue;
+                        }
+                    };
+                    if let Some(distribution) = envelope
+                        .content
+                        .as_ref()
+                        .and_then(|content| content.sender_key_distribution_message.clone())
+                    {
+                        self.handle_deniable_sender_key_distribution(&envelope, &distribution)
                             .await?;
-                        self.storage
-                            .device
-                            .lock()
-                            .await
-                            .remove_key_request_sent(service_id.service_id_string())
-                            .await
-                            .map_err(DatabaseError::from)?;
-                        let messages = self
-                            .storage
-                            .device
-                            .lock()
-                            .await
-                            .get_messages_awaiting_encryption(alias.to_owned())
-                            .await
-                            .map_err(DatabaseError::from)?;
-                        for message in messages {
-                            self.send_deniable_message(&message, &alias).await?;
+                        continue;
+                    }
+                    if let Some(sync_message) = envelope
+                        .content
+                        .as_ref()
+                        .and_then(|content| content.sync_message.clone())
+                    {
+                        self.handle_deniable_sync(&envelope, sync_message).await?;
+                        continue;
+                    }
+                    let receipt = envelope
+                        .content
+                        .as_ref()
+                        .and_then(|content| content.receipt_message.clone());
+                    match receipt {
+                        Some(receipt) => self.handle_deniable_receipt(&envelope, receipt).await?,
+                        None => {
+                            self.send_deniable_receipt(&envelope, receipt_message::Type::Delivery)
+                                .await?;
+                            processed.push(envelope);
                         }
                     }
-                    _ => todo!(),
                 }
+                DeniablePayload::KeyResponse(pre_key_response) => {
+                    self.handle_deniable_key_response(pre_key_response).await?;
+                }
+                DeniablePayload::IdentifierResponse(identifier_response) => {
+                    // Responses to lookups that were never requested are ignored
+                    let phone_number = identifier_response.phone_number;
+                    let Some(alias) = self
+                        .storage
+                        .device
+                        .lock()
+                        .await
+                        .try_get_identifier_request_sent(phone_number.clone())
+                        .await
+                        .map_err(DatabaseError::from)?
+                    else {
+                        continue;
+                    };
+                    self.storage
+                        .device
+                        .lock()
+                        .await
+                        .remove_identifier_request_sent(phone_number.clone())
+                        .await
+                        .map_err(DatabaseError::from)?;
+                    let Some(pre_key_response) = identifier_response.keys else {
+                        self.deniable_events
+                            .push(DeniableEvent::NoDeniableAccount(phone_number));
+                        continue;
+                    };
+                    let Some(service_id) =
+                        ServiceId::parse_from_service_id_string(pre_key_response.service_id())
+                    else {
+                        continue;
+                    };
+                    self.start_deniable_conversation(&alias, &service_id, pre_key_response)
+                        .await?;
+                }
+                // Requests, key uploads and outgoing messages are only handled by the server
+                DeniablePayload::KeyRequest(_)
+                | DeniablePayload::IdentifierRequest(_)
+                | DeniablePayload::KeyUpload(_)
+                | DeniablePayload::SignalMessage(_)
+                | DeniablePayload::GroupMessage(_)
+                | DeniablePayload::SyncMessage(_) => {}
             }
         }
 
-        // The final message is stored within a DataMessage inside a Content.
         Ok(processed)
     }
 
+    /// Set up sessions from deniable keys that were requested, responses to keys that were
+    /// never requested are ignored. The keys of this account are for its other devices.
+    async fn handle_deniable_key_response(
+        &mut self,
+        pre_key_response: PreKeyResponse,
+    ) -> Result<()> {
+        let Some(service_id) =
+            ServiceId::parse_from_service_id_string(pre_key_response.service_id())
+        else {
+            return Ok(());
+        };
+        let Some(alias) = self
+            .storage
+            .device
+            .lock()
+            .await
+            .try_get_key_request_sent(service_id.service_id_string())
+            .await
+            .map_err(DatabaseError::from)?
+        else {
+            return Ok(());
+        };
+        self.storage
+            .device
+            .lock()
+            .await
+            .remove_key_request_sent(service_id.service_id_string())
+            .await
+            .map_err(DatabaseError::from)?;
+        if service_id == ServiceId::from(self.aci) {
+            self.start_deniable_sync(pre_key_response).await
+        } else {
+            self.start_deniable_conversation(&alias, &service_id, pre_key_response)
+                .await
+        }
+    }
+
+    /// Reassemble deniable payloads from new and previously stored chunks.
+    /// Chunks of incomplete payloads are stored until the rest arrives.
     pub async fn handle_incoming_chunks(
         &mut self,
         new_chunks: Vec<DenimChunk>,
     ) -> Result<Vec<DeniablePayload>> {
-        let mut chunks = if new_chunks.iter().any(|chunk| chunk.is_final()) {
-            self.storage
-                .device
-                .lock()
-                .await
-                .get_and_remove_incoming_deniable_chunks()
-                .await
-                .map_err(DatabaseError::from)?
-        } else {
-            Vec::new()
-        };
-
-        let mut deniable_payloads = Vec::new();
-        for chunk in new_chunks {
-            if chunk.is_final() {
-                chunks.sort();
-                chunks.push(chunk.clone());
-                let deniable_bytes: Vec<u8> = chunks.into_iter().flat_map(|c| c.chunk).collect();
-                let deniable_payload =
-                    deserialize(&deniable_bytes).expect("Should be deniable payload");
-                deniable_payloads.push(deniable_payload);
-
-                chunks = Vec::new();
-            } else {
-                chunks.push(chunk.clone());
-            }
-        }
+        let (deniable_payloads, _, errors) = self
+            .storage
+            .protocol_store
+            .deniable_store
+            .receive_chunks(new_chunks)
+            .await?;
 
-        if !chunks.is_empty() {
-            self.storage
-                .device
-                .lock()
-                .await
-                .store_incoming_deniable_chunks(chunks)
-                .await
-                .map_err(DatabaseError::from)?;
-        }
+        // Malformed chunks or payloads must not keep the valid ones from being handled
+        self.deniable_events
+            .extend(errors.into_iter().map(DeniableEvent::DroppedPayload));
 
         Ok(deniable_payloads)
     }
@@ -707,9 +2036,89 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
         self.update_contact(alias, new_device_ids).await
     }
 
-    pub async fn add_deniable_contact_and_queue_message(
+    /// Add the devices of a deniable contact, creating the contact with the nickname `alias`
+    /// if there is none yet. Deniable contacts are only kept in the deniable store.
+    pub async fn add_deniable_contact(
+        &mut self,
+        alias: &str,
+        service_id: &ServiceId,
+        device_ids: Vec<DeviceId>,
+    ) -> Result<()> {
+        if self
+            .deniable_contact_manager
+            .get_contact(service_id)
+            .is_err()
+        {
+            self.deniable_contact_manager
+                .add_contact(service_id)
+                .map_err(SignalClientError::ContactManagerError)?;
+            self.storage
+                .device
+                .lock()
+                .await
+                .insert_service_id_for_deniable_nickname(alias, service_id)
+                .await
+                .map_err(DatabaseError::from)?;
+        }
+        self.update_deniable_contact(service_id, device_ids).await
+    }
+
+    async fn update_deniable_contact(
+        &mut self,
+        service_id: &ServiceId,
+        device_ids: Vec<DeviceId>,
+    ) -> Result<()> {
+        self.deniable_contact_manager
+            .update_contact(service_id, device_ids)
+            .map_err(SignalClientError::ContactManagerError)?;
+        let contact = self
+            .deniable_contact_manager
+            .get_contact(service_id)
+            .map_err(SignalClientError::ContactManagerError)?;
+        self.storage
+            .device
+            .lock()
+            .await
+            .store_deniable_contact(contact)
+            .await
+            .map_err(DatabaseError::from)?;
+        Ok(())
+    }
+
+    /// Set up sessions with the devices of a deniable contact from the keys the server sent
+    /// and send the messages that waited for them
+    async fn start_deniable_conversation(
         &mut self,
+        alias: &str,
         service_id: &ServiceId,
+        pre_key_response: PreKeyResponse,
+    ) -> Result<()> {
+        let bundles: Vec<PreKeyBundle> = Vec::<PreKeyBundle>::try_from(pre_key_response)
+            .map_err(|err| SignalClientError::KeyError(err.to_string()))?;
+        let device_ids = self
+            .initialize_sessions_from_bundle(service_id, &bundles, true)
+            .await?;
+        self.add_deniable_contact(alias, service_id, device_ids)
+            .await?;
+        let messages = self
+            .storage
+            .device
+            .lock()
+            .await
+            .get_messages_awaiting_encryption(alias.to_owned())
+            .await
+            .map_err(DatabaseError::from)?;
+        for message in messages {
+            self.send_deniable_message(&message, alias).await?;
+        }
+        Ok(())
+    }
+
+    /// Queue a deniable message to the account behind the phone number `alias`.
+    /// The account and its keys are looked up through the deniable channel,
+    /// so no regular request reveals who is contacted.
+    pub async fn add_deniable_contact_and_queue_message(
+        &mut self,
         text: &str,
         alias: &str,
     ) -> Result<()> {
@@ -718,16 +2127,17 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
             .device
             .lock()
             .await
-            .try_get_key_request_sent(service_id.service_id_string())
+            .try_get_identifier_request_sent(alias.to_owned())
             .await
             .map_err(DatabaseError::from)?
             .is_none()
         {
-            let deniable_keyrequest_payload = DeniablePayload::KeyRequest(PreKeyRequest {
-                service_id: service_id.service_id_string(),
-            });
+            let deniable_identifier_request_payload =
+                DeniablePayload::IdentifierRequest(IdentifierRequest {
+                    phone_number: alias.to_owned(),
+                });
             let deniable_payload_serialized =
-                serialize(&deniable_keyrequest_payload).expect("Should serialize payload");
+                serialize(&deniable_identifier_request_payload).expect("Should serialize payload");
 
             self.storage
                 .device
@@ -740,7 +2150,7 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
                 .device
                 .lock()
                 .await
-                .store_key_request_sent(service_id.service_id_string(), alias.to_owned())
+                .store_identifier_request_sent(alias.to_owned(), alias.to_owned())
                 .await
                 .map_err(DatabaseError::from)?;
         }
@@ -850,3 +2260,265 @@ impl<T: ClientDB, U: SignalServerAPI> Client<T, U> {
         Ok(device_ids)
     }
 }
+
+/// Deniable message carrying `msg`, encrypted for device `id` of `service_id`
+fn deniable_signal_message(
+    id: DeviceId,
+    msg: (u32, CiphertextMessage),
+    service_id: &ServiceId,
+    expires_at: Option<u64>,
+) -> SignalMessage {
+    SignalMessage {
+        r#type: match msg.1 {
+            CiphertextMessage::SignalMessage(_) => envelope::Type::Ciphertext.into(),
+            CiphertextMessage::SenderKeyMessage(_) => envelope::Type::KeyExchange.into(),
+            CiphertextMessage::PreKeySignalMessage(_) => envelope::Type::PrekeyBundle.into(),
+            CiphertextMessage::PlaintextContent(_) => envelope::Type::PlaintextContent.into(),
+        },
+        destination_device_id: id.into(),
+        destination_service_id: Some(service_id.service_id_string()),
+        destination_registration_id: msg.0,
+        content: BASE64_STANDARD.encode(msg.1.serialize()),
+        expires_at,
+    }
+}
+
+#[cfg(test)]
+mod client_tests {
+    use super::*;
+    use crate::test_utils::{
+        server::MockServer,
+        user::{new_aci, new_pni, new_service_id},
+    };
+    use common::web_api::{DeniableKeyUpload, IdentifierResponse, PreKeyResponseItem};
+    use libsignal_protocol::IdentityKey;
+
+    /// Client for device `device_id` of the account `aci`, with deniable keys
+    async fn client(aci: Aci, device_id: DeviceId) -> Client<Device, MockServer> {
+        let conn = Client::<Device, MockServer>::connect_to_db(":memory:")
+            .await
+            .unwrap();
+        let device = Arc::new(Mutex::new(Device::new(
+            conn,
+            DeniableVault::in_memory().unwrap(),
+        )));
+        {
+            let mut device = device.lock().await;
+            device
+                .insert_account_information(aci, new_pni(), "password".to_owned())
+                .await
+                .unwrap();
+            device
+                .insert_account_key_information(
+                    IdentityKeyPair::generate(&mut OsRng),
+                    OsRng.gen_range(1..16383),
+                )
+                .await
+                .unwrap();
+            device.set_device_id(device_id).await.unwrap();
+        }
+        let mut client = Client::new(
+            "alias".to_owned(),
+            aci,
+            new_pni(),
+            ContactManager::new(),
+            ContactManager::new(),
+            MockServer,
+            KeyManager::default(),
+            Storage::new(device.clone(), ProtocolStore::new(device)),
+            Chunker::new(1.0),
+        );
+        client.queue_deniable_key_upload().await.unwrap();
+        client
+    }
+
+    /// Remove the oldest deniable payload waiting to be sent
+    async fn take_queued_payload(client: &Client<Device, MockServer>) -> DeniablePayload {
+        let device = client.storage.device.lock().await;
+        let (id, content, _, _) = device.get_deniable_payload().await.unwrap();
+        device.remove_deniable_payload(id).await.unwrap();
+        deserialize(&content).unwrap()
+    }
+
+    async fn take_key_upload(client: &Client<Device, MockServer>) -> DeniableKeyUpload {
+        match take_queued_payload(client).await {
+            DeniablePayload::KeyUpload(key_upload) => key_upload,
+            payload => panic!("Expected a key upload, got {payload:?}"),
+        }
+    }
+
+    /// Keys of device `device_id` the server sends from its deniable key upload
+    fn key_response_item(device_id: DeviceId, key_upload: DeniableKeyUpload) -> PreKeyResponseItem {
+        PreKeyResponseItem::new(
+            device_id,
+            key_upload.registration_id,
+            key_upload.pre_keys.into_iter().next(),
+            key_upload.pq_last_resort_pre_key,
+            key_upload.signed_pre_key,
+        )
+        .with_identity_key(IdentityKey::decode(&key_upload.identity_key).unwrap())
+    }
+
+    #[tokio::test]
+    async fn undecryptable_envelope_does_not_stop_the_payloads_after_it() {
+        let mut client = client(new_aci(), 1.into()).await;
+        client
+            .storage
+            .device
+            .lock()
+            .await
+            .store_identifier_request_sent("+4512345678".to_owned(), "bob".to_owned())
+            .await
+            .unwrap();
+
+        let processed = client
+            .handle_deniable_payloads(vec![
+                DeniablePayload::Envelope(Envelope {
+                    r#type: Some(envelope::Type::Ciphertext.into()),
+                    content: Some(vec![1, 2, 3]),
+                    ..Default::default()
+                }),
+                DeniablePayload::IdentifierResponse(IdentifierResponse {
+                    phone_number: "+4512345678".to_owned(),
+                    keys: None,
+                }),
+            ])
+            .await
+            .unwrap();
+
+        assert!(processed.is_empty());
+        assert!(matches!(
+            client.take_deniable_events().as_slice(),
+            [
+                DeniableEvent::UndecryptableEnvelope(_),
+                DeniableEvent::NoDeniableAccount(phone_number),
+            ] if phone_number == "+4512345678"
+        ));
+    }
+
+    #[tokio::test]
+    async fn deniable_attachment_waits_for_cover_upload() {
+        let mut client = client(new_aci(), 1.into()).await;
+        take_key_upload(&client).await;
+        client
+            .storage
+            .device
+            .lock()
+            .await
+            .insert_service_id_for_deniable_nickname("bob", &new_service_id())
+            .await
+            .unwrap();
+
+        // The mock server panics if it is reached, so nothing is uploaded yet
+        client
+            .send_deniable_attachment(&[7; 1000], "photo.jpg", "bob")
+            .await
+            .unwrap();
+        assert!(client
+            .send_deniable_attachment(&[7; 1000], "photo.jpg", "carol")
+            .await
+            .is_err());
+
+        assert_eq!(client.deniable_uploads.len(), 1);
+        assert!(client
+            .storage
+            .device
+            .lock()
+            .await
+            .get_deniable_payload()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn linked_device_syncs_to_device_without_session() {
+        let aci = new_aci();
+        let mut primary = client(aci, 1.into()).await;
+        let mut linked = client(aci, 2.into()).await;
+        let primary_keys = take_key_upload(&primary).await;
+        let linked_keys = take_key_upload(&linked).await;
+
+        let message = DataMessage {
+            body: Some("hello".to_owned()),
+            ..Default::default()
+        };
+        linked
+            .queue_deniable_sync(&new_service_id(), "bob", 1, message)
+            .await
+            .unwrap();
+        assert_eq!(
+            take_queued_payload(&linked).await,
+            DeniablePayload::KeyRequest(PreKeyRequest {
+                service_id: aci.service_id_string(),
+            })
+        );
+
+        // The server answers with the keys of all devices of the account
+        let key_response = PreKeyResponse::new(
+            aci.service_id_string(),
+            IdentityKey::decode(&primary_keys.identity_key).unwrap(),
+            vec![
+                key_response_item(1.into(), primary_keys),
+                key_response_item(2.into(), linked_keys),
+            ],
+        );
+        linked
+            .handle_deniable_key_response(key_response)
+            .await
+            .unwrap();
+        let sync_message = match take_queued_payload(&linked).await {
+            DeniablePayload::SyncMessage(sync_message) => sync_message,
+            payload => panic!("Expected a sync message, got {payload:?}"),
+        };
+        assert_eq!(sync_message.messages.len(), 1);
+        let message = &sync_message.messages[0];
+        assert_eq!(message.destination_device_id, 1);
+
+        let envelope = Envelope {
+            r#type: Some(message.r#type),
+            source_service_id: Some(aci.service_id_string()),
+            source_device: Some(2),
+            content: Some(BASE64_STANDARD.decode(&message.content).unwrap()),
+            ..Default::default()
+        }
+        .decrypt(
+            &mut primary.storage.protocol_store.deniable_store,
+            &mut primary.storage.protocol_store.deniable_identity_key_store,
+            &mut primary.storage.protocol_store.pre_key_store,
+            &mut primary.storage.protocol_store.signed_pre_key_store,
+            &mut primary.storage.protocol_store.kyber_pre_key_store,
+            &mut OsRng,
+        )
+        .await
+        .unwrap();
+        let sync = envelope
+            .content
+            .as_ref()
+            .and_then(|content| content.sync_message.clone())
+            .unwrap();
+        primary.handle_deniable_sync(&envelope, sync).await.unwrap();
+
+        assert!(matches!(
+            primary.take_deniable_events().as_slice(),
+            [DeniableEvent::SyncedMessage { destination, body }]
+                if destination == "bob" && body == "hello"
+        ));
+        assert_eq!(
+            primary
+                .deniable_contact_manager
+                .get_contact(&ServiceId::from(aci))
+                .unwrap()
+                .device_ids,
+            HashSet::from([DeviceId::from(2)])
+        );
+
+        // The own devices are deniable contacts, which the regular database knows nothing of
+        assert!(primary
+            .contact_manager
+            .get_contact(&ServiceId::from(aci))
+            .is_err());
+        let device = primary.storage.device.lock().await;
+        assert!(device.load_contacts().await.unwrap().is_empty());
+        assert_eq!(device.load_deniable_contacts().await.unwrap().len(), 1);
+    }
+}
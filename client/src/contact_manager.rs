@@ -66,6 +66,10 @@ impl ContactManager {
             ))
     }
 
+    pub fn get_contacts(&self) -> Vec<&Contact> {
+        self.contacts.values().collect()
+    }
+
     fn get_contact_mut(
         &mut self,
         service_id: &ServiceId,
@@ -2,7 +2,10 @@ use std::error::Error;
 use std::fmt;
 use std::fmt::Display;
 
-use common::{protocol_address::ParseProtocolAddressError, SignalError};
+use common::{
+    attachment::AttachmentError, deniable::reassembly::ReassemblyError,
+    protocol_address::ParseProtocolAddressError, SignalError,
+};
 use derive_more::derive::{Display, Error, From};
 use libsignal_core::{DeviceId, ServiceId};
 use libsignal_protocol::SignalProtocolError;
@@ -33,6 +36,11 @@ pub enum SignalClientError {
     ProcessPreKeyBundle(ProcessPreKeyBundleError),
     #[display("Tried to get a session that does not exist")]
     NoSession,
+    #[display("No deniable group named '{_0}'")]
+    DeniableGroupNotFound(String),
+    AttachmentError(String),
+    #[from]
+    AttachmentCrypto(AttachmentError),
     #[from]
     Protocol(SignalProtocolError),
     #[from]
@@ -143,7 +151,10 @@ pub enum ReceiveMessageError {
     ProtobufDecodeContentError(prost::DecodeError),
     InvalidMessageContent,
     NoMessageReceived,
+    DenimMessageDecodeError,
     EnvelopeDecodeError,
+    DeniableChunkError(ReassemblyError),
+    DeniablePayloadDecodeError(bincode::Error),
 }
 
 impl From<ParseProtocolAddressError> for SignalClientError {
@@ -0,0 +1,20 @@
+use crate::errors::ReceiveMessageError;
+use common::{deniable::chunk::DenimSizeError, SignalError};
+use derive_more::derive::Display;
+
+/// Deniable activity the client handled on its own that the user may want to know about
+#[derive(Debug, Display)]
+pub enum DeniableEvent {
+    #[display("Dropped the deniable chunks of a denim message: {_0}")]
+    OversizedMessage(DenimSizeError),
+    #[display("No account with deniable keys found for {_0}")]
+    NoDeniableAccount(String),
+    #[display("No deniable session with group member {_0}, it was left out of the message")]
+    SkippedGroupMember(String),
+    #[display("Dropped deniable payload: {_0}")]
+    DroppedPayload(ReceiveMessageError),
+    #[display("Dropped deniable envelope that could not be decrypted: {_0}")]
+    UndecryptableEnvelope(SignalError),
+    #[display("Deniable to {destination} from another device: {body}")]
+    SyncedMessage { destination: String, body: String },
+}
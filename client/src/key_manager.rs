@@ -1,7 +1,7 @@
 use crate::storage::database::ClientDB;
 use crate::storage::generic::ProtocolStore;
 use common::utils::time_now;
-use common::web_api::{SetKeyRequest, UploadPreKey, UploadSignedPreKey};
+use common::web_api::{DeniableKeyUpload, SetKeyRequest, UploadPreKey, UploadSignedPreKey};
 use derive_more::derive::{Display, Error, From};
 use libsignal_protocol::{
     kem, GenericSignedPreKey, IdentityKeyStore, KeyPair, KyberPreKeyRecord, KyberPreKeyStore,
@@ -11,6 +11,9 @@ use rand::rngs::OsRng;
 use rand::{CryptoRng, Rng};
 use std::collections::HashMap;
 
+/// Number of one time prekeys in a deniable key upload, kept low as it travels in chunks
+const DENIABLE_PRE_KEY_COUNT: usize = 10;
+
 #[derive(Debug, Clone, Hash, Eq, PartialEq, Display)]
 pub enum PreKeyType {
     #[display("signed pre key")]
@@ -222,6 +225,64 @@ impl KeyManager {
             Some(UploadSignedPreKey::from(pq_last_resort_pre_key)),
         ))
     }
+
+    /// Generate prekeys signed by the deniable identity key. They are kept in the regular
+    /// prekey stores, the key ids are shared so they never collide with the regular prekeys.
+    pub async fn generate_deniable_key_bundle<T: ClientDB>(
+        &mut self,
+        store: &mut ProtocolStore<T>,
+    ) -> Result<DeniableKeyUpload, KeyManagerError> {
+        let mut pre_keys: Vec<UploadPreKey> = Vec::new();
+        let mut rng = OsRng;
+
+        for _ in 0..DENIABLE_PRE_KEY_COUNT {
+            pre_keys.push(UploadPreKey::from(
+                self.generate_pre_key(&mut store.pre_key_store, &mut rng)
+                    .await?,
+            ));
+        }
+
+        let signed_pre_key = self
+            .generate_signed_pre_key(
+                &mut store.deniable_identity_key_store,
+                &mut store.signed_pre_key_store,
+                &mut rng,
+            )
+            .await?;
+        let pq_last_resort_pre_key = self
+            .generate_kyber_pre_key(
+                &mut store.deniable_identity_key_store,
+                &mut store.kyber_pre_key_store,
+            )
+            .await?;
+
+        let identity_key_pair = store
+            .deniable_identity_key_store
+            .get_identity_key_pair()
+            .await
+            .map_err(|error| KeyManagerError {
+                key_type: KeyType::IdentityKey,
+                err_type: KeyManagerErrorType::Get,
+                error,
+            })?;
+        let registration_id = store
+            .deniable_identity_key_store
+            .get_local_registration_id()
+            .await
+            .map_err(|error| KeyManagerError {
+                key_type: KeyType::IdentityKey,
+                err_type: KeyManagerErrorType::Get,
+                error,
+            })?;
+
+        Ok(DeniableKeyUpload {
+            identity_key: identity_key_pair.identity_key().serialize(),
+            registration_id,
+            signed_pre_key: UploadSignedPreKey::from(signed_pre_key),
+            pq_last_resort_pre_key: UploadSignedPreKey::from(pq_last_resort_pre_key),
+            pre_keys,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -1,4 +1,7 @@
 use client::Client;
+use common::deniable::cover_traffic::{CoverTrafficSchedule, CoverTrafficScheduler};
+use common::deniable::q_value::QValueRange;
+use common::envelope::ProcessedEnvelope;
 use dotenv::dotenv;
 use regex::Regex;
 use server::SignalServer;
@@ -7,13 +10,16 @@ use std::{
     error::Error,
     fs,
     path::{Path, PathBuf},
+    time::Duration,
 };
 use storage::device::Device;
+use tokio::sync::mpsc;
 
 mod client;
 mod contact_manager;
 mod encryption;
 mod errors;
+mod event;
 mod key_manager;
 mod persistent_receiver;
 mod server;
@@ -38,11 +44,20 @@ async fn make_client(
     phone: &str,
     certificate_path: &Option<String>,
     server_url: &str,
+    q_value: Option<QValueRange>,
+    deniable_passphrase: Option<&str>,
 ) -> Client<Device, SignalServer> {
     let db_path = client_db_path() + "/" + name + ".db";
     let client = if Path::exists(Path::new(&db_path)) {
-        Client::<Device, SignalServer>::login(&db_path, certificate_path, server_url, phone.into())
-            .await
+        Client::<Device, SignalServer>::login(
+            &db_path,
+            certificate_path,
+            server_url,
+            phone.into(),
+            q_value,
+            deniable_passphrase,
+        )
+        .await
     } else {
         Client::<Device, SignalServer>::register(
             name,
@@ -51,12 +66,23 @@ async fn make_client(
             server_url,
             certificate_path,
             phone.into(),
+            q_value,
+            deniable_passphrase,
         )
         .await
     };
     client.expect("Failed to create client")
 }
 
+/// Read the passphrase of the deniable store, an empty line leaves the store locked
+fn read_deniable_passphrase() -> Result<Option<String>, Box<dyn Error>> {
+    println!("Deniable passphrase (leave empty to skip):");
+    let mut passphrase = String::new();
+    std::io::stdin().read_line(&mut passphrase)?;
+    let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+    Ok((!passphrase.is_empty()).then(|| passphrase.to_owned()))
+}
+
 fn get_server_info() -> (Option<String>, String) {
     let use_tls = !env::args().any(|arg| arg == "--no-tls");
     // println!("Using tls: {}", use_tls);
@@ -76,24 +102,102 @@ fn get_server_info() -> (Option<String>, String) {
     }
 }
 
+/// Download the attachments of a message into `attachments`.
+/// Attachments are always fetched, so fetching deniable ones does not stand out.
+async fn save_attachments(client: &Client<Device, SignalServer>, msg: &ProcessedEnvelope) {
+    let attachments = msg
+        .content
+        .as_ref()
+        .and_then(|content| content.data_message.as_ref())
+        .map(|data_message| data_message.attachments.clone())
+        .unwrap_or_default();
+    for pointer in attachments {
+        let file_name = Path::new(pointer.file_name())
+            .file_name()
+            .map(|file_name| file_name.to_owned())
+            .unwrap_or("attachment".into());
+        let path = Path::new("attachments").join(file_name);
+        let saved = match client.download_attachment(&pointer).await {
+            Ok(attachment) => fs::create_dir_all("attachments")
+                .and_then(|_| fs::write(&path, attachment))
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        match saved {
+            Ok(()) => println!("Saved attachment to {}", path.display()),
+            Err(err) => println!("Could not save attachment: {err}"),
+        }
+    }
+}
+
 async fn receive_message(client: &mut Client<Device, SignalServer>) {
-    let msgs = client.receive_message().await.expect("Expected Message");
-    for (i, msg) in msgs.iter().enumerate() {
+    let (regular, deniable) = client.receive_message().await.expect("Expected Message");
+    for (msg, is_deniable) in regular
+        .iter()
+        .map(|msg| (msg, false))
+        .chain(deniable.iter().map(|msg| (msg, true)))
+    {
         let msg_text = msg.try_get_message_as_string().expect("No Text Content");
         let msg_name = msg.try_get_name_as_string().expect("No Name Content");
-        client
-            .add_contact(
-                &msg_name,
-                &msg.source_service_id().expect("Should contain service id"),
-                Some(vec![msg.source_device.expect("Should contain device id")]),
-            )
-            .await
-            .expect("Should add contact");
-        match i {
-            0 => println!("{msg_name}: {msg_text}"),
-            _ => println!("Deniable {msg_name}: {msg_text}"),
+        let service_id = msg.source_service_id().expect("Should contain service id");
+        let device_id = msg.source_device.expect("Should contain device id");
+        if is_deniable {
+            // Senders of deniable messages must not show up among the regular contacts
+            client
+                .add_deniable_contact(&msg_name, &service_id, vec![device_id])
+                .await
+                .expect("Should add deniable contact");
+            match client
+                .get_deniable_group_name(msg)
+                .await
+                .expect("Should look up group")
+            {
+                Some(group_name) => println!("Deniable {group_name} {msg_name}: {msg_text}"),
+                None => println!("Deniable {msg_name}: {msg_text}"),
+            }
+            client
+                .send_deniable_read_receipt(msg)
+                .await
+                .expect("Should queue read receipt");
+        } else {
+            client
+                .add_contact(&msg_name, &service_id, Some(vec![device_id]))
+                .await
+                .expect("Should add contact");
+            println!("{msg_name}: {msg_text}");
         }
+        save_attachments(client, msg).await;
+    }
+    print_deniable_events(client);
+}
+
+fn print_deniable_events(client: &mut Client<Device, SignalServer>) {
+    for event in client.take_deniable_events() {
+        println!("{event}");
+    }
+}
+
+async fn send_attachment(
+    client: &mut Client<Device, SignalServer>,
+    path: &str,
+    alias: &str,
+    deniable: bool,
+) -> Result<(), Box<dyn Error>> {
+    let attachment = fs::read(path)?;
+    let file_name = Path::new(path)
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .unwrap_or("attachment");
+    if deniable {
+        client
+            .send_deniable_attachment(&attachment, file_name, alias)
+            .await?;
+    } else {
+        client
+            .send_attachment(&attachment, file_name, alias)
+            .await?;
     }
+    Ok(())
 }
 
 async fn receive_all_messages(client: &mut Client<Device, SignalServer>) {
@@ -102,6 +206,29 @@ async fn receive_all_messages(client: &mut Client<Device, SignalServer>) {
     }
 }
 
+async fn next_cover_tick(cover_traffic: &mut Option<CoverTrafficScheduler>) {
+    match cover_traffic {
+        Some(scheduler) => scheduler.tick().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn spawn_input_reader() -> mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || loop {
+        let mut input = String::new();
+        match std::io::stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                if tx.send(input).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
@@ -114,20 +241,66 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .unwrap_or(false);
 
     let (cert_path, server_url) = get_server_info();
-    let mut user = make_client(&args[1], &args[2], &cert_path, &server_url).await;
+    let q_value = QValueRange::from_env("Q_VALUE")?;
+    let deniable_passphrase = read_deniable_passphrase()?;
+    let mut user = make_client(
+        &args[1],
+        &args[2],
+        &cert_path,
+        &server_url,
+        q_value,
+        deniable_passphrase.as_deref(),
+    )
+    .await;
+    let deniable_ttl = var("DENIABLE_TTL")
+        .ok()
+        .map(|ttl| ttl.trim().parse().map(Duration::from_secs))
+        .transpose()?;
+    user.set_deniable_ttl(deniable_ttl);
 
     if debug_print {
         println!("Started client with id: {}", &user.aci.service_id_string());
     }
 
+    let mut cover_traffic =
+        CoverTrafficSchedule::from_env("COVER_TRAFFIC")?.map(CoverTrafficScheduler::new);
+    let mut cover_uploads =
+        CoverTrafficSchedule::from_env("COVER_UPLOADS")?.map(CoverTrafficScheduler::new);
+    let mut input_reader = spawn_input_reader();
+
     let send_regex = Regex::new(r"send:(?<alias>\w+):(?<text>(\w+\s)*)").unwrap();
     let denim_regex = Regex::new(r"denim:(?<alias>\w+):(?<text>(\w+\s)*)").unwrap();
+    let attach_regex = Regex::new(r"attach:(?<alias>\w+):(?<path>\S+)").unwrap();
+    let denim_attach_regex = Regex::new(r"denimattach:(?<alias>\w+):(?<path>\S+)").unwrap();
+    let group_regex = Regex::new(r"group:(?<name>\w+):(?<members>\w+(,\w+)*)").unwrap();
+    let denim_group_regex = Regex::new(r"denimgroup:(?<name>\w+):(?<text>(\w+\s)*)").unwrap();
+    let duress_regex = Regex::new(r"duress:(?<passphrase>.+)").unwrap();
     loop {
         if debug_print {
             println!("Enter command: ");
         }
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input)?;
+        let input = loop {
+            tokio::select! {
+                input = input_reader.recv() => break input,
+                _ = next_cover_tick(&mut cover_traffic) => {
+                    if let Err(err) = user.send_cover_message().await {
+                        if debug_print {
+                            println!("Could not send cover message: {err}");
+                        }
+                    }
+                }
+                _ = next_cover_tick(&mut cover_uploads) => {
+                    if let Err(err) = user.send_cover_upload().await {
+                        if debug_print {
+                            println!("Could not send cover upload: {err}");
+                        }
+                    }
+                }
+            }
+        };
+        let Some(input) = input else {
+            break;
+        };
         if input.starts_with("send") {
             if let Some(caps) = send_regex.captures(&input) {
                 if user
@@ -151,6 +324,54 @@ async fn main() -> Result<(), Box<dyn Error>> {
             } else {
                 println!("Not valid send command format")
             };
+        } else if input.starts_with("attach") {
+            if let Some(caps) = attach_regex.captures(&input) {
+                if let Err(err) =
+                    send_attachment(&mut user, &caps["path"], &caps["alias"], false).await
+                {
+                    println!("{}", err);
+                }
+            } else {
+                println!("Not valid attachment command format")
+            };
+        } else if input.starts_with("denimattach") {
+            if let Some(caps) = denim_attach_regex.captures(&input) {
+                if let Err(err) =
+                    send_attachment(&mut user, &caps["path"], &caps["alias"], true).await
+                {
+                    println!("{}", err);
+                } else if cover_uploads.is_none() {
+                    println!("Deniable attachments are sent with cover uploads, set COVER_UPLOADS");
+                }
+            } else {
+                println!("Not valid deniable attachment command format")
+            };
+        } else if input.starts_with("group") {
+            if let Some(caps) = group_regex.captures(&input) {
+                let members = caps["members"].split(',').collect::<Vec<&str>>();
+                if let Err(err) = user.create_deniable_group(&caps["name"], &members).await {
+                    println!("{}", err);
+                }
+            } else {
+                println!("Not valid deniable group command format")
+            };
+        } else if input.starts_with("denimgroup") {
+            if let Some(caps) = denim_group_regex.captures(&input) {
+                if let Err(err) = user
+                    .send_deniable_group_message(&caps["text"], &caps["name"])
+                    .await
+                {
+                    println!("{}", err);
+                }
+                print_deniable_events(&mut user);
+            } else {
+                println!("Not valid deniable group send command format")
+            };
+        } else if input.starts_with("denimstore") {
+            match user.create_deniable_store().await {
+                Ok(()) => println!("Deniable store created"),
+                Err(err) => println!("{}", err),
+            }
         } else if input.starts_with("denim") {
             if let Some(caps) = denim_regex.captures(&input) {
                 if user
@@ -158,31 +379,34 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     .await
                     .is_err()
                 {
-                    match user.get_service_id_from_server(&caps["alias"]).await {
-                        Ok(service_id) => {
-                            user.add_deniable_contact_and_queue_message(
-                                &service_id,
-                                &caps["text"],
-                                &caps["alias"],
-                            )
-                            .await
-                            .expect("No bob?");
-                        }
-                        Err(err) => {
-                            println!("{}", err);
-                            continue;
-                        }
-                    }
+                    user.add_deniable_contact_and_queue_message(&caps["text"], &caps["alias"])
+                        .await
+                        .expect("No bob?");
                 };
             } else {
                 println!("Not valid deniable send command format")
             };
+        } else if input.starts_with("duress") {
+            if let Some(caps) = duress_regex.captures(input.trim_end_matches(['\r', '\n'])) {
+                match user.set_duress_passphrase(&caps["passphrase"]).await {
+                    Ok(()) => println!("Duress passphrase set"),
+                    Err(err) => println!("{}", err),
+                }
+            } else {
+                println!("Not valid duress command format")
+            };
         } else if input.starts_with("read") {
             receive_all_messages(&mut user).await;
         } else if input.starts_with("help") {
             println!("Supported commands are:");
             println!("  send:{{phone_number}}:{{message}}");
             println!("  denim:{{phone_number}}:{{message}}");
+            println!("  attach:{{phone_number}}:{{file_path}}");
+            println!("  denimattach:{{phone_number}}:{{file_path}}");
+            println!("  group:{{group_name}}:{{phone_number}},{{phone_number}}");
+            println!("  denimgroup:{{group_name}}:{{message}}");
+            println!("  denimstore");
+            println!("  duress:{{passphrase}}");
             println!("  read");
             println!("  help");
             println!("  quit");
@@ -7,18 +7,23 @@ use crate::{
 };
 use async_native_tls::{Certificate, TlsConnector};
 use axum::async_trait;
+use common::deniable::counter::{
+    parse_retransmit_header, CarrierCounter, CarrierOrder, CarrierWindow,
+};
+use common::deniable::q_value::QValueRange;
 use common::signalservice::{web_socket_message, WebSocketMessage, WebSocketRequestMessage};
 use common::web_api::{
-    authorization::BasicAuthorizationHeader, PreKeyResponse, RegistrationRequest,
-    RegistrationResponse,
+    authorization::BasicAuthorizationHeader, AttachmentUploadResponse, PreKeyResponse,
+    RegistrationRequest, RegistrationResponse,
 };
-use common::web_api::{DenimMessages, SetKeyRequest};
+use common::web_api::{ChunkHeader, DenimMessages, SetKeyRequest};
 use common::websocket::net_helper::{create_request, create_response};
 use flate2::read::GzDecoder;
 use http_client::h1::H1Client;
 use libsignal_core::{Aci, DeviceId, ServiceId};
 use libsignal_protocol::PreKeyBundle;
-use serde_json::{from_slice, to_vec};
+use serde_json::from_slice;
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt::Display;
 use std::io::Read;
@@ -31,6 +36,9 @@ const REGISTER_URI: &str = "v1/registration";
 const GET_SERVICE_ID_URI: &str = "v1/identifier";
 const MSG_URI: &str = "/v1/messages";
 const KEY_BUNDLE_URI: &str = "/v2/keys";
+const ATTACHMENT_URI: &str = "/v1/attachments";
+/// How many sent denim messages are remembered for retransmission
+const SENT_CARRIERS_LIMIT: usize = 256;
 
 #[allow(dead_code)]
 pub struct VerifiedSession {
@@ -46,13 +54,15 @@ impl VerifiedSession {
 
 #[async_trait]
 pub trait SignalServerAPI {
-    /// Connect with Websockets to the backend.
+    /// Connect with Websockets to the backend, proposing a q value or a range of q values.
+    /// Returns the q value chosen by the server.
     async fn connect(
         &mut self,
         username: &str,
         password: &str,
         url: &str,
         tls_path: &Option<String>,
+        q_value: Option<QValueRange>,
     ) -> Result<Option<f32>, SignalClientError>;
 
     // Disconnect websocket to the backend
@@ -86,20 +96,31 @@ pub trait SignalServerAPI {
     ) -> Result<ServiceId, SignalClientError>;
 
     /// Send a message to another user.
+    /// Returns the deniable chunks carried by denim messages the server reported missing.
     async fn send_msg(
         &mut self,
         messages: &DenimMessages,
         service_id: &ServiceId,
-    ) -> Result<(), SignalClientError>;
+    ) -> Result<Vec<ChunkHeader>, SignalClientError>;
+
+    /// Upload an encrypted attachment blob. Returns the key it is downloaded with.
+    async fn upload_attachment(&self, blob: Vec<u8>) -> Result<String, SignalClientError>;
+
+    /// Download the encrypted attachment blob stored under `cdn_key`.
+    async fn download_attachment(&self, cdn_key: &str) -> Result<Vec<u8>, SignalClientError>;
 
     async fn has_message(&mut self) -> bool;
 
     async fn get_message(&mut self) -> Option<WebSocketRequestMessage>;
 
+    /// Track the counter of a denim message received on the current connection
+    fn observe_carrier(&mut self, counter: Option<i32>) -> CarrierOrder;
+
     async fn send_response(
         &mut self,
         request: WebSocketRequestMessage,
         status_code: axum::http::StatusCode,
+        headers: Vec<String>,
     ) -> Result<(), SignalClientError>;
 
     fn create_auth_header(&mut self, aci: Aci, password: String, device_id: DeviceId) -> ();
@@ -110,6 +131,10 @@ pub struct SignalServer {
     http_client: Client,
     socket_manager: SocketManager<SignalStream>,
     message_queue: PersistentReceiver<WebSocketMessage>,
+    outgoing_counter: CarrierCounter,
+    incoming_carriers: CarrierWindow,
+    /// Deniable chunks carried by the last denim messages sent, by their counter
+    sent_carriers: VecDeque<(i32, Vec<ChunkHeader>)>,
 }
 
 #[allow(dead_code)]
@@ -143,11 +168,12 @@ impl SignalServerAPI for SignalServer {
         password: &str,
         url: &str,
         tls_path: &Option<String>,
+        q_value: Option<QValueRange>,
     ) -> Result<Option<f32>, SignalClientError> {
         if self.socket_manager.is_active().await {
             return Ok(None);
         }
-        let (ws, q_value) = signal_ws_connect(tls_path, url, username, password)
+        let (ws, q_value) = signal_ws_connect(tls_path, url, username, password, q_value)
             .await
             .map_err(SignalClientError::WebSocketError)?;
         let ws = SignalStream::new(ws);
@@ -155,6 +181,10 @@ impl SignalServerAPI for SignalServer {
             .set_stream(ws)
             .await
             .map_err(SignalClientError::WebSocketError)?;
+        // Counters start over on every connection
+        self.outgoing_counter = CarrierCounter::default();
+        self.incoming_carriers = CarrierWindow::default();
+        self.sent_carriers.clear();
         Ok(Some(q_value))
     }
     async fn disconnect(&mut self) {
@@ -270,13 +300,30 @@ impl SignalServerAPI for SignalServer {
         &mut self,
         messages: &DenimMessages,
         recipient: &ServiceId,
-    ) -> Result<(), SignalClientError> {
-        let payload = to_vec(&messages).unwrap();
+    ) -> Result<Vec<ChunkHeader>, SignalClientError> {
+        let mut messages = messages.clone();
+        for message in messages.messages.iter_mut() {
+            let counter = self.outgoing_counter.next_counter();
+            message.counter = Some(counter);
+            if self.sent_carriers.len() == SENT_CARRIERS_LIMIT {
+                self.sent_carriers.pop_front();
+            }
+            self.sent_carriers.push_back((
+                counter,
+                message
+                    .chunks
+                    .iter()
+                    .filter(|chunk| !chunk.is_dummy())
+                    .map(|chunk| chunk.header)
+                    .collect(),
+            ));
+        }
+        let payload = messages.encode_to_vec();
         let uri = format!("{}/{}?story=false", MSG_URI, recipient.service_id_string());
         // println!("Sending message to: {}", uri);
 
         let id = self.socket_manager.next_id();
-        let _response = self
+        let response = self
             .socket_manager
             .send(
                 id,
@@ -284,14 +331,73 @@ impl SignalServerAPI for SignalServer {
                     id,
                     "PUT",
                     &uri,
-                    vec!["content-type:application/json".to_string()],
+                    vec!["content-type:application/x-protobuf".to_string()],
                     Some(payload),
                 ),
             )
             .await
             .map_err(SendMessageError::WebSocketError)?;
 
-        Ok(())
+        let missing = response
+            .response
+            .map(|response| parse_retransmit_header(&response.headers))
+            .unwrap_or_default();
+        Ok(self
+            .sent_carriers
+            .iter()
+            .filter(|(counter, _)| missing.iter().any(|missing| missing.contains(*counter)))
+            .flat_map(|(_, headers)| headers.iter().copied())
+            .collect())
+    }
+
+    async fn upload_attachment(&self, blob: Vec<u8>) -> Result<String, SignalClientError> {
+        let header = match &self.auth_header {
+            Some(header) => header,
+            _ => Err(SignalClientError::NoSession)?,
+        };
+        let mut res = self
+            .http_client
+            .put(ATTACHMENT_URI)
+            .body(blob)
+            .header("content-type", "application/octet-stream")
+            .header("Authorization", header.encode())
+            .await
+            .map_err(|err| SignalClientError::AttachmentError(format!("{err}")))?;
+        if !res.status().is_success() {
+            return Err(SignalClientError::AttachmentError(format!(
+                "Received {}: {:?}",
+                res.status(),
+                res.body_string().await
+            )));
+        }
+        let upload: AttachmentUploadResponse = res
+            .body_json()
+            .await
+            .map_err(|err| SignalClientError::AttachmentError(format!("{err}")))?;
+        Ok(upload.cdn_key)
+    }
+
+    async fn download_attachment(&self, cdn_key: &str) -> Result<Vec<u8>, SignalClientError> {
+        let header = match &self.auth_header {
+            Some(header) => header,
+            _ => Err(SignalClientError::NoSession)?,
+        };
+        let mut res = self
+            .http_client
+            .get(format!("{}/{}", ATTACHMENT_URI, cdn_key))
+            .header("Authorization", header.encode())
+            .await
+            .map_err(|err| SignalClientError::AttachmentError(format!("{err}")))?;
+        if !res.status().is_success() {
+            return Err(SignalClientError::AttachmentError(format!(
+                "Received {}: {:?}",
+                res.status(),
+                res.body_string().await
+            )));
+        }
+        res.body_bytes()
+            .await
+            .map_err(|err| SignalClientError::AttachmentError(format!("{err}")))
     }
 
     // FIX:
@@ -299,11 +405,12 @@ impl SignalServerAPI for SignalServer {
         &mut self,
         request: WebSocketRequestMessage,
         status_code: axum::http::StatusCode,
+        headers: Vec<String>,
     ) -> Result<(), SignalClientError> {
         let id = request.id.expect("This is always some");
         self.socket_manager
             .send_response(
-                create_response(id, status_code, vec![], None).expect("This always goes well"),
+                create_response(id, status_code, headers, None).expect("This always goes well"),
             )
             .await
             .map_err(SendMessageError::WebSocketError)?;
@@ -319,6 +426,10 @@ impl SignalServerAPI for SignalServer {
         self.message_queue.recv().await?.request
     }
 
+    fn observe_carrier(&mut self, counter: Option<i32>) -> CarrierOrder {
+        self.incoming_carriers.observe(counter)
+    }
+
     fn create_auth_header(&mut self, aci: Aci, password: String, device_id: DeviceId) -> () {
         self.auth_header = Some(BasicAuthorizationHeader::new(
             aci.service_id_string(),
@@ -400,6 +511,9 @@ impl SignalServer {
             http_client,
             socket_manager: socket_mgr,
             message_queue: msg_queue,
+            outgoing_counter: CarrierCounter::default(),
+            incoming_carriers: CarrierWindow::default(),
+            sent_carriers: VecDeque::new(),
         }
     }
 
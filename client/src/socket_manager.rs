@@ -1,4 +1,5 @@
 use base64::{prelude::BASE64_STANDARD, Engine as _};
+use common::deniable::q_value::{QValueRange, Q_VALUE_HEADER};
 use common::signalservice::{web_socket_message, WebSocketMessage};
 use common::websocket::{connection_state::ConnectionState, wsstream::WSStream};
 use futures_util::lock::Mutex;
@@ -104,6 +105,7 @@ pub async fn signal_ws_connect(
     url: &str,
     username: &str,
     password: &str,
+    q_value: Option<QValueRange>,
 ) -> Result<(TLSWebSocket, f32), String> {
     let url = format!("{}/v1/websocket", url.replace("http", "ws"));
     let mut req = url
@@ -112,7 +114,8 @@ pub async fn signal_ws_connect(
 
     // TODO: do some smarter header handling than this, now both middleware and this defines headers
     // Create Signal auth
-    let headers = [
+    // Compression is never requested, compressed sizes would reveal deniable chunks
+    let mut headers = vec![
         (
             "Authorization",
             format!(
@@ -120,12 +123,13 @@ pub async fn signal_ws_connect(
                 BASE64_STANDARD.encode(format!("{}:{}", username, password))
             ),
         ),
-        ("Accept-Encoding", "gzip".to_string()),
         ("user-agent", "Signal Clone Client".to_string()),
         ("x-signal-agent", "OWA".to_string()),
-        ("sec-websocket-extensions", "permessage-deflate".to_string()), // TODO: does not actually support this: https://github.com/snapview/tungstenite-rs/pull/426
         ("x-signal-receive-stories", "false".to_string()),
     ];
+    if let Some(q_value) = q_value {
+        headers.push((Q_VALUE_HEADER, q_value.to_string()));
+    }
 
     for (key, value) in headers.iter() {
         req.headers_mut().insert(
@@ -166,15 +170,22 @@ pub async fn signal_ws_connect(
 
     let res = client_async_tls_with_config(req, stream, Some(config), connector).await;
     let (ws, response) = res.map_err(|_| "Failed to connect to server".to_string())?;
-    let q_value = response
+    let negotiated: f32 = response
         .headers()
-        .get("q-value")
-        .unwrap()
+        .get(Q_VALUE_HEADER)
+        .ok_or("Server did not send a q value".to_string())?
         .to_str()
-        .unwrap()
+        .map_err(|_| "Server sent a q value that is not text".to_string())?
         .parse()
-        .unwrap();
-    Ok((ws, q_value))
+        .map_err(|err| format!("Server sent an invalid q value: {err}"))?;
+    if let Some(q_value) = q_value {
+        if !q_value.contains(negotiated) {
+            return Err(format!(
+                "Server chose q value {negotiated} outside of proposed {q_value}"
+            ));
+        }
+    }
+    Ok((ws, negotiated))
 }
 
 type MessageType = WebSocketMessage;
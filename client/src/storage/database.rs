@@ -2,8 +2,16 @@ use std::sync::Arc;
 
 use async_std::sync::Mutex;
 use axum::async_trait;
-use common::{deniable::DeniableSendingBuffer, web_api::DenimChunk};
-use libsignal_core::{Aci, Pni, ProtocolAddress, ServiceId};
+use bincode::deserialize;
+use common::{
+    deniable::{
+        counter::stream_ids,
+        reassembly::{reassemble_after, CompletedStreams},
+        DeniableSendingBuffer,
+    },
+    web_api::{ChunkHeader, DeniablePayload, DenimChunk},
+};
+use libsignal_core::{Aci, DeviceId, Pni, ProtocolAddress, ServiceId};
 use libsignal_protocol::{
     Direction, IdentityKey, IdentityKeyPair, IdentityKeyStore, KyberPreKeyId, KyberPreKeyRecord,
     KyberPreKeyStore, PreKeyId, PreKeyRecord, PreKeyStore, SenderKeyRecord, SenderKeyStore,
@@ -12,7 +20,44 @@ use libsignal_protocol::{
 };
 use uuid::Uuid;
 
-use crate::contact_manager::{Contact, ContactName};
+use crate::{
+    contact_manager::{Contact, ContactName},
+    errors::{DatabaseError, ReceiveMessageError},
+};
+
+/// Delivery state of a sent deniable message, ordered by progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeniableMessageStatus {
+    Sent = 0,
+    Delivered = 1,
+    Read = 2,
+    /// Expired before it was sent
+    Failed = 3,
+}
+
+impl TryFrom<i32> for DeniableMessageStatus {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Sent),
+            1 => Ok(Self::Delivered),
+            2 => Ok(Self::Read),
+            3 => Ok(Self::Failed),
+            _ => Err(format!("Unknown deniable message status {value}")),
+        }
+    }
+}
+
+/// Group that only exists between its members, known to the server only as a list of receivers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeniableGroup {
+    /// Distribution id of the sender keys of the group
+    pub group_id: Uuid,
+    pub name: String,
+    /// Members of the group, not including the local account
+    pub members: Vec<ServiceId>,
+}
 
 #[allow(dead_code)]
 #[async_trait(?Send)]
@@ -41,8 +86,27 @@ pub trait ClientDB {
         service_id: &ServiceId,
     ) -> Result<(), Self::Error>;
     async fn get_service_id_by_nickname(&self, nickname: &str) -> Result<ServiceId, Self::Error>;
+    async fn store_deniable_contact(&self, contact: &Contact) -> Result<(), Self::Error>;
+    async fn load_deniable_contacts(&self) -> Result<Vec<Contact>, Self::Error>;
+    async fn insert_service_id_for_deniable_nickname(
+        &self,
+        nickname: &str,
+        service_id: &ServiceId,
+    ) -> Result<(), Self::Error>;
+    async fn get_service_id_by_deniable_nickname(
+        &self,
+        nickname: &str,
+    ) -> Result<ServiceId, Self::Error>;
     async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair, Self::Error>;
     async fn get_local_registration_id(&self) -> Result<u32, Self::Error>;
+    async fn insert_deniable_key_information(
+        &self,
+        key_pair: IdentityKeyPair,
+        registration_id: u32,
+    ) -> Result<(), Self::Error>;
+    async fn has_deniable_key_information(&self) -> Result<bool, Self::Error>;
+    async fn get_deniable_identity_key_pair(&self) -> Result<IdentityKeyPair, Self::Error>;
+    async fn get_deniable_local_registration_id(&self) -> Result<u32, Self::Error>;
     async fn save_identity(
         &mut self,
         address: &ProtocolAddress,
@@ -133,7 +197,9 @@ pub trait ClientDB {
     async fn get_aci(&self) -> Result<Aci, Self::Error>;
     async fn set_pni(&mut self, new_pni: Pni) -> Result<(), Self::Error>;
     async fn get_pni(&self) -> Result<Pni, Self::Error>;
-    async fn get_deniable_payload(&self) -> Result<(u32, Vec<u8>, i32), Self::Error>;
+    async fn set_device_id(&mut self, new_device_id: DeviceId) -> Result<(), Self::Error>;
+    async fn get_device_id(&self) -> Result<DeviceId, Self::Error>;
+    async fn get_deniable_payload(&self) -> Result<(u32, Vec<u8>, i32, u32), Self::Error>;
     async fn get_deniable_payload_by_id(
         &self,
         payload_id: u32,
@@ -145,6 +211,18 @@ pub trait ClientDB {
         payload: Vec<u8>,
     ) -> Result<(), Self::Error>;
     async fn remove_deniable_payload(&self, payload_id: u32) -> Result<(), Self::Error>;
+    async fn store_expiring_deniable_payload(
+        &self,
+        payload: Vec<u8>,
+        expires_at: u64,
+        message_timestamp: Option<u64>,
+    ) -> Result<(), Self::Error>;
+    /// Removes payloads that expired at `now` before any chunk was sent.
+    /// Returns their content and the timestamp of the message they belong to.
+    async fn remove_expired_deniable_payloads(
+        &self,
+        now: u64,
+    ) -> Result<Vec<(Vec<u8>, Option<u64>)>, Self::Error>;
     async fn try_get_key_request_sent(
         &self,
         service_id: String,
@@ -155,6 +233,17 @@ pub trait ClientDB {
         alias: String,
     ) -> Result<(), Self::Error>;
     async fn remove_key_request_sent(&self, service_id: String) -> Result<(), Self::Error>;
+    async fn try_get_identifier_request_sent(
+        &self,
+        phone_number: String,
+    ) -> Result<Option<String>, Self::Error>;
+    async fn store_identifier_request_sent(
+        &self,
+        phone_number: String,
+        alias: String,
+    ) -> Result<(), Self::Error>;
+    async fn remove_identifier_request_sent(&self, phone_number: String)
+        -> Result<(), Self::Error>;
     async fn get_messages_awaiting_encryption(
         &self,
         alias: String,
@@ -165,6 +254,10 @@ pub trait ClientDB {
         alias: String,
     ) -> Result<(), Self::Error>;
     async fn remove_message_awaiting_encryption(&self, message_id: u32) -> Result<(), Self::Error>;
+    /// Keeps an encoded sync message until there are sessions with the other own devices
+    async fn store_sync_awaiting_encryption(&self, content: Vec<u8>) -> Result<(), Self::Error>;
+    /// Removes the kept sync messages, oldest first
+    async fn take_syncs_awaiting_encryption(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
     async fn get_and_remove_incoming_deniable_chunks(
         &mut self,
     ) -> Result<Vec<DenimChunk>, Self::Error>;
@@ -172,6 +265,60 @@ pub trait ClientDB {
         &mut self,
         chunks: Vec<DenimChunk>,
     ) -> Result<(), Self::Error>;
+    /// Keeps sent chunks, so they can be sent again, forgetting the oldest beyond the limit
+    async fn store_sent_deniable_chunks(
+        &mut self,
+        chunks: Vec<DenimChunk>,
+    ) -> Result<(), Self::Error>;
+    /// Flags the kept chunks with `headers` to be sent again
+    async fn flag_sent_deniable_chunks(&self, headers: &[ChunkHeader]) -> Result<(), Self::Error>;
+    /// Takes the oldest flagged chunk with at most `max_len` bytes of data, it stays kept
+    async fn take_retransmit_deniable_chunk(
+        &self,
+        max_len: usize,
+    ) -> Result<Option<DenimChunk>, Self::Error>;
+    async fn store_deniable_message_sent(
+        &self,
+        service_id: String,
+        timestamp: u64,
+    ) -> Result<(), Self::Error>;
+    /// Moves a sent deniable message forward to `status`, never back.
+    /// Returns whether the status changed.
+    async fn update_deniable_message_status(
+        &self,
+        service_id: String,
+        timestamp: u64,
+        status: DeniableMessageStatus,
+    ) -> Result<bool, Self::Error>;
+    /// Marks a deniable message as failed, unless it was delivered to any device
+    async fn fail_deniable_message(
+        &self,
+        service_id: String,
+        timestamp: u64,
+    ) -> Result<bool, Self::Error>;
+    async fn get_deniable_message_status(
+        &self,
+        service_id: String,
+        timestamp: u64,
+    ) -> Result<Option<DeniableMessageStatus>, Self::Error>;
+    async fn store_deniable_group(&self, group: &DeniableGroup) -> Result<(), Self::Error>;
+    async fn get_deniable_group(
+        &self,
+        group_id: Uuid,
+    ) -> Result<Option<DeniableGroup>, Self::Error>;
+    async fn get_deniable_group_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Option<DeniableGroup>, Self::Error>;
+    /// Whether the deniable tables are kept, i.e. the deniable store was unlocked
+    async fn is_deniable_store_open(&self) -> Result<bool, Self::Error>;
+    /// Create the deniable store under the passphrase it was opened with,
+    /// replacing a store the passphrase did not open
+    async fn create_deniable_store(&mut self) -> Result<(), Self::Error>;
+    /// Write the changes to the deniable tables to the deniable store
+    async fn flush_deniable_store(&self) -> Result<(), Self::Error>;
+    async fn set_deniable_duress_passphrase(&mut self, passphrase: &str)
+        -> Result<(), Self::Error>;
 }
 
 pub struct DeviceIdentityKeyStore<T: ClientDB> {
@@ -451,11 +598,51 @@ impl<T: ClientDB> SenderKeyStore for DeviceSenderKeyStore<T> {
 
 pub struct DeniableStore<T: ClientDB> {
     db: Arc<Mutex<T>>,
+    /// Streams reassembled recently, late duplicates of their chunks are dropped
+    completed: Mutex<CompletedStreams>,
 }
 
 impl<T: ClientDB> DeniableStore<T> {
     pub fn new(db: Arc<Mutex<T>>) -> Self {
-        Self { db }
+        Self {
+            db,
+            completed: Mutex::default(),
+        }
+    }
+
+    /// Reassemble deniable payloads from new and previously stored chunks.
+    /// Chunks of incomplete payloads are stored until the rest arrives and their stream ids returned,
+    /// malformed chunks and payloads are dropped and returned as errors.
+    pub async fn receive_chunks(
+        &self,
+        new_chunks: Vec<DenimChunk>,
+    ) -> Result<(Vec<DeniablePayload>, Vec<u32>, Vec<ReceiveMessageError>), DatabaseError> {
+        let mut db = self.db.lock().await;
+        let mut chunks = db.get_and_remove_incoming_deniable_chunks().await?;
+        chunks.extend(new_chunks);
+
+        let reassembly = reassemble_after(chunks, &mut *self.completed.lock().await);
+        let incomplete_streams = stream_ids(&reassembly.pending);
+
+        if !reassembly.pending.is_empty() {
+            db.store_incoming_deniable_chunks(reassembly.pending)
+                .await?;
+        }
+
+        let mut errors: Vec<ReceiveMessageError> = reassembly
+            .errors
+            .into_iter()
+            .map(ReceiveMessageError::DeniableChunkError)
+            .collect();
+        let mut payloads = Vec::new();
+        for bytes in reassembly.payloads {
+            match deserialize(&bytes) {
+                Ok(payload) => payloads.push(payload),
+                Err(err) => errors.push(ReceiveMessageError::DeniablePayloadDecodeError(err)),
+            }
+        }
+
+        Ok((payloads, incomplete_streams, errors))
     }
 }
 
@@ -489,7 +676,9 @@ impl<T: ClientDB> SessionStore for DeniableStore<T> {
 
 #[async_trait(?Send)]
 impl<T: ClientDB> DeniableSendingBuffer for DeniableStore<T> {
-    async fn get_outgoing_message(&mut self) -> Result<(u32, Vec<u8>, i32), SignalProtocolError> {
+    async fn get_outgoing_message(
+        &mut self,
+    ) -> Result<(u32, Vec<u8>, i32, u32), SignalProtocolError> {
         self.db
             .lock()
             .await
@@ -523,6 +712,18 @@ impl<T: ClientDB> DeniableSendingBuffer for DeniableStore<T> {
             .await
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{err}")))
     }
+
+    async fn take_retransmit_chunk(
+        &mut self,
+        max_len: usize,
+    ) -> Result<Option<DenimChunk>, SignalProtocolError> {
+        self.db
+            .lock()
+            .await
+            .take_retransmit_deniable_chunk(max_len)
+            .await
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{err}")))
+    }
 }
 
 pub struct DeniableIdentityKeyStore<T: ClientDB> {
@@ -541,7 +742,7 @@ impl<T: ClientDB> IdentityKeyStore for DeniableIdentityKeyStore<T> {
         self.db
             .lock()
             .await
-            .get_identity_key_pair()
+            .get_deniable_identity_key_pair()
             .await
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{err}")))
     }
@@ -550,7 +751,7 @@ impl<T: ClientDB> IdentityKeyStore for DeniableIdentityKeyStore<T> {
         self.db
             .lock()
             .await
-            .get_local_registration_id()
+            .get_deniable_local_registration_id()
             .await
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{err}")))
     }
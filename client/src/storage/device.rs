@@ -1,27 +1,146 @@
 use std::collections::HashSet;
 
-use super::database::ClientDB;
+use super::{
+    database::{ClientDB, DeniableGroup, DeniableMessageStatus},
+    vault::DeniableVault,
+};
 use crate::contact_manager::{Contact, ContactName};
 use axum::async_trait;
 use base64::{prelude::BASE64_STANDARD, Engine as _};
-use common::web_api::DenimChunk;
+use common::web_api::{ChunkHeader, DenimChunk};
 use libsignal_core::{Aci, DeviceId, Pni, ProtocolAddress, ServiceId};
 use libsignal_protocol::{
     Direction, GenericSignedPreKey as _, IdentityKey, IdentityKeyPair, KyberPreKeyId,
     KyberPreKeyRecord, PreKeyId, PreKeyRecord, PrivateKey, SenderKeyRecord, SessionRecord,
     SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord,
 };
-use rusqlite::{params, Connection, OptionalExtension};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension};
 use uuid::Uuid;
 
+/// Number of sent deniable chunks kept to send again
+const MAX_SENT_DENIABLE_CHUNKS: usize = 256;
+
+/// Deniable tables older versions kept in the regular database
+const LEGACY_DENIABLE_TABLES: [&str; 10] = [
+    "DeniableIdentityKeys",
+    "DeniableDeviceIdentityKeyStore",
+    "DeniableDeviceSessionStore",
+    "DeniablePayload",
+    "DeniableKeyRequestsSent",
+    "DeniableIdentifierRequestsSent",
+    "DeniableMessageAwaitingEncryption",
+    "IncomingDeniableChunk",
+    "DeniableMessageStatus",
+    "DeniableGroups",
+];
+
 #[derive(Debug)]
 pub struct Device {
     conn: Connection,
+    /// Deniable tables, kept apart from the regular ones
+    vault: DeniableVault,
 }
 
 impl Device {
-    pub fn new(conn: Connection) -> Self {
-        Self { conn }
+    pub fn new(conn: Connection, vault: DeniableVault) -> Self {
+        Self { conn, vault }
+    }
+
+    /// Write the deniable tables back to the deniable store file
+    fn save_deniable(&self) -> Result<(), SignalProtocolError> {
+        self.vault
+            .save()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))
+    }
+
+    /// Move the rows of the deniable tables left in the regular database into the deniable
+    /// store, then drop the tables and scrub them from the database file.
+    /// Rows are only moved into a store that is kept in its file, empty tables are always dropped.
+    pub fn move_legacy_deniable_tables(&mut self) -> Result<(), SignalProtocolError> {
+        self.try_move_legacy_deniable_tables()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))
+    }
+
+    fn try_move_legacy_deniable_tables(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let store_open = self.vault.is_open();
+        let mut dropped = Vec::new();
+        let tx = self.vault.conn_mut().transaction()?;
+        for table in LEGACY_DENIABLE_TABLES {
+            let exists: bool = self.conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+                [table],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                continue;
+            }
+            let mut stmt = self.conn.prepare(&format!("SELECT * FROM \"{table}\""))?;
+            let columns = stmt
+                .column_names()
+                .into_iter()
+                .map(|column| format!("\"{column}\""))
+                .collect::<Vec<String>>();
+            let rows = stmt
+                .query_map([], |row| {
+                    (0..columns.len())
+                        .map(|index| row.get::<_, Value>(index))
+                        .collect::<rusqlite::Result<Vec<Value>>>()
+                })?
+                .collect::<rusqlite::Result<Vec<Vec<Value>>>>()?;
+            if !rows.is_empty() {
+                if !store_open {
+                    continue;
+                }
+                let mut insert = tx.prepare(&format!(
+                    "INSERT INTO \"{table}\" ({}) VALUES ({})",
+                    columns.join(", "),
+                    vec!["?"; columns.len()].join(", ")
+                ))?;
+                for row in rows {
+                    insert.execute(params_from_iter(row))?;
+                }
+            }
+            dropped.push(table);
+        }
+        if dropped.is_empty() {
+            return Ok(());
+        }
+        // Moved payloads keep their ids, which are used as stream ids
+        tx.execute(
+            r#"
+            UPDATE DeniableStreamCounter
+            SET next = MAX(next, COALESCE((SELECT MAX(id) FROM DeniablePayload), 0) + 1)
+            "#,
+            [],
+        )?;
+        tx.commit()?;
+        self.vault.save()?;
+        self.vault.flush()?;
+
+        // The rows must not be left in free pages of the database file
+        self.conn.pragma_update(None, "secure_delete", true)?;
+        for table in dropped {
+            self.conn
+                .execute_batch(&format!("DROP TABLE \"{table}\""))?;
+        }
+        self.conn.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    /// Take the next stream id for an outgoing deniable payload, ids are never handed out twice
+    fn next_deniable_stream_id(&self) -> Result<u32, SignalProtocolError> {
+        self.vault
+            .conn()
+            .query_row(
+                r#"
+            UPDATE DeniableStreamCounter
+            SET next = next + 1
+            RETURNING next - 1
+            "#,
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))
     }
 
     async fn insert_identity(
@@ -58,7 +177,8 @@ impl Device {
         let key = BASE64_STANDARD.encode(identity.serialize());
 
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             INSERT INTO DeniableDeviceIdentityKeyStore (address, identity_key)
@@ -71,6 +191,7 @@ impl Device {
         stmt.execute(params![addr, key, key])
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
+        self.save_deniable()?;
         Ok(())
     }
 }
@@ -184,80 +305,11 @@ impl ClientDB for Device {
     }
 
     async fn store_contact(&self, contact: &Contact) -> Result<(), Self::Error> {
-        let service_id = contact.service_id.service_id_string();
-        let device_ids = contact
-            .device_ids
-            .iter()
-            .map(|id| id.to_string())
-            .collect::<Vec<_>>()
-            .join(",");
-
-        let mut stmt = self
-            .conn
-            .prepare(
-                r#"
-            INSERT INTO Contacts(service_id, device_ids)
-            VALUES(?1, ?2)
-            ON CONFLICT(service_id) DO UPDATE SET device_ids = ?3
-            "#,
-            )
-            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
-
-        stmt.execute(params![service_id, device_ids, device_ids])
-            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
-
-        Ok(())
+        store_contact_in(&self.conn, "Contacts", contact)
     }
 
     async fn load_contacts(&self) -> Result<Vec<Contact>, Self::Error> {
-        let mut stmt = self
-            .conn
-            .prepare(
-                r#"
-            SELECT
-                service_id,
-                device_ids
-            FROM
-                Contacts
-            "#,
-            )
-            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
-        let mut rows = stmt
-            .query([])
-            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
-
-        let mut contacts = vec![];
-        while let Some(row) = rows
-            .next()
-            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?
-        {
-            let mut device_ids = HashSet::new();
-            let row_service_id: String = row
-                .get(0)
-                .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
-            let row_device_ids: String = row
-                .get(1)
-                .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
-            if row_device_ids != "" {
-                for device_id in row_device_ids.split(",") {
-                    device_ids.insert(DeviceId::from(device_id.parse::<u32>().map_err(|err| {
-                        SignalProtocolError::InvalidArgument(format!(
-                            "Could not parse device id: {err}"
-                        ))
-                    })?));
-                }
-            }
-            contacts.push(Contact {
-                service_id: ServiceId::parse_from_service_id_string(row_service_id.as_str())
-                    .ok_or(SignalProtocolError::InvalidArgument(format!(
-                        "Could not parse service_id: {}",
-                        row_service_id
-                    )))?,
-                device_ids,
-            });
-        }
-
-        Ok(contacts)
+        load_contacts_from(&self.conn, "Contacts")
     }
 
     async fn remove_contact(&self, service_id: &ServiceId) -> Result<(), Self::Error> {
@@ -323,57 +375,146 @@ impl ClientDB for Device {
         nickname: &str,
         service_id: &ServiceId,
     ) -> Result<(), Self::Error> {
-        let service_id = service_id.service_id_string();
+        insert_nickname_in(&self.conn, "Nicknames", nickname, service_id)
+    }
+
+    async fn get_service_id_by_nickname(&self, nickname: &str) -> Result<ServiceId, Self::Error> {
+        get_service_id_by_nickname_from(&self.conn, "Nicknames", nickname)
+    }
+
+    async fn store_deniable_contact(&self, contact: &Contact) -> Result<(), Self::Error> {
+        store_contact_in(self.vault.conn(), "DeniableContacts", contact)?;
+        self.save_deniable()
+    }
+
+    async fn load_deniable_contacts(&self) -> Result<Vec<Contact>, Self::Error> {
+        load_contacts_from(self.vault.conn(), "DeniableContacts")
+    }
+
+    async fn insert_service_id_for_deniable_nickname(
+        &self,
+        nickname: &str,
+        service_id: &ServiceId,
+    ) -> Result<(), Self::Error> {
+        insert_nickname_in(self.vault.conn(), "DeniableNicknames", nickname, service_id)?;
+        self.save_deniable()
+    }
+
+    async fn get_service_id_by_deniable_nickname(
+        &self,
+        nickname: &str,
+    ) -> Result<ServiceId, Self::Error> {
+        get_service_id_by_nickname_from(self.vault.conn(), "DeniableNicknames", nickname)
+    }
 
+    async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair, Self::Error> {
         let mut stmt = self
             .conn
             .prepare(
                 r#"
-            INSERT INTO Nicknames(name, service_id)
-            VALUES(?1, ?2)
+            SELECT
+                public_key, private_key
+            FROM
+                IdentityKeys 
             "#,
             )
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        stmt.execute(params![nickname, service_id])
+        let row: (String, String) = stmt
+            .query_row([], |row| Ok((row.get(0)?, row.get(1)?)))
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        Ok(())
+        Ok(IdentityKeyPair::new(
+            IdentityKey::decode(
+                &BASE64_STANDARD
+                    .decode(row.0)
+                    .map_err(|err| SignalProtocolError::InvalidArgument(format!("{err}")))?,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{err}")))?,
+            PrivateKey::deserialize(
+                &BASE64_STANDARD
+                    .decode(row.1)
+                    .map_err(|err| SignalProtocolError::InvalidArgument(format!("{err}")))?,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{err}")))?,
+        ))
     }
 
-    async fn get_service_id_by_nickname(&self, nickname: &str) -> Result<ServiceId, Self::Error> {
+    async fn get_local_registration_id(&self) -> Result<u32, Self::Error> {
         let mut stmt = self
             .conn
             .prepare(
                 r#"
             SELECT
-                service_id
+                registration_id
             FROM
-                Nicknames
-            WHERE
-                name = ?1
+                IdentityKeys
             "#,
             )
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        let row: String = stmt
-            .query_row([nickname], |row| Ok(row.get(0)?))
+        let row: u32 = stmt
+            .query_row([], |row| Ok(row.get(0)?))
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        ServiceId::parse_from_service_id_string(&row).ok_or(SignalProtocolError::InvalidArgument(
-            format!("Could not parse service_id"),
-        ))
+        Ok(row)
     }
 
-    async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair, Self::Error> {
+    async fn insert_deniable_key_information(
+        &self,
+        key_pair: IdentityKeyPair,
+        registration_id: u32,
+    ) -> Result<(), Self::Error> {
+        let pk = BASE64_STANDARD.encode(key_pair.identity_key().serialize());
+        let sk = BASE64_STANDARD.encode(key_pair.private_key().serialize());
+
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            INSERT INTO DeniableIdentityKeys (public_key, private_key, registration_id)
+            VALUES (?1, ?2, ?3)
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        stmt.execute(params![pk, sk, registration_id])
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        self.save_deniable()?;
+        Ok(())
+    }
+
+    async fn has_deniable_key_information(&self) -> Result<bool, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            SELECT
+                EXISTS(SELECT 1 FROM DeniableIdentityKeys)
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let row: bool = stmt
+            .query_row([], |row| row.get(0))
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        Ok(row)
+    }
+
+    async fn get_deniable_identity_key_pair(&self) -> Result<IdentityKeyPair, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
             .prepare(
                 r#"
             SELECT
                 public_key, private_key
             FROM
-                IdentityKeys 
+                DeniableIdentityKeys
             "#,
             )
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
@@ -398,21 +539,22 @@ impl ClientDB for Device {
         ))
     }
 
-    async fn get_local_registration_id(&self) -> Result<u32, Self::Error> {
+    async fn get_deniable_local_registration_id(&self) -> Result<u32, Self::Error> {
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             SELECT
                 registration_id
             FROM
-                IdentityKeys
+                DeniableIdentityKeys
             "#,
             )
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
         let row: u32 = stmt
-            .query_row([], |row| Ok(row.get(0)?))
+            .query_row([], |row| row.get(0))
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
         Ok(row)
@@ -538,7 +680,8 @@ impl ClientDB for Device {
         let addr = format!("{}", address);
 
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             SELECT
@@ -803,7 +946,8 @@ impl ClientDB for Device {
         let addr = format!("{}", address);
 
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             SELECT
@@ -867,7 +1011,8 @@ impl ClientDB for Device {
         let rec = BASE64_STANDARD.encode(record.serialize()?);
 
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             INSERT INTO DeniableDeviceSessionStore (address, session_record)
@@ -880,6 +1025,7 @@ impl ClientDB for Device {
         stmt.execute(params![addr, rec, rec])
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
+        self.save_deniable()?;
         Ok(())
     }
 
@@ -1066,21 +1212,61 @@ impl ClientDB for Device {
         )?)
     }
 
-    async fn get_deniable_payload(&self) -> Result<(u32, Vec<u8>, i32), Self::Error> {
+    async fn set_device_id(&mut self, new_device_id: DeviceId) -> Result<(), Self::Error> {
         let mut stmt = self
             .conn
+            .prepare(
+                r#"
+            UPDATE Identity
+            SET device_id = ?1
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        stmt.execute(params![u32::from(new_device_id)])
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        Ok(())
+    }
+
+    async fn get_device_id(&self) -> Result<DeviceId, Self::Error> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                r#"
+            SELECT
+                device_id
+            FROM
+                Identity
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let row: u32 = stmt
+            .query_row([], |row| row.get(0))
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        Ok(row.into())
+    }
+
+    async fn get_deniable_payload(&self) -> Result<(u32, Vec<u8>, i32, u32), Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
             .prepare(
                 r#"
             SELECT
-                id, content, chunk_count
+                id, content, chunk_count, total_length
             FROM
                 DeniablePayload
             "#,
             )
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        let row: (u32, Vec<u8>, i32) = stmt
-            .query_row([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        let row: (u32, Vec<u8>, i32, u32) = stmt
+            .query_row([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
         Ok(row)
     }
@@ -1090,7 +1276,8 @@ impl ClientDB for Device {
         payload_id: u32,
     ) -> Result<(Vec<u8>, i32), Self::Error> {
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             SELECT
@@ -1117,7 +1304,8 @@ impl ClientDB for Device {
     ) -> Result<(), Self::Error> {
         if let Some(id) = payload_id {
             let mut stmt = self
-                .conn
+                .vault
+                .conn()
                 .prepare(
                     r#"
                 UPDATE DeniablePayload
@@ -1131,24 +1319,31 @@ impl ClientDB for Device {
                 .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
         } else {
             let mut stmt = self
-                .conn
+                .vault
+                .conn()
                 .prepare(
                     r#"
-                INSERT INTO DeniablePayload (content, chunk_count)
-                VALUES (?1, 0)
+                INSERT INTO DeniablePayload (id, content, chunk_count, total_length)
+                VALUES (?1, ?2, 0, ?3)
                 "#,
                 )
                 .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-            stmt.execute(params![payload])
-                .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+            stmt.execute(params![
+                self.next_deniable_stream_id()?,
+                payload,
+                payload.len() as u32
+            ])
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
         }
+        self.save_deniable()?;
         Ok(())
     }
 
     async fn remove_deniable_payload(&self, payload_id: u32) -> Result<(), Self::Error> {
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             DELETE FROM
@@ -1161,15 +1356,86 @@ impl ClientDB for Device {
 
         stmt.execute(params![payload_id])
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
+        Ok(())
+    }
+
+    async fn store_expiring_deniable_payload(
+        &self,
+        payload: Vec<u8>,
+        expires_at: u64,
+        message_timestamp: Option<u64>,
+    ) -> Result<(), Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            INSERT INTO DeniablePayload
+                (id, content, chunk_count, total_length, expires_at, message_timestamp)
+            VALUES (?1, ?2, 0, ?3, ?4, ?5)
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        stmt.execute(params![
+            self.next_deniable_stream_id()?,
+            payload,
+            payload.len() as u32,
+            expires_at as i64,
+            message_timestamp.map(|timestamp| timestamp as i64)
+        ])
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
         Ok(())
     }
 
+    async fn remove_expired_deniable_payloads(
+        &self,
+        now: u64,
+    ) -> Result<Vec<(Vec<u8>, Option<u64>)>, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            DELETE FROM
+                DeniablePayload
+            WHERE
+                expires_at <= ?1 AND chunk_count = 0
+            RETURNING
+                content, message_timestamp
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let rows = stmt
+            .query_map([now as i64], |row| {
+                let timestamp: Option<i64> = row.get(1)?;
+                Ok((row.get(0)?, timestamp.map(|timestamp| timestamp as u64)))
+            })
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let mut payloads = Vec::new();
+        for payload in rows {
+            payloads.push(
+                payload.map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?,
+            );
+        }
+
+        if !payloads.is_empty() {
+            self.save_deniable()?;
+        }
+        Ok(payloads)
+    }
+
     async fn try_get_key_request_sent(
         &self,
         service_id: String,
     ) -> Result<Option<String>, Self::Error> {
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             SELECT
@@ -1195,7 +1461,8 @@ impl ClientDB for Device {
         alias: String,
     ) -> Result<(), Self::Error> {
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             INSERT INTO DeniableKeyRequestsSent (service_id, alias)
@@ -1208,12 +1475,14 @@ impl ClientDB for Device {
         stmt.execute(params![service_id, alias, alias])
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
+        self.save_deniable()?;
         Ok(())
     }
 
     async fn remove_key_request_sent(&self, service_id: String) -> Result<(), Self::Error> {
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             DELETE FROM
@@ -1226,38 +1495,113 @@ impl ClientDB for Device {
 
         stmt.execute(params![service_id])
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
         Ok(())
     }
 
-    async fn get_messages_awaiting_encryption(
+    async fn try_get_identifier_request_sent(
         &self,
-        alias: String,
-    ) -> Result<Vec<String>, Self::Error> {
+        phone_number: String,
+    ) -> Result<Option<String>, Self::Error> {
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             SELECT
-                message
+                alias
             FROM
-                DeniableMessageAwaitingEncryption
+                DeniableIdentifierRequestsSent
             WHERE
-                alias = ?1
+                phone_number = ?1
             "#,
             )
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        let rows = stmt
-            .query_map([alias], |row| Ok(row.get(0)?))
+        let row: Option<String> = stmt
+            .query_row([phone_number], |row| row.get(0))
+            .optional()
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        Ok(row)
+    }
+
+    async fn store_identifier_request_sent(
+        &self,
+        phone_number: String,
+        alias: String,
+    ) -> Result<(), Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            INSERT INTO DeniableIdentifierRequestsSent (phone_number, alias)
+            VALUES (?1, ?2)
+            ON CONFLICT(phone_number) DO UPDATE SET alias = ?3
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        stmt.execute(params![phone_number, alias, alias])
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        self.save_deniable()?;
+        Ok(())
+    }
+
+    async fn remove_identifier_request_sent(
+        &self,
+        phone_number: String,
+    ) -> Result<(), Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            DELETE FROM
+                DeniableIdentifierRequestsSent
+            WHERE
+                phone_number = ?1
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        stmt.execute(params![phone_number])
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
+        Ok(())
+    }
+
+    async fn get_messages_awaiting_encryption(
+        &self,
+        alias: String,
+    ) -> Result<Vec<String>, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            SELECT
+                message
+            FROM
+                DeniableMessageAwaitingEncryption
+            WHERE
+                alias = ?1
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let rows = stmt
+            .query_map([alias], |row| Ok(row.get(0)?))
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let mut messages = Vec::new();
+        for message in rows {
+            messages.push(
+                message.map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?,
+            );
+        }
 
-        let mut messages = Vec::new();
-        for message in rows {
-            messages.push(
-                message.map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?,
-            );
-        }
-
         Ok(messages)
     }
 
@@ -1267,7 +1611,8 @@ impl ClientDB for Device {
         alias: String,
     ) -> Result<(), Self::Error> {
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             INSERT INTO DeniableMessageAwaitingEncryption (message, alias)
@@ -1278,12 +1623,14 @@ impl ClientDB for Device {
 
         stmt.execute(params![message, alias])
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
         Ok(())
     }
 
     async fn remove_message_awaiting_encryption(&self, message_id: u32) -> Result<(), Self::Error> {
         let mut stmt = self
-            .conn
+            .vault
+            .conn()
             .prepare(
                 r#"
             DELETE FROM
@@ -1296,15 +1643,71 @@ impl ClientDB for Device {
 
         stmt.execute(params![message_id])
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
+        Ok(())
+    }
+
+    async fn store_sync_awaiting_encryption(&self, content: Vec<u8>) -> Result<(), Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            INSERT INTO DeniableSyncAwaitingEncryption (content)
+            VALUES (?1)
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        stmt.execute(params![content])
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
         Ok(())
     }
 
+    async fn take_syncs_awaiting_encryption(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            SELECT
+                content
+            FROM
+                DeniableSyncAwaitingEncryption
+            ORDER BY
+                id
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let contents = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?
+            .collect::<rusqlite::Result<Vec<Vec<u8>>>>()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        self.vault
+            .conn()
+            .execute(
+                r#"
+            DELETE FROM
+                DeniableSyncAwaitingEncryption
+            "#,
+                [],
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
+        Ok(contents)
+    }
+
     async fn get_and_remove_incoming_deniable_chunks(
         &mut self,
     ) -> Result<Vec<DenimChunk>, Self::Error> {
         let mut chunks = Vec::new();
         let tx = self
-            .conn
+            .vault
+            .conn_mut()
             .transaction()
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
         {
@@ -1312,7 +1715,7 @@ impl ClientDB for Device {
                 .prepare(
                     r#"
                 SELECT
-                    id, chunk, flags
+                    id, chunk, flags, version, stream_id, sequence, total_length
                 FROM
                     IncomingDeniableChunk
                 "#,
@@ -1320,7 +1723,21 @@ impl ClientDB for Device {
                 .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
             let rows = stmt
-                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .query_map([], |row| {
+                    Ok((
+                        row.get(0)?,
+                        DenimChunk {
+                            chunk: row.get(1)?,
+                            flags: row.get(2)?,
+                            header: ChunkHeader {
+                                version: row.get(3)?,
+                                stream_id: row.get(4)?,
+                                sequence: row.get(5)?,
+                                total_length: row.get(6)?,
+                            },
+                        },
+                    ))
+                })
                 .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
             let mut delete_stmt = tx
@@ -1334,21 +1751,21 @@ impl ClientDB for Device {
                 )
                 .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
             for row in rows {
-                let chunk: (u32, Vec<u8>, i32) =
+                let (id, chunk): (u32, DenimChunk) =
                     row.map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
-                chunks.push(DenimChunk {
-                    chunk: chunk.1,
-                    flags: chunk.2,
-                });
+                chunks.push(chunk);
 
                 delete_stmt
-                    .execute(params![chunk.0])
+                    .execute(params![id])
                     .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
             }
         }
         tx.commit()
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
+        if !chunks.is_empty() {
+            self.save_deniable()?;
+        }
         Ok(chunks)
     }
 
@@ -1357,137 +1774,681 @@ impl ClientDB for Device {
         chunks: Vec<DenimChunk>,
     ) -> Result<(), Self::Error> {
         let tx = self
-            .conn
+            .vault
+            .conn_mut()
             .transaction()
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
         {
             let mut stmt = tx
                 .prepare(
                     r#"
-                INSERT INTO IncomingDeniableChunk (chunk, flags)
-                VALUES (?1, ?2)
+                INSERT INTO IncomingDeniableChunk
+                    (chunk, flags, version, stream_id, sequence, total_length)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
                 "#,
                 )
                 .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
             for chunk in chunks {
-                stmt.execute(params![chunk.chunk, chunk.flags])
-                    .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+                stmt.execute(params![
+                    chunk.chunk,
+                    chunk.flags,
+                    chunk.header.version,
+                    chunk.header.stream_id,
+                    chunk.header.sequence,
+                    chunk.header.total_length
+                ])
+                .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
             }
         }
         tx.commit()
             .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
+        self.save_deniable()?;
         Ok(())
     }
-}
-
-#[cfg(test)]
-mod device_protocol_test {
-    use crate::{
-        key_manager::KeyManager,
-        storage::{
-            database::{
-                ClientDB, DeviceIdentityKeyStore, DeviceKyberPreKeyStore, DevicePreKeyStore,
-                DeviceSessionStore, DeviceSignedPreKeyStore,
-            },
-            device::Device,
-        },
-        test_utils::user::{new_contact, new_protocol_address, new_rand_number, new_service_id},
-    };
-    use async_std::sync::Mutex;
-    use include_dir::{include_dir, Dir};
-    use libsignal_protocol::{
-        Direction, GenericSignedPreKey, IdentityKeyPair, IdentityKeyStore, KyberPreKeyStore,
-        PreKeyStore, SessionRecord, SessionStore, SignedPreKeyStore,
-    };
-    use rand::rngs::OsRng;
-    use rusqlite::Connection;
-    use rusqlite_migration::Migrations;
-    use std::{
-        collections::HashMap,
-        sync::{Arc, LazyLock},
-    };
 
-    static MIGRATIONS_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/client_db/migrations");
-    static MIGRATIONS: LazyLock<Migrations<'static>> =
-        LazyLock::new(|| Migrations::from_directory(&MIGRATIONS_DIR).unwrap());
+    async fn store_sent_deniable_chunks(
+        &mut self,
+        chunks: Vec<DenimChunk>,
+    ) -> Result<(), Self::Error> {
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        let tx = self
+            .vault
+            .conn_mut()
+            .transaction()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        {
+            let mut stmt = tx
+                .prepare(
+                    r#"
+                INSERT INTO SentDeniableChunk
+                    (chunk, flags, version, stream_id, sequence, total_length)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT(stream_id, sequence) DO NOTHING
+                "#,
+                )
+                .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-    async fn connect() -> Connection {
-        let mut conn = Connection::open_in_memory().expect("Could not open database");
+            for chunk in chunks {
+                stmt.execute(params![
+                    chunk.chunk,
+                    chunk.flags,
+                    chunk.header.version,
+                    chunk.header.stream_id,
+                    chunk.header.sequence,
+                    chunk.header.total_length
+                ])
+                .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+            }
 
-        MIGRATIONS
-            .to_latest(&mut conn)
-            .expect("Could not run migrations");
+            tx.execute(
+                r#"
+            DELETE FROM
+                SentDeniableChunk
+            WHERE
+                id NOT IN (SELECT id FROM SentDeniableChunk ORDER BY id DESC LIMIT ?1)
+            "#,
+                params![MAX_SENT_DENIABLE_CHUNKS],
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        }
+        tx.commit()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        conn
+        self.save_deniable()?;
+        Ok(())
     }
 
-    #[tokio::test]
-    async fn save_and_get_identity_test() {
-        let device = Arc::new(Mutex::new(Device::new(connect().await)));
-        let mut device_identity_key_store = DeviceIdentityKeyStore::new(device);
-        let address = new_protocol_address();
-        let other_key_pair = IdentityKeyPair::generate(&mut OsRng);
-        let new_other_key_pair = IdentityKeyPair::generate(&mut OsRng);
+    async fn flag_sent_deniable_chunks(&self, headers: &[ChunkHeader]) -> Result<(), Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            UPDATE SentDeniableChunk
+            SET retransmit = 1
+            WHERE stream_id = ?1 AND sequence = ?2
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        // Test no identity exists
-        assert_eq!(
-            device_identity_key_store
-                .get_identity(&address)
-                .await
-                .unwrap(),
-            None
-        );
+        let mut flagged = 0;
+        for header in headers {
+            flagged += stmt
+                .execute(params![header.stream_id, header.sequence])
+                .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        }
+        if flagged > 0 {
+            self.save_deniable()?;
+        }
+        Ok(())
+    }
 
-        // Test that a new identity have been added
-        assert!(device_identity_key_store
-            .save_identity(&address, other_key_pair.identity_key())
-            .await
-            .unwrap());
+    async fn take_retransmit_deniable_chunk(
+        &self,
+        max_len: usize,
+    ) -> Result<Option<DenimChunk>, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            SELECT
+                id, chunk, flags, version, stream_id, sequence, total_length
+            FROM
+                SentDeniableChunk
+            WHERE
+                retransmit = 1 AND length(chunk) <= ?1
+            ORDER BY
+                id
+            LIMIT 1
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        assert_eq!(
-            device_identity_key_store
-                .get_identity(&address)
-                .await
-                .unwrap()
-                .unwrap(),
-            *other_key_pair.identity_key()
-        );
+        let row: Option<(u32, DenimChunk)> = stmt
+            .query_row(params![max_len], |row| {
+                Ok((
+                    row.get(0)?,
+                    DenimChunk {
+                        chunk: row.get(1)?,
+                        flags: row.get(2)?,
+                        header: ChunkHeader {
+                            version: row.get(3)?,
+                            stream_id: row.get(4)?,
+                            sequence: row.get(5)?,
+                            total_length: row.get(6)?,
+                        },
+                    },
+                ))
+            })
+            .optional()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        let Some((id, chunk)) = row else {
+            return Ok(None);
+        };
 
-        // Test we did not overwrite our identity
-        assert!(!device_identity_key_store
-            .save_identity(&address, other_key_pair.identity_key())
-            .await
-            .unwrap());
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            UPDATE SentDeniableChunk
+            SET retransmit = 0
+            WHERE id = ?1
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        assert_eq!(
-            device_identity_key_store
-                .get_identity(&address)
-                .await
-                .unwrap()
-                .unwrap(),
-            *other_key_pair.identity_key()
-        );
+        stmt.execute(params![id])
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
+        Ok(Some(chunk))
+    }
 
-        // Test we overwrite our identity
-        assert!(!device_identity_key_store
-            .save_identity(&address, new_other_key_pair.identity_key())
-            .await
-            .unwrap());
+    async fn store_deniable_message_sent(
+        &self,
+        service_id: String,
+        timestamp: u64,
+    ) -> Result<(), Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            INSERT INTO DeniableMessageStatus (service_id, timestamp, status)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT(service_id, timestamp) DO NOTHING
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
 
-        assert_eq!(
-            device_identity_key_store
-                .get_identity(&address)
-                .await
-                .unwrap()
-                .unwrap(),
-            *new_other_key_pair.identity_key()
-        );
+        stmt.execute(params![
+            service_id,
+            timestamp as i64,
+            DeniableMessageStatus::Sent as i32
+        ])
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
+        Ok(())
+    }
+
+    async fn update_deniable_message_status(
+        &self,
+        service_id: String,
+        timestamp: u64,
+        status: DeniableMessageStatus,
+    ) -> Result<bool, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            UPDATE DeniableMessageStatus
+            SET status = ?3
+            WHERE service_id = ?1 AND timestamp = ?2 AND status < ?3
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let updated = stmt
+            .execute(params![service_id, timestamp as i64, status as i32])
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
+        Ok(updated > 0)
+    }
+
+    async fn fail_deniable_message(
+        &self,
+        service_id: String,
+        timestamp: u64,
+    ) -> Result<bool, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            UPDATE DeniableMessageStatus
+            SET status = ?3
+            WHERE service_id = ?1 AND timestamp = ?2 AND status = ?4
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let updated = stmt
+            .execute(params![
+                service_id,
+                timestamp as i64,
+                DeniableMessageStatus::Failed as i32,
+                DeniableMessageStatus::Sent as i32
+            ])
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.save_deniable()?;
+        Ok(updated > 0)
+    }
+
+    async fn get_deniable_message_status(
+        &self,
+        service_id: String,
+        timestamp: u64,
+    ) -> Result<Option<DeniableMessageStatus>, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            SELECT
+                status
+            FROM
+                DeniableMessageStatus
+            WHERE
+                service_id = ?1 AND timestamp = ?2
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let row: Option<i32> = stmt
+            .query_row(params![service_id, timestamp as i64], |row| row.get(0))
+            .optional()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        row.map(DeniableMessageStatus::try_from)
+            .transpose()
+            .map_err(SignalProtocolError::InvalidArgument)
+    }
+
+    async fn store_deniable_group(&self, group: &DeniableGroup) -> Result<(), Self::Error> {
+        let members = group
+            .members
+            .iter()
+            .map(ServiceId::service_id_string)
+            .collect::<Vec<String>>()
+            .join(",");
+
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            INSERT INTO DeniableGroups (group_id, name, members)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT(group_id) DO UPDATE SET name = ?2, members = ?3
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        stmt.execute(params![group.group_id.to_string(), group.name, members])
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        self.save_deniable()?;
+        Ok(())
+    }
+
+    async fn get_deniable_group(
+        &self,
+        group_id: Uuid,
+    ) -> Result<Option<DeniableGroup>, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            SELECT
+                group_id, name, members
+            FROM
+                DeniableGroups
+            WHERE
+                group_id = ?1
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let row: Option<(String, String, String)> = stmt
+            .query_row([group_id.to_string()], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .optional()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        row.map(deniable_group_from_row).transpose()
+    }
+
+    async fn get_deniable_group_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Option<DeniableGroup>, Self::Error> {
+        let mut stmt = self
+            .vault
+            .conn()
+            .prepare(
+                r#"
+            SELECT
+                group_id, name, members
+            FROM
+                DeniableGroups
+            WHERE
+                name = ?1
+            "#,
+            )
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+        let row: Option<(String, String, String)> = stmt
+            .query_row([name], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .optional()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        row.map(deniable_group_from_row).transpose()
+    }
+
+    async fn is_deniable_store_open(&self) -> Result<bool, Self::Error> {
+        Ok(self.vault.is_open())
+    }
+
+    async fn create_deniable_store(&mut self) -> Result<(), Self::Error> {
+        self.vault
+            .create()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        self.move_legacy_deniable_tables()
+    }
+
+    async fn flush_deniable_store(&self) -> Result<(), Self::Error> {
+        self.vault
+            .flush()
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))
+    }
+
+    async fn set_deniable_duress_passphrase(
+        &mut self,
+        passphrase: &str,
+    ) -> Result<(), Self::Error> {
+        self.vault
+            .set_duress_passphrase(passphrase)
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))
+    }
+}
+
+/// Deniable group from its group id, name and comma separated members
+fn deniable_group_from_row(
+    (group_id, name, members): (String, String, String),
+) -> Result<DeniableGroup, SignalProtocolError> {
+    let members = members
+        .split(',')
+        .filter(|member| !member.is_empty())
+        .map(|member| {
+            ServiceId::parse_from_service_id_string(member).ok_or_else(|| {
+                SignalProtocolError::InvalidArgument(format!("Invalid group member {member}"))
+            })
+        })
+        .collect::<Result<Vec<ServiceId>, SignalProtocolError>>()?;
+    Ok(DeniableGroup {
+        group_id: Uuid::parse_str(&group_id)
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?,
+        name,
+        members,
+    })
+}
+
+/// Store a contact in the contacts table `table`, which is either the regular or the deniable one
+fn store_contact_in(
+    conn: &Connection,
+    table: &str,
+    contact: &Contact,
+) -> Result<(), SignalProtocolError> {
+    let service_id = contact.service_id.service_id_string();
+    let device_ids = contact
+        .device_ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut stmt = conn
+        .prepare(&format!(
+            r#"
+            INSERT INTO {table}(service_id, device_ids)
+            VALUES(?1, ?2)
+            ON CONFLICT(service_id) DO UPDATE SET device_ids = ?3
+            "#
+        ))
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+    stmt.execute(params![service_id, device_ids, device_ids])
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+    Ok(())
+}
+
+/// Load the contacts of the contacts table `table`
+fn load_contacts_from(conn: &Connection, table: &str) -> Result<Vec<Contact>, SignalProtocolError> {
+    let mut stmt = conn
+        .prepare(&format!(
+            r#"
+            SELECT
+                service_id,
+                device_ids
+            FROM
+                {table}
+            "#
+        ))
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+    let mut rows = stmt
+        .query([])
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+    let mut contacts = vec![];
+    while let Some(row) = rows
+        .next()
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?
+    {
+        let mut device_ids = HashSet::new();
+        let row_service_id: String = row
+            .get(0)
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        let row_device_ids: String = row
+            .get(1)
+            .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+        if !row_device_ids.is_empty() {
+            for device_id in row_device_ids.split(",") {
+                device_ids.insert(DeviceId::from(device_id.parse::<u32>().map_err(|err| {
+                    SignalProtocolError::InvalidArgument(format!(
+                        "Could not parse device id: {err}"
+                    ))
+                })?));
+            }
+        }
+        contacts.push(Contact {
+            service_id: ServiceId::parse_from_service_id_string(row_service_id.as_str()).ok_or(
+                SignalProtocolError::InvalidArgument(format!(
+                    "Could not parse service_id: {}",
+                    row_service_id
+                )),
+            )?,
+            device_ids,
+        });
+    }
+
+    Ok(contacts)
+}
+
+/// Insert a nickname of a contact in the nicknames table `table`
+fn insert_nickname_in(
+    conn: &Connection,
+    table: &str,
+    nickname: &str,
+    service_id: &ServiceId,
+) -> Result<(), SignalProtocolError> {
+    let service_id = service_id.service_id_string();
+
+    let mut stmt = conn
+        .prepare(&format!(
+            r#"
+            INSERT INTO {table}(name, service_id)
+            VALUES(?1, ?2)
+            "#
+        ))
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+    stmt.execute(params![nickname, service_id])
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+    Ok(())
+}
+
+/// Look up the contact with the nickname `nickname` in the nicknames table `table`
+fn get_service_id_by_nickname_from(
+    conn: &Connection,
+    table: &str,
+    nickname: &str,
+) -> Result<ServiceId, SignalProtocolError> {
+    let mut stmt = conn
+        .prepare(&format!(
+            r#"
+            SELECT
+                service_id
+            FROM
+                {table}
+            WHERE
+                name = ?1
+            "#
+        ))
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+    let row: String = stmt
+        .query_row([nickname], |row| row.get(0))
+        .map_err(|err| SignalProtocolError::InvalidArgument(format!("{}", err)))?;
+
+    ServiceId::parse_from_service_id_string(&row).ok_or(SignalProtocolError::InvalidArgument(
+        "Could not parse service_id".to_owned(),
+    ))
+}
+
+#[cfg(test)]
+mod device_protocol_test {
+    use crate::{
+        key_manager::KeyManager,
+        storage::{
+            database::{
+                ClientDB, DeniableGroup, DeniableMessageStatus, DeniableStore,
+                DeviceIdentityKeyStore, DeviceKyberPreKeyStore, DevicePreKeyStore,
+                DeviceSessionStore, DeviceSignedPreKeyStore,
+            },
+            device::Device,
+            generic::ProtocolStore,
+            vault::DeniableVault,
+        },
+        test_utils::user::{new_contact, new_protocol_address, new_rand_number, new_service_id},
+    };
+    use async_std::sync::Mutex;
+    use common::{
+        deniable::chunk::Chunker,
+        signalservice::Envelope,
+        web_api::{
+            ChunkHeader, DeniableGroupMessage, DeniableKeyUpload, DeniablePayload,
+            DeniableSyncMessage, DenimChunk, IdentifierRequest, PreKeyRequest, SignalMessage,
+            UploadPreKey, UploadSignedPreKey,
+        },
+    };
+    use include_dir::{include_dir, Dir};
+    use libsignal_protocol::{
+        Direction, GenericSignedPreKey, IdentityKeyPair, IdentityKeyStore, KyberPreKeyStore,
+        PreKeyStore, SessionRecord, SessionStore, SignedPreKeyStore,
+    };
+    use proptest::prelude::*;
+    use rand::rngs::OsRng;
+    use rusqlite::{params, Connection};
+    use rusqlite_migration::Migrations;
+    use std::{
+        collections::HashMap,
+        env, fs,
+        sync::{Arc, LazyLock},
+    };
+    use uuid::Uuid;
+
+    static MIGRATIONS_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/client_db/migrations");
+    static MIGRATIONS: LazyLock<Migrations<'static>> =
+        LazyLock::new(|| Migrations::from_directory(&MIGRATIONS_DIR).unwrap());
+
+    async fn connect() -> Connection {
+        let mut conn = Connection::open_in_memory().expect("Could not open database");
+
+        MIGRATIONS
+            .to_latest(&mut conn)
+            .expect("Could not run migrations");
+
+        conn
+    }
+
+    #[tokio::test]
+    async fn save_and_get_identity_test() {
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
+        let mut device_identity_key_store = DeviceIdentityKeyStore::new(device);
+        let address = new_protocol_address();
+        let other_key_pair = IdentityKeyPair::generate(&mut OsRng);
+        let new_other_key_pair = IdentityKeyPair::generate(&mut OsRng);
+
+        // Test no identity exists
+        assert_eq!(
+            device_identity_key_store
+                .get_identity(&address)
+                .await
+                .unwrap(),
+            None
+        );
+
+        // Test that a new identity have been added
+        assert!(device_identity_key_store
+            .save_identity(&address, other_key_pair.identity_key())
+            .await
+            .unwrap());
+
+        assert_eq!(
+            device_identity_key_store
+                .get_identity(&address)
+                .await
+                .unwrap()
+                .unwrap(),
+            *other_key_pair.identity_key()
+        );
+
+        // Test we did not overwrite our identity
+        assert!(!device_identity_key_store
+            .save_identity(&address, other_key_pair.identity_key())
+            .await
+            .unwrap());
+
+        assert_eq!(
+            device_identity_key_store
+                .get_identity(&address)
+                .await
+                .unwrap()
+                .unwrap(),
+            *other_key_pair.identity_key()
+        );
+
+        // Test we overwrite our identity
+        assert!(!device_identity_key_store
+            .save_identity(&address, new_other_key_pair.identity_key())
+            .await
+            .unwrap());
+
+        assert_eq!(
+            device_identity_key_store
+                .get_identity(&address)
+                .await
+                .unwrap()
+                .unwrap(),
+            *new_other_key_pair.identity_key()
+        );
     }
     #[tokio::test]
     async fn is_trusted_identity_test() {
-        let device = Arc::new(Mutex::new(Device::new(connect().await)));
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
         let mut device_identity_key_store = DeviceIdentityKeyStore::new(device);
         let address = new_protocol_address();
         let other_key_pair = IdentityKeyPair::generate(&mut OsRng);
@@ -1527,7 +2488,10 @@ mod device_protocol_test {
     #[tokio::test]
     async fn save_and_get_pre_key_test() {
         let mut key_man = KeyManager::default();
-        let device = Arc::new(Mutex::new(Device::new(connect().await)));
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
         let mut device_pre_key_store = DevicePreKeyStore::new(device);
         let pre_key_record = key_man
             .generate_pre_key(&mut device_pre_key_store, &mut OsRng)
@@ -1559,7 +2523,10 @@ mod device_protocol_test {
     #[tokio::test]
     async fn remove_pre_key_test() {
         let mut key_man = KeyManager::default();
-        let device = Arc::new(Mutex::new(Device::new(connect().await)));
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
         let mut device_pre_key_store = DevicePreKeyStore::new(device);
         let pre_key_record = key_man
             .generate_pre_key(&mut device_pre_key_store, &mut OsRng)
@@ -1589,7 +2556,10 @@ mod device_protocol_test {
 
     #[tokio::test]
     async fn get_and_save_signed_pre_key_test() {
-        let device = Arc::new(Mutex::new(Device::new(connect().await)));
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
         device
             .lock()
             .await
@@ -1641,7 +2611,10 @@ mod device_protocol_test {
 
     #[tokio::test]
     async fn get_and_save_kyber_pre_key_test() {
-        let device = Arc::new(Mutex::new(Device::new(connect().await)));
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
 
         device
             .lock()
@@ -1700,7 +2673,10 @@ mod device_protocol_test {
 
     #[tokio::test]
     async fn load_and_store_session_test() {
-        let device = Arc::new(Mutex::new(Device::new(connect().await)));
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
         let mut device_session_store = DeviceSessionStore::new(device);
         let address = new_protocol_address();
         let record = SessionRecord::new_fresh();
@@ -1732,7 +2708,10 @@ mod device_protocol_test {
 
     #[tokio::test]
     async fn insert_and_get_key_ids() {
-        let device = Arc::new(Mutex::new(Device::new(connect().await)));
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
 
         device
             .lock()
@@ -1833,7 +2812,10 @@ mod device_protocol_test {
 
     #[tokio::test]
     async fn remove_key_and_get_ids_test() {
-        let device = Arc::new(Mutex::new(Device::new(connect().await)));
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
 
         device
             .lock()
@@ -1946,7 +2928,10 @@ mod device_protocol_test {
 
     #[tokio::test]
     async fn store_and_load_contact() {
-        let device = Arc::new(Mutex::new(Device::new(connect().await)));
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
 
         let contacts = vec![new_contact(), new_contact(), new_contact()];
 
@@ -1976,7 +2961,7 @@ mod device_protocol_test {
 
     #[tokio::test]
     async fn insert_and_get_address_by_nickname() {
-        let device = Arc::new(Device::new(connect().await));
+        let device = Device::new(connect().await, DeniableVault::in_memory().unwrap());
 
         let nicknames = vec!["Alice", "Bob", "Charlie"];
 
@@ -2021,4 +3006,704 @@ mod device_protocol_test {
             nickname_map[nicknames[2]]
         );
     }
+
+    #[tokio::test]
+    async fn deniable_contacts_are_kept_in_store() {
+        let device = Device::new(connect().await, DeniableVault::in_memory().unwrap());
+        let contact = new_contact();
+
+        device.store_deniable_contact(&contact).await.unwrap();
+        device
+            .insert_service_id_for_deniable_nickname("Alice", &contact.service_id)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            device
+                .get_service_id_by_deniable_nickname("Alice")
+                .await
+                .unwrap(),
+            contact.service_id
+        );
+        assert_eq!(
+            device.load_deniable_contacts().await.unwrap(),
+            vec![contact]
+        );
+        assert!(device.load_contacts().await.unwrap().is_empty());
+        assert!(device.get_service_id_by_nickname("Alice").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn generate_deniable_key_bundle_test() {
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
+        let identity_key_pair = IdentityKeyPair::generate(&mut OsRng);
+        let deniable_identity_key_pair = IdentityKeyPair::generate(&mut OsRng);
+        device
+            .lock()
+            .await
+            .insert_account_key_information(identity_key_pair, 1)
+            .await
+            .unwrap();
+        assert!(!device
+            .lock()
+            .await
+            .has_deniable_key_information()
+            .await
+            .unwrap());
+        device
+            .lock()
+            .await
+            .insert_deniable_key_information(deniable_identity_key_pair, 2)
+            .await
+            .unwrap();
+        assert!(device
+            .lock()
+            .await
+            .has_deniable_key_information()
+            .await
+            .unwrap());
+
+        let mut store = ProtocolStore::new(device.clone());
+        let key_upload = KeyManager::default()
+            .generate_deniable_key_bundle(&mut store)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            key_upload.identity_key,
+            deniable_identity_key_pair.identity_key().serialize()
+        );
+        assert_eq!(key_upload.registration_id, 2);
+        assert_eq!(
+            store
+                .identity_key_store
+                .get_identity_key_pair()
+                .await
+                .unwrap()
+                .identity_key(),
+            identity_key_pair.identity_key()
+        );
+        for signed_pre_key in [
+            &key_upload.signed_pre_key,
+            &key_upload.pq_last_resort_pre_key,
+        ] {
+            assert!(deniable_identity_key_pair
+                .public_key()
+                .verify_signature(&signed_pre_key.public_key, &signed_pre_key.signature)
+                .unwrap());
+            assert!(!identity_key_pair
+                .public_key()
+                .verify_signature(&signed_pre_key.public_key, &signed_pre_key.signature)
+                .unwrap());
+        }
+        for pre_key in key_upload.pre_keys {
+            assert_eq!(
+                store
+                    .pre_key_store
+                    .get_pre_key(pre_key.key_id.into())
+                    .await
+                    .unwrap()
+                    .public_key()
+                    .unwrap()
+                    .serialize(),
+                pre_key.public_key
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn store_and_remove_identifier_request_sent() {
+        let device = Device::new(connect().await, DeniableVault::in_memory().unwrap());
+        let phone_number = "+4512345678".to_owned();
+
+        assert_eq!(
+            device
+                .try_get_identifier_request_sent(phone_number.clone())
+                .await
+                .unwrap(),
+            None
+        );
+        device
+            .store_identifier_request_sent(phone_number.clone(), "Bob".to_owned())
+            .await
+            .unwrap();
+        device
+            .store_identifier_request_sent(phone_number.clone(), "Bobby".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            device
+                .try_get_identifier_request_sent(phone_number.clone())
+                .await
+                .unwrap(),
+            Some("Bobby".to_owned())
+        );
+
+        device
+            .remove_identifier_request_sent(phone_number.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            device
+                .try_get_identifier_request_sent(phone_number)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn deniable_message_status_only_moves_forward() {
+        let device = Device::new(connect().await, DeniableVault::in_memory().unwrap());
+        let service_id = new_service_id().service_id_string();
+        let timestamp = 1730217386123;
+
+        assert_eq!(
+            device
+                .get_deniable_message_status(service_id.clone(), timestamp)
+                .await
+                .unwrap(),
+            None
+        );
+        // Receipts for unknown messages are ignored
+        assert!(!device
+            .update_deniable_message_status(
+                service_id.clone(),
+                timestamp,
+                DeniableMessageStatus::Delivered
+            )
+            .await
+            .unwrap());
+
+        device
+            .store_deniable_message_sent(service_id.clone(), timestamp)
+            .await
+            .unwrap();
+        assert_eq!(
+            device
+                .get_deniable_message_status(service_id.clone(), timestamp)
+                .await
+                .unwrap(),
+            Some(DeniableMessageStatus::Sent)
+        );
+
+        assert!(device
+            .update_deniable_message_status(
+                service_id.clone(),
+                timestamp,
+                DeniableMessageStatus::Read
+            )
+            .await
+            .unwrap());
+        // A late delivery receipt does not move a read message back
+        assert!(!device
+            .update_deniable_message_status(
+                service_id.clone(),
+                timestamp,
+                DeniableMessageStatus::Delivered
+            )
+            .await
+            .unwrap());
+        assert_eq!(
+            device
+                .get_deniable_message_status(service_id, timestamp)
+                .await
+                .unwrap(),
+            Some(DeniableMessageStatus::Read)
+        );
+    }
+
+    #[tokio::test]
+    async fn remove_expired_deniable_payloads_keeps_started_payloads() {
+        let device = Device::new(connect().await, DeniableVault::in_memory().unwrap());
+
+        device
+            .store_expiring_deniable_payload(vec![1; 10], 100, Some(42))
+            .await
+            .unwrap();
+        device
+            .store_expiring_deniable_payload(vec![2; 10], 200, None)
+            .await
+            .unwrap();
+        device
+            .store_deniable_payload(None, 0, vec![3; 10])
+            .await
+            .unwrap();
+
+        // The first payload started sending, so its rest is still sent
+        let (id, content, chunk_count, _) = device.get_deniable_payload().await.unwrap();
+        device
+            .store_deniable_payload(Some(id), chunk_count - 1, content[5..].to_vec())
+            .await
+            .unwrap();
+
+        assert!(device
+            .remove_expired_deniable_payloads(50)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            device.remove_expired_deniable_payloads(300).await.unwrap(),
+            vec![(vec![2; 10], None)]
+        );
+    }
+
+    #[tokio::test]
+    async fn deniable_stream_ids_are_not_reused() {
+        let device = Device::new(connect().await, DeniableVault::in_memory().unwrap());
+
+        device
+            .store_deniable_payload(None, 0, vec![1; 10])
+            .await
+            .unwrap();
+        let (first, _, _, _) = device.get_deniable_payload().await.unwrap();
+        device.remove_deniable_payload(first).await.unwrap();
+        device
+            .store_expiring_deniable_payload(vec![2; 10], 100, None)
+            .await
+            .unwrap();
+        let (second, _, _, _) = device.get_deniable_payload().await.unwrap();
+        device.remove_deniable_payload(second).await.unwrap();
+        device
+            .store_deniable_payload(None, 0, vec![3; 10])
+            .await
+            .unwrap();
+        let (third, _, _, _) = device.get_deniable_payload().await.unwrap();
+
+        assert!(first < second && second < third);
+    }
+
+    #[tokio::test]
+    async fn legacy_deniable_tables_move_into_store() {
+        let conn = connect().await;
+        conn.execute(
+            r#"
+            INSERT INTO DeniablePayload (id, content, chunk_count, total_length)
+            VALUES (7, ?1, 0, 3)
+            "#,
+            params![vec![1u8, 2, 3]],
+        )
+        .unwrap();
+        let table_exists = |device: &Device, table: &str| -> bool {
+            device
+                .conn
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = ?1)",
+                    [table],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        let path = env::temp_dir().join(format!("{}.vault", Uuid::new_v4()));
+        let mut device = Device::new(
+            conn,
+            DeniableVault::open(&path, Some("correct horse")).unwrap(),
+        );
+
+        // Rows are not moved into a store that is not kept, empty tables are dropped
+        device.move_legacy_deniable_tables().unwrap();
+        assert!(table_exists(&device, "DeniablePayload"));
+        assert!(!table_exists(&device, "DeniableGroups"));
+
+        device.create_deniable_store().await.unwrap();
+        assert!(!table_exists(&device, "DeniablePayload"));
+        assert_eq!(device.next_deniable_stream_id().unwrap(), 8);
+        drop(device);
+
+        let device = Device::new(
+            connect().await,
+            DeniableVault::open(&path, Some("correct horse")).unwrap(),
+        );
+        assert_eq!(
+            device.get_deniable_payload().await.unwrap(),
+            (7, vec![1, 2, 3], 0, 3)
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_deniable_message_was_not_delivered() {
+        let device = Device::new(connect().await, DeniableVault::in_memory().unwrap());
+        let service_id = new_service_id().service_id_string();
+
+        device
+            .store_deniable_message_sent(service_id.clone(), 1)
+            .await
+            .unwrap();
+        device
+            .store_deniable_message_sent(service_id.clone(), 2)
+            .await
+            .unwrap();
+        device
+            .update_deniable_message_status(service_id.clone(), 2, DeniableMessageStatus::Delivered)
+            .await
+            .unwrap();
+
+        assert!(device
+            .fail_deniable_message(service_id.clone(), 1)
+            .await
+            .unwrap());
+        assert!(!device
+            .fail_deniable_message(service_id.clone(), 2)
+            .await
+            .unwrap());
+        assert_eq!(
+            device
+                .get_deniable_message_status(service_id.clone(), 1)
+                .await
+                .unwrap(),
+            Some(DeniableMessageStatus::Failed)
+        );
+        assert_eq!(
+            device
+                .get_deniable_message_status(service_id, 2)
+                .await
+                .unwrap(),
+            Some(DeniableMessageStatus::Delivered)
+        );
+    }
+
+    #[tokio::test]
+    async fn store_and_take_syncs_awaiting_encryption() {
+        let device = Device::new(connect().await, DeniableVault::in_memory().unwrap());
+        device
+            .store_sync_awaiting_encryption(vec![1, 2])
+            .await
+            .unwrap();
+        device
+            .store_sync_awaiting_encryption(vec![3])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            device.take_syncs_awaiting_encryption().await.unwrap(),
+            vec![vec![1, 2], vec![3]]
+        );
+        assert!(device
+            .take_syncs_awaiting_encryption()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn store_and_get_deniable_group() {
+        let device = Device::new(connect().await, DeniableVault::in_memory().unwrap());
+        let mut group = DeniableGroup {
+            group_id: Uuid::new_v4(),
+            name: "friends".to_owned(),
+            members: vec![new_service_id(), new_service_id()],
+        };
+
+        assert_eq!(
+            device.get_deniable_group(group.group_id).await.unwrap(),
+            None
+        );
+        device.store_deniable_group(&group).await.unwrap();
+        assert_eq!(
+            device.get_deniable_group(group.group_id).await.unwrap(),
+            Some(group.clone())
+        );
+
+        group.members.push(new_service_id());
+        device.store_deniable_group(&group).await.unwrap();
+        assert_eq!(
+            device.get_deniable_group_by_name("friends").await.unwrap(),
+            Some(group)
+        );
+        assert_eq!(
+            device.get_deniable_group_by_name("family").await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn deniable_tables_are_not_in_regular_database() {
+        let mut device = Device::new(connect().await, DeniableVault::in_memory().unwrap());
+        device.move_legacy_deniable_tables().unwrap();
+        device
+            .store_deniable_payload(None, 0, vec![1, 2, 3])
+            .await
+            .unwrap();
+        assert_eq!(
+            device.get_deniable_payload().await.unwrap().1,
+            vec![1, 2, 3]
+        );
+
+        let deniable_tables: u32 = device
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name LIKE '%Deniable%'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(deniable_tables, 0);
+    }
+
+    fn arbitrary_payload() -> impl Strategy<Value = DeniablePayload> {
+        prop_oneof![
+            (".*", any::<u32>(), proptest::option::of(any::<u64>())).prop_map(
+                |(content, destination_device_id, expires_at)| {
+                    DeniablePayload::SignalMessage(SignalMessage {
+                        r#type: 1,
+                        destination_device_id,
+                        destination_service_id: Some(new_service_id().service_id_string()),
+                        content,
+                        expires_at,
+                        ..Default::default()
+                    })
+                }
+            ),
+            (prop::collection::vec(any::<u8>(), 0..2000), any::<u64>()).prop_map(
+                |(content, timestamp)| {
+                    DeniablePayload::Envelope(Envelope {
+                        content: Some(content),
+                        timestamp: Some(timestamp),
+                        ..Default::default()
+                    })
+                }
+            ),
+            ".*".prop_map(|service_id| DeniablePayload::KeyRequest(PreKeyRequest { service_id })),
+            ".*".prop_map(
+                |phone_number| DeniablePayload::IdentifierRequest(IdentifierRequest {
+                    phone_number
+                })
+            ),
+            (any::<u32>(), prop::collection::vec(any::<u8>(), 0..64)).prop_map(
+                |(key_id, public_key)| {
+                    let signed_pre_key = UploadSignedPreKey {
+                        key_id,
+                        public_key: public_key.clone().into(),
+                        signature: public_key.clone().into(),
+                    };
+                    DeniablePayload::KeyUpload(DeniableKeyUpload {
+                        identity_key: IdentityKeyPair::generate(&mut OsRng)
+                            .identity_key()
+                            .serialize(),
+                        registration_id: key_id,
+                        signed_pre_key: signed_pre_key.clone(),
+                        pq_last_resort_pre_key: signed_pre_key,
+                        pre_keys: vec![UploadPreKey {
+                            key_id,
+                            public_key: public_key.into(),
+                        }],
+                    })
+                }
+            ),
+            (".*", proptest::option::of(any::<u64>())).prop_map(|(content, expires_at)| {
+                DeniablePayload::GroupMessage(DeniableGroupMessage {
+                    members: vec![new_service_id().service_id_string()],
+                    content,
+                    expires_at,
+                })
+            }),
+            (
+                prop::collection::vec((any::<u32>(), ".*"), 0..4),
+                proptest::option::of(any::<u64>())
+            )
+                .prop_map(|(messages, expires_at)| {
+                    DeniablePayload::SyncMessage(DeniableSyncMessage {
+                        messages: messages
+                            .into_iter()
+                            .map(|(destination_device_id, content)| SignalMessage {
+                                r#type: 1,
+                                destination_device_id,
+                                content,
+                                ..Default::default()
+                            })
+                            .collect(),
+                        expires_at,
+                    })
+                }),
+        ]
+    }
+
+    /// Send the payloads from one device to another through deniable chunks
+    async fn send_deniable_payloads(
+        payloads: &[DeniablePayload],
+        regular_payload_size: f32,
+        chunker: &Chunker,
+    ) -> (Vec<DeniablePayload>, usize) {
+        let sender = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
+        let mut sender_store = DeniableStore::new(sender.clone());
+        let receiver_store = DeniableStore::new(Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        ))));
+
+        for payload in payloads {
+            sender
+                .lock()
+                .await
+                .store_deniable_payload(None, 0, bincode::serialize(payload).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let mut received = Vec::new();
+        let mut error_count = 0;
+        loop {
+            let (chunks, _) = chunker
+                .create_chunks(regular_payload_size, &mut sender_store)
+                .await
+                .unwrap();
+            let chunks: Vec<DenimChunk> = chunks
+                .into_iter()
+                .filter(|chunk| !chunk.is_dummy())
+                .collect();
+            if chunks.is_empty() {
+                break;
+            }
+            let (new_payloads, _, errors) = receiver_store.receive_chunks(chunks).await.unwrap();
+            received.extend(new_payloads);
+            error_count += errors.len();
+        }
+
+        (received, error_count)
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn deniable_payloads_round_trip(
+            payloads in prop::collection::vec(arbitrary_payload(), 0..8),
+            q_value in 0.5_f32..5.0,
+            regular_payload_size in 100_usize..2000,
+        ) {
+            let (received, error_count) = tokio::runtime::Runtime::new().unwrap().block_on(
+                send_deniable_payloads(&payloads, regular_payload_size as f32, &Chunker::new(q_value)),
+            );
+
+            prop_assert_eq!(received, payloads);
+            prop_assert_eq!(error_count, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn receive_chunks_drops_malformed_chunks_and_payloads() {
+        let store = DeniableStore::new(Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        ))));
+        let payload = DeniablePayload::KeyRequest(PreKeyRequest {
+            service_id: new_service_id().service_id_string(),
+        });
+        let data = bincode::serialize(&payload).unwrap();
+
+        let chunks = vec![
+            DenimChunk {
+                chunk: data.clone(),
+                flags: 2,
+                header: ChunkHeader::new(1, 0, data.len() as u32),
+            },
+            // Unknown flags
+            DenimChunk {
+                chunk: data.clone(),
+                flags: 7,
+                header: ChunkHeader::new(2, 0, data.len() as u32),
+            },
+            // Not a serialized payload
+            DenimChunk {
+                chunk: vec![0xff; 8],
+                flags: 2,
+                header: ChunkHeader::new(3, 0, 8),
+            },
+            // Incomplete
+            DenimChunk {
+                chunk: data.clone(),
+                flags: 0,
+                header: ChunkHeader::new(4, 0, 2 * data.len() as u32),
+            },
+        ];
+
+        let (payloads, incomplete_streams, errors) = store.receive_chunks(chunks).await.unwrap();
+
+        assert_eq!(payloads, vec![payload]);
+        assert_eq!(incomplete_streams, vec![4]);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn receive_chunks_drops_late_duplicates() {
+        let store = DeniableStore::new(Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        ))));
+        let payload = DeniablePayload::KeyRequest(PreKeyRequest {
+            service_id: new_service_id().service_id_string(),
+        });
+        let data = bincode::serialize(&payload).unwrap();
+        let chunks = vec![
+            DenimChunk {
+                chunk: data[..1].to_vec(),
+                flags: 0,
+                header: ChunkHeader::new(1, 0, data.len() as u32),
+            },
+            DenimChunk {
+                chunk: data[1..].to_vec(),
+                flags: 2,
+                header: ChunkHeader::new(1, 1, data.len() as u32),
+            },
+        ];
+
+        let (payloads, _, _) = store.receive_chunks(chunks.clone()).await.unwrap();
+        let (duplicates, incomplete_streams, errors) =
+            store.receive_chunks(chunks[..1].to_vec()).await.unwrap();
+
+        assert_eq!(payloads, vec![payload]);
+        assert!(duplicates.is_empty());
+        assert!(incomplete_streams.is_empty());
+        assert!(errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn flagged_sent_chunks_are_sent_again() {
+        let device = Arc::new(Mutex::new(Device::new(
+            connect().await,
+            DeniableVault::in_memory().unwrap(),
+        )));
+        let mut store = DeniableStore::new(device.clone());
+        let chunker = Chunker::default();
+        device
+            .lock()
+            .await
+            .store_deniable_payload(None, 0, vec![7; 300])
+            .await
+            .unwrap();
+
+        let (chunks, _) = chunker.create_chunks(100.0, &mut store).await.unwrap();
+        let sent: Vec<DenimChunk> = chunks
+            .into_iter()
+            .filter(|chunk| !chunk.is_dummy())
+            .collect();
+        device
+            .lock()
+            .await
+            .store_sent_deniable_chunks(sent.clone())
+            .await
+            .unwrap();
+        let (unflagged, _) = chunker.create_chunks(100.0, &mut store).await.unwrap();
+
+        // The server missed the denim message carrying them
+        device
+            .lock()
+            .await
+            .flag_sent_deniable_chunks(&sent.iter().map(|chunk| chunk.header).collect::<Vec<_>>())
+            .await
+            .unwrap();
+        let (resent, _) = chunker.create_chunks(100.0, &mut store).await.unwrap();
+
+        assert!(!sent.is_empty());
+        assert_ne!(unflagged[..sent.len()], sent[..]);
+        assert_eq!(resent[..sent.len()], sent[..]);
+    }
 }
@@ -1,8 +1,8 @@
-use super::database::ClientDB;
+use super::database::{ClientDB, DeniableGroup, DeniableMessageStatus};
 use crate::contact_manager::{Contact, ContactName};
 use axum::async_trait;
-use common::web_api::DenimChunk;
-use libsignal_core::{Aci, Pni, ProtocolAddress, ServiceId};
+use common::web_api::{ChunkHeader, DenimChunk};
+use libsignal_core::{Aci, DeviceId, Pni, ProtocolAddress, ServiceId};
 use libsignal_protocol::{
     Direction, IdentityKey, IdentityKeyPair, IdentityKeyStore, InMemIdentityKeyStore,
     InMemKyberPreKeyStore, InMemPreKeyStore, InMemSenderKeyStore, InMemSessionStore,
@@ -17,6 +17,7 @@ pub struct InMemory {
     password: String,
     aci: Aci,
     pni: Pni,
+    device_id: DeviceId,
     identity_key_store: InMemIdentityKeyStore,
     pre_key_store: InMemPreKeyStore,
     signed_pre_key_store: InMemSignedPreKeyStore,
@@ -38,6 +39,7 @@ impl InMemory {
             password,
             aci,
             pni,
+            device_id: 1.into(),
             identity_key_store: InMemIdentityKeyStore::new(key_pair, registration_id),
             pre_key_store: InMemPreKeyStore::new(),
             signed_pre_key_store: InMemSignedPreKeyStore::new(),
@@ -101,6 +103,29 @@ impl ClientDB for InMemory {
         todo!()
     }
 
+    async fn store_deniable_contact(&self, _contact: &Contact) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn load_deniable_contacts(&self) -> Result<Vec<Contact>, Self::Error> {
+        todo!()
+    }
+
+    async fn insert_service_id_for_deniable_nickname(
+        &self,
+        _nickname: &str,
+        _service_id: &ServiceId,
+    ) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn get_service_id_by_deniable_nickname(
+        &self,
+        _nickname: &str,
+    ) -> Result<ServiceId, Self::Error> {
+        todo!()
+    }
+
     async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair, Self::Error> {
         self.identity_key_store.get_identity_key_pair().await
     }
@@ -109,6 +134,26 @@ impl ClientDB for InMemory {
         self.identity_key_store.get_local_registration_id().await
     }
 
+    async fn insert_deniable_key_information(
+        &self,
+        _key_pair: IdentityKeyPair,
+        _registration_id: u32,
+    ) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn has_deniable_key_information(&self) -> Result<bool, Self::Error> {
+        todo!()
+    }
+
+    async fn get_deniable_identity_key_pair(&self) -> Result<IdentityKeyPair, Self::Error> {
+        todo!()
+    }
+
+    async fn get_deniable_local_registration_id(&self) -> Result<u32, Self::Error> {
+        todo!()
+    }
+
     async fn save_identity(
         &mut self,
         address: &ProtocolAddress,
@@ -295,7 +340,16 @@ impl ClientDB for InMemory {
         Ok(self.pni)
     }
 
-    async fn get_deniable_payload(&self) -> Result<(u32, Vec<u8>, i32), Self::Error> {
+    async fn set_device_id(&mut self, new_device_id: DeviceId) -> Result<(), Self::Error> {
+        self.device_id = new_device_id;
+        Ok(())
+    }
+
+    async fn get_device_id(&self) -> Result<DeviceId, Self::Error> {
+        Ok(self.device_id)
+    }
+
+    async fn get_deniable_payload(&self) -> Result<(u32, Vec<u8>, i32, u32), Self::Error> {
         todo!()
     }
 
@@ -315,6 +369,22 @@ impl ClientDB for InMemory {
         todo!()
     }
 
+    async fn store_expiring_deniable_payload(
+        &self,
+        _: Vec<u8>,
+        _: u64,
+        _: Option<u64>,
+    ) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn remove_expired_deniable_payloads(
+        &self,
+        _: u64,
+    ) -> Result<Vec<(Vec<u8>, Option<u64>)>, Self::Error> {
+        todo!()
+    }
+
     async fn try_get_key_request_sent(&self, _: String) -> Result<Option<String>, Self::Error> {
         todo!()
     }
@@ -327,6 +397,21 @@ impl ClientDB for InMemory {
         todo!()
     }
 
+    async fn try_get_identifier_request_sent(
+        &self,
+        _: String,
+    ) -> Result<Option<String>, Self::Error> {
+        todo!()
+    }
+
+    async fn store_identifier_request_sent(&self, _: String, _: String) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn remove_identifier_request_sent(&self, _: String) -> Result<(), Self::Error> {
+        todo!()
+    }
+
     async fn get_messages_awaiting_encryption(
         &self,
         _: String,
@@ -346,6 +431,14 @@ impl ClientDB for InMemory {
         todo!()
     }
 
+    async fn store_sync_awaiting_encryption(&self, _: Vec<u8>) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn take_syncs_awaiting_encryption(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        todo!()
+    }
+
     async fn get_and_remove_incoming_deniable_chunks(
         &mut self,
     ) -> Result<Vec<DenimChunk>, Self::Error> {
@@ -358,4 +451,75 @@ impl ClientDB for InMemory {
     ) -> Result<(), Self::Error> {
         todo!()
     }
+
+    async fn store_sent_deniable_chunks(&mut self, _: Vec<DenimChunk>) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn flag_sent_deniable_chunks(&self, _: &[ChunkHeader]) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn take_retransmit_deniable_chunk(
+        &self,
+        _: usize,
+    ) -> Result<Option<DenimChunk>, Self::Error> {
+        todo!()
+    }
+
+    async fn store_deniable_message_sent(&self, _: String, _: u64) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn update_deniable_message_status(
+        &self,
+        _: String,
+        _: u64,
+        _: DeniableMessageStatus,
+    ) -> Result<bool, Self::Error> {
+        todo!()
+    }
+
+    async fn fail_deniable_message(&self, _: String, _: u64) -> Result<bool, Self::Error> {
+        todo!()
+    }
+
+    async fn get_deniable_message_status(
+        &self,
+        _: String,
+        _: u64,
+    ) -> Result<Option<DeniableMessageStatus>, Self::Error> {
+        todo!()
+    }
+
+    async fn store_deniable_group(&self, _: &DeniableGroup) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn get_deniable_group(&self, _: Uuid) -> Result<Option<DeniableGroup>, Self::Error> {
+        todo!()
+    }
+
+    async fn get_deniable_group_by_name(
+        &self,
+        _: &str,
+    ) -> Result<Option<DeniableGroup>, Self::Error> {
+        todo!()
+    }
+
+    async fn is_deniable_store_open(&self) -> Result<bool, Self::Error> {
+        todo!()
+    }
+
+    async fn create_deniable_store(&mut self) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn flush_deniable_store(&self) -> Result<(), Self::Error> {
+        todo!()
+    }
+
+    async fn set_deniable_duress_passphrase(&mut self, _: &str) -> Result<(), Self::Error> {
+        todo!()
+    }
 }
@@ -2,3 +2,4 @@ pub mod database;
 pub mod device;
 pub mod generic;
 pub mod in_memory;
+pub mod vault;
@@ -0,0 +1,725 @@
+use std::{
+    cell::Cell,
+    error::Error,
+    fmt,
+    fs::{self, File, FileTimes, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use aes::Aes256;
+use bincode::{deserialize, serialize};
+use cbc::cipher::{block_padding::NoPadding, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use derive_more::derive::{Display, From};
+use hmac::{Hmac, Mac};
+use include_dir::{include_dir, Dir};
+use pbkdf2::pbkdf2_hmac;
+use rand::{rngs::OsRng, CryptoRng, Rng};
+use rusqlite::{params_from_iter, types::Value, Connection};
+use rusqlite_migration::Migrations;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Size of every deniable store file, whether it holds a store or not
+pub const VAULT_SIZE: usize = 4 * 1024 * 1024;
+
+const SALT_LENGTH: usize = 16;
+const DURESS_TAG_LENGTH: usize = 32;
+const IV_LENGTH: usize = 16;
+const MAC_LENGTH: usize = 32;
+const CIPHER_KEY_LENGTH: usize = 32;
+/// An AES-256 key followed by an HMAC-SHA256 key
+const KEY_LENGTH: usize = 64;
+const LENGTH_PREFIX: usize = 8;
+/// Bytes of padded plaintext sealed in a store file
+const CAPACITY: usize = VAULT_SIZE - SALT_LENGTH - DURESS_TAG_LENGTH - IV_LENGTH - MAC_LENGTH;
+/// Changes are sealed in the file at most this often, sealing re-encrypts the whole file
+const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+#[cfg(not(test))]
+const KDF_ROUNDS: u32 = 600_000;
+#[cfg(test)]
+const KDF_ROUNDS: u32 = 1_000;
+
+type Aes256CbcEnc = cbc::Encryptor<Aes256>;
+type Aes256CbcDec = cbc::Decryptor<Aes256>;
+
+static DENIABLE_MIGRATIONS_DIR: Dir =
+    include_dir!("$CARGO_MANIFEST_DIR/client_db/deniable_migrations");
+static DENIABLE_MIGRATIONS: LazyLock<Migrations<'static>> =
+    LazyLock::new(|| Migrations::from_directory(&DENIABLE_MIGRATIONS_DIR).unwrap());
+
+#[derive(Debug, Display, From)]
+pub enum DeniableVaultError {
+    #[from]
+    Io(std::io::Error),
+    #[from]
+    Database(rusqlite::Error),
+    #[from]
+    Migration(rusqlite_migration::Error),
+    #[from]
+    Encoding(bincode::Error),
+    #[display("The deniable store is full")]
+    Full,
+    #[display("The deniable store is locked")]
+    Locked,
+    #[display("The duress passphrase must differ from the deniable passphrase")]
+    DuressIsPassphrase,
+}
+
+impl Error for DeniableVaultError {}
+
+/// The deniable tables, kept in memory and sealed in a file of `VAULT_SIZE` bytes
+/// under a key derived from the deniable passphrase.
+/// A file that holds no store is filled with random bytes, and a passphrase that does not
+/// open the file opens an empty store, so a locked store cannot be told from no store.
+/// Such a store is never written to the file until it is created with [DeniableVault::create],
+/// the file may hold a store under another passphrase.
+///
+/// Changes are sealed in the file at most every `SAVE_INTERVAL`, and when the store is flushed
+/// or dropped. The sealed store replaces the file at once, and the file keeps the access and
+/// modification times it had when it was opened, like a file that holds no store.
+///
+/// The file is laid out as salt, duress tag, IV, ciphertext and MAC. The duress tag is derived
+/// from the duress passphrase like the key is from the deniable passphrase, and is random
+/// until a duress passphrase is set.
+#[derive(Debug)]
+pub struct DeniableVault {
+    conn: Connection,
+    file: Option<VaultFile>,
+    /// Whether there are changes that are not sealed in the file yet
+    dirty: Cell<bool>,
+    last_saved: Cell<Instant>,
+}
+
+struct VaultFile {
+    path: PathBuf,
+    salt: [u8; SALT_LENGTH],
+    duress_tag: [u8; DURESS_TAG_LENGTH],
+    key: [u8; KEY_LENGTH],
+    /// Access and modification times of the file when it was opened
+    times: FileTimes,
+    /// The passphrase opened the file or a new store was created in it
+    writable: bool,
+}
+
+impl fmt::Debug for VaultFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VaultFile")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DeniableVault {
+    /// Store that lives in memory only and is lost when it is dropped
+    pub fn in_memory() -> Result<Self, DeniableVaultError> {
+        let mut conn = Connection::open_in_memory()?;
+        DENIABLE_MIGRATIONS.to_latest(&mut conn)?;
+        Ok(Self::new(conn, None))
+    }
+
+    fn new(conn: Connection, file: Option<VaultFile>) -> Self {
+        Self {
+            conn,
+            file,
+            dirty: Cell::new(false),
+            last_saved: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Open the store sealed in the file at `path` with `passphrase`, creating the file if it
+    /// does not exist. Without a passphrase the store lives in memory and the file is left as is.
+    /// A passphrase that does not open the file gives an empty store, which is kept in memory
+    /// until it is created.
+    ///
+    /// The duress passphrase erases the file, keeping its size and access and modification
+    /// times, and opens the store like no passphrase was given.
+    pub fn open(path: &Path, passphrase: Option<&str>) -> Result<Self, DeniableVaultError> {
+        if !path.exists() {
+            let mut noise = vec![0; VAULT_SIZE];
+            OsRng.fill(noise.as_mut_slice());
+            fs::write(path, noise)?;
+        }
+
+        let Some(passphrase) = passphrase else {
+            return Self::in_memory();
+        };
+
+        let metadata = fs::metadata(path)?;
+        let times = FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?);
+        let sealed = fs::read(path)?;
+        let salt: [u8; SALT_LENGTH] = sealed
+            .get(..SALT_LENGTH)
+            .and_then(|salt| salt.try_into().ok())
+            .unwrap_or_default();
+        let duress_tag: [u8; DURESS_TAG_LENGTH] = sealed
+            .get(SALT_LENGTH..SALT_LENGTH + DURESS_TAG_LENGTH)
+            .and_then(|duress_tag| duress_tag.try_into().ok())
+            .unwrap_or_default();
+        let (key, passphrase_tag) = derive_keys(passphrase, &salt);
+
+        let mut conn = Connection::open_in_memory()?;
+        let file = match unseal(&sealed, &key) {
+            Some(contents) => {
+                restore(&mut conn, deserialize(&contents)?)?;
+                VaultFile {
+                    path: path.to_owned(),
+                    salt,
+                    duress_tag,
+                    key,
+                    times,
+                    writable: true,
+                }
+            }
+            None if sealed.len() == VAULT_SIZE && passphrase_tag == duress_tag => {
+                wipe(path, times)?;
+                return Self::in_memory();
+            }
+            None => {
+                DENIABLE_MIGRATIONS.to_latest(&mut conn)?;
+                let mut salt = [0; SALT_LENGTH];
+                OsRng.fill(&mut salt);
+                let mut duress_tag = [0; DURESS_TAG_LENGTH];
+                OsRng.fill(&mut duress_tag);
+                VaultFile {
+                    path: path.to_owned(),
+                    salt,
+                    duress_tag,
+                    key: derive_keys(passphrase, &salt).0,
+                    times,
+                    writable: false,
+                }
+            }
+        };
+
+        Ok(Self::new(conn, Some(file)))
+    }
+
+    /// Whether the store is kept in its file, i.e. the passphrase opened it or it was created
+    pub fn is_open(&self) -> bool {
+        self.file.as_ref().is_some_and(|file| file.writable)
+    }
+
+    /// Create the store in its file, replacing whatever the file held
+    pub fn create(&mut self) -> Result<(), DeniableVaultError> {
+        let Some(file) = &mut self.file else {
+            return Err(DeniableVaultError::Locked);
+        };
+        file.writable = true;
+        self.dirty.set(true);
+        self.flush()
+    }
+
+    pub fn conn(&self) -> &Connection {
+        &self.conn
+    }
+
+    pub fn conn_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    /// Set the passphrase that erases the store when it is given
+    /// in place of the deniable passphrase
+    pub fn set_duress_passphrase(&mut self, passphrase: &str) -> Result<(), DeniableVaultError> {
+        let Some(file) = self.file.as_mut().filter(|file| file.writable) else {
+            return Err(DeniableVaultError::Locked);
+        };
+        let (key, duress_tag) = derive_keys(passphrase, &file.salt);
+        if key == file.key {
+            return Err(DeniableVaultError::DuressIsPassphrase);
+        }
+        file.duress_tag = duress_tag;
+        self.dirty.set(true);
+        self.flush()
+    }
+
+    /// Note that the store changed. The changes are sealed in the file if `SAVE_INTERVAL` passed
+    /// since it was last written, later changes are sealed along with them.
+    /// Does nothing for a store that is not kept in its file.
+    pub fn save(&self) -> Result<(), DeniableVaultError> {
+        if !self.is_open() {
+            return Ok(());
+        }
+        self.dirty.set(true);
+        if self.last_saved.get().elapsed() < SAVE_INTERVAL {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    /// Seal the changes to the store in its file, padded to `VAULT_SIZE`
+    pub fn flush(&self) -> Result<(), DeniableVaultError> {
+        let Some(file) = self.file.as_ref().filter(|file| file.writable) else {
+            return Ok(());
+        };
+        if !self.dirty.get() {
+            return Ok(());
+        }
+        let contents = serialize(&dump(&self.conn)?)?;
+        replace(
+            &file.path,
+            &seal(
+                &contents,
+                &file.salt,
+                &file.duress_tag,
+                &file.key,
+                &mut OsRng,
+            )?,
+            file.times,
+        )?;
+        self.dirty.set(false);
+        self.last_saved.set(Instant::now());
+        Ok(())
+    }
+}
+
+impl Drop for DeniableVault {
+    /// Best effort, callers that must know whether the changes were sealed flush first
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Schema and rows of the deniable tables
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct VaultContents {
+    user_version: i32,
+    schema: Vec<String>,
+    tables: Vec<VaultTable>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VaultTable {
+    name: String,
+    columns: Vec<String>,
+    rows: Vec<Vec<VaultValue>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum VaultValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl From<Value> for VaultValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => Self::Null,
+            Value::Integer(value) => Self::Integer(value),
+            Value::Real(value) => Self::Real(value),
+            Value::Text(value) => Self::Text(value),
+            Value::Blob(value) => Self::Blob(value),
+        }
+    }
+}
+
+impl From<VaultValue> for Value {
+    fn from(value: VaultValue) -> Self {
+        match value {
+            VaultValue::Null => Self::Null,
+            VaultValue::Integer(value) => Self::Integer(value),
+            VaultValue::Real(value) => Self::Real(value),
+            VaultValue::Text(value) => Self::Text(value),
+            VaultValue::Blob(value) => Self::Blob(value),
+        }
+    }
+}
+
+fn dump(conn: &Connection) -> rusqlite::Result<VaultContents> {
+    let user_version = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let schema = conn
+        .prepare(
+            r#"
+        SELECT
+            sql
+        FROM
+            sqlite_master
+        WHERE
+            sql IS NOT NULL AND name NOT LIKE 'sqlite_%'
+        ORDER BY
+            rowid
+        "#,
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    let names = conn
+        .prepare(
+            r#"
+        SELECT
+            name
+        FROM
+            sqlite_master
+        WHERE
+            type = 'table' AND name NOT LIKE 'sqlite_%'
+        ORDER BY
+            rowid
+        "#,
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut tables = Vec::new();
+    for name in names {
+        let mut stmt = conn.prepare(&format!("SELECT * FROM \"{name}\""))?;
+        let columns = stmt
+            .column_names()
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<String>>();
+        let rows = stmt
+            .query_map([], |row| {
+                (0..columns.len())
+                    .map(|index| row.get::<_, Value>(index).map(VaultValue::from))
+                    .collect()
+            })?
+            .collect::<rusqlite::Result<Vec<Vec<VaultValue>>>>()?;
+        tables.push(VaultTable {
+            name,
+            columns,
+            rows,
+        });
+    }
+
+    Ok(VaultContents {
+        user_version,
+        schema,
+        tables,
+    })
+}
+
+/// Recreate the dumped tables and bring them up to the latest deniable migration
+fn restore(conn: &mut Connection, contents: VaultContents) -> Result<(), DeniableVaultError> {
+    let tx = conn.transaction()?;
+    for sql in &contents.schema {
+        tx.execute_batch(sql)?;
+    }
+    for table in contents.tables {
+        let columns = table
+            .columns
+            .iter()
+            .map(|column| format!("\"{column}\""))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let values = vec!["?"; table.columns.len()].join(", ");
+        let mut stmt = tx.prepare(&format!(
+            "INSERT INTO \"{}\" ({columns}) VALUES ({values})",
+            table.name
+        ))?;
+        for row in table.rows {
+            stmt.execute(params_from_iter(row.into_iter().map(Value::from)))?;
+        }
+    }
+    tx.pragma_update(None, "user_version", contents.user_version)?;
+    tx.commit()?;
+
+    DENIABLE_MIGRATIONS.to_latest(conn)?;
+    Ok(())
+}
+
+/// Key of the store and duress tag derived from `passphrase`
+fn derive_keys(passphrase: &str, salt: &[u8]) -> ([u8; KEY_LENGTH], [u8; DURESS_TAG_LENGTH]) {
+    let mut derived = [0; KEY_LENGTH + DURESS_TAG_LENGTH];
+    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, KDF_ROUNDS, &mut derived);
+    let (key, duress_tag) = derived.split_at(KEY_LENGTH);
+    (
+        key.try_into().expect("Key has the key length"),
+        duress_tag
+            .try_into()
+            .expect("Duress tag has the duress tag length"),
+    )
+}
+
+/// Overwrite the file at `path` with random bytes of the same size and set its `times`
+fn wipe(path: &Path, times: FileTimes) -> Result<(), DeniableVaultError> {
+    let mut noise = vec![0; VAULT_SIZE];
+    OsRng.fill(noise.as_mut_slice());
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&noise)?;
+    file.sync_all()?;
+    file.set_times(times)?;
+    Ok(())
+}
+
+/// Write `sealed` to a file next to `path`, set its `times` and rename it over the file at
+/// `path`, so a crash leaves either the old or the new store in place
+fn replace(path: &Path, sealed: &[u8], times: FileTimes) -> Result<(), DeniableVaultError> {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".tmp");
+    let tmp_path = path.with_file_name(file_name);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(sealed)?;
+    file.sync_all()?;
+    file.set_times(times)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Pad and encrypt `contents` into a store file
+fn seal<R: Rng + CryptoRng>(
+    contents: &[u8],
+    salt: &[u8; SALT_LENGTH],
+    duress_tag: &[u8; DURESS_TAG_LENGTH],
+    key: &[u8; KEY_LENGTH],
+    csprng: &mut R,
+) -> Result<Vec<u8>, DeniableVaultError> {
+    if contents.len() > CAPACITY - LENGTH_PREFIX {
+        return Err(DeniableVaultError::Full);
+    }
+    let mut plaintext = Vec::with_capacity(CAPACITY);
+    plaintext.extend((contents.len() as u64).to_le_bytes());
+    plaintext.extend(contents);
+    plaintext.resize(CAPACITY, 0);
+
+    let mut iv = [0; IV_LENGTH];
+    csprng.fill(&mut iv);
+    let ciphertext = Aes256CbcEnc::new_from_slices(&key[..CIPHER_KEY_LENGTH], &iv)
+        .expect("Key and IV have valid lengths")
+        .encrypt_padded_vec_mut::<NoPadding>(&plaintext);
+
+    let mut sealed = salt.to_vec();
+    sealed.extend(duress_tag);
+    sealed.extend(iv);
+    sealed.extend(ciphertext);
+    let mac = vault_mac(&key[CIPHER_KEY_LENGTH..], &sealed).finalize();
+    sealed.extend(mac.into_bytes());
+    Ok(sealed)
+}
+
+/// Contents of a store file, or `None` if `key` does not open it
+fn unseal(sealed: &[u8], key: &[u8; KEY_LENGTH]) -> Option<Vec<u8>> {
+    if sealed.len() != VAULT_SIZE {
+        return None;
+    }
+    let (data, mac) = sealed.split_at(VAULT_SIZE - MAC_LENGTH);
+    vault_mac(&key[CIPHER_KEY_LENGTH..], data)
+        .verify_slice(mac)
+        .ok()?;
+
+    let (iv, ciphertext) = data[SALT_LENGTH + DURESS_TAG_LENGTH..].split_at(IV_LENGTH);
+    let plaintext = Aes256CbcDec::new_from_slices(&key[..CIPHER_KEY_LENGTH], iv)
+        .expect("Key and IV have valid lengths")
+        .decrypt_padded_vec_mut::<NoPadding>(ciphertext)
+        .ok()?;
+    let len = u64::from_le_bytes(plaintext[..LENGTH_PREFIX].try_into().ok()?) as usize;
+    plaintext
+        .get(LENGTH_PREFIX..LENGTH_PREFIX.checked_add(len)?)
+        .map(<[u8]>::to_vec)
+}
+
+fn vault_mac(mac_key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(mac_key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DeniableVault, DeniableVaultError, VAULT_SIZE};
+    use rusqlite::params;
+    use std::{env, fs, path::PathBuf};
+    use uuid::Uuid;
+
+    fn vault_path() -> PathBuf {
+        env::temp_dir().join(format!("{}.vault", Uuid::new_v4()))
+    }
+
+    fn insert_payload(vault: &DeniableVault, content: &[u8]) {
+        vault
+            .conn()
+            .execute(
+                r#"
+            INSERT INTO DeniablePayload (content, chunk_count, total_length)
+            VALUES (?1, 0, ?2)
+            "#,
+                params![content, content.len() as u32],
+            )
+            .unwrap();
+    }
+
+    fn payloads(vault: &DeniableVault) -> Vec<Vec<u8>> {
+        vault
+            .conn()
+            .prepare("SELECT content FROM DeniablePayload ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<Vec<u8>>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn store_reopens_with_passphrase() {
+        let path = vault_path();
+        let mut vault = DeniableVault::open(&path, Some("correct horse")).unwrap();
+        assert!(!vault.is_open());
+        vault.create().unwrap();
+        assert!(vault.is_open());
+        insert_payload(&vault, b"deniable payload");
+        vault.save().unwrap();
+        vault.flush().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), VAULT_SIZE as u64);
+
+        let vault = DeniableVault::open(&path, Some("correct horse")).unwrap();
+        assert_eq!(payloads(&vault), vec![b"deniable payload".to_vec()]);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn locked_store_looks_empty() {
+        let path = vault_path();
+        let vault = DeniableVault::open(&path, None).unwrap();
+        assert!(!vault.is_open());
+        assert_eq!(fs::metadata(&path).unwrap().len(), VAULT_SIZE as u64);
+
+        let mut vault = DeniableVault::open(&path, Some("correct horse")).unwrap();
+        vault.create().unwrap();
+        insert_payload(&vault, b"deniable payload");
+        vault.save().unwrap();
+        vault.flush().unwrap();
+
+        assert!(payloads(&DeniableVault::open(&path, None).unwrap()).is_empty());
+        assert!(payloads(&DeniableVault::open(&path, Some("wrong horse")).unwrap()).is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().len(), VAULT_SIZE as u64);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn store_without_passphrase_is_not_saved() {
+        let path = vault_path();
+        let vault = DeniableVault::open(&path, None).unwrap();
+        let noise = fs::read(&path).unwrap();
+
+        insert_payload(&vault, b"deniable payload");
+        vault.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), noise);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn wrong_passphrase_never_overwrites_store() {
+        let path = vault_path();
+        let mut vault = DeniableVault::open(&path, Some("correct horse")).unwrap();
+        vault.create().unwrap();
+        insert_payload(&vault, b"deniable payload");
+        drop(vault);
+        let sealed = fs::read(&path).unwrap();
+
+        let mut vault = DeniableVault::open(&path, Some("wrong horse")).unwrap();
+        assert!(!vault.is_open());
+        insert_payload(&vault, b"decoy payload");
+        vault.save().unwrap();
+        vault.flush().unwrap();
+        assert!(matches!(
+            vault.set_duress_passphrase("battery staple"),
+            Err(DeniableVaultError::Locked)
+        ));
+        drop(vault);
+        assert_eq!(fs::read(&path).unwrap(), sealed);
+
+        let mut vault = DeniableVault::open(&path, Some("wrong horse")).unwrap();
+        insert_payload(&vault, b"new payload");
+        vault.create().unwrap();
+        drop(vault);
+        assert!(payloads(&DeniableVault::open(&path, Some("correct horse")).unwrap()).is_empty());
+        assert_eq!(
+            payloads(&DeniableVault::open(&path, Some("wrong horse")).unwrap()),
+            vec![b"new payload".to_vec()]
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn saves_are_batched() {
+        let path = vault_path();
+        let mut vault = DeniableVault::open(&path, Some("correct horse")).unwrap();
+        vault.create().unwrap();
+        let sealed = fs::read(&path).unwrap();
+
+        insert_payload(&vault, b"first payload");
+        vault.save().unwrap();
+        insert_payload(&vault, b"second payload");
+        vault.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), sealed);
+
+        vault.flush().unwrap();
+        let flushed = fs::read(&path).unwrap();
+        assert_ne!(flushed, sealed);
+        vault.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), flushed);
+        assert_eq!(
+            payloads(&DeniableVault::open(&path, Some("correct horse")).unwrap()),
+            vec![b"first payload".to_vec(), b"second payload".to_vec()]
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn flush_keeps_file_times() {
+        let path = vault_path();
+        drop(DeniableVault::open(&path, None).unwrap());
+        let metadata = fs::metadata(&path).unwrap();
+
+        let mut vault = DeniableVault::open(&path, Some("correct horse")).unwrap();
+        vault.create().unwrap();
+        insert_payload(&vault, b"deniable payload");
+        vault.save().unwrap();
+        vault.flush().unwrap();
+
+        let flushed = fs::metadata(&path).unwrap();
+        assert_eq!(flushed.len(), VAULT_SIZE as u64);
+        assert_eq!(flushed.modified().unwrap(), metadata.modified().unwrap());
+        assert!(!path.with_extension("vault.tmp").exists());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn duress_passphrase_erases_store() {
+        let path = vault_path();
+        let mut vault = DeniableVault::open(&path, Some("correct horse")).unwrap();
+        vault.create().unwrap();
+        insert_payload(&vault, b"deniable payload");
+        assert!(matches!(
+            vault.set_duress_passphrase("correct horse"),
+            Err(DeniableVaultError::DuressIsPassphrase)
+        ));
+        vault.set_duress_passphrase("battery staple").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+
+        let vault = DeniableVault::open(&path, Some("battery staple")).unwrap();
+        assert!(!vault.is_open());
+        assert!(payloads(&vault).is_empty());
+
+        let wiped = fs::metadata(&path).unwrap();
+        assert_eq!(wiped.len(), VAULT_SIZE as u64);
+        assert_eq!(wiped.modified().unwrap(), metadata.modified().unwrap());
+        assert!(payloads(&DeniableVault::open(&path, Some("correct horse")).unwrap()).is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn duress_passphrase_needs_open_store() {
+        let path = vault_path();
+        let mut vault = DeniableVault::open(&path, None).unwrap();
+        assert!(matches!(
+            vault.set_duress_passphrase("battery staple"),
+            Err(DeniableVaultError::Locked)
+        ));
+
+        fs::remove_file(path).unwrap();
+    }
+}
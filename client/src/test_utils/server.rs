@@ -0,0 +1,101 @@
+use crate::{
+    errors::SignalClientError,
+    server::{SignalServerAPI, VerifiedSession},
+};
+use axum::async_trait;
+use common::{
+    deniable::{counter::CarrierOrder, q_value::QValueRange},
+    signalservice::WebSocketRequestMessage,
+    web_api::{
+        ChunkHeader, DenimMessages, RegistrationRequest, RegistrationResponse, SetKeyRequest,
+    },
+};
+use libsignal_core::{Aci, DeviceId, ServiceId};
+use libsignal_protocol::PreKeyBundle;
+
+/// Server for tests that never reach it
+pub struct MockServer;
+
+#[async_trait]
+impl SignalServerAPI for MockServer {
+    async fn connect(
+        &mut self,
+        _: &str,
+        _: &str,
+        _: &str,
+        _: &Option<String>,
+        _: Option<QValueRange>,
+    ) -> Result<Option<f32>, SignalClientError> {
+        todo!()
+    }
+
+    async fn disconnect(&mut self) {
+        todo!()
+    }
+
+    async fn publish_pre_key_bundle(&mut self, _: SetKeyRequest) -> Result<(), SignalClientError> {
+        todo!()
+    }
+
+    async fn fetch_pre_key_bundles(
+        &self,
+        _: &ServiceId,
+    ) -> Result<Vec<PreKeyBundle>, SignalClientError> {
+        todo!()
+    }
+
+    async fn register_client(
+        &self,
+        _: String,
+        _: String,
+        _: RegistrationRequest,
+        _: Option<&VerifiedSession>,
+    ) -> Result<RegistrationResponse, SignalClientError> {
+        todo!()
+    }
+
+    async fn get_service_id_from_server(&self, _: &str) -> Result<ServiceId, SignalClientError> {
+        todo!()
+    }
+
+    async fn send_msg(
+        &mut self,
+        _: &DenimMessages,
+        _: &ServiceId,
+    ) -> Result<Vec<ChunkHeader>, SignalClientError> {
+        todo!()
+    }
+
+    async fn upload_attachment(&self, _: Vec<u8>) -> Result<String, SignalClientError> {
+        todo!()
+    }
+
+    async fn download_attachment(&self, _: &str) -> Result<Vec<u8>, SignalClientError> {
+        todo!()
+    }
+
+    async fn has_message(&mut self) -> bool {
+        todo!()
+    }
+
+    async fn get_message(&mut self) -> Option<WebSocketRequestMessage> {
+        todo!()
+    }
+
+    fn observe_carrier(&mut self, _: Option<i32>) -> CarrierOrder {
+        todo!()
+    }
+
+    async fn send_response(
+        &mut self,
+        _: WebSocketRequestMessage,
+        _: axum::http::StatusCode,
+        _: Vec<String>,
+    ) -> Result<(), SignalClientError> {
+        todo!()
+    }
+
+    fn create_auth_header(&mut self, _: Aci, _: String, _: DeviceId) {
+        todo!()
+    }
+}
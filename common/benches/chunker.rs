@@ -0,0 +1,95 @@
+use async_trait::async_trait;
+use common::{
+    deniable::{chunk::Chunker, DeniableSendingBuffer},
+    web_api::PayloadData,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use libsignal_protocol::SignalProtocolError;
+use std::collections::VecDeque;
+use tokio::runtime::Runtime;
+
+/// Size of the regular payload the deniable part is relative to
+const REGULAR_PAYLOAD_SIZE: f32 = 1000.0;
+
+/// Buffer of small outgoing messages as (id, remaining data, chunk count, total length)
+struct SmallMessages(VecDeque<(u32, Vec<u8>, i32, u32)>);
+
+impl SmallMessages {
+    fn new(count: u32, len: usize) -> Self {
+        Self(
+            (1..=count)
+                .map(|id| (id, vec![0xab; len], 0, len as u32))
+                .collect(),
+        )
+    }
+}
+
+#[async_trait(?Send)]
+impl DeniableSendingBuffer for SmallMessages {
+    async fn get_outgoing_message(
+        &mut self,
+    ) -> Result<(u32, Vec<u8>, i32, u32), SignalProtocolError> {
+        Ok(self.0.front().cloned().unwrap_or_default())
+    }
+
+    async fn set_outgoing_message(
+        &mut self,
+        message_id: Option<u32>,
+        chunk_count: i32,
+        outgoing_message: Vec<u8>,
+    ) -> Result<(), SignalProtocolError> {
+        if let Some(message) = self.0.iter_mut().find(|m| Some(m.0) == message_id) {
+            message.1 = outgoing_message;
+            message.2 = chunk_count;
+        }
+        Ok(())
+    }
+
+    async fn remove_outgoing_message(
+        &mut self,
+        message_id: u32,
+    ) -> Result<(), SignalProtocolError> {
+        self.0.retain(|message| message.0 != message_id);
+        Ok(())
+    }
+}
+
+fn large_q_values(c: &mut Criterion) {
+    let mut group = c.benchmark_group("create_ordered_chunks");
+    for q_value in [1.0, 10.0, 100.0] {
+        let chunker = Chunker::new(q_value);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(q_value),
+            &chunker,
+            |b, chunker| {
+                b.iter_batched(
+                    || PayloadData::new(vec![0xab; 1 << 20]),
+                    |payload| chunker.create_ordered_chunks(REGULAR_PAYLOAD_SIZE, payload),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+fn many_small_chunks(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("create_chunks");
+    for len in [1, 16, 64] {
+        let chunker = Chunker::new(10.0);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &len, |b, &len| {
+            b.iter_batched(
+                || SmallMessages::new(2000, len),
+                |mut buffer| {
+                    runtime.block_on(chunker.create_chunks(REGULAR_PAYLOAD_SIZE, &mut buffer))
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, large_q_values, many_small_chunks);
+criterion_main!(benches);
@@ -9,7 +9,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .type_attribute("Envelope", "#[derive(bon::Builder)]")
         .include_file("_includes.rs")
         .compile_protos(
-            &["proto/SignalService.proto", "proto/WebSocketProtocol.proto"],
+            &[
+                "proto/SignalService.proto",
+                "proto/WebSocketProtocol.proto",
+                "proto/DenimProtocol.proto",
+            ],
             &["proto"],
         )?;
 
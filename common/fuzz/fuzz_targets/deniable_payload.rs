@@ -0,0 +1,8 @@
+#![no_main]
+
+use common::web_api::DeniablePayload;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = bincode::deserialize::<DeniablePayload>(data);
+});
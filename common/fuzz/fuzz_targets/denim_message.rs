@@ -0,0 +1,22 @@
+#![no_main]
+
+use common::{
+    deniable::reassembly::reassemble,
+    web_api::{DeniablePayload, DenimMessage, DenimMessages},
+};
+use libfuzzer_sys::fuzz_target;
+
+fn reassemble_message(message: DenimMessage) {
+    for payload in reassemble(message.chunks).payloads {
+        let _ = bincode::deserialize::<DeniablePayload>(&payload);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = DenimMessage::decode(data) {
+        reassemble_message(message);
+    }
+    if let Ok(messages) = DenimMessages::decode(data) {
+        messages.messages.into_iter().for_each(reassemble_message);
+    }
+});
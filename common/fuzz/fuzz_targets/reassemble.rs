@@ -0,0 +1,54 @@
+#![no_main]
+
+use common::{
+    deniable::reassembly::reassemble,
+    web_api::{ChunkHeader, DeniablePayload, DenimChunk},
+};
+use libfuzzer_sys::{
+    arbitrary::{self, Arbitrary},
+    fuzz_target,
+};
+
+/// Chunk with arbitrary flags and header values
+#[derive(Debug, Arbitrary)]
+struct FuzzChunk {
+    chunk: Vec<u8>,
+    flags: i32,
+    version: u8,
+    stream_id: u32,
+    sequence: u32,
+    total_length: u32,
+}
+
+impl From<FuzzChunk> for DenimChunk {
+    fn from(value: FuzzChunk) -> Self {
+        DenimChunk {
+            chunk: value.chunk,
+            flags: value.flags,
+            header: ChunkHeader {
+                version: value.version,
+                stream_id: value.stream_id,
+                sequence: value.sequence,
+                total_length: value.total_length,
+            },
+        }
+    }
+}
+
+fuzz_target!(|batches: (Vec<FuzzChunk>, Vec<FuzzChunk>)| {
+    let (first, second) = batches;
+
+    // Pending chunks of the first batch are kept for the second, as on a client
+    let first = reassemble(first.into_iter().map(Into::into).collect());
+    let second = reassemble(
+        first
+            .pending
+            .into_iter()
+            .chain(second.into_iter().map(Into::into))
+            .collect(),
+    );
+
+    for payload in first.payloads.into_iter().chain(second.payloads) {
+        let _ = bincode::deserialize::<DeniablePayload>(&payload);
+    }
+});
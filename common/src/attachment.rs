@@ -0,0 +1,272 @@
+use aes::Aes256;
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use derive_more::derive::{Display, Error};
+use hmac::{Hmac, Mac};
+use rand::{CryptoRng, Rng};
+use sha2::{Digest, Sha256};
+
+/// Largest attachment that can be uploaded, before padding
+pub const MAX_ATTACHMENT_SIZE: usize = 100 * 1024 * 1024;
+
+/// Length of an attachment key, an AES-256 key followed by an HMAC-SHA256 key
+pub const ATTACHMENT_KEY_LENGTH: usize = 64;
+
+const CIPHER_KEY_LENGTH: usize = 32;
+const IV_LENGTH: usize = 16;
+const BLOCK_LENGTH: usize = 16;
+const MAC_LENGTH: usize = 32;
+
+/// Cover uploads are at most the size of a photo, before padding
+pub const MAX_COVER_UPLOAD_SIZE: usize = 4 * 1024 * 1024;
+
+/// Attachments are padded to the sizes `1.05^n`, but never less than this
+const MIN_PADDED_SIZE: usize = 541;
+const PADDING_BASE: f64 = 1.05;
+
+type Aes256CbcEnc = cbc::Encryptor<Aes256>;
+type Aes256CbcDec = cbc::Decryptor<Aes256>;
+
+#[derive(Debug, Display, Error, PartialEq, Eq)]
+pub enum AttachmentError {
+    #[display("Attachment key must be {ATTACHMENT_KEY_LENGTH} bytes")]
+    InvalidKey,
+    #[display("Attachment blob is malformed")]
+    InvalidBlob,
+    #[display("Attachment digest does not match")]
+    DigestMismatch,
+    #[display("Attachment MAC does not match")]
+    MacMismatch,
+    #[display("Attachment is shorter than its size")]
+    InvalidSize,
+}
+
+/// Attachment encrypted for upload. Only `blob` is uploaded, the rest is sent to the receiver.
+#[derive(Debug, Clone)]
+pub struct EncryptedAttachment {
+    /// IV, ciphertext and MAC
+    pub blob: Vec<u8>,
+    pub key: Vec<u8>,
+    /// SHA-256 digest of `blob`
+    pub digest: Vec<u8>,
+    /// Size of the attachment before padding
+    pub size: usize,
+}
+
+/// Size an attachment of `size` bytes is padded to before encryption.
+/// Every upload falls in one of few sizes, so the size hardly tells attachments apart.
+pub fn padded_size(size: usize) -> usize {
+    let size = size.max(MIN_PADDED_SIZE);
+    let exponent = ((size as f64).ln() / PADDING_BASE.ln()).ceil();
+    (PADDING_BASE.powf(exponent).floor() as usize).max(size)
+}
+
+/// Size of the blob of an attachment padded to `padded_size` bytes
+pub fn blob_size(padded_size: usize) -> usize {
+    IV_LENGTH + (padded_size / BLOCK_LENGTH + 1) * BLOCK_LENGTH + MAC_LENGTH
+}
+
+/// Largest blob an attachment can be uploaded as
+pub fn max_blob_size() -> usize {
+    blob_size(padded_size(MAX_ATTACHMENT_SIZE))
+}
+
+/// Whether a blob of `len` bytes is the size of an encrypted, padded attachment
+pub fn is_attachment_blob_size(len: usize) -> bool {
+    if len > max_blob_size() || len < blob_size(0) {
+        return false;
+    }
+    let ciphertext_len = len - IV_LENGTH - MAC_LENGTH;
+    if !ciphertext_len.is_multiple_of(BLOCK_LENGTH) {
+        return false;
+    }
+    // The padded attachment is the smallest padded size that fills the ciphertext
+    let padded = padded_size(ciphertext_len - BLOCK_LENGTH);
+    blob_size(padded) == len
+}
+
+/// Samples the padded size of a cover upload. Sizes up to `max_size` are drawn evenly on a
+/// log scale, so small and large attachments are uploaded about as often.
+pub fn sample_cover_size<R: Rng>(max_size: usize, rng: &mut R) -> usize {
+    let max = padded_size(max_size.min(MAX_ATTACHMENT_SIZE));
+    let exponent = rng.gen_range((MIN_PADDED_SIZE as f64).ln()..=(max as f64).ln());
+    padded_size(exponent.exp() as usize).min(max)
+}
+
+/// Random blob of an attachment padded to `padded_size` bytes. Encrypted attachments look
+/// random, so it can be uploaded in place of one.
+pub fn cover_blob<R: Rng + CryptoRng>(padded_size: usize, csprng: &mut R) -> Vec<u8> {
+    let mut blob = vec![0; blob_size(padded_size)];
+    csprng.fill_bytes(&mut blob);
+    blob
+}
+
+/// Pad and encrypt an attachment with a new random key
+pub fn encrypt_attachment<R: Rng + CryptoRng>(
+    attachment: &[u8],
+    csprng: &mut R,
+) -> EncryptedAttachment {
+    let mut key = vec![0; ATTACHMENT_KEY_LENGTH];
+    csprng.fill_bytes(&mut key);
+    let mut iv = [0; IV_LENGTH];
+    csprng.fill_bytes(&mut iv);
+
+    let mut padded = attachment.to_vec();
+    padded.resize(padded_size(attachment.len()), 0);
+    let ciphertext = Aes256CbcEnc::new_from_slices(&key[..CIPHER_KEY_LENGTH], &iv)
+        .expect("Key and IV have valid lengths")
+        .encrypt_padded_vec_mut::<Pkcs7>(&padded);
+
+    let mut blob = iv.to_vec();
+    blob.extend(ciphertext);
+    let mac = attachment_mac(&key[CIPHER_KEY_LENGTH..], &blob).finalize();
+    blob.extend(mac.into_bytes());
+    let digest = Sha256::digest(&blob).to_vec();
+
+    EncryptedAttachment {
+        blob,
+        key,
+        digest,
+        size: attachment.len(),
+    }
+}
+
+/// Verify and decrypt a downloaded attachment blob, removing the padding
+pub fn decrypt_attachment(
+    blob: &[u8],
+    key: &[u8],
+    digest: &[u8],
+    size: usize,
+) -> Result<Vec<u8>, AttachmentError> {
+    if key.len() != ATTACHMENT_KEY_LENGTH {
+        return Err(AttachmentError::InvalidKey);
+    }
+    if blob.len() < IV_LENGTH + MAC_LENGTH {
+        return Err(AttachmentError::InvalidBlob);
+    }
+    if Sha256::digest(blob).as_slice() != digest {
+        return Err(AttachmentError::DigestMismatch);
+    }
+
+    let (data, mac) = blob.split_at(blob.len() - MAC_LENGTH);
+    attachment_mac(&key[CIPHER_KEY_LENGTH..], data)
+        .verify_slice(mac)
+        .map_err(|_| AttachmentError::MacMismatch)?;
+
+    let (iv, ciphertext) = data.split_at(IV_LENGTH);
+    let mut attachment = Aes256CbcDec::new_from_slices(&key[..CIPHER_KEY_LENGTH], iv)
+        .expect("Key and IV have valid lengths")
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| AttachmentError::InvalidBlob)?;
+    if attachment.len() < size {
+        return Err(AttachmentError::InvalidSize);
+    }
+    attachment.truncate(size);
+    Ok(attachment)
+}
+
+fn attachment_mac(mac_key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(mac_key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        blob_size, cover_blob, decrypt_attachment, encrypt_attachment, is_attachment_blob_size,
+        max_blob_size, padded_size, sample_cover_size, AttachmentError, MAX_COVER_UPLOAD_SIZE,
+        MIN_PADDED_SIZE,
+    };
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn padded_sizes_are_buckets() {
+        assert_eq!(padded_size(0), MIN_PADDED_SIZE);
+        assert_eq!(padded_size(MIN_PADDED_SIZE), MIN_PADDED_SIZE);
+        for size in [542, 1000, 4096, 65_537, 1_000_000] {
+            let padded = padded_size(size);
+            assert!(padded >= size);
+            assert!(padded < size + size / 20 + 1);
+            assert_eq!(padded_size(padded), padded);
+        }
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_attachment() {
+        let attachment = b"A picture worth a thousand chunks".repeat(40);
+        let encrypted = encrypt_attachment(&attachment, &mut OsRng);
+
+        assert_eq!(encrypted.size, attachment.len());
+        assert_eq!(
+            encrypted.blob.len(),
+            blob_size(padded_size(attachment.len()))
+        );
+        assert!(is_attachment_blob_size(encrypted.blob.len()));
+        assert_eq!(
+            decrypt_attachment(
+                &encrypted.blob,
+                &encrypted.key,
+                &encrypted.digest,
+                encrypted.size
+            ),
+            Ok(attachment)
+        );
+    }
+
+    #[test]
+    fn tampered_attachment_is_rejected() {
+        let encrypted = encrypt_attachment(b"attachment", &mut OsRng);
+
+        let mut blob = encrypted.blob.clone();
+        blob[20] ^= 1;
+        assert_eq!(
+            decrypt_attachment(&blob, &encrypted.key, &encrypted.digest, encrypted.size),
+            Err(AttachmentError::DigestMismatch)
+        );
+
+        let mut digest = encrypted.digest.clone();
+        digest[0] ^= 1;
+        assert_eq!(
+            decrypt_attachment(&encrypted.blob, &encrypted.key, &digest, encrypted.size),
+            Err(AttachmentError::DigestMismatch)
+        );
+
+        let mut key = encrypted.key.clone();
+        key[63] ^= 1;
+        assert_eq!(
+            decrypt_attachment(&encrypted.blob, &key, &encrypted.digest, encrypted.size),
+            Err(AttachmentError::MacMismatch)
+        );
+        assert_eq!(
+            decrypt_attachment(
+                &encrypted.blob,
+                &encrypted.key[..32],
+                &encrypted.digest,
+                encrypted.size
+            ),
+            Err(AttachmentError::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn only_padded_blob_sizes_are_accepted() {
+        assert!(is_attachment_blob_size(blob_size(MIN_PADDED_SIZE)));
+        assert!(is_attachment_blob_size(max_blob_size()));
+        assert!(!is_attachment_blob_size(blob_size(MIN_PADDED_SIZE) + 16));
+        assert!(!is_attachment_blob_size(blob_size(MIN_PADDED_SIZE) + 1));
+        assert!(!is_attachment_blob_size(0));
+        assert!(!is_attachment_blob_size(max_blob_size() + 16));
+    }
+
+    #[test]
+    fn cover_blobs_have_attachment_sizes() {
+        let max = padded_size(MAX_COVER_UPLOAD_SIZE);
+        for _ in 0..100 {
+            let size = sample_cover_size(MAX_COVER_UPLOAD_SIZE, &mut OsRng);
+            assert_eq!(padded_size(size), size);
+            assert!((MIN_PADDED_SIZE..=max).contains(&size));
+            assert!(is_attachment_blob_size(cover_blob(size, &mut OsRng).len()));
+        }
+        assert_eq!(sample_cover_size(0, &mut OsRng), MIN_PADDED_SIZE);
+    }
+}
@@ -1,7 +1,11 @@
 use super::{constants, DeniableSendingBuffer};
-use crate::web_api::{DenimChunk, PayloadData};
-use bincode::serialize;
-use std::usize;
+use crate::web_api::{ChunkHeader, DenimChunk, DenimMessage, PayloadData};
+use derive_more::derive::{Display, Error};
+use rand::{rngs::OsRng, RngCore};
+use std::collections::VecDeque;
+
+/// An outgoing deniable message as (id, remaining data, chunk count, total length)
+pub type OutgoingMessage = (u32, Vec<u8>, i32, u32);
 
 pub enum ChunkType {
     Data(i32),
@@ -9,13 +13,15 @@ pub enum ChunkType {
     Final,
 }
 
-impl From<i32> for ChunkType {
-    fn from(value: i32) -> Self {
+impl TryFrom<i32> for ChunkType {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
         match value {
-            1 => ChunkType::Dummy,
-            2 => ChunkType::Final,
-            ..=0 => ChunkType::Data(value),
-            v => unreachable!("ChunkType: {v} not supported!"),
+            1 => Ok(ChunkType::Dummy),
+            2 => Ok(ChunkType::Final),
+            ..=0 => Ok(ChunkType::Data(value)),
+            v => Err(format!("ChunkType: {v} not supported")),
         }
     }
 }
@@ -30,6 +36,23 @@ impl From<ChunkType> for i32 {
     }
 }
 
+/// Random bytes for dummy chunks and ballast.
+/// Zeros would compress to almost nothing, while the ciphertext in real chunks does not.
+pub fn random_filler(len: usize) -> Vec<u8> {
+    let mut filler = vec![0; len];
+    OsRng.fill_bytes(&mut filler);
+    filler
+}
+
+/// A denim message whose size does not follow from its regular payload and q value
+#[derive(Debug, Display, Error, Clone, Copy, PartialEq)]
+pub enum DenimSizeError {
+    #[display("Denim message uses q value {found} instead of {expected}")]
+    QValueMismatch { expected: f32, found: f32 },
+    #[display("Chunks and ballast take {found} bytes but the budget is {expected} bytes")]
+    BudgetMismatch { expected: usize, found: usize },
+}
+
 #[derive(Debug, Clone)]
 pub struct Chunker {
     pub q_value: f32,
@@ -50,67 +73,129 @@ impl Chunker {
         self.q_value = q_value;
     }
 
+    /// Encoded size of the chunks and ballast that go along a regular payload
     pub fn get_free_space_in_bytes(&self, regular_payload_size: f32) -> usize {
-        let total_free_space = (regular_payload_size * self.q_value).ceil() as usize;
-        total_free_space - constants::EMPTY_VEC_SIZE
+        (regular_payload_size * self.q_value).ceil() as usize
+    }
+
+    /// Encoded size the chunks and ballast along a regular payload end up with.
+    /// A budget of one or two bytes can not be filled, so nothing is sent in it.
+    pub fn get_deniable_size(&self, regular_payload_size: usize) -> usize {
+        match self.get_free_space_in_bytes(regular_payload_size as f32) {
+            budget @ 3.. => budget,
+            _ => 0,
+        }
+    }
+
+    /// Check that a denim message has the size a message with its regular payload
+    /// and this q value has, any other size tells it apart from other messages
+    pub fn check_message_size(&self, message: &DenimMessage) -> Result<(), DenimSizeError> {
+        if let Some(q_value) = message.q.filter(|&q_value| q_value != self.q_value) {
+            return Err(DenimSizeError::QValueMismatch {
+                expected: self.q_value,
+                found: q_value,
+            });
+        }
+
+        let expected = self.get_deniable_size(message.regular_payload.encoded_len());
+        let found = message
+            .chunks
+            .iter()
+            .map(|chunk| constants::chunk_size(chunk.chunk.len()))
+            .sum::<usize>()
+            + constants::ballast_size(message.ballast.len());
+        if found != expected {
+            return Err(DenimSizeError::BudgetMismatch { expected, found });
+        }
+        Ok(())
     }
 
+    /// Fill the free space with chunks from the buffer and a dummy chunk,
+    /// returns the chunks and the length of the ballast that fills the rest
     pub async fn create_chunks<T: DeniableSendingBuffer>(
         &self,
         regular_payload_size: f32,
         buffer: &mut T,
     ) -> Result<(Vec<DenimChunk>, usize), String> {
         let mut outgoing_chunks: Vec<DenimChunk> = vec![];
-        let total_free_space = (regular_payload_size * self.q_value).ceil() as usize;
-
-        let mut free_space = total_free_space - constants::EMPTY_VEC_SIZE;
-        while free_space >= constants::EMPTY_DENIMCHUNK_SIZE {
-            let chunk_size = free_space - constants::EMPTY_DENIMCHUNK_SIZE;
-            let current_outgoing_message = buffer.get_outgoing_message().await.unwrap_or_default();
-
-            let new_chunk;
-            if !current_outgoing_message.1.is_empty() && chunk_size != 0 {
-                //Deniable
-                if current_outgoing_message.1.len() <= chunk_size {
-                    new_chunk = DenimChunk {
-                        chunk: current_outgoing_message.1.to_vec(),
-                        flags: ChunkType::Final.into(),
-                    };
-                    buffer
-                        .remove_outgoing_message(current_outgoing_message.0)
-                        .await
-                        .map_err(|err| format!("{err}"))?;
-                } else {
-                    new_chunk = DenimChunk {
-                        chunk: current_outgoing_message.1[..chunk_size].to_vec(),
-                        flags: ChunkType::Data(current_outgoing_message.2).into(),
-                    };
-                    let remaining_current_outgoing_message =
-                        current_outgoing_message.1[chunk_size..].to_vec();
-                    buffer
-                        .set_outgoing_message(
-                            Some(current_outgoing_message.0),
-                            current_outgoing_message.2 - 1,
-                            remaining_current_outgoing_message,
-                        )
-                        .await
-                        .map_err(|err| format!("{err}"))?;
+        let mut free_space = self.get_free_space_in_bytes(regular_payload_size);
+
+        while let Some(dummy_size) = constants::chunk_data_len(free_space, usize::MAX) {
+            // Chunks flagged for retransmission are sent as they were before, if they fit
+            if let Some(chunk) = buffer
+                .take_retransmit_chunk(dummy_size)
+                .await
+                .map_err(|err| format!("{err}"))?
+            {
+                free_space -= constants::chunk_size(chunk.chunk.len());
+                outgoing_chunks.push(chunk);
+                continue;
+            }
+
+            let message = buffer.get_outgoing_message().await.unwrap_or_default();
+            let message_id = message.0;
+            let (new_chunk, remainder) = cut_chunk(free_space, dummy_size, message);
+            match remainder {
+                Remainder::Unchanged => {}
+                Remainder::Sent => buffer
+                    .remove_outgoing_message(message_id)
+                    .await
+                    .map_err(|err| format!("{err}"))?,
+                Remainder::Rest(data, chunk_count) => buffer
+                    .set_outgoing_message(Some(message_id), chunk_count, data)
+                    .await
+                    .map_err(|err| format!("{err}"))?,
+            }
+
+            free_space -= constants::chunk_size(new_chunk.chunk.len());
+            outgoing_chunks.push(new_chunk);
+        }
+
+        Ok((
+            outgoing_chunks,
+            constants::ballast_len(free_space).unwrap_or_default(),
+        ))
+    }
+
+    /// Fill the free space with chunks from outgoing messages kept in memory, like
+    /// [Chunker::create_chunks] does with a sending buffer. Returns the chunks and
+    /// the length of the ballast that fills the rest.
+    pub fn create_queued_chunks(
+        &self,
+        regular_payload_size: f32,
+        queue: &mut VecDeque<OutgoingMessage>,
+    ) -> (Vec<DenimChunk>, usize) {
+        let mut outgoing_chunks: Vec<DenimChunk> = vec![];
+        let mut free_space = self.get_free_space_in_bytes(regular_payload_size);
+
+        while let Some(dummy_size) = constants::chunk_data_len(free_space, usize::MAX) {
+            let message = queue.front().cloned().unwrap_or_default();
+            let (new_chunk, remainder) = cut_chunk(free_space, dummy_size, message);
+            match remainder {
+                Remainder::Unchanged => {}
+                Remainder::Sent => {
+                    queue.pop_front();
+                }
+                Remainder::Rest(data, chunk_count) => {
+                    if let Some(message) = queue.front_mut() {
+                        message.1 = data;
+                        message.2 = chunk_count;
+                    }
                 }
-            } else {
-                //Dummy
-                new_chunk = DenimChunk {
-                    chunk: vec![0; chunk_size],
-                    flags: ChunkType::Dummy.into(),
-                };
             }
 
+            free_space -= constants::chunk_size(new_chunk.chunk.len());
             outgoing_chunks.push(new_chunk);
-            free_space = total_free_space - serialize(&outgoing_chunks).unwrap().len();
         }
 
-        Ok((outgoing_chunks, free_space))
+        (
+            outgoing_chunks,
+            constants::ballast_len(free_space).unwrap_or_default(),
+        )
     }
 
+    /// Fill the free space with chunks of the payload and a dummy chunk,
+    /// returns the chunks, the length of the ballast and the payload data that did not fit
     pub fn create_ordered_chunks(
         &self,
         regular_payload_size: f32,
@@ -118,63 +203,132 @@ impl Chunker {
     ) -> (Vec<DenimChunk>, usize, PayloadData) {
         let mut result_chunks: Vec<DenimChunk> = vec![];
 
-        let mut payload_data = payload.chunk.clone();
+        let mut payload_data = payload.chunk;
         let mut payload_data_count = payload.flags;
-
-        let total_free_space = (regular_payload_size * self.q_value).ceil() as usize;
-        let mut free_space = total_free_space - constants::EMPTY_VEC_SIZE;
-
-        while free_space >= constants::EMPTY_DENIMCHUNK_SIZE {
-            let chunk_size = free_space - constants::EMPTY_DENIMCHUNK_SIZE;
-            let new_chunk;
-            if !payload_data.is_empty() && chunk_size != 0 {
-                if payload_data.len() <= chunk_size {
-                    // Deniable
-                    new_chunk = DenimChunk {
-                        chunk: payload_data.to_vec(),
-                        flags: ChunkType::Final.into(),
-                    };
-                    payload_data.clear();
-                } else {
-                    // Data
-                    new_chunk = DenimChunk {
-                        chunk: payload_data[..chunk_size].to_vec(),
-                        flags: ChunkType::Data(payload_data_count).into(),
-                    };
-                    payload_data_count -= 1;
-                    payload_data = payload_data[chunk_size..].to_vec();
-                }
-            } else {
+        // Start of the data that is not chunked yet
+        let mut offset = 0;
+
+        let mut free_space = self.get_free_space_in_bytes(regular_payload_size);
+
+        while let Some(dummy_size) = constants::chunk_data_len(free_space, usize::MAX) {
+            let remaining = &payload_data[offset..];
+            let chunk_size =
+                constants::chunk_data_len(free_space, remaining.len()).unwrap_or_default();
+            let header = ChunkHeader {
+                sequence: payload_data_count.unsigned_abs(),
+                ..payload.header
+            };
+            let new_chunk = if chunk_size == 0 {
                 // Dummy
-                new_chunk = DenimChunk {
-                    chunk: vec![0; chunk_size],
+                DenimChunk {
+                    chunk: random_filler(dummy_size),
                     flags: ChunkType::Dummy.into(),
+                    header: ChunkHeader::default(),
+                }
+            } else if chunk_size == remaining.len() {
+                // Deniable
+                offset = payload_data.len();
+                DenimChunk {
+                    chunk: remaining.to_vec(),
+                    flags: ChunkType::Final.into(),
+                    header,
+                }
+            } else {
+                // Data
+                let new_chunk = DenimChunk {
+                    chunk: remaining[..chunk_size].to_vec(),
+                    flags: ChunkType::Data(payload_data_count).into(),
+                    header,
                 };
-            }
+                offset += chunk_size;
+                payload_data_count -= 1;
+                new_chunk
+            };
 
+            free_space -= constants::chunk_size(new_chunk.chunk.len());
             result_chunks.push(new_chunk);
-            free_space = total_free_space - serialize(&result_chunks).unwrap().len();
         }
 
         // Return payload data that need to be chunked up at a later time
+        payload_data.drain(..offset);
         let pending_payload = PayloadData {
             chunk: payload_data,
             flags: payload_data_count,
+            header: payload.header,
         };
 
-        (result_chunks, free_space, pending_payload)
+        (
+            result_chunks,
+            constants::ballast_len(free_space).unwrap_or_default(),
+            pending_payload,
+        )
+    }
+}
+
+/// What is left of an outgoing message after a chunk was cut from it
+enum Remainder {
+    /// No data fit, the chunk is a dummy
+    Unchanged,
+    /// The chunk holds the rest of the message
+    Sent,
+    /// Data and chunk count of the message that are sent later
+    Rest(Vec<u8>, i32),
+}
+
+/// Cut a chunk filling at most `free_space` encoded bytes from the front of an outgoing
+/// message, or a dummy chunk of `dummy_size` bytes when no data fits
+fn cut_chunk(
+    free_space: usize,
+    dummy_size: usize,
+    message: OutgoingMessage,
+) -> (DenimChunk, Remainder) {
+    let (message_id, mut data, chunk_count, total_length) = message;
+    let header = ChunkHeader::new(message_id, chunk_count.unsigned_abs(), total_length);
+    let chunk_size = constants::chunk_data_len(free_space, data.len()).unwrap_or_default();
+
+    if chunk_size == 0 {
+        // Dummy
+        let chunk = DenimChunk {
+            chunk: random_filler(dummy_size),
+            flags: ChunkType::Dummy.into(),
+            header: ChunkHeader::default(),
+        };
+        (chunk, Remainder::Unchanged)
+    } else if chunk_size == data.len() {
+        // Deniable
+        let chunk = DenimChunk {
+            chunk: data,
+            flags: ChunkType::Final.into(),
+            header,
+        };
+        (chunk, Remainder::Sent)
+    } else {
+        // Data, the rest is moved to the front and sent later
+        let chunk = DenimChunk {
+            chunk: data.drain(..chunk_size).collect(),
+            flags: ChunkType::Data(chunk_count).into(),
+            header,
+        };
+        (chunk, Remainder::Rest(data, chunk_count - 1))
     }
 }
 
 #[cfg(test)]
 mod test {
     use axum::async_trait;
-    use bincode::serialize;
+    use flate2::{write::GzEncoder, Compression};
     use libsignal_protocol::SignalProtocolError;
+    use proptest::prelude::*;
+    use prost::Message;
+    use std::{collections::VecDeque, io::Write};
 
     use crate::{
-        deniable::{chunk::Chunker, constants, DeniableSendingBuffer},
-        web_api::DenimChunk,
+        deniable::{
+            chunk::{random_filler, ChunkType, Chunker, DenimSizeError},
+            constants, DeniableSendingBuffer,
+        },
+        denim as proto,
+        web_api::{DenimChunk, DenimMessage, PayloadData, RegularPayload, SignalMessage},
     };
 
     struct MockDeniableSendingBuffer;
@@ -183,9 +337,9 @@ mod test {
     impl DeniableSendingBuffer for MockDeniableSendingBuffer {
         async fn get_outgoing_message(
             &mut self,
-        ) -> Result<(u32, Vec<u8>, i32), SignalProtocolError> {
+        ) -> Result<(u32, Vec<u8>, i32, u32), SignalProtocolError> {
             let message: [u8; 32] = rand::random();
-            Ok((1, message.to_vec(), 0))
+            Ok((1, message.to_vec(), 0, 32))
         }
         async fn set_outgoing_message(
             &mut self,
@@ -200,57 +354,60 @@ mod test {
         }
     }
 
-    #[test]
-    fn empty_chunk_size_checker() {
-        let empty_chunk = DenimChunk {
-            chunk: vec![],
-            flags: 1,
-        };
-        let empty_chunk_serialized = serialize(&empty_chunk).unwrap();
-        assert_eq!(
-            empty_chunk_serialized.len(),
-            constants::EMPTY_DENIMCHUNK_SIZE
-        );
-    }
-
-    #[test]
-    fn dummy_chunk_size_checker() {
-        let dummy_payload_size = 10;
-        let dummy_chunk = DenimChunk {
-            chunk: vec![0; dummy_payload_size],
-            flags: 1,
-        };
-        let dummy_serialized = serialize(&dummy_chunk).unwrap();
-        assert_eq!(
-            dummy_serialized.len(),
-            constants::EMPTY_DENIMCHUNK_SIZE + dummy_payload_size
-        );
+    /// Buffer of outgoing messages as (id, remaining data, chunk count, total length)
+    struct QueueSendingBuffer(VecDeque<(u32, Vec<u8>, i32, u32)>);
+
+    impl QueueSendingBuffer {
+        fn new(messages: Vec<Vec<u8>>) -> Self {
+            Self(
+                messages
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, data)| {
+                        let total_length = data.len() as u32;
+                        (id as u32 + 1, data, 0, total_length)
+                    })
+                    .collect(),
+            )
+        }
     }
 
-    #[test]
-    fn nonempty_chunk_size_checker() {
-        let nonempty_chunk = DenimChunk {
-            chunk: vec![1],
-            flags: 1,
-        };
-        let nonempty_chunk_serialized = serialize(&nonempty_chunk).unwrap();
-        assert_eq!(
-            nonempty_chunk_serialized.len(),
-            constants::EMPTY_DENIMCHUNK_SIZE + 1
-        );
+    #[async_trait(?Send)]
+    impl DeniableSendingBuffer for QueueSendingBuffer {
+        async fn get_outgoing_message(
+            &mut self,
+        ) -> Result<(u32, Vec<u8>, i32, u32), SignalProtocolError> {
+            Ok(self.0.front().cloned().unwrap_or_default())
+        }
+        async fn set_outgoing_message(
+            &mut self,
+            message_id: Option<u32>,
+            chunk_count: i32,
+            outgoing_message: Vec<u8>,
+        ) -> Result<(), SignalProtocolError> {
+            if let Some(message) = self.0.iter_mut().find(|m| Some(m.0) == message_id) {
+                message.1 = outgoing_message;
+                message.2 = chunk_count;
+            }
+            Ok(())
+        }
+        async fn remove_outgoing_message(
+            &mut self,
+            message_id: u32,
+        ) -> Result<(), SignalProtocolError> {
+            self.0.retain(|message| message.0 != message_id);
+            Ok(())
+        }
     }
 
-    #[test]
-    fn denim_array_with_empty_chunk_size_checker() {
-        let denim_array: Vec<DenimChunk> = vec![DenimChunk {
-            chunk: vec![],
-            flags: 1,
-        }];
-        let denim_array_serialized = serialize(&denim_array).unwrap();
-        assert_eq!(
-            denim_array_serialized.len(),
-            constants::DENIMARRAY_WITH_EMPTY_DENIMCHUNK_SIZE
-        );
+    /// Chunks and ballast as they are encoded in a denim message
+    fn encode_deniable_part(chunks: Vec<DenimChunk>, ballast: usize) -> Vec<u8> {
+        proto::DenimMessage {
+            chunks: chunks.into_iter().map(Into::into).collect(),
+            ballast: (ballast != 0).then(|| random_filler(ballast)),
+            ..Default::default()
+        }
+        .encode_to_vec()
     }
 
     #[tokio::test]
@@ -263,29 +420,199 @@ mod test {
             .await
             .unwrap();
 
-        let denim_array_serialized = serialize(&chunks.0).unwrap();
         assert!(chunks.0.is_empty());
         assert_eq!(
-            denim_array_serialized.len() + chunks.1,
+            encode_deniable_part(chunks.0, chunks.1).len(),
             expected_deniable_payload_length
         );
     }
 
     #[tokio::test]
-    async fn create_chunks_dummy_chunk() {
-        let expected_deniable_payload_length = (40.0_f32 * 0.6_f32).ceil() as usize;
+    async fn create_chunks_data_chunk() {
+        let expected_deniable_payload_length = (60.0_f32 * 0.6_f32).ceil() as usize;
 
         let chunker = Chunker::new(0.6);
         let chunks = chunker
-            .create_chunks(40.0, &mut MockDeniableSendingBuffer {})
+            .create_chunks(60.0, &mut MockDeniableSendingBuffer {})
             .await
             .unwrap();
 
-        let denim_array_serialized = serialize(&chunks.0).unwrap();
         assert_eq!(chunks.0.len(), 1);
         assert_eq!(
-            denim_array_serialized.len() + chunks.1,
+            chunks.0[0].chunk.len(),
+            expected_deniable_payload_length - constants::EMPTY_DENIMCHUNK_SIZE
+        );
+        assert_eq!(
+            encode_deniable_part(chunks.0, chunks.1).len(),
             expected_deniable_payload_length
         );
     }
+
+    #[test]
+    fn deniable_size_only_depends_on_regular_payload_size() {
+        for q_value in [0.6, 1.5] {
+            let chunker = Chunker::new(q_value);
+            for regular_payload_size in (5..2000).step_by(7) {
+                let expected = chunker.get_free_space_in_bytes(regular_payload_size as f32);
+                for payload_length in [0, 1, 57, 300, 20000] {
+                    let (chunks, ballast, _) = chunker.create_ordered_chunks(
+                        regular_payload_size as f32,
+                        PayloadData::new(random_filler(payload_length)),
+                    );
+                    assert_eq!(
+                        encode_deniable_part(chunks, ballast).len(),
+                        expected,
+                        "q {q_value}, regular payload {regular_payload_size}, payload {payload_length}"
+                    );
+                }
+            }
+        }
+    }
+
+    fn gzip_len(data: &[u8]) -> usize {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap().len()
+    }
+
+    #[test]
+    fn dummy_and_real_chunks_compress_equally() {
+        let chunker = Chunker::default();
+        // Stands in for an encrypted deniable payload
+        let ciphertext = random_filler(300);
+
+        let (real_chunks, real_ballast, _) =
+            chunker.create_ordered_chunks(1000.0, PayloadData::new(ciphertext));
+        let (dummy_chunks, dummy_ballast, _) =
+            chunker.create_ordered_chunks(1000.0, PayloadData::new(Vec::new()));
+
+        let real = encode_deniable_part(real_chunks, real_ballast);
+        let dummy = encode_deniable_part(dummy_chunks, dummy_ballast);
+
+        assert_eq!(real.len(), dummy.len());
+        assert_eq!(gzip_len(&real), gzip_len(&dummy));
+    }
+
+    #[test]
+    fn check_message_size() {
+        let chunker = Chunker::new(0.6);
+        for content_length in [0, 1, 5, 100, 1000] {
+            let regular_payload = RegularPayload::SignalMessage(SignalMessage {
+                content: "a".repeat(content_length),
+                ..Default::default()
+            });
+            let (chunks, ballast, _) = chunker.create_ordered_chunks(
+                regular_payload.encoded_len() as f32,
+                PayloadData::new(random_filler(300)),
+            );
+            let mut message = DenimMessage {
+                regular_payload,
+                chunks,
+                counter: None,
+                q: Some(0.6),
+                ballast: random_filler(ballast),
+            };
+
+            assert_eq!(chunker.check_message_size(&message), Ok(()));
+
+            message.q = Some(1.5);
+            assert_eq!(
+                chunker.check_message_size(&message),
+                Err(DenimSizeError::QValueMismatch {
+                    expected: 0.6,
+                    found: 1.5
+                })
+            );
+
+            message.q = None;
+            message.ballast.push(0);
+            assert!(matches!(
+                chunker.check_message_size(&message),
+                Err(DenimSizeError::BudgetMismatch { .. })
+            ));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn ordered_chunks_fill_budget(
+            q_value in 0.05_f32..10.0,
+            regular_payload_size in 1_usize..5000,
+            payload_length in 0_usize..40000,
+        ) {
+            let chunker = Chunker::new(q_value);
+            let budget = chunker.get_free_space_in_bytes(regular_payload_size as f32);
+            // One or two bytes can not be filled by chunks or ballast
+            prop_assume!(budget >= 3);
+            let payload = random_filler(payload_length);
+
+            let (chunks, ballast, pending) = chunker.create_ordered_chunks(
+                regular_payload_size as f32,
+                PayloadData::new(payload.clone()),
+            );
+
+            let data: Vec<u8> = chunks
+                .iter()
+                .filter(|chunk| chunk.flags != i32::from(ChunkType::Dummy))
+                .flat_map(|chunk| chunk.chunk.clone())
+                .chain(pending.chunk)
+                .collect();
+            prop_assert_eq!(data, payload);
+            prop_assert_eq!(
+                encode_deniable_part(chunks, 0).len() + constants::ballast_size(ballast),
+                budget
+            );
+        }
+
+        #[test]
+        fn buffered_chunks_fill_budget(
+            q_value in 0.05_f32..10.0,
+            regular_payload_size in 1_usize..5000,
+            message_lengths in prop::collection::vec(1_usize..300, 0..50),
+        ) {
+            let chunker = Chunker::new(q_value);
+            let budget = chunker.get_free_space_in_bytes(regular_payload_size as f32);
+            prop_assume!(budget >= 3);
+            let messages: Vec<Vec<u8>> = message_lengths.into_iter().map(random_filler).collect();
+            let mut buffer = QueueSendingBuffer::new(messages);
+
+            let (chunks, ballast) = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(chunker.create_chunks(regular_payload_size as f32, &mut buffer))
+                .unwrap();
+
+            prop_assert_eq!(
+                encode_deniable_part(chunks, 0).len() + constants::ballast_size(ballast),
+                budget
+            );
+        }
+
+        #[test]
+        fn queued_chunks_match_buffered_chunks(
+            q_value in 0.05_f32..10.0,
+            regular_payload_size in 1_usize..5000,
+            message_lengths in prop::collection::vec(1_usize..300, 0..50),
+        ) {
+            let chunker = Chunker::new(q_value);
+            let messages: Vec<Vec<u8>> = message_lengths.into_iter().map(random_filler).collect();
+            let mut buffer = QueueSendingBuffer::new(messages);
+            let mut queue = buffer.0.clone();
+
+            let (buffered, buffered_ballast) = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(chunker.create_chunks(regular_payload_size as f32, &mut buffer))
+                .unwrap();
+            let (queued, queued_ballast) =
+                chunker.create_queued_chunks(regular_payload_size as f32, &mut queue);
+
+            let data = |chunks: Vec<DenimChunk>| -> Vec<DenimChunk> {
+                chunks.into_iter().filter(|chunk| !chunk.is_dummy()).collect()
+            };
+            prop_assert_eq!(data(queued), data(buffered));
+            prop_assert_eq!(queued_ballast, buffered_ballast);
+            prop_assert_eq!(queue, buffer.0);
+        }
+    }
 }
@@ -1,3 +1,67 @@
-pub const EMPTY_VEC_SIZE: usize = 8;
-pub const EMPTY_DENIMCHUNK_SIZE: usize = 12;
-pub const DENIMARRAY_WITH_EMPTY_DENIMCHUNK_SIZE: usize = 20;
+// Sizes in the DenimProtocol.proto encoding, checked against prost in `web_api::wire`
+
+pub const CHUNK_HEADER_VERSION: u8 = 1;
+/// Field keys take one byte for field numbers below 16
+pub const FIELD_KEY_SIZE: usize = 1;
+/// Encoded size of a fixed32 or sfixed32 field
+pub const FIXED32_FIELD_SIZE: usize = FIELD_KEY_SIZE + 4;
+/// Encoded size of the chunk header fields: version, stream id, sequence and total length
+pub const CHUNK_HEADER_SIZE: usize = 4 * FIXED32_FIELD_SIZE;
+/// Encoded size of a chunk without data in a `DenimMessage`
+pub const EMPTY_DENIMCHUNK_SIZE: usize = chunk_size(0);
+/// Largest size of a varint encoded usize
+const MAX_VARINT_SIZE: usize = 10;
+/// Data lengths below the largest one that may not fit: every varint byte beyond the first
+/// of the two length prefixes takes a byte, and a rest of 1 or 2 bytes can not be filled
+const CHUNK_DATA_LEN_SLACK: usize = 2 * (MAX_VARINT_SIZE - 1) + 2;
+
+/// Number of bytes needed to varint encode `value`
+pub const fn varint_size(value: usize) -> usize {
+    let mut size = 1;
+    let mut value = value >> 7;
+    while value != 0 {
+        size += 1;
+        value >>= 7;
+    }
+    size
+}
+
+/// Encoded size of a length delimited field with `len` bytes of content
+pub const fn length_delimited_size(len: usize) -> usize {
+    FIELD_KEY_SIZE + varint_size(len) + len
+}
+
+/// Encoded size of a chunk with `data_len` bytes of data in a `DenimMessage`
+pub const fn chunk_size(data_len: usize) -> usize {
+    length_delimited_size(length_delimited_size(data_len) + FIXED32_FIELD_SIZE + CHUNK_HEADER_SIZE)
+}
+
+/// Encoded size of the ballast, empty ballast is left out of the message
+pub const fn ballast_size(len: usize) -> usize {
+    if len == 0 {
+        0
+    } else {
+        length_delimited_size(len)
+    }
+}
+
+/// Ballast length that fills exactly `space` bytes.
+/// Not every size can be hit, e.g. 1 and 2 bytes or where the length prefix grows.
+pub fn ballast_len(space: usize) -> Option<usize> {
+    if space == 0 {
+        return Some(0);
+    }
+    (space.saturating_sub(FIELD_KEY_SIZE + MAX_VARINT_SIZE)..space)
+        .rev()
+        .find(|&len| len > 0 && ballast_size(len) == space)
+}
+
+/// Largest amount of data, at most `wanted` bytes, a chunk can carry in `space` bytes
+/// while leaving a rest that can be filled with ballast or more chunks.
+/// Only the few lengths just below the largest possible one are tried.
+pub fn chunk_data_len(space: usize, wanted: usize) -> Option<usize> {
+    let max = wanted.min(space.checked_sub(EMPTY_DENIMCHUNK_SIZE)?);
+    (max.saturating_sub(CHUNK_DATA_LEN_SLACK)..=max)
+        .rev()
+        .find(|&len| chunk_size(len) <= space && ballast_len(space - chunk_size(len)).is_some())
+}
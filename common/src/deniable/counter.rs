@@ -0,0 +1,278 @@
+use crate::web_api::DenimChunk;
+use std::collections::{BTreeSet, VecDeque};
+
+/// Header on the response to a denim message, listing the counters of the denim messages
+/// before it that the receiver found missing. The sender knows which chunks they carried
+/// and sends those again.
+pub const RETRANSMIT_HEADER: &str = "X-Denim-Retransmit";
+
+/// Number of missing counters remembered to tell late denim messages from duplicates
+const MAX_MISSING_COUNTERS: usize = 256;
+
+/// How a received denim message relates to the ones received before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CarrierOrder {
+    /// Directly follows the previous denim message
+    InOrder,
+    /// The `missing` denim messages before it were not received
+    Gap { missing: u32 },
+    /// Was missing, but arrived after a later denim message
+    Late,
+    /// Was received before
+    Duplicate,
+    /// Has no counter
+    Unnumbered,
+}
+
+impl CarrierOrder {
+    /// The denim messages found missing when the one with `counter` arrived
+    pub fn missing_before(self, counter: Option<i32>) -> Option<MissingCarriers> {
+        match (self, counter) {
+            (CarrierOrder::Gap { missing }, Some(counter)) => {
+                Some(MissingCarriers::before(counter, missing))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Counters of the denim messages sent in one direction of a connection, starting at 0
+#[derive(Debug, Default, Clone)]
+pub struct CarrierCounter {
+    next: i32,
+}
+
+impl CarrierCounter {
+    pub fn next_counter(&mut self) -> i32 {
+        let counter = self.next;
+        self.next = self.next.wrapping_add(1);
+        counter
+    }
+}
+
+/// Tracks the counters of the denim messages received in one direction of a connection.
+/// Counters wrap around, so they are compared with serial number arithmetic: a counter up to
+/// `i32::MAX` ahead of the expected one is new, anything else is old.
+#[derive(Debug, Default, Clone)]
+pub struct CarrierWindow {
+    expected: i32,
+    /// Missing counters, oldest first
+    missing: VecDeque<i32>,
+}
+
+impl CarrierWindow {
+    pub fn observe(&mut self, counter: Option<i32>) -> CarrierOrder {
+        let Some(counter) = counter else {
+            return CarrierOrder::Unnumbered;
+        };
+
+        let distance = counter.wrapping_sub(self.expected);
+        if distance == 0 {
+            self.expected = counter.wrapping_add(1);
+            CarrierOrder::InOrder
+        } else if distance > 0 {
+            let remembered = distance.min(MAX_MISSING_COUNTERS as i32);
+            self.missing.extend(
+                (1..=remembered)
+                    .rev()
+                    .map(|offset| counter.wrapping_sub(offset)),
+            );
+            while self.missing.len() > MAX_MISSING_COUNTERS {
+                self.missing.pop_front();
+            }
+            self.expected = counter.wrapping_add(1);
+            CarrierOrder::Gap {
+                missing: distance as u32,
+            }
+        } else if let Some(index) = self.missing.iter().position(|missing| *missing == counter) {
+            self.missing.remove(index);
+            CarrierOrder::Late
+        } else {
+            CarrierOrder::Duplicate
+        }
+    }
+}
+
+/// Counters of consecutive denim messages that went missing, `count` counters from `first`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingCarriers {
+    pub first: i32,
+    pub count: u32,
+}
+
+impl MissingCarriers {
+    /// The `missing` counters right before `counter`, as reported by [CarrierOrder::Gap]
+    pub fn before(counter: i32, missing: u32) -> Self {
+        Self {
+            first: counter.wrapping_sub(missing as i32),
+            count: missing,
+        }
+    }
+
+    pub fn contains(&self, counter: i32) -> bool {
+        (counter.wrapping_sub(self.first) as u32) < self.count
+    }
+}
+
+/// Sorted ids of the streams `chunks` belong to
+pub fn stream_ids(chunks: &[DenimChunk]) -> Vec<u32> {
+    chunks
+        .iter()
+        .filter(|chunk| !chunk.is_dummy())
+        .map(|chunk| chunk.header.stream_id)
+        .collect::<BTreeSet<u32>>()
+        .into_iter()
+        .collect()
+}
+
+/// Header reporting the `missing` denim messages, each range is written as `<first>+<count>`
+pub fn retransmit_header(missing: &[MissingCarriers]) -> String {
+    let missing = missing
+        .iter()
+        .map(|missing| format!("{}+{}", missing.first, missing.count))
+        .collect::<Vec<String>>()
+        .join(",");
+    format!("{RETRANSMIT_HEADER}: {missing}")
+}
+
+/// Missing denim messages reported in `headers`
+pub fn parse_retransmit_header(headers: &[String]) -> Vec<MissingCarriers> {
+    headers
+        .iter()
+        .filter_map(|header| header.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case(RETRANSMIT_HEADER))
+        .flat_map(|(_, missing)| missing.split(','))
+        .filter_map(|missing| {
+            let (first, count) = missing.trim().split_once('+')?;
+            Some(MissingCarriers {
+                first: first.parse().ok()?,
+                count: count.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        parse_retransmit_header, retransmit_header, stream_ids, CarrierCounter, CarrierOrder,
+        CarrierWindow, MissingCarriers, MAX_MISSING_COUNTERS,
+    };
+    use crate::{
+        deniable::chunk::ChunkType,
+        web_api::{ChunkHeader, DenimChunk},
+    };
+
+    #[test]
+    fn counters_in_order() {
+        let mut counter = CarrierCounter::default();
+        let mut window = CarrierWindow::default();
+        for _ in 0..5 {
+            assert_eq!(
+                window.observe(Some(counter.next_counter())),
+                CarrierOrder::InOrder
+            );
+        }
+        assert_eq!(window.observe(None), CarrierOrder::Unnumbered);
+    }
+
+    #[test]
+    fn counters_with_gap_late_and_duplicate() {
+        let mut window = CarrierWindow::default();
+        assert_eq!(window.observe(Some(0)), CarrierOrder::InOrder);
+        assert_eq!(window.observe(Some(3)), CarrierOrder::Gap { missing: 2 });
+        assert_eq!(window.observe(Some(2)), CarrierOrder::Late);
+        assert_eq!(window.observe(Some(2)), CarrierOrder::Duplicate);
+        assert_eq!(window.observe(Some(3)), CarrierOrder::Duplicate);
+        assert_eq!(window.observe(Some(4)), CarrierOrder::InOrder);
+        assert_eq!(window.observe(Some(1)), CarrierOrder::Late);
+        assert_eq!(window.observe(Some(0)), CarrierOrder::Duplicate);
+    }
+
+    #[test]
+    fn missing_counters_are_bounded() {
+        let mut window = CarrierWindow::default();
+        assert_eq!(
+            window.observe(Some(10_000)),
+            CarrierOrder::Gap { missing: 10_000 }
+        );
+        assert_eq!(window.missing.len(), MAX_MISSING_COUNTERS);
+        assert_eq!(window.observe(Some(9_999)), CarrierOrder::Late);
+        assert_eq!(window.observe(Some(0)), CarrierOrder::Duplicate);
+    }
+
+    #[test]
+    fn counters_wrap_around() {
+        let mut counter = CarrierCounter { next: i32::MAX - 1 };
+        let mut window = CarrierWindow {
+            expected: i32::MAX - 1,
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            assert_eq!(
+                window.observe(Some(counter.next_counter())),
+                CarrierOrder::InOrder
+            );
+        }
+        assert_eq!(counter.next_counter(), i32::MIN + 1);
+
+        assert_eq!(
+            window.observe(Some(i32::MIN + 4)),
+            CarrierOrder::Gap { missing: 3 }
+        );
+        assert_eq!(window.observe(Some(i32::MIN + 2)), CarrierOrder::Late);
+        assert_eq!(window.observe(Some(i32::MAX)), CarrierOrder::Duplicate);
+        assert_eq!(window.observe(Some(i32::MIN + 5)), CarrierOrder::InOrder);
+    }
+
+    #[test]
+    fn stream_ids_of_chunks() {
+        let chunks = [
+            (7, ChunkType::Data(0)),
+            (2, ChunkType::Final),
+            (7, ChunkType::Dummy),
+            (2, ChunkType::Data(0)),
+            (5, ChunkType::Dummy),
+        ]
+        .into_iter()
+        .map(|(stream_id, flags)| DenimChunk {
+            chunk: vec![1],
+            flags: flags.into(),
+            header: ChunkHeader::new(stream_id, 0, 1),
+        })
+        .collect::<Vec<DenimChunk>>();
+        assert_eq!(stream_ids(&chunks), vec![2, 7]);
+    }
+
+    #[test]
+    fn retransmit_header_round_trip() {
+        let mut window = CarrierWindow::default();
+        let missing = [Some(3), Some(4), Some(7)]
+            .into_iter()
+            .filter_map(|counter| window.observe(counter).missing_before(counter))
+            .collect::<Vec<MissingCarriers>>();
+        assert_eq!(
+            missing,
+            [
+                MissingCarriers { first: 0, count: 3 },
+                MissingCarriers { first: 5, count: 2 }
+            ]
+        );
+
+        let headers = vec!["Content-Length: 0".to_string(), retransmit_header(&missing)];
+        assert_eq!(headers[1], "X-Denim-Retransmit: 0+3,5+2");
+        assert_eq!(parse_retransmit_header(&headers), missing);
+        assert!(parse_retransmit_header(&headers[..1]).is_empty());
+    }
+
+    #[test]
+    fn missing_carriers_wrap_around() {
+        let missing = MissingCarriers::before(i32::MIN + 1, 3);
+        assert_eq!(missing.first, i32::MAX - 1);
+        assert!(missing.contains(i32::MAX - 1));
+        assert!(missing.contains(i32::MAX));
+        assert!(missing.contains(i32::MIN));
+        assert!(!missing.contains(i32::MIN + 1));
+        assert!(!missing.contains(i32::MAX - 2));
+    }
+}
@@ -0,0 +1,277 @@
+use rand::{distributions::WeightedIndex, prelude::Distribution, rngs::StdRng, Rng, SeedableRng};
+use std::{env::var, time::Duration};
+use tokio::time::{sleep_until, Instant};
+
+/// How often cover messages are sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoverTrafficSchedule {
+    /// Exponentially distributed gaps with on average `rate` messages per second.
+    Poisson { rate: f64 },
+    /// A cover message every `interval`.
+    Fixed { interval: Duration },
+}
+
+impl CoverTrafficSchedule {
+    /// Parses `poisson:<messages per second>` or `fixed:<interval in milliseconds>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (kind, param) = value.split_once(':').ok_or(format!(
+            "Cover traffic schedule '{value}' is missing a parameter"
+        ))?;
+        match kind.trim() {
+            "poisson" => {
+                let rate: f64 = param
+                    .trim()
+                    .parse()
+                    .map_err(|err| format!("Invalid poisson rate '{param}': {err}"))?;
+                if !(rate.is_finite() && rate > 0.0) {
+                    return Err(format!("Poisson rate must be positive, got {rate}"));
+                }
+                Ok(Self::Poisson { rate })
+            }
+            "fixed" => {
+                let millis: u64 = param
+                    .trim()
+                    .parse()
+                    .map_err(|err| format!("Invalid fixed interval '{param}': {err}"))?;
+                if millis == 0 {
+                    return Err("Fixed interval must be larger than 0 ms".to_owned());
+                }
+                Ok(Self::Fixed {
+                    interval: Duration::from_millis(millis),
+                })
+            }
+            other => Err(format!("Unknown cover traffic schedule '{other}'")),
+        }
+    }
+
+    /// Reads the schedule from the environment variable `key`.
+    /// Cover traffic is disabled when the variable is not set.
+    pub fn from_env(key: &str) -> Result<Option<Self>, String> {
+        match var(key) {
+            Ok(value) => Self::parse(&value).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Bytes the client pads the plaintext of every message to a multiple of.
+pub const PADDING_BLOCK_SIZE: usize = 160;
+/// Bytes a Signal message adds to the ciphertext of the padded plaintext:
+/// version, ratchet key, counters and MAC.
+pub const SIGNAL_MESSAGE_OVERHEAD: usize = 51;
+
+/// Sizes of the content of cover envelopes. The plaintext of a regular message is padded to a
+/// whole number of blocks, so cover content is a whole number of blocks and the overhead of a
+/// Signal message, with the number of blocks drawn by weight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverEnvelopeSizes {
+    /// Weight of one block, two blocks and so on
+    block_weights: Vec<u32>,
+}
+
+impl Default for CoverEnvelopeSizes {
+    /// Mostly single blocks, like short text messages
+    fn default() -> Self {
+        Self {
+            block_weights: vec![80, 15, 5],
+        }
+    }
+}
+
+impl CoverEnvelopeSizes {
+    /// Parses comma separated block weights, e.g. `80,15,5` for one block 80% of the time,
+    /// two blocks 15% of the time and three blocks 5% of the time.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let block_weights = value
+            .split(',')
+            .map(|weight| {
+                weight
+                    .trim()
+                    .parse()
+                    .map_err(|err| format!("Invalid block weight '{weight}': {err}"))
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        if !block_weights.iter().any(|weight| *weight > 0) {
+            return Err(format!(
+                "Cover envelope sizes '{value}' need a positive weight"
+            ));
+        }
+        Ok(Self { block_weights })
+    }
+
+    /// Reads the block weights from the environment variable `key`.
+    /// The default weights are used when the variable is not set.
+    pub fn from_env(key: &str) -> Result<Self, String> {
+        match var(key) {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Samples the size of the content of a cover envelope.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        let blocks = WeightedIndex::new(&self.block_weights)
+            .expect("Block weights have a positive weight")
+            .sample(rng)
+            + 1;
+        blocks * PADDING_BLOCK_SIZE + SIGNAL_MESSAGE_OVERHEAD
+    }
+}
+
+/// Decides when the next cover message should be sent.
+pub struct CoverTrafficScheduler<R: Rng = StdRng> {
+    schedule: CoverTrafficSchedule,
+    rng: R,
+    next: Instant,
+}
+
+impl CoverTrafficScheduler<StdRng> {
+    pub fn new(schedule: CoverTrafficSchedule) -> Self {
+        Self::with_rng(schedule, StdRng::from_entropy())
+    }
+}
+
+impl<R: Rng> CoverTrafficScheduler<R> {
+    pub fn with_rng(schedule: CoverTrafficSchedule, rng: R) -> Self {
+        let mut scheduler = Self {
+            schedule,
+            rng,
+            next: Instant::now(),
+        };
+        let delay = scheduler.next_delay();
+        scheduler.next += delay;
+        scheduler
+    }
+
+    /// Samples the gap until the following cover message.
+    pub fn next_delay(&mut self) -> Duration {
+        match self.schedule {
+            CoverTrafficSchedule::Fixed { interval } => interval,
+            CoverTrafficSchedule::Poisson { rate } => {
+                // Inverse transform sampling of the exponential distribution.
+                let uniform: f64 = self.rng.gen_range(f64::EPSILON..1.0);
+                Duration::from_secs_f64(-uniform.ln() / rate)
+            }
+        }
+    }
+
+    /// Waits until the next cover message is due.
+    /// This is cancel safe, so it can be used in `tokio::select!`.
+    pub async fn tick(&mut self) {
+        sleep_until(self.next).await;
+
+        let delay = self.next_delay();
+        let now = Instant::now();
+        // Keep a fixed rate, but do not burst to catch up if we fell behind.
+        self.next = if self.next + delay < now {
+            now + delay
+        } else {
+            self.next + delay
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        CoverEnvelopeSizes, CoverTrafficSchedule, CoverTrafficScheduler, PADDING_BLOCK_SIZE,
+        SIGNAL_MESSAGE_OVERHEAD,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_schedule() {
+        assert_eq!(
+            CoverTrafficSchedule::parse("poisson:0.5").unwrap(),
+            CoverTrafficSchedule::Poisson { rate: 0.5 }
+        );
+        assert_eq!(
+            CoverTrafficSchedule::parse("fixed:2000").unwrap(),
+            CoverTrafficSchedule::Fixed {
+                interval: Duration::from_secs(2)
+            }
+        );
+        assert!(CoverTrafficSchedule::parse("poisson").is_err());
+        assert!(CoverTrafficSchedule::parse("poisson:-1").is_err());
+        assert!(CoverTrafficSchedule::parse("fixed:0").is_err());
+        assert!(CoverTrafficSchedule::parse("burst:10").is_err());
+    }
+
+    #[test]
+    fn parse_cover_envelope_sizes() {
+        assert_eq!(
+            CoverEnvelopeSizes::parse("80, 15,5").unwrap(),
+            CoverEnvelopeSizes::default()
+        );
+        assert!(CoverEnvelopeSizes::parse("0,0").is_err());
+        assert!(CoverEnvelopeSizes::parse("80,-1").is_err());
+        assert!(CoverEnvelopeSizes::parse("").is_err());
+    }
+
+    #[test]
+    fn cover_envelope_sizes_follow_block_weights() {
+        let sizes = CoverEnvelopeSizes::parse("6,0,3,1").unwrap();
+        let samples = 20_000;
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let mut counts = [0; 4];
+        for _ in 0..samples {
+            let size = sizes.sample(&mut rng);
+            assert_eq!((size - SIGNAL_MESSAGE_OVERHEAD) % PADDING_BLOCK_SIZE, 0);
+            counts[(size - SIGNAL_MESSAGE_OVERHEAD) / PADDING_BLOCK_SIZE - 1] += 1;
+        }
+
+        assert_eq!(counts[1], 0);
+        for (count, weight) in counts.into_iter().zip([6, 0, 3, 1]) {
+            let share = count as f64 / samples as f64;
+            assert!(
+                (share - weight as f64 / 10.0).abs() < 0.02,
+                "share was {share}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fixed_schedule_is_constant() {
+        let interval = Duration::from_millis(250);
+        let mut scheduler = CoverTrafficScheduler::with_rng(
+            CoverTrafficSchedule::Fixed { interval },
+            StdRng::seed_from_u64(42),
+        );
+        for _ in 0..10 {
+            assert_eq!(scheduler.next_delay(), interval);
+        }
+    }
+
+    #[tokio::test]
+    async fn poisson_schedule_has_expected_mean() {
+        let rate = 2.0;
+        let samples = 20_000;
+        let mut scheduler = CoverTrafficScheduler::with_rng(
+            CoverTrafficSchedule::Poisson { rate },
+            StdRng::seed_from_u64(42),
+        );
+
+        let total: f64 = (0..samples)
+            .map(|_| scheduler.next_delay().as_secs_f64())
+            .sum();
+        let mean = total / samples as f64;
+
+        assert!((mean - 1.0 / rate).abs() < 0.02, "mean was {mean}");
+    }
+
+    #[tokio::test]
+    async fn tick_waits_for_interval() {
+        let interval = Duration::from_millis(50);
+        let mut scheduler = CoverTrafficScheduler::with_rng(
+            CoverTrafficSchedule::Fixed { interval },
+            StdRng::seed_from_u64(42),
+        );
+
+        let start = tokio::time::Instant::now();
+        scheduler.tick().await;
+        scheduler.tick().await;
+        assert!(start.elapsed() >= 2 * interval);
+    }
+}
@@ -1,12 +1,24 @@
 use axum::async_trait;
 use libsignal_protocol::SignalProtocolError;
 
+use crate::web_api::DenimChunk;
+
 pub mod chunk;
 pub mod constants;
+pub mod counter;
+pub mod cover_traffic;
+pub mod q_value;
+pub mod quota;
+pub mod reassembly;
+pub mod relay;
+pub mod scheduler;
 
 #[async_trait(?Send)]
 pub trait DeniableSendingBuffer {
-    async fn get_outgoing_message(&mut self) -> Result<(u32, Vec<u8>, i32), SignalProtocolError>;
+    /// Returns the message id, remaining data, chunk count and total length of the message
+    async fn get_outgoing_message(
+        &mut self,
+    ) -> Result<(u32, Vec<u8>, i32, u32), SignalProtocolError>;
     async fn set_outgoing_message(
         &mut self,
         message_id: Option<u32>,
@@ -15,4 +27,11 @@ pub trait DeniableSendingBuffer {
     ) -> Result<(), SignalProtocolError>;
     async fn remove_outgoing_message(&mut self, message_id: u32)
         -> Result<(), SignalProtocolError>;
+    /// Takes the oldest chunk flagged for retransmission with at most `max_len` bytes of data
+    async fn take_retransmit_chunk(
+        &mut self,
+        _max_len: usize,
+    ) -> Result<Option<DenimChunk>, SignalProtocolError> {
+        Ok(None)
+    }
 }
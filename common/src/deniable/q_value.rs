@@ -0,0 +1,185 @@
+use std::{env::var, fmt::Display};
+
+/// Header used by clients to propose a q value and by the server to announce the chosen one.
+pub const Q_VALUE_HEADER: &str = "q-value";
+
+/// Inclusive range of q values, a single value is a range with equal bounds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QValueRange {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl QValueRange {
+    pub fn new(min: f32, max: f32) -> Result<Self, String> {
+        for q in [min, max] {
+            if !(q.is_finite() && q > 0.0) {
+                return Err(format!("Q value must be positive, got {q}"));
+            }
+        }
+        if min > max {
+            return Err(format!("Q value range {min}-{max} is empty"));
+        }
+        Ok(Self { min, max })
+    }
+
+    pub fn exact(q_value: f32) -> Result<Self, String> {
+        Self::new(q_value, q_value)
+    }
+
+    /// Parses `<q>` or `<min>-<max>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let parse = |q: &str| {
+            q.trim()
+                .parse::<f32>()
+                .map_err(|err| format!("Invalid q value '{q}': {err}"))
+        };
+        match value.split_once('-') {
+            Some((min, max)) => Self::new(parse(min)?, parse(max)?),
+            None => Self::exact(parse(value)?),
+        }
+    }
+
+    /// Reads the range from the environment variable `key`, `None` when it is not set.
+    pub fn from_env(key: &str) -> Result<Option<Self>, String> {
+        match var(key) {
+            Ok(value) => Self::parse(&value).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, q_value: f32) -> bool {
+        self.min <= q_value && q_value <= self.max
+    }
+
+    fn intersect(&self, other: &Self) -> Option<Self> {
+        Self::new(self.min.max(other.min), self.max.min(other.max)).ok()
+    }
+}
+
+impl Display for QValueRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+/// Operator configured bounds for the q value of a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QValuePolicy {
+    /// Used when a client does not propose a q value.
+    pub default: f32,
+    /// Proposals are clamped to this range.
+    pub allowed: QValueRange,
+}
+
+impl Default for QValuePolicy {
+    fn default() -> Self {
+        Self::fixed(0.6).expect("Default q value is valid")
+    }
+}
+
+impl QValuePolicy {
+    pub fn new(default: f32, allowed: QValueRange) -> Result<Self, String> {
+        if !allowed.contains(default) {
+            return Err(format!(
+                "Default q value {default} is outside the allowed range {allowed}"
+            ));
+        }
+        Ok(Self { default, allowed })
+    }
+
+    /// A policy that ignores client proposals.
+    pub fn fixed(q_value: f32) -> Result<Self, String> {
+        Self::new(q_value, QValueRange::exact(q_value)?)
+    }
+
+    /// Reads the default q value from `default_key` and the allowed range from `range_key`.
+    /// Without a range only the default is allowed, which is 0.6 when not set.
+    pub fn from_env(default_key: &str, range_key: &str) -> Result<Self, String> {
+        let default = match var(default_key) {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .map_err(|err| format!("Invalid q value '{value}': {err}"))?,
+            Err(_) => Self::default().default,
+        };
+        match QValueRange::from_env(range_key)? {
+            Some(allowed) => Self::new(default, allowed),
+            None => Self::fixed(default),
+        }
+    }
+
+    /// Picks the q value for a connection.
+    /// The default is used when it fits the proposal, otherwise the closest allowed value.
+    pub fn negotiate(&self, proposal: Option<QValueRange>) -> f32 {
+        let Some(proposal) = proposal else {
+            return self.default;
+        };
+        match self.allowed.intersect(&proposal) {
+            Some(range) => self.default.clamp(range.min, range.max),
+            None if proposal.max < self.allowed.min => self.allowed.min,
+            None => self.allowed.max,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{QValuePolicy, QValueRange};
+
+    #[test]
+    fn parse_range() {
+        assert_eq!(
+            QValueRange::parse("0.6").unwrap(),
+            QValueRange { min: 0.6, max: 0.6 }
+        );
+        assert_eq!(
+            QValueRange::parse("0.2 - 1.5").unwrap(),
+            QValueRange { min: 0.2, max: 1.5 }
+        );
+        assert!(QValueRange::parse("0.8-0.2").is_err());
+        assert!(QValueRange::parse("-1").is_err());
+        assert!(QValueRange::parse("0").is_err());
+        assert!(QValueRange::parse("high").is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for value in ["0.6", "0.2-1.5"] {
+            let range = QValueRange::parse(value).unwrap();
+            assert_eq!(range.to_string(), value);
+            assert_eq!(QValueRange::parse(&range.to_string()).unwrap(), range);
+        }
+    }
+
+    #[test]
+    fn negotiate_within_policy() {
+        let policy = QValuePolicy::new(0.6, QValueRange::new(0.2, 1.0).unwrap()).unwrap();
+
+        assert_eq!(policy.negotiate(None), 0.6);
+        assert_eq!(policy.negotiate(QValueRange::exact(0.3).ok()), 0.3);
+        assert_eq!(policy.negotiate(QValueRange::new(0.4, 2.0).ok()), 0.6);
+        assert_eq!(policy.negotiate(QValueRange::new(0.8, 2.0).ok()), 0.8);
+        assert_eq!(policy.negotiate(QValueRange::new(0.1, 0.5).ok()), 0.5);
+    }
+
+    #[test]
+    fn negotiate_outside_policy() {
+        let policy = QValuePolicy::new(0.6, QValueRange::new(0.2, 1.0).unwrap()).unwrap();
+
+        assert_eq!(policy.negotiate(QValueRange::exact(0.1).ok()), 0.2);
+        assert_eq!(policy.negotiate(QValueRange::new(1.5, 3.0).ok()), 1.0);
+    }
+
+    #[test]
+    fn fixed_policy_ignores_proposals() {
+        let policy = QValuePolicy::fixed(0.6).unwrap();
+
+        assert_eq!(policy.negotiate(QValueRange::exact(2.0).ok()), 0.6);
+        assert!(QValuePolicy::new(2.0, QValueRange::new(0.2, 1.0).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use crate::web_api::DenimChunk;
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    env::var,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Number of dropped streams remembered per sender
+const MAX_DROPPED_STREAMS: usize = 64;
+
+/// Most bytes and items a DenIM buffer may hold, a `None` limit leaves it unbounded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BufferQuota {
+    pub max_bytes: Option<usize>,
+    pub max_items: Option<usize>,
+}
+
+impl BufferQuota {
+    pub fn new(max_bytes: usize, max_items: usize) -> Self {
+        Self {
+            max_bytes: Some(max_bytes),
+            max_items: Some(max_items),
+        }
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.max_bytes.is_none() && self.max_items.is_none()
+    }
+
+    /// Whether a buffer holding `items` items of `bytes` bytes in total
+    /// has room for one more item of `len` bytes
+    pub fn admits(&self, items: usize, bytes: usize, len: usize) -> bool {
+        self.max_items.is_none_or(|max_items| items < max_items)
+            && self
+                .max_bytes
+                .is_none_or(|max_bytes| bytes.saturating_add(len) <= max_bytes)
+    }
+
+    /// Parses `<max bytes>:<max items>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (bytes, items) = value
+            .split_once(':')
+            .ok_or(format!("Buffer quota '{value}' is missing the item limit"))?;
+        let max_bytes: usize = bytes
+            .trim()
+            .parse()
+            .map_err(|err| format!("Invalid buffer byte limit '{bytes}': {err}"))?;
+        let max_items: usize = items
+            .trim()
+            .parse()
+            .map_err(|err| format!("Invalid buffer item limit '{items}': {err}"))?;
+        if max_bytes == 0 || max_items == 0 {
+            return Err("Buffer quota limits must be larger than 0".to_owned());
+        }
+        Ok(Self::new(max_bytes, max_items))
+    }
+
+    /// Reads the quota from the environment variable `key`.
+    /// Buffers are unbounded when the variable is not set.
+    pub fn from_env(key: &str) -> Result<Self, String> {
+        match var(key) {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// Quotas of the incoming chunk buffer of each sender
+/// and the outgoing payload buffer of each receiver
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DenimQuotas {
+    pub sender: BufferQuota,
+    pub receiver: BufferQuota,
+}
+
+impl DenimQuotas {
+    pub fn from_env(sender_key: &str, receiver_key: &str) -> Result<Self, String> {
+        Ok(Self {
+            sender: BufferQuota::from_env(sender_key)?,
+            receiver: BufferQuota::from_env(receiver_key)?,
+        })
+    }
+}
+
+/// Streams of each sender that lost chunks to the sender quota. The remaining chunks of
+/// such a stream are dropped as well, they could never be reassembled.
+#[derive(Debug, Clone, Default)]
+pub struct DroppedStreams(Arc<Mutex<HashMap<String, VecDeque<u32>>>>);
+
+impl DroppedStreams {
+    pub fn contains(&self, sender: &str, stream_id: u32) -> bool {
+        self.0
+            .lock()
+            .expect("Dropped streams lock is not poisoned")
+            .get(sender)
+            .is_some_and(|streams| streams.contains(&stream_id))
+    }
+
+    pub fn insert(&self, sender: &str, stream_id: u32) {
+        let mut dropped = self.0.lock().expect("Dropped streams lock is not poisoned");
+        let streams = dropped.entry(sender.to_owned()).or_default();
+        if !streams.contains(&stream_id) {
+            streams.push_back(stream_id);
+        }
+        if streams.len() > MAX_DROPPED_STREAMS {
+            streams.pop_front();
+        }
+    }
+
+    /// Remember the stream of a chunk from `sender` that did not fit its quota,
+    /// until the final chunk of the stream is dropped as well
+    pub fn drop_chunk(&self, sender: &str, chunk: &DenimChunk) {
+        if chunk.is_final() {
+            self.remove(sender, chunk.header.stream_id);
+        } else {
+            self.insert(sender, chunk.header.stream_id);
+        }
+    }
+
+    /// Forget a stream once its final chunk was dropped
+    pub fn remove(&self, sender: &str, stream_id: u32) {
+        let mut dropped = self.0.lock().expect("Dropped streams lock is not poisoned");
+        if let Some(streams) = dropped.get_mut(sender) {
+            streams.retain(|id| *id != stream_id);
+            if streams.is_empty() {
+                dropped.remove(sender);
+            }
+        }
+    }
+}
+
+/// Admission of the chunks of a denim message to the incoming chunk buffer of their sender.
+/// Each chunk is buffered if the sender quota has room for it, checking the quota and taking
+/// room in it in one step. Chunks that do not fit are dropped with the rest of their stream,
+/// including the chunks of it that were buffered before.
+#[derive(Debug)]
+pub struct ChunkAdmission<'a> {
+    sender: &'a str,
+    dropped_streams: &'a DroppedStreams,
+    /// Streams that lost their first chunk during this admission
+    newly_dropped: BTreeSet<u32>,
+    dropped_count: usize,
+    dropped_bytes: usize,
+}
+
+impl<'a> ChunkAdmission<'a> {
+    pub fn new(sender: &'a str, dropped_streams: &'a DroppedStreams) -> Self {
+        Self {
+            sender,
+            dropped_streams,
+            newly_dropped: BTreeSet::new(),
+            dropped_count: 0,
+            dropped_bytes: 0,
+        }
+    }
+
+    /// Whether `chunk` may be buffered, as far as the quota has room for it
+    pub fn admits(&self, chunk: &DenimChunk) -> bool {
+        let stream_id = chunk.header.stream_id;
+        !self.dropped_streams.contains(self.sender, stream_id)
+            && !self.newly_dropped.contains(&stream_id)
+    }
+
+    /// Drop a chunk that is not admitted or did not fit in the quota
+    pub fn drop_chunk(&mut self, chunk: &DenimChunk) {
+        let stream_id = chunk.header.stream_id;
+        if !self.dropped_streams.contains(self.sender, stream_id) {
+            self.newly_dropped.insert(stream_id);
+        }
+        self.dropped_streams.drop_chunk(self.sender, chunk);
+        self.dropped_count += 1;
+        self.dropped_bytes += chunk.chunk.len();
+    }
+
+    /// Whether chunks buffered before have to be dropped, [ChunkAdmission::retain] picks them
+    pub fn drops_buffered(&self) -> bool {
+        !self.newly_dropped.is_empty()
+    }
+
+    /// Drop the buffered chunks of streams dropped during this admission,
+    /// returning the chunks to keep buffered
+    pub fn retain(&mut self, buffered: Vec<DenimChunk>) -> Vec<DenimChunk> {
+        let (kept, dropped): (Vec<DenimChunk>, Vec<DenimChunk>) = buffered
+            .into_iter()
+            .partition(|chunk| !self.newly_dropped.contains(&chunk.header.stream_id));
+        self.dropped_count += dropped.len();
+        self.dropped_bytes += dropped.iter().map(|chunk| chunk.chunk.len()).sum::<usize>();
+        kept
+    }
+
+    /// Count the dropped chunks, returns the quota stats if any chunk was dropped
+    pub fn finish(self, counters: &QuotaCounters) -> Option<QuotaStats> {
+        (self.dropped_count > 0)
+            .then(|| counters.chunks_dropped(self.dropped_count, self.dropped_bytes))
+    }
+}
+
+/// Deniable data dropped for exceeding a quota since the server started
+#[derive(Debug, Clone, Default)]
+pub struct QuotaCounters {
+    dropped_chunks: Arc<AtomicU64>,
+    dropped_chunk_bytes: Arc<AtomicU64>,
+    dropped_payloads: Arc<AtomicU64>,
+    dropped_payload_bytes: Arc<AtomicU64>,
+}
+
+impl QuotaCounters {
+    pub fn chunks_dropped(&self, count: usize, bytes: usize) -> QuotaStats {
+        self.dropped_chunks
+            .fetch_add(count as u64, Ordering::Relaxed);
+        self.dropped_chunk_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.stats()
+    }
+
+    pub fn payloads_dropped(&self, count: usize, bytes: usize) -> QuotaStats {
+        self.dropped_payloads
+            .fetch_add(count as u64, Ordering::Relaxed);
+        self.dropped_payload_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.stats()
+    }
+
+    pub fn stats(&self) -> QuotaStats {
+        QuotaStats {
+            dropped_chunks: self.dropped_chunks.load(Ordering::Relaxed),
+            dropped_chunk_bytes: self.dropped_chunk_bytes.load(Ordering::Relaxed),
+            dropped_payloads: self.dropped_payloads.load(Ordering::Relaxed),
+            dropped_payload_bytes: self.dropped_payload_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of the quota counters, written to the server log when something is dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct QuotaStats {
+    pub dropped_chunks: u64,
+    pub dropped_chunk_bytes: u64,
+    pub dropped_payloads: u64,
+    pub dropped_payload_bytes: u64,
+}
+
+impl fmt::Display for QuotaStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} chunks ({} bytes) and {} payloads ({} bytes) dropped in total",
+            self.dropped_chunks,
+            self.dropped_chunk_bytes,
+            self.dropped_payloads,
+            self.dropped_payload_bytes
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{deniable::chunk::ChunkType, web_api::ChunkHeader};
+
+    #[test]
+    fn parse_quota() {
+        assert_eq!(
+            BufferQuota::parse("65536:512"),
+            Ok(BufferQuota::new(65536, 512))
+        );
+        assert_eq!(
+            BufferQuota::parse(" 1024 : 8 "),
+            Ok(BufferQuota::new(1024, 8))
+        );
+        assert!(BufferQuota::parse("65536").is_err());
+        assert!(BufferQuota::parse("0:512").is_err());
+        assert!(BufferQuota::parse("65536:0").is_err());
+        assert!(BufferQuota::parse("lots:512").is_err());
+    }
+
+    #[test]
+    fn quota_admits_within_limits() {
+        let quota = BufferQuota::new(100, 2);
+        assert!(quota.admits(0, 0, 100));
+        assert!(!quota.admits(0, 0, 101));
+        assert!(quota.admits(1, 60, 40));
+        assert!(!quota.admits(1, 60, 41));
+        assert!(!quota.admits(2, 0, 1));
+        assert!(BufferQuota::default().is_unbounded());
+        assert!(BufferQuota::default().admits(usize::MAX, usize::MAX, usize::MAX));
+    }
+
+    #[test]
+    fn dropped_streams_are_bounded() {
+        let dropped = DroppedStreams::default();
+        for stream_id in 0..=MAX_DROPPED_STREAMS as u32 {
+            dropped.insert("alice.1", stream_id);
+        }
+        assert!(!dropped.contains("alice.1", 0));
+        assert!(dropped.contains("alice.1", 1));
+        assert!(!dropped.contains("bob.1", 1));
+
+        dropped.remove("alice.1", 1);
+        assert!(!dropped.contains("alice.1", 1));
+    }
+
+    fn chunk(stream_id: u32, sequence: u32, chunk_type: ChunkType) -> DenimChunk {
+        DenimChunk {
+            chunk: vec![0; 10],
+            flags: chunk_type.into(),
+            header: ChunkHeader::new(stream_id, sequence, 100),
+        }
+    }
+
+    /// Admit chunks to a buffer that holds at most `max_items` chunks
+    fn admit(
+        buffer: &mut Vec<DenimChunk>,
+        chunks: Vec<DenimChunk>,
+        max_items: usize,
+        dropped_streams: &DroppedStreams,
+        counters: &QuotaCounters,
+    ) -> Option<QuotaStats> {
+        let quota = BufferQuota::new(usize::MAX, max_items);
+        let mut admission = ChunkAdmission::new("alice.1", dropped_streams);
+        for chunk in chunks {
+            if admission.admits(&chunk) && quota.admits(buffer.len(), 0, chunk.chunk.len()) {
+                buffer.push(chunk);
+            } else {
+                admission.drop_chunk(&chunk);
+            }
+        }
+        if admission.drops_buffered() {
+            *buffer = admission.retain(std::mem::take(buffer));
+        }
+        admission.finish(counters)
+    }
+
+    #[test]
+    fn admission_drops_whole_streams() {
+        let dropped_streams = DroppedStreams::default();
+        let counters = QuotaCounters::default();
+        let mut buffer = Vec::new();
+
+        // Stream 2 does not fit, so its buffered chunk goes as well
+        let stats = admit(
+            &mut buffer,
+            vec![
+                chunk(1, 0, ChunkType::Data(0)),
+                chunk(2, 0, ChunkType::Data(0)),
+                chunk(2, 1, ChunkType::Data(-1)),
+            ],
+            2,
+            &dropped_streams,
+            &counters,
+        );
+        assert_eq!(buffer, vec![chunk(1, 0, ChunkType::Data(0))]);
+        assert_eq!(stats.map(|stats| stats.dropped_chunks), Some(2));
+
+        // The rest of stream 2 is dropped though there is room, until its final chunk
+        let stats = admit(
+            &mut buffer,
+            vec![chunk(2, 2, ChunkType::Final), chunk(3, 0, ChunkType::Final)],
+            3,
+            &dropped_streams,
+            &counters,
+        );
+        assert_eq!(buffer.len(), 2);
+        assert!(!dropped_streams.contains("alice.1", 2));
+        assert_eq!(stats.map(|stats| stats.dropped_chunks), Some(3));
+
+        assert_eq!(
+            admit(
+                &mut buffer,
+                vec![chunk(4, 0, ChunkType::Final)],
+                3,
+                &dropped_streams,
+                &counters
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn counters_accumulate() {
+        let counters = QuotaCounters::default();
+        counters.chunks_dropped(3, 30);
+        let stats = counters.clone().payloads_dropped(1, 200);
+        assert_eq!(
+            stats,
+            QuotaStats {
+                dropped_chunks: 3,
+                dropped_chunk_bytes: 30,
+                dropped_payloads: 1,
+                dropped_payload_bytes: 200,
+            }
+        );
+        assert_eq!(counters.stats(), stats);
+    }
+}
@@ -0,0 +1,522 @@
+use super::{chunk::ChunkType, constants::CHUNK_HEADER_VERSION};
+use crate::web_api::DenimChunk;
+use derive_more::derive::{Display, Error};
+use std::collections::{BTreeMap, VecDeque};
+
+/// Number of completed streams remembered to drop their late duplicates
+pub const MAX_COMPLETED_STREAMS: usize = 256;
+
+#[derive(Debug, Display, Error, Clone, PartialEq, Eq)]
+pub enum ReassemblyError {
+    #[display("Stream {stream_id}: chunk flags {flags} are not supported")]
+    UnsupportedFlags { stream_id: u32, flags: i32 },
+    #[display("Stream {stream_id}: chunk header version {version} is not supported")]
+    UnsupportedVersion { stream_id: u32, version: u8 },
+    #[display("Stream {stream_id}: total length {found} does not match {expected}")]
+    ConflictingTotalLength {
+        stream_id: u32,
+        expected: u32,
+        found: u32,
+    },
+    #[display("Stream {stream_id}: final chunk {found} does not match final chunk {expected}")]
+    ConflictingFinal {
+        stream_id: u32,
+        expected: u32,
+        found: u32,
+    },
+    #[display("Stream {stream_id}: chunk {sequence} is after final chunk {final_sequence}")]
+    SequenceAfterFinal {
+        stream_id: u32,
+        sequence: u32,
+        final_sequence: u32,
+    },
+    #[display("Stream {stream_id}: reassembled {received} bytes but expected {expected}")]
+    LengthMismatch {
+        stream_id: u32,
+        expected: u32,
+        received: usize,
+    },
+}
+
+/// Result of reassembling a batch of chunks
+#[derive(Debug, Default)]
+pub struct Reassembly {
+    /// Complete payloads in the order they were completed
+    pub payloads: Vec<Vec<u8>>,
+    /// Chunks of incomplete streams ordered by stream id and sequence number
+    pub pending: Vec<DenimChunk>,
+    /// Chunks or streams that were dropped
+    pub errors: Vec<ReassemblyError>,
+}
+
+#[derive(Debug)]
+struct Stream {
+    total_length: u32,
+    final_sequence: Option<u32>,
+    chunks: BTreeMap<u32, DenimChunk>,
+}
+
+impl Stream {
+    fn is_complete(&self) -> bool {
+        self.final_sequence
+            .is_some_and(|final_sequence| self.chunks.len() as u64 == final_sequence as u64 + 1)
+    }
+}
+
+/// Ids of the most recently completed streams of a sender.
+/// Late duplicates of these streams are dropped instead of opening a stream that never completes.
+#[derive(Debug, Clone, Default)]
+pub struct CompletedStreams(VecDeque<u32>);
+
+impl CompletedStreams {
+    pub fn contains(&self, stream_id: u32) -> bool {
+        self.0.contains(&stream_id)
+    }
+
+    pub fn insert(&mut self, stream_id: u32) {
+        if self.contains(stream_id) {
+            return;
+        }
+        self.0.push_back(stream_id);
+        if self.0.len() > MAX_COMPLETED_STREAMS {
+            self.0.pop_front();
+        }
+    }
+}
+
+/// Reassemble payloads from chunks of interleaved streams.
+/// Dummy chunks and duplicate sequence numbers are ignored, streams with gaps stay pending.
+pub fn reassemble(chunks: Vec<DenimChunk>) -> Reassembly {
+    reassemble_after(chunks, &mut CompletedStreams::default())
+}
+
+/// Reassemble like [reassemble] for a sender whose streams in `completed` were completed in
+/// earlier batches. Their chunks are dropped and the streams completed now are added.
+pub fn reassemble_after(chunks: Vec<DenimChunk>, completed: &mut CompletedStreams) -> Reassembly {
+    let mut result = Reassembly::default();
+    let mut streams: BTreeMap<u32, Stream> = BTreeMap::new();
+
+    for chunk in chunks {
+        let header = chunk.header;
+        let is_final = match ChunkType::try_from(chunk.flags) {
+            Ok(ChunkType::Dummy) => continue,
+            Ok(ChunkType::Final) => true,
+            Ok(ChunkType::Data(_)) => false,
+            Err(_) => {
+                result.errors.push(ReassemblyError::UnsupportedFlags {
+                    stream_id: header.stream_id,
+                    flags: chunk.flags,
+                });
+                continue;
+            }
+        };
+
+        if header.version != CHUNK_HEADER_VERSION {
+            result.errors.push(ReassemblyError::UnsupportedVersion {
+                stream_id: header.stream_id,
+                version: header.version,
+            });
+            continue;
+        }
+
+        // Late duplicates of a completed stream
+        if completed.contains(header.stream_id) {
+            continue;
+        }
+
+        let stream = streams.entry(header.stream_id).or_insert_with(|| Stream {
+            total_length: header.total_length,
+            final_sequence: None,
+            chunks: BTreeMap::new(),
+        });
+
+        if stream.total_length != header.total_length {
+            result.errors.push(ReassemblyError::ConflictingTotalLength {
+                stream_id: header.stream_id,
+                expected: stream.total_length,
+                found: header.total_length,
+            });
+            continue;
+        }
+
+        if stream.chunks.contains_key(&header.sequence) {
+            continue;
+        }
+
+        if is_final {
+            if let Some(final_sequence) = stream.final_sequence {
+                result.errors.push(ReassemblyError::ConflictingFinal {
+                    stream_id: header.stream_id,
+                    expected: final_sequence,
+                    found: header.sequence,
+                });
+                continue;
+            }
+            if let Some((&last, _)) = stream.chunks.last_key_value() {
+                if last > header.sequence {
+                    result.errors.push(ReassemblyError::SequenceAfterFinal {
+                        stream_id: header.stream_id,
+                        sequence: last,
+                        final_sequence: header.sequence,
+                    });
+                    continue;
+                }
+            }
+            stream.final_sequence = Some(header.sequence);
+        } else if let Some(final_sequence) = stream.final_sequence {
+            if header.sequence > final_sequence {
+                result.errors.push(ReassemblyError::SequenceAfterFinal {
+                    stream_id: header.stream_id,
+                    sequence: header.sequence,
+                    final_sequence,
+                });
+                continue;
+            }
+        }
+
+        stream.chunks.insert(header.sequence, chunk);
+
+        if stream.is_complete() {
+            let stream = streams
+                .remove(&header.stream_id)
+                .expect("Stream was just updated");
+            completed.insert(header.stream_id);
+
+            let payload = stream
+                .chunks
+                .into_values()
+                .flat_map(|chunk| chunk.chunk)
+                .collect::<Vec<u8>>();
+            if payload.len() != stream.total_length as usize {
+                result.errors.push(ReassemblyError::LengthMismatch {
+                    stream_id: header.stream_id,
+                    expected: stream.total_length,
+                    received: payload.len(),
+                });
+                continue;
+            }
+            result.payloads.push(payload);
+        }
+    }
+
+    result.pending = streams
+        .into_values()
+        .flat_map(|stream| stream.chunks.into_values())
+        .collect();
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        reassemble, reassemble_after, CompletedStreams, ReassemblyError, MAX_COMPLETED_STREAMS,
+    };
+    use crate::{
+        deniable::chunk::{ChunkType, Chunker},
+        web_api::{ChunkHeader, DenimChunk, PayloadData},
+    };
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+    fn split(stream_id: u32, data: &[u8], chunk_size: usize) -> Vec<DenimChunk> {
+        let parts = data.chunks(chunk_size).collect::<Vec<&[u8]>>();
+        parts
+            .iter()
+            .enumerate()
+            .map(|(sequence, part)| DenimChunk {
+                chunk: part.to_vec(),
+                flags: if sequence + 1 == parts.len() {
+                    ChunkType::Final.into()
+                } else {
+                    ChunkType::Data(-(sequence as i32)).into()
+                },
+                header: ChunkHeader::new(stream_id, sequence as u32, data.len() as u32),
+            })
+            .collect()
+    }
+
+    fn dummy() -> DenimChunk {
+        DenimChunk {
+            chunk: vec![0; 4],
+            flags: ChunkType::Dummy.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reassemble_in_order() {
+        let data = b"A message to Bob is here written".to_vec();
+
+        let result = reassemble(split(1, &data, 5));
+
+        assert_eq!(result.payloads, vec![data]);
+        assert!(result.pending.is_empty());
+        assert!(result.errors.is_empty());
+    }
+
+    #[test]
+    fn reassemble_interleaved_streams() {
+        let data1 = b"A message to Bob is here written".to_vec();
+        let data2 = b"A message to Eve".to_vec();
+        let mut chunks1 = split(1, &data1, 5).into_iter();
+        let mut chunks2 = split(2, &data2, 5).into_iter();
+
+        let mut chunks = Vec::new();
+        loop {
+            match (chunks1.next(), chunks2.next()) {
+                (None, None) => break,
+                (chunk1, chunk2) => {
+                    chunks.extend(chunk2);
+                    chunks.push(dummy());
+                    chunks.extend(chunk1);
+                }
+            }
+        }
+
+        let result = reassemble(chunks);
+
+        assert_eq!(result.payloads, vec![data2, data1]);
+        assert!(result.pending.is_empty());
+        assert!(result.errors.is_empty());
+    }
+
+    #[test]
+    fn reassemble_out_of_order_with_duplicates() {
+        let data = b"A message to Bob is here written".to_vec();
+        let chunks = split(1, &data, 5);
+
+        let mut shuffled = chunks.clone();
+        shuffled.reverse();
+        shuffled.insert(2, chunks[3].clone());
+        shuffled.push(chunks[0].clone());
+
+        let result = reassemble(shuffled);
+
+        assert_eq!(result.payloads, vec![data]);
+        assert!(result.pending.is_empty());
+        assert!(result.errors.is_empty());
+    }
+
+    #[test]
+    fn reassemble_gap_stays_pending() {
+        let data = b"A message to Bob is here written".to_vec();
+        let mut chunks = split(1, &data, 5);
+        let missing = chunks.remove(2);
+
+        let first = reassemble(chunks.clone());
+
+        assert!(first.payloads.is_empty());
+        assert!(first.errors.is_empty());
+        assert_eq!(
+            first
+                .pending
+                .iter()
+                .map(|chunk| chunk.header.sequence)
+                .collect::<Vec<u32>>(),
+            vec![0, 1, 3, 4, 5, 6]
+        );
+
+        let mut retry = first.pending;
+        retry.push(missing);
+        let second = reassemble(retry);
+
+        assert_eq!(second.payloads, vec![data]);
+        assert!(second.pending.is_empty());
+    }
+
+    #[test]
+    fn reassemble_drops_late_duplicates_of_earlier_batches() {
+        let data = b"A message to Bob is here written".to_vec();
+        let chunks = split(1, &data, 5);
+        let mut completed = CompletedStreams::default();
+
+        let first = reassemble_after(chunks.clone(), &mut completed);
+        let second = reassemble_after(vec![chunks[2].clone(), dummy()], &mut completed);
+
+        assert_eq!(first.payloads, vec![data]);
+        assert!(second.payloads.is_empty());
+        assert!(second.pending.is_empty());
+        assert!(second.errors.is_empty());
+    }
+
+    #[test]
+    fn completed_streams_are_bounded() {
+        let mut completed = CompletedStreams::default();
+        for stream_id in 0..=MAX_COMPLETED_STREAMS as u32 {
+            completed.insert(stream_id);
+        }
+        completed.insert(1);
+
+        assert!(!completed.contains(0));
+        assert!(completed.contains(1));
+        assert!(completed.contains(MAX_COMPLETED_STREAMS as u32));
+    }
+
+    #[test]
+    fn reassemble_pending_is_deterministic() {
+        let data1 = b"A message to Bob".to_vec();
+        let data2 = b"A message to Eve".to_vec();
+        let mut chunks1 = split(7, &data1, 5);
+        let mut chunks2 = split(3, &data2, 5);
+        chunks1.pop();
+        chunks2.pop();
+
+        let forward = reassemble([chunks1.clone(), chunks2.clone()].concat());
+        let backward = reassemble([chunks2, chunks1].concat());
+
+        assert_eq!(forward.pending, backward.pending);
+        assert_eq!(forward.pending[0].header.stream_id, 3);
+    }
+
+    #[test]
+    fn reassemble_unsupported_version() {
+        let data = b"A message to Bob".to_vec();
+        let mut chunks = split(1, &data, 5);
+        chunks[1].header.version = 0;
+
+        let result = reassemble(chunks);
+
+        assert!(result.payloads.is_empty());
+        assert_eq!(result.pending.len(), 3);
+        assert_eq!(
+            result.errors,
+            vec![ReassemblyError::UnsupportedVersion {
+                stream_id: 1,
+                version: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn reassemble_length_mismatch() {
+        let data = b"A message to Bob".to_vec();
+        let chunks = split(1, &data, 5)
+            .into_iter()
+            .map(|mut chunk| {
+                chunk.header.total_length = 20;
+                chunk
+            })
+            .collect();
+
+        let result = reassemble(chunks);
+
+        assert!(result.payloads.is_empty());
+        assert!(result.pending.is_empty());
+        assert_eq!(
+            result.errors,
+            vec![ReassemblyError::LengthMismatch {
+                stream_id: 1,
+                expected: 20,
+                received: 16
+            }]
+        );
+    }
+
+    #[test]
+    fn reassemble_unsupported_flags() {
+        let data = b"A message to Bob".to_vec();
+        let mut chunks = split(1, &data, 5);
+        chunks[2].flags = 3;
+
+        let result = reassemble(chunks);
+
+        assert!(result.payloads.is_empty());
+        assert_eq!(result.pending.len(), 3);
+        assert_eq!(
+            result.errors,
+            vec![ReassemblyError::UnsupportedFlags {
+                stream_id: 1,
+                flags: 3
+            }]
+        );
+    }
+
+    /// Chunks of a payload as they are sent in consecutive denim messages
+    fn ordered_chunks(
+        chunker: &Chunker,
+        regular_payload_size: f32,
+        payload: PayloadData,
+    ) -> Vec<DenimChunk> {
+        let mut chunks = Vec::new();
+        let mut pending = payload;
+        while !pending.chunk.is_empty() {
+            let (new_chunks, _, rest) =
+                chunker.create_ordered_chunks(regular_payload_size, pending);
+            chunks.extend(new_chunks);
+            pending = rest;
+        }
+        chunks
+    }
+
+    fn arbitrary_chunk() -> impl Strategy<Value = DenimChunk> {
+        (
+            prop::collection::vec(any::<u8>(), 0..16),
+            any::<i32>(),
+            any::<u8>(),
+            0_u32..4,
+            0_u32..8,
+            0_u32..64,
+        )
+            .prop_map(
+                |(chunk, flags, version, stream_id, sequence, total_length)| DenimChunk {
+                    chunk,
+                    flags,
+                    header: ChunkHeader {
+                        version,
+                        stream_id,
+                        sequence,
+                        total_length,
+                    },
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn reassemble_round_trip(
+            payloads in prop::collection::vec(prop::collection::vec(any::<u8>(), 1..3000), 0..10),
+            q_value in 0.5_f32..5.0,
+            regular_payload_size in 100_usize..2000,
+            batch_size in 1_usize..20,
+            seed in any::<u64>(),
+        ) {
+            let chunker = Chunker::new(q_value);
+            let mut chunks: Vec<DenimChunk> = payloads
+                .iter()
+                .enumerate()
+                .flat_map(|(stream_id, payload)| {
+                    let payload = PayloadData::with_stream_id(stream_id as u32, payload.clone());
+                    ordered_chunks(&chunker, regular_payload_size as f32, payload)
+                })
+                .collect();
+            chunks.shuffle(&mut StdRng::seed_from_u64(seed));
+
+            // Chunks arrive in batches and incomplete streams are kept between them
+            let mut received = Vec::new();
+            let mut pending = Vec::new();
+            for batch in chunks.chunks(batch_size) {
+                let result = reassemble([pending, batch.to_vec()].concat());
+                prop_assert!(result.errors.is_empty(), "{:?}", result.errors);
+                received.extend(result.payloads);
+                pending = result.pending;
+            }
+
+            let mut expected = payloads;
+            expected.sort();
+            received.sort();
+            prop_assert_eq!(received, expected);
+            prop_assert!(pending.is_empty());
+        }
+
+        #[test]
+        fn reassemble_arbitrary_chunks(chunks in prop::collection::vec(arbitrary_chunk(), 0..64)) {
+            let count = chunks.len();
+
+            let result = reassemble(chunks);
+
+            prop_assert!(result.payloads.len() + result.pending.len() + result.errors.len() <= count);
+            prop_assert!(result.pending.iter().all(|chunk| chunk.flags <= 2));
+        }
+    }
+}
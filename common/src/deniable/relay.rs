@@ -0,0 +1,192 @@
+use crate::{
+    deniable::{
+        chunk::{random_filler, Chunker},
+        cover_traffic::CoverEnvelopeSizes,
+    },
+    signalservice::{envelope, Envelope},
+    web_api::{DenimChunk, DenimMessage, RegularPayload, SignalMessage},
+};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use rand::{CryptoRng, Rng};
+use uuid::Builder;
+
+/// Envelope a message from device `source_device` of `source` is delivered in.
+/// Returns `None` when the content of the message is not valid base64.
+pub fn message_envelope(
+    message: &SignalMessage,
+    source: String,
+    source_device: u32,
+    destination: String,
+    timestamp: u64,
+    server_timestamp: u64,
+    urgent: bool,
+) -> Option<Envelope> {
+    Some(Envelope {
+        r#type: Some(message.r#type),
+        source_service_id: Some(source),
+        source_device: Some(source_device),
+        timestamp: Some(timestamp),
+        content: Some(BASE64_STANDARD.decode(&message.content).ok()?),
+        server_guid: None,
+        server_timestamp: Some(server_timestamp),
+        ephemeral: None,
+        destination_service_id: Some(destination),
+        urgent: Some(urgent),
+        updated_pni: None,
+        story: None,
+        reporting_token: None,
+    })
+}
+
+/// Envelope of a cover message to `receiver`, used to drain its outgoing payload buffer when
+/// it has no regular messages waiting. It comes from a random source like an envelope from
+/// someone unknown, and is marked with the unknown type so the receiver drops it.
+pub fn cover_envelope<R: Rng + CryptoRng>(
+    receiver: &str,
+    sizes: &CoverEnvelopeSizes,
+    timestamp: u64,
+    rng: &mut R,
+) -> Envelope {
+    let mut content = vec![0; sizes.sample(rng)];
+    rng.fill_bytes(&mut content);
+
+    Envelope {
+        r#type: Some(envelope::Type::Unknown.into()),
+        source_service_id: Some(random_uuid(rng)),
+        source_device: Some(1),
+        destination_service_id: Some(receiver.to_owned()),
+        timestamp: Some(timestamp),
+        content: Some(content),
+        server_guid: Some(random_uuid(rng)),
+        server_timestamp: Some(timestamp),
+        urgent: Some(false),
+        story: Some(false),
+        ..Default::default()
+    }
+}
+
+fn random_uuid<R: Rng + CryptoRng>(rng: &mut R) -> String {
+    Builder::from_random_bytes(rng.gen())
+        .into_uuid()
+        .to_string()
+}
+
+/// A regular envelope on its way to a receiver, before the chunks of the denim message
+/// carrying it are taken from the outgoing payload buffer of the receiver
+#[derive(Debug, Clone)]
+pub struct Carrier {
+    regular_payload: RegularPayload,
+    q_value: f32,
+    counter: i32,
+}
+
+impl Carrier {
+    /// Carrier with the q value and the next counter of the connection of the receiver
+    pub fn new(envelope: Envelope, q_value: f32, counter: i32) -> Self {
+        Self {
+            regular_payload: RegularPayload::Envelope(envelope),
+            q_value,
+            counter,
+        }
+    }
+
+    /// Encoded bytes of chunks and ballast the denim message has room for
+    pub fn free_space(&self) -> usize {
+        Chunker::new(self.q_value)
+            .get_free_space_in_bytes(self.regular_payload.encoded_len() as f32)
+    }
+
+    /// Denim message with `chunks` from the outgoing payload buffer,
+    /// and `ballast` bytes of ballast filling the rest of the free space
+    pub fn into_message(self, chunks: Vec<DenimChunk>, ballast: usize) -> DenimMessage {
+        DenimMessage {
+            regular_payload: self.regular_payload,
+            chunks,
+            counter: Some(self.counter),
+            q: Some(self.q_value),
+            ballast: random_filler(ballast),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::deniable::{
+        constants,
+        cover_traffic::{PADDING_BLOCK_SIZE, SIGNAL_MESSAGE_OVERHEAD},
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn message_envelope_decodes_content() {
+        let message = SignalMessage {
+            r#type: envelope::Type::Ciphertext.into(),
+            content: BASE64_STANDARD.encode([1, 2, 3]),
+            ..Default::default()
+        };
+
+        let envelope = message_envelope(
+            &message,
+            "alice".to_owned(),
+            2,
+            "bob".to_owned(),
+            10,
+            20,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(envelope.content(), [1, 2, 3]);
+        assert_eq!(envelope.source_device(), 2);
+        assert_eq!(envelope.destination_service_id(), "bob");
+        assert!(message_envelope(
+            &SignalMessage {
+                content: "not base64!".to_owned(),
+                ..Default::default()
+            },
+            "alice".to_owned(),
+            2,
+            "bob".to_owned(),
+            10,
+            20,
+            false,
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn cover_envelopes_come_from_random_sources() {
+        let sizes = CoverEnvelopeSizes::parse("0,1").unwrap();
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let first = cover_envelope("bob", &sizes, 10, &mut rng);
+        let second = cover_envelope("bob", &sizes, 10, &mut rng);
+
+        assert_eq!(first.r#type(), envelope::Type::Unknown);
+        assert_eq!(
+            first.content().len(),
+            2 * PADDING_BLOCK_SIZE + SIGNAL_MESSAGE_OVERHEAD
+        );
+        assert_ne!(first.source_service_id(), "bob");
+        assert_ne!(first.source_service_id(), second.source_service_id());
+        assert_ne!(first.server_guid(), second.server_guid());
+    }
+
+    #[test]
+    fn carrier_fills_free_space() {
+        let envelope = Envelope {
+            content: Some(vec![0; 200]),
+            ..Default::default()
+        };
+        let carrier = Carrier::new(envelope, 0.6, 7);
+        let free_space = carrier.free_space();
+
+        let ballast = constants::ballast_len(free_space).unwrap();
+        let message = carrier.into_message(vec![], ballast);
+
+        assert_eq!(message.counter, Some(7));
+        assert_eq!(message.q, Some(0.6));
+        assert_eq!(Chunker::new(0.6).check_message_size(&message), Ok(()));
+    }
+}
@@ -0,0 +1,588 @@
+use super::{
+    chunk::{random_filler, ChunkType},
+    constants,
+};
+use crate::web_api::{ChunkHeader, DeniablePayload, DenimChunk};
+use std::{collections::VecDeque, env::var, fmt::Debug};
+
+/// Bytes a sender may take per turn with deficit round-robin when no quantum is given
+const DEFAULT_QUANTUM: usize = 512;
+
+/// Scheduling class of a deniable payload, key material is needed before messages can be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PayloadClass {
+    Message = 0,
+    KeyMaterial = 1,
+}
+
+impl From<&DeniablePayload> for PayloadClass {
+    fn from(payload: &DeniablePayload) -> Self {
+        match payload {
+            DeniablePayload::KeyRequest(_)
+            | DeniablePayload::KeyResponse(_)
+            | DeniablePayload::IdentifierRequest(_)
+            | DeniablePayload::IdentifierResponse(_)
+            | DeniablePayload::KeyUpload(_) => Self::KeyMaterial,
+            DeniablePayload::SignalMessage(_)
+            | DeniablePayload::Envelope(_)
+            | DeniablePayload::GroupMessage(_)
+            | DeniablePayload::SyncMessage(_) => Self::Message,
+        }
+    }
+}
+
+impl From<u8> for PayloadClass {
+    fn from(priority: u8) -> Self {
+        match priority {
+            0 => Self::Message,
+            _ => Self::KeyMaterial,
+        }
+    }
+}
+
+impl From<PayloadClass> for u8 {
+    fn from(class: PayloadClass) -> Self {
+        class as u8
+    }
+}
+
+/// A payload waiting in an outgoing payload buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedPayload {
+    pub id: u64,
+    pub class: PayloadClass,
+    /// Device that sent the payload, `None` for payloads restored from the database
+    pub sender: Option<String>,
+}
+
+impl QueuedPayload {
+    pub fn new(id: u64, priority: u8, sender: Option<String>) -> Self {
+        Self {
+            id,
+            class: priority.into(),
+            sender,
+        }
+    }
+
+    fn sender(&self) -> &str {
+        self.sender.as_deref().unwrap_or_default()
+    }
+}
+
+/// Payload to take the next chunk from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pick {
+    /// Index of the payload in the queue
+    pub index: usize,
+    /// Most bytes of data to take, `None` takes as much as fits
+    pub max_bytes: Option<usize>,
+}
+
+/// Decides which payloads of a receiver are chunked first.
+/// One scheduler is kept per receiver, so it can remember whose turn it is.
+pub trait PayloadScheduler: Debug + Send {
+    /// Pick a payload from `queue`, which is in the order the payloads arrived
+    fn next(&mut self, queue: &[QueuedPayload]) -> Option<Pick>;
+
+    /// Called when `bytes` of data were taken from the picked `payload`
+    fn taken(&mut self, _payload: &QueuedPayload, _bytes: usize) {}
+}
+
+/// Payloads in the order they arrived
+#[derive(Debug, Default)]
+pub struct Fifo;
+
+impl PayloadScheduler for Fifo {
+    fn next(&mut self, queue: &[QueuedPayload]) -> Option<Pick> {
+        (!queue.is_empty()).then_some(Pick {
+            index: 0,
+            max_bytes: None,
+        })
+    }
+}
+
+/// One chunk per sender in turn
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    /// Senders with payloads, the one whose turn it is first
+    active: VecDeque<String>,
+}
+
+impl PayloadScheduler for RoundRobin {
+    fn next(&mut self, queue: &[QueuedPayload]) -> Option<Pick> {
+        // Senders without payloads leave the round, new senders join at the end of it
+        self.active
+            .retain(|sender| queue.iter().any(|payload| payload.sender() == sender));
+        for payload in queue {
+            if !self.active.iter().any(|sender| sender == payload.sender()) {
+                self.active.push_back(payload.sender().to_owned());
+            }
+        }
+
+        let sender = self.active.front()?;
+        let index = queue
+            .iter()
+            .position(|payload| payload.sender() == sender)?;
+        Some(Pick {
+            index,
+            max_bytes: None,
+        })
+    }
+
+    fn taken(&mut self, payload: &QueuedPayload, _bytes: usize) {
+        if self.active.front().map(String::as_str) == Some(payload.sender()) {
+            self.active.rotate_left(1);
+        }
+    }
+}
+
+/// Key material before messages, in arrival order within a class
+#[derive(Debug, Default)]
+pub struct Priority;
+
+impl PayloadScheduler for Priority {
+    fn next(&mut self, queue: &[QueuedPayload]) -> Option<Pick> {
+        let class = queue.iter().map(|payload| payload.class).max()?;
+        let index = queue.iter().position(|payload| payload.class == class)?;
+        Some(Pick {
+            index,
+            max_bytes: None,
+        })
+    }
+}
+
+/// Senders in turn, each taking up to `quantum` bytes per turn.
+/// A turn that is cut short by a full message continues in the next message.
+#[derive(Debug)]
+pub struct DeficitRoundRobin {
+    quantum: usize,
+    active: VecDeque<String>,
+    deficit: usize,
+}
+
+impl DeficitRoundRobin {
+    pub fn new(quantum: usize) -> Self {
+        Self {
+            quantum,
+            active: VecDeque::new(),
+            deficit: 0,
+        }
+    }
+}
+
+impl PayloadScheduler for DeficitRoundRobin {
+    fn next(&mut self, queue: &[QueuedPayload]) -> Option<Pick> {
+        // Senders without payloads leave the round and lose what is left of their turn
+        let current = self.active.front().cloned();
+        self.active
+            .retain(|sender| queue.iter().any(|payload| payload.sender() == sender));
+        if self.active.front() != current.as_ref() {
+            self.deficit = 0;
+        }
+        for payload in queue {
+            if !self.active.iter().any(|sender| sender == payload.sender()) {
+                self.active.push_back(payload.sender().to_owned());
+            }
+        }
+
+        let sender = self.active.front()?;
+        if self.deficit == 0 {
+            self.deficit = self.quantum;
+        }
+        let index = queue
+            .iter()
+            .position(|payload| payload.sender() == sender)?;
+        Some(Pick {
+            index,
+            max_bytes: Some(self.deficit),
+        })
+    }
+
+    fn taken(&mut self, _payload: &QueuedPayload, bytes: usize) {
+        self.deficit = self.deficit.saturating_sub(bytes);
+        if self.deficit == 0 {
+            if let Some(sender) = self.active.pop_front() {
+                self.active.push_back(sender);
+            }
+        }
+    }
+}
+
+/// How payloads in outgoing payload buffers are scheduled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchedulingStrategy {
+    #[default]
+    Fifo,
+    RoundRobin,
+    Priority,
+    DeficitRoundRobin {
+        quantum: usize,
+    },
+}
+
+impl SchedulingStrategy {
+    /// Parses `fifo`, `round-robin`, `priority` or `drr` with an optional `:<quantum in bytes>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (kind, param) = match value.split_once(':') {
+            Some((kind, param)) => (kind, Some(param)),
+            None => (value, None),
+        };
+        match (kind.trim(), param) {
+            ("fifo", None) => Ok(Self::Fifo),
+            ("round-robin", None) => Ok(Self::RoundRobin),
+            ("priority", None) => Ok(Self::Priority),
+            ("drr", None) => Ok(Self::DeficitRoundRobin {
+                quantum: DEFAULT_QUANTUM,
+            }),
+            ("drr", Some(param)) => {
+                let quantum: usize = param.trim().parse().map_err(|err| {
+                    format!("Invalid deficit round-robin quantum '{param}': {err}")
+                })?;
+                if quantum == 0 {
+                    return Err("Deficit round-robin quantum must be larger than 0".to_owned());
+                }
+                Ok(Self::DeficitRoundRobin { quantum })
+            }
+            (other, Some(_)) if ["fifo", "round-robin", "priority"].contains(&other) => Err(
+                format!("Payload scheduling strategy '{other}' takes no parameter"),
+            ),
+            (other, _) => Err(format!("Unknown payload scheduling strategy '{other}'")),
+        }
+    }
+
+    /// Reads the strategy from the environment variable `key`.
+    /// Payloads are sent in arrival order when the variable is not set.
+    pub fn from_env(key: &str) -> Result<Self, String> {
+        match var(key) {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn scheduler(&self) -> Box<dyn PayloadScheduler> {
+        match *self {
+            Self::Fifo => Box::new(Fifo),
+            Self::RoundRobin => Box::<RoundRobin>::default(),
+            Self::Priority => Box::new(Priority),
+            Self::DeficitRoundRobin { quantum } => Box::new(DeficitRoundRobin::new(quantum)),
+        }
+    }
+}
+
+/// Chunks filling the free space of a denim message from an outgoing payload buffer.
+/// The buffer keeps the payload data, it takes the chunks of the payloads this picks.
+#[derive(Debug)]
+pub struct ChunkFill {
+    chunks: Vec<DenimChunk>,
+    space: usize,
+    filled: bool,
+}
+
+impl ChunkFill {
+    /// Fill `bytes_amount` encoded bytes, starting with `chunks` that were taken already
+    pub fn new(bytes_amount: usize, chunks: Vec<DenimChunk>) -> Self {
+        let taken: usize = chunks
+            .iter()
+            .map(|chunk| constants::chunk_size(chunk.chunk.len()))
+            .sum();
+        Self {
+            chunks,
+            space: bytes_amount - taken,
+            filled: false,
+        }
+    }
+
+    /// Index of the payload in `queue` to take the next chunk from and the most encoded bytes
+    /// the chunk may have, `None` once the space is filled
+    pub fn next(
+        &mut self,
+        scheduler: &mut dyn PayloadScheduler,
+        queue: &[QueuedPayload],
+    ) -> Option<(usize, usize)> {
+        if self.filled {
+            return None;
+        }
+        constants::chunk_data_len(self.space, usize::MAX)?;
+        let Some(pick) = scheduler.next(queue) else {
+            self.fill_with_dummy();
+            return None;
+        };
+        // A rest of one or two bytes could not be filled with ballast
+        let limit = match pick.max_bytes.map(constants::chunk_size) {
+            Some(max_size) if self.space.saturating_sub(max_size) > 2 => max_size,
+            _ => self.space,
+        };
+        Some((pick.index, limit))
+    }
+
+    /// Add the chunk taken from the picked `payload`, `None` when nothing could be taken.
+    /// Returns whether the chunk was the last one of the payload.
+    pub fn push(
+        &mut self,
+        scheduler: &mut dyn PayloadScheduler,
+        payload: &QueuedPayload,
+        chunk: Option<DenimChunk>,
+    ) -> bool {
+        let Some(chunk) = chunk else {
+            self.fill_with_dummy();
+            return false;
+        };
+        self.space -= constants::chunk_size(chunk.chunk.len());
+        scheduler.taken(payload, chunk.chunk.len());
+        let is_final = chunk.is_final();
+        self.chunks.push(chunk);
+        is_final
+    }
+
+    /// The chunks and the length of the ballast that fills the rest
+    pub fn finish(self) -> (Vec<DenimChunk>, usize) {
+        (
+            self.chunks,
+            constants::ballast_len(self.space).unwrap_or_default(),
+        )
+    }
+
+    fn fill_with_dummy(&mut self) {
+        if let Some(dummy_size) = constants::chunk_data_len(self.space, usize::MAX) {
+            self.space -= constants::chunk_size(dummy_size);
+            self.chunks.push(DenimChunk {
+                chunk: random_filler(dummy_size),
+                flags: ChunkType::Dummy.into(),
+                header: ChunkHeader::default(),
+            });
+        }
+        self.filled = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Payloads as (sender, class, length)
+    fn queue(payloads: &[(&str, PayloadClass, usize)]) -> (Vec<QueuedPayload>, Vec<usize>) {
+        payloads
+            .iter()
+            .enumerate()
+            .map(|(id, (sender, class, len))| {
+                (
+                    QueuedPayload {
+                        id: id as u64,
+                        class: *class,
+                        sender: Some(sender.to_string()),
+                    },
+                    *len,
+                )
+            })
+            .unzip()
+    }
+
+    /// Take chunks of at most `chunk_size` bytes until the queue is empty,
+    /// returns the sender and size of every chunk
+    fn drain(
+        scheduler: &mut dyn PayloadScheduler,
+        payloads: &[(&str, PayloadClass, usize)],
+        chunk_size: usize,
+    ) -> Vec<(String, usize)> {
+        let (mut queue, mut remaining) = queue(payloads);
+        let mut chunks = Vec::new();
+        while let Some(pick) = scheduler.next(&queue) {
+            let bytes = remaining[pick.index]
+                .min(chunk_size)
+                .min(pick.max_bytes.unwrap_or(usize::MAX));
+            scheduler.taken(&queue[pick.index], bytes);
+            chunks.push((queue[pick.index].sender().to_owned(), bytes));
+            remaining[pick.index] -= bytes;
+            if remaining[pick.index] == 0 {
+                queue.remove(pick.index);
+                remaining.remove(pick.index);
+            }
+        }
+        chunks
+    }
+
+    fn senders(chunks: &[(String, usize)]) -> Vec<&str> {
+        chunks.iter().map(|(sender, _)| sender.as_str()).collect()
+    }
+
+    /// Bytes each sender got in the first `budget` bytes
+    fn share(chunks: &[(String, usize)], budget: usize) -> HashMap<&str, usize> {
+        let mut share = HashMap::new();
+        let mut total = 0;
+        for (sender, bytes) in chunks {
+            let bytes = (*bytes).min(budget - total);
+            *share.entry(sender.as_str()).or_default() += bytes;
+            total += bytes;
+            if total == budget {
+                break;
+            }
+        }
+        share
+    }
+
+    const MESSAGE: PayloadClass = PayloadClass::Message;
+    const KEY: PayloadClass = PayloadClass::KeyMaterial;
+
+    #[test]
+    fn fifo_keeps_arrival_order() {
+        let chunks = drain(
+            &mut Fifo,
+            &[("a", MESSAGE, 300), ("a", MESSAGE, 300), ("b", KEY, 100)],
+            100,
+        );
+
+        assert_eq!(senders(&chunks), ["a", "a", "a", "a", "a", "a", "b"]);
+    }
+
+    #[test]
+    fn round_robin_alternates_senders() {
+        let chunks = drain(
+            &mut RoundRobin::default(),
+            &[
+                ("a", MESSAGE, 300),
+                ("a", MESSAGE, 100),
+                ("b", MESSAGE, 100),
+                ("c", MESSAGE, 200),
+            ],
+            100,
+        );
+
+        assert_eq!(senders(&chunks), ["a", "b", "c", "a", "c", "a", "a"]);
+    }
+
+    #[test]
+    fn round_robin_does_not_starve_later_senders() {
+        let chunks = drain(
+            &mut RoundRobin::default(),
+            &[("a", MESSAGE, 10_000), ("b", MESSAGE, 200)],
+            100,
+        );
+
+        let position = senders(&chunks).iter().rposition(|sender| *sender == "b");
+        assert_eq!(position, Some(3));
+    }
+
+    #[test]
+    fn priority_sends_key_material_first() {
+        let chunks = drain(
+            &mut Priority,
+            &[("a", MESSAGE, 200), ("b", KEY, 100), ("c", KEY, 100)],
+            100,
+        );
+
+        assert_eq!(senders(&chunks), ["b", "c", "a", "a"]);
+    }
+
+    #[test]
+    fn deficit_round_robin_shares_bytes_between_senders() {
+        let quantum = 250;
+        // Sender b sends small chunks, but gets the same number of bytes per turn as a
+        let chunks = drain(
+            &mut DeficitRoundRobin::new(quantum),
+            &[
+                ("a", MESSAGE, 5000),
+                ("b", MESSAGE, 100),
+                ("b", MESSAGE, 100),
+                ("b", MESSAGE, 3000),
+            ],
+            1000,
+        );
+
+        let share = share(&chunks, 2000);
+        assert!(share["a"].abs_diff(share["b"]) <= quantum);
+        assert_eq!(chunks.iter().map(|(_, bytes)| bytes).sum::<usize>(), 8200);
+    }
+
+    #[test]
+    fn deficit_round_robin_continues_turn_after_full_message() {
+        let mut scheduler = DeficitRoundRobin::new(500);
+        let (queue, _) = queue(&[("a", MESSAGE, 1000), ("b", MESSAGE, 1000)]);
+
+        let pick = scheduler.next(&queue).unwrap();
+        scheduler.taken(&queue[pick.index], 200);
+        let pick = scheduler.next(&queue).unwrap();
+
+        assert_eq!(queue[pick.index].sender(), "a");
+        assert_eq!(pick.max_bytes, Some(300));
+    }
+
+    #[test]
+    fn deficit_round_robin_resets_turn_of_empty_sender() {
+        let mut scheduler = DeficitRoundRobin::new(500);
+        let (queue, _) = queue(&[("a", MESSAGE, 100), ("b", MESSAGE, 1000)]);
+
+        let pick = scheduler.next(&queue).unwrap();
+        scheduler.taken(&queue[pick.index], 100);
+        let pick = scheduler.next(&queue[1..]).unwrap();
+
+        assert_eq!(pick.index, 0);
+        assert_eq!(pick.max_bytes, Some(500));
+    }
+
+    #[test]
+    fn chunk_fill_fills_space_exactly() {
+        for (space, len) in [(3, 10), (40, 0), (40, 10), (100, 500), (333, 90)] {
+            let mut scheduler = SchedulingStrategy::RoundRobin.scheduler();
+            let (mut queue, mut remaining) = queue(&[("a", MESSAGE, len), ("b", MESSAGE, len)]);
+            let mut fill = ChunkFill::new(space, Vec::new());
+
+            while let Some((index, limit)) = fill.next(scheduler.as_mut(), &queue) {
+                let chunk = constants::chunk_data_len(limit, remaining[index])
+                    .filter(|bytes| *bytes > 0)
+                    .map(|bytes| DenimChunk {
+                        chunk: vec![0; bytes],
+                        flags: if bytes == remaining[index] {
+                            ChunkType::Final.into()
+                        } else {
+                            ChunkType::Data(0).into()
+                        },
+                        header: ChunkHeader::default(),
+                    });
+                let bytes = chunk.as_ref().map_or(0, |chunk| chunk.chunk.len());
+                remaining[index] -= bytes;
+                if fill.push(scheduler.as_mut(), &queue[index], chunk) {
+                    queue.remove(index);
+                    remaining.remove(index);
+                }
+            }
+            let (chunks, ballast) = fill.finish();
+
+            let size = chunks
+                .iter()
+                .map(|chunk| constants::chunk_size(chunk.chunk.len()))
+                .sum::<usize>()
+                + constants::ballast_size(ballast);
+            assert_eq!(size, space, "space {space}, payloads of {len} bytes");
+        }
+    }
+
+    #[test]
+    fn parse_strategies() {
+        assert_eq!(
+            SchedulingStrategy::parse("fifo"),
+            Ok(SchedulingStrategy::Fifo)
+        );
+        assert_eq!(
+            SchedulingStrategy::parse("round-robin"),
+            Ok(SchedulingStrategy::RoundRobin)
+        );
+        assert_eq!(
+            SchedulingStrategy::parse("priority"),
+            Ok(SchedulingStrategy::Priority)
+        );
+        assert_eq!(
+            SchedulingStrategy::parse("drr"),
+            Ok(SchedulingStrategy::DeficitRoundRobin {
+                quantum: DEFAULT_QUANTUM
+            })
+        );
+        assert_eq!(
+            SchedulingStrategy::parse("drr: 1024"),
+            Ok(SchedulingStrategy::DeficitRoundRobin { quantum: 1024 })
+        );
+        assert!(SchedulingStrategy::parse("drr:0").is_err());
+        assert!(SchedulingStrategy::parse("fifo:1").is_err());
+        assert!(SchedulingStrategy::parse("lifo").is_err());
+    }
+}
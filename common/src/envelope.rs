@@ -6,8 +6,9 @@ use crate::{
 use libsignal_protocol::Pni;
 use libsignal_protocol::ServiceId;
 use libsignal_protocol::{
-    message_decrypt, CiphertextMessage, DeviceId, IdentityKeyStore, KyberPreKeyStore, PreKeyStore,
-    ProtocolAddress, SessionStore, SignedPreKeyStore,
+    group_decrypt, message_decrypt, CiphertextMessage, DeviceId, IdentityKeyStore,
+    KyberPreKeyStore, PreKeyStore, ProtocolAddress, SenderKeyStore, SessionStore,
+    SignedPreKeyStore,
 };
 use prost::Message;
 use rand::{CryptoRng, Rng};
@@ -78,15 +79,89 @@ impl Envelope {
         kyber_pre_key_store: &mut dyn KyberPreKeyStore,
         csprng: &mut R,
     ) -> Result<ProcessedEnvelope, SignalError> {
-        let r#type = if let Some(r#type) = self.r#type {
-            Type::try_from(r#type)
-                .map_err(|_| DecodeEnvelopeError("Unknown message type in envelope.".to_owned()))?
+        let r#type = self.message_type()?;
+        let remote_address = self.remote_address()?;
+
+        let content_bytes = self
+            .content
+            .clone()
+            .ok_or(DecodeEnvelopeError("No content in message.".to_owned()))?;
+
+        let ciphertext = CiphertextMessage::decode(content_bytes, r#type)?;
+
+        let padded_msg = message_decrypt(
+            &ciphertext,
+            &remote_address,
+            session_store,
+            identity_store,
+            pre_key_store,
+            signed_pre_key_store,
+            kyber_pre_key_store,
+            csprng,
+        )
+        .await?;
+
+        self.into_processed(r#type, &padded_msg)
+    }
+
+    /// Decrypt a message that was encrypted once for a whole group, with the sender key
+    /// its source distributed to the group before.
+    pub async fn decrypt_sender_key(
+        self,
+        sender_key_store: &mut dyn SenderKeyStore,
+    ) -> Result<ProcessedEnvelope, SignalError> {
+        let r#type = self.message_type()?;
+        if r#type != Type::KeyExchange {
+            Err(DecodeEnvelopeError(
+                "Message is not encrypted with a sender key.".to_owned(),
+            ))?
+        }
+        let remote_address = self.remote_address()?;
+
+        let content_bytes = self
+            .content
+            .as_deref()
+            .ok_or(DecodeEnvelopeError("No content in message.".to_owned()))?;
+
+        let padded_msg = group_decrypt(content_bytes, sender_key_store, &remote_address).await?;
+
+        self.into_processed(r#type, &padded_msg)
+    }
+
+    fn message_type(&self) -> Result<Type, SignalError> {
+        if let Some(r#type) = self.r#type {
+            Ok(Type::try_from(r#type)
+                .map_err(|_| DecodeEnvelopeError("Unknown message type in envelope.".to_owned()))?)
         } else {
             Err(DecodeEnvelopeError(
                 "No message type in envelope.".to_owned(),
             ))?
-        };
+        }
+    }
+
+    fn remote_address(&self) -> Result<ProtocolAddress, SignalError> {
+        let source_service_id = self
+            .source_service_id
+            .as_ref()
+            .and_then(|string| ServiceId::parse_from_service_id_string(string));
+
+        let source_device: Option<DeviceId> = self.source_device.map(|int| int.into());
+
+        match (source_service_id, source_device) {
+            (Some(service_id), Some(device_id)) => Ok(ProtocolAddress::new(
+                service_id.service_id_string(),
+                device_id,
+            )),
+            (None, _) => Err(DecodeEnvelopeError("Missing Service ID.".to_owned()))?,
+            (_, None) => Err(DecodeEnvelopeError("Missing Device ID.".to_owned()))?,
+        }
+    }
 
+    fn into_processed(
+        self,
+        r#type: Type,
+        padded_msg: &[u8],
+    ) -> Result<ProcessedEnvelope, SignalError> {
         let source_service_id = self
             .source_service_id
             .as_ref()
@@ -104,33 +179,7 @@ impl Envelope {
             .as_ref()
             .and_then(|string| Pni::parse_from_service_id_string(&string));
 
-        let remote_address = match (source_service_id, source_device) {
-            (Some(service_id), Some(device_id)) => {
-                ProtocolAddress::new(service_id.service_id_string(), device_id)
-            }
-            (None, _) => Err(DecodeEnvelopeError("Missing Service ID.".to_owned()))?,
-            (_, None) => Err(DecodeEnvelopeError("Missing Device ID.".to_owned()))?,
-        };
-
-        let content_bytes = self
-            .content
-            .ok_or(DecodeEnvelopeError("No content in message.".to_owned()))?;
-
-        let ciphertext = CiphertextMessage::decode(content_bytes, r#type)?;
-
-        let padded_msg = message_decrypt(
-            &ciphertext,
-            &remote_address,
-            session_store,
-            identity_store,
-            pre_key_store,
-            signed_pre_key_store,
-            kyber_pre_key_store,
-            csprng,
-        )
-        .await?;
-
-        let content = Content::decode(unpad_message(padded_msg.as_slice().as_ref())?.as_ref())?;
+        let content = Content::decode(unpad_message(padded_msg)?.as_ref())?;
 
         Ok(ProcessedEnvelope {
             r#type: Some(r#type),
@@ -1,3 +1,4 @@
+pub mod attachment;
 pub mod deniable;
 pub mod envelope;
 pub mod errors;
@@ -1,5 +1,6 @@
 pub mod authorization;
 pub mod errors;
+pub mod wire;
 
 use libsignal_protocol::{
     kem::{self},
@@ -10,7 +11,7 @@ use serde::{Deserialize, Serialize};
 use serde_with::{base64::Base64, serde_as};
 use uuid::Uuid;
 
-use crate::signalservice::Envelope;
+use crate::{deniable::constants::CHUNK_HEADER_VERSION, signalservice::Envelope};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
@@ -308,7 +309,7 @@ pub struct DeviceActivationRequest {
     pub pni_pq_last_resort_pre_key: UploadSignedPreKey,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum RegularPayload {
     SignalMessage(SignalMessage), // client -> Server
@@ -322,9 +323,36 @@ pub enum DeniablePayload {
     Envelope(Envelope),           // server -> Client
     KeyRequest(PreKeyRequest),
     KeyResponse(PreKeyResponse),
+    IdentifierRequest(IdentifierRequest),
+    IdentifierResponse(IdentifierResponse),
+    KeyUpload(DeniableKeyUpload),
+    GroupMessage(DeniableGroupMessage), // client -> Server
+    SyncMessage(DeniableSyncMessage),   // client -> Server
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Message for the members of a deniable group, encrypted once with the sender key of the
+/// group. The server turns it into an envelope for every device of every member.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeniableGroupMessage {
+    /// Service ids of the members to deliver it to, leaving out the sender
+    pub members: Vec<String>,
+    pub content: String,
+    /// Seconds since epoch after which a deniable payload is dropped if it was not sent yet
+    pub expires_at: Option<u64>,
+}
+
+/// Sync message for the other devices of the sending account, like a sent transcript or a
+/// new deniable contact. It holds one message for each of those devices, encrypted for it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeniableSyncMessage {
+    pub messages: Vec<SignalMessage>,
+    /// Seconds since epoch after which a deniable payload is dropped if it was not sent yet
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct DenimMessage {
     pub regular_payload: RegularPayload,
@@ -334,11 +362,39 @@ pub struct DenimMessage {
     pub ballast: Vec<u8>,
 }
 
+/// Identifies which payload a chunk belongs to and where it goes
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkHeader {
+    pub version: u8,
+    pub stream_id: u32,
+    pub sequence: u32,
+    pub total_length: u32,
+}
+
+impl ChunkHeader {
+    pub fn new(stream_id: u32, sequence: u32, total_length: u32) -> Self {
+        Self {
+            version: CHUNK_HEADER_VERSION,
+            stream_id,
+            sequence,
+            total_length,
+        }
+    }
+}
+
+impl Default for ChunkHeader {
+    fn default() -> Self {
+        Self::new(0, 0, 0)
+    }
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct DenimChunk {
     pub chunk: Vec<u8>,
     pub flags: i32, // Bit flag 1 is dummy, 2 is final
+    pub header: ChunkHeader,
 }
 
 impl DenimChunk {
@@ -367,10 +423,17 @@ impl PartialOrd for DenimChunk {
 pub type PayloadData = DenimChunk;
 
 impl PayloadData {
+    /// Payload data for a new stream with a random id
     pub fn new(data: Vec<u8>) -> Self {
+        Self::with_stream_id(rand::random(), data)
+    }
+
+    pub fn with_stream_id(stream_id: u32, data: Vec<u8>) -> Self {
+        let total_length = data.len() as u32;
         PayloadData {
             chunk: data,
             flags: 0,
+            header: ChunkHeader::new(stream_id, 0, total_length),
         }
     }
 }
@@ -457,6 +520,13 @@ impl UploadKeys {
     }
 }
 
+/// Key an uploaded attachment blob is downloaded with
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AttachmentUploadResponse {
+    pub cdn_key: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct PreKeyCount {
@@ -473,6 +543,18 @@ pub struct DevicePreKeyBundle {
     pub pni_pq_pre_key: UploadSignedPreKey,
 }
 
+/// Deniable identity key and prekeys of a device that are handed out until it uploads new ones
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeniableDevicePreKeyBundle {
+    #[serde_as(as = "Base64")]
+    pub identity_key: Box<[u8]>,
+    pub registration_id: u32,
+    pub signed_pre_key: UploadSignedPreKey,
+    pub pq_last_resort_pre_key: UploadSignedPreKey,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SetKeyRequest {
@@ -504,6 +586,36 @@ pub struct PreKeyRequest {
     pub service_id: String,
 }
 
+/// Deniable lookup of the account behind a phone number, answered with its keys
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentifierRequest {
+    pub phone_number: String,
+}
+
+/// Deniable keys of the account behind a phone number,
+/// `None` when there is no such account or it has no deniable keys
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentifierResponse {
+    pub phone_number: String,
+    pub keys: Option<PreKeyResponse>,
+}
+
+/// Deniable identity key and prekeys of a device, kept apart from its regular keys
+/// and only handed out to answer deniable key requests
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeniableKeyUpload {
+    #[serde_as(as = "Base64")]
+    pub identity_key: Box<[u8]>,
+    pub registration_id: u32,
+    pub signed_pre_key: UploadSignedPreKey,
+    pub pq_last_resort_pre_key: UploadSignedPreKey,
+    pub pre_keys: Vec<UploadPreKey>,
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
@@ -540,6 +652,7 @@ impl PreKeyResponse {
     }
 }
 
+#[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct PreKeyResponseItem {
@@ -548,6 +661,11 @@ pub struct PreKeyResponseItem {
     pre_key: Option<UploadPreKey>,
     pq_pre_key: UploadSignedPreKey,
     signed_pre_key: UploadSignedPreKey,
+    /// Identity key of this device in place of the one of the response,
+    /// each device has its own deniable identity key
+    #[serde_as(as = "Option<Base64>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    identity_key: Option<Box<[u8]>>,
 }
 
 impl PreKeyResponseItem {
@@ -564,8 +682,13 @@ impl PreKeyResponseItem {
             pre_key,
             pq_pre_key,
             signed_pre_key,
+            identity_key: None,
         }
     }
+    pub fn with_identity_key(mut self, identity_key: IdentityKey) -> Self {
+        self.identity_key = Some(identity_key.serialize());
+        self
+    }
     pub fn device_id(&self) -> DeviceId {
         self.device_id.into()
     }
@@ -581,6 +704,9 @@ impl PreKeyResponseItem {
     pub fn signed_pre_key(&self) -> &UploadSignedPreKey {
         &self.signed_pre_key
     }
+    pub fn identity_key(&self) -> &Option<Box<[u8]>> {
+        &self.identity_key
+    }
 }
 
 impl TryFrom<PreKeyResponse> for Vec<PreKeyBundle> {
@@ -601,6 +727,11 @@ impl TryFrom<PreKeyResponse> for Vec<PreKeyBundle> {
             } else {
                 None
             };
+            let identity_key = match pre_key_items.identity_key() {
+                Some(identity_key) => IdentityKey::decode(identity_key)
+                    .map_err(|_| "Failed decoding device identity key")?,
+                None => identity_key,
+            };
             let bundle = PreKeyBundle::new(
                 pre_key_items.registration_id(),
                 pre_key_items.device_id(),
@@ -626,7 +757,7 @@ impl TryFrom<PreKeyResponse> for Vec<PreKeyBundle> {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct DenimMessages {
     pub messages: Vec<DenimMessage>,
@@ -643,6 +774,8 @@ pub struct SignalMessage {
     pub destination_service_id: Option<String>,
     pub destination_registration_id: u32,
     pub content: String,
+    /// Seconds since epoch after which a deniable payload is dropped if it was not sent yet
+    pub expires_at: Option<u64>,
 }
 
 #[cfg(test)]
@@ -681,4 +814,48 @@ mod api_structs_tests {
         let res = PreKeyResponse::new("".to_owned(), *identity_key.identity_key(), keys);
         let _: Vec<PreKeyBundle> = res.try_into().unwrap();
     }
+
+    #[test]
+    fn test_try_from_pre_key_response_with_device_identity_keys() {
+        let identity_key = IdentityKeyPair::generate(&mut OsRng);
+        let device_identity_key = IdentityKeyPair::generate(&mut OsRng);
+        let prekey = KeyPair::generate(&mut OsRng);
+        let pq_pre_key = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+        let item = |device_id: u32| {
+            PreKeyResponseItem::new(
+                device_id.into(),
+                1,
+                None,
+                UploadSignedPreKey {
+                    key_id: 1,
+                    public_key: pq_pre_key.public_key.serialize(),
+                    signature: Box::new([1, 2, 3, 4]),
+                },
+                UploadSignedPreKey {
+                    key_id: 1,
+                    public_key: prekey.public_key.serialize(),
+                    signature: Box::new([1, 2, 3, 4]),
+                },
+            )
+        };
+
+        let res = PreKeyResponse::new(
+            "".to_owned(),
+            *identity_key.identity_key(),
+            vec![
+                item(1),
+                item(2).with_identity_key(*device_identity_key.identity_key()),
+            ],
+        );
+        let bundles: Vec<PreKeyBundle> = res.try_into().unwrap();
+
+        assert_eq!(
+            bundles[0].identity_key().unwrap(),
+            identity_key.identity_key()
+        );
+        assert_eq!(
+            bundles[1].identity_key().unwrap(),
+            device_identity_key.identity_key()
+        );
+    }
 }
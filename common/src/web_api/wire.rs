@@ -0,0 +1,334 @@
+use super::{ChunkHeader, DenimChunk, DenimMessage, DenimMessages, RegularPayload, SignalMessage};
+use crate::denim::{self as proto, denim_message};
+use prost::Message;
+
+impl From<SignalMessage> for proto::SignalMessage {
+    fn from(value: SignalMessage) -> Self {
+        Self {
+            r#type: value.r#type,
+            destination_device_id: value.destination_device_id,
+            destination_service_id: value.destination_service_id,
+            destination_registration_id: value.destination_registration_id,
+            content: value.content,
+            expires_at: value.expires_at,
+        }
+    }
+}
+
+impl From<proto::SignalMessage> for SignalMessage {
+    fn from(value: proto::SignalMessage) -> Self {
+        Self {
+            r#type: value.r#type,
+            destination_device_id: value.destination_device_id,
+            destination_service_id: value.destination_service_id,
+            destination_registration_id: value.destination_registration_id,
+            content: value.content,
+            expires_at: value.expires_at,
+        }
+    }
+}
+
+impl From<RegularPayload> for denim_message::RegularPayload {
+    fn from(value: RegularPayload) -> Self {
+        match value {
+            RegularPayload::SignalMessage(message) => Self::SignalMessage(message.into()),
+            RegularPayload::Envelope(envelope) => Self::Envelope(envelope),
+        }
+    }
+}
+
+impl From<denim_message::RegularPayload> for RegularPayload {
+    fn from(value: denim_message::RegularPayload) -> Self {
+        match value {
+            denim_message::RegularPayload::SignalMessage(message) => {
+                Self::SignalMessage(message.into())
+            }
+            denim_message::RegularPayload::Envelope(envelope) => Self::Envelope(envelope),
+        }
+    }
+}
+
+impl From<DenimChunk> for proto::DenimChunk {
+    fn from(value: DenimChunk) -> Self {
+        Self {
+            chunk: value.chunk,
+            flags: value.flags,
+            version: value.header.version.into(),
+            stream_id: value.header.stream_id,
+            sequence: value.header.sequence,
+            total_length: value.header.total_length,
+        }
+    }
+}
+
+impl TryFrom<proto::DenimChunk> for DenimChunk {
+    type Error = String;
+
+    fn try_from(value: proto::DenimChunk) -> Result<Self, Self::Error> {
+        Ok(Self {
+            chunk: value.chunk,
+            flags: value.flags,
+            header: ChunkHeader {
+                version: value
+                    .version
+                    .try_into()
+                    .map_err(|_| format!("Invalid chunk header version {}", value.version))?,
+                stream_id: value.stream_id,
+                sequence: value.sequence,
+                total_length: value.total_length,
+            },
+        })
+    }
+}
+
+impl From<DenimMessage> for proto::DenimMessage {
+    fn from(value: DenimMessage) -> Self {
+        Self {
+            regular_payload: Some(value.regular_payload.into()),
+            chunks: value.chunks.into_iter().map(Into::into).collect(),
+            counter: value.counter,
+            q: value.q,
+            ballast: (!value.ballast.is_empty()).then_some(value.ballast),
+        }
+    }
+}
+
+impl TryFrom<proto::DenimMessage> for DenimMessage {
+    type Error = String;
+
+    fn try_from(value: proto::DenimMessage) -> Result<Self, Self::Error> {
+        Ok(Self {
+            regular_payload: value
+                .regular_payload
+                .ok_or("Denim message has no regular payload")?
+                .into(),
+            chunks: value
+                .chunks
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()?,
+            counter: value.counter,
+            q: value.q,
+            ballast: value.ballast.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<DenimMessages> for proto::DenimMessages {
+    fn from(value: DenimMessages) -> Self {
+        Self {
+            messages: value.messages.into_iter().map(Into::into).collect(),
+            online: value.online,
+            urgent: value.urgent,
+            timestamp: value.timestamp,
+        }
+    }
+}
+
+impl TryFrom<proto::DenimMessages> for DenimMessages {
+    type Error = String;
+
+    fn try_from(value: proto::DenimMessages) -> Result<Self, Self::Error> {
+        Ok(Self {
+            messages: value
+                .messages
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()?,
+            online: value.online,
+            urgent: value.urgent,
+            timestamp: value.timestamp,
+        })
+    }
+}
+
+impl RegularPayload {
+    /// Encoded size of the regular payload in a `DenimMessage`, which the q value is relative to
+    pub fn encoded_len(&self) -> usize {
+        denim_message::RegularPayload::from(self.clone()).encoded_len()
+    }
+}
+
+impl DenimMessage {
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        proto::DenimMessage::from(self.clone()).encode_to_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        proto::DenimMessage::decode(buf)
+            .map_err(|err| format!("Failed to decode denim message: {err}"))?
+            .try_into()
+    }
+}
+
+impl DenimMessages {
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        proto::DenimMessages::from(self.clone()).encode_to_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        proto::DenimMessages::decode(buf)
+            .map_err(|err| format!("Failed to decode denim messages: {err}"))?
+            .try_into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::proto;
+    use crate::{
+        deniable::constants,
+        signalservice::Envelope,
+        web_api::{
+            ChunkHeader, DenimChunk, DenimMessage, DenimMessages, RegularPayload, SignalMessage,
+        },
+    };
+    use prost::Message;
+
+    const LENGTHS: [usize; 12] = [0, 1, 10, 97, 98, 99, 127, 128, 200, 16383, 16384, 20000];
+
+    fn signal_message() -> RegularPayload {
+        RegularPayload::SignalMessage(SignalMessage {
+            r#type: 1,
+            destination_device_id: 3,
+            destination_service_id: None,
+            destination_registration_id: 22,
+            content: "aGVsbG8=".to_string(),
+            expires_at: None,
+        })
+    }
+
+    #[test]
+    fn chunk_size_matches_encoding() {
+        for len in LENGTHS {
+            for (flags, header) in [
+                (1, ChunkHeader::default()),
+                (-7, ChunkHeader::new(u32::MAX, u32::MAX, u32::MAX)),
+            ] {
+                let message = proto::DenimMessage {
+                    chunks: vec![DenimChunk {
+                        chunk: vec![0xff; len],
+                        flags,
+                        header,
+                    }
+                    .into()],
+                    ..Default::default()
+                };
+                assert_eq!(message.encoded_len(), constants::chunk_size(len));
+            }
+        }
+        assert_eq!(constants::EMPTY_DENIMCHUNK_SIZE, constants::chunk_size(0));
+    }
+
+    #[test]
+    fn ballast_size_matches_encoding() {
+        for len in LENGTHS {
+            let message = proto::DenimMessage::from(DenimMessage {
+                regular_payload: signal_message(),
+                chunks: vec![],
+                counter: None,
+                q: None,
+                ballast: vec![0; len],
+            });
+            assert_eq!(
+                message.encoded_len(),
+                signal_message().encoded_len() + constants::ballast_size(len)
+            );
+        }
+    }
+
+    #[test]
+    fn ballast_len_fills_space() {
+        for space in 0..20000 {
+            if let Some(len) = constants::ballast_len(space) {
+                assert_eq!(constants::ballast_size(len), space);
+            }
+        }
+        assert_eq!(constants::ballast_len(1), None);
+        assert_eq!(constants::ballast_len(2), None);
+        assert_eq!(constants::ballast_len(3), Some(1));
+    }
+
+    #[test]
+    fn chunk_data_len_matches_exhaustive_search() {
+        let exhaustive = |space: usize, wanted: usize| {
+            let max = wanted.min(space.checked_sub(constants::EMPTY_DENIMCHUNK_SIZE)?);
+            (0..=max).rev().find(|&len| {
+                constants::chunk_size(len) <= space
+                    && constants::ballast_len(space - constants::chunk_size(len)).is_some()
+            })
+        };
+        for space in (0..20000).chain(2097100..2097300) {
+            for wanted in [0, 1, 3, 100, 127, 128, 16383, 16384, usize::MAX] {
+                assert_eq!(
+                    constants::chunk_data_len(space, wanted),
+                    exhaustive(space, wanted),
+                    "space {space}, wanted {wanted}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn denim_messages_round_trip() {
+        let messages = DenimMessages {
+            messages: vec![
+                DenimMessage {
+                    regular_payload: signal_message(),
+                    chunks: vec![DenimChunk {
+                        chunk: vec![1, 2, 3],
+                        flags: 0,
+                        header: ChunkHeader::new(7, 0, 10),
+                    }],
+                    counter: Some(3),
+                    q: None,
+                    ballast: vec![0; 5],
+                },
+                DenimMessage {
+                    regular_payload: RegularPayload::Envelope(Envelope {
+                        content: Some(vec![4, 5, 6]),
+                        ..Default::default()
+                    }),
+                    chunks: vec![],
+                    counter: None,
+                    q: Some(0.6),
+                    ballast: vec![],
+                },
+            ],
+            online: false,
+            urgent: true,
+            timestamp: 1730217386,
+        };
+
+        let decoded = DenimMessages::decode(&messages.encode_to_vec()).unwrap();
+
+        assert_eq!(decoded, messages);
+    }
+
+    #[test]
+    fn decode_rejects_missing_regular_payload() {
+        let message = proto::DenimMessage::default().encode_to_vec();
+
+        assert!(DenimMessage::decode(&message).is_err());
+    }
+
+    #[test]
+    fn decode_rejects_invalid_chunk_version() {
+        let message = proto::DenimMessage {
+            chunks: vec![proto::DenimChunk {
+                version: 256,
+                ..Default::default()
+            }],
+            ..proto::DenimMessage::from(DenimMessage {
+                regular_payload: signal_message(),
+                chunks: vec![],
+                counter: None,
+                q: None,
+                ballast: vec![],
+            })
+        }
+        .encode_to_vec();
+
+        assert!(DenimMessage::decode(&message).is_err());
+    }
+}
@@ -97,10 +97,7 @@ pub fn create_request(
 }
 
 pub fn unpack_messages(body: Option<Vec<u8>>) -> Result<DenimMessages, String> {
-    let json = String::from_utf8(body.ok_or_else(|| "Body was none".to_string())?)
-        .map_err(|_| "Failed to convert req body to string".to_string())?;
-
-    serde_json::from_str(&json).map_err(|_| "Failed to convert json to DenimMessages".to_string())
+    DenimMessages::decode(&body.ok_or_else(|| "Body was none".to_string())?)
 }
 
 pub fn generate_req_id() -> u64 {
@@ -116,7 +113,10 @@ pub fn current_millis() -> Result<u128, SystemTimeError> {
 #[cfg(test)]
 mod test {
     use super::{create_request, create_response, unpack_messages, PathExtractor};
-    use crate::{signalservice::web_socket_message, web_api::RegularPayload};
+    use crate::{
+        signalservice::web_socket_message,
+        web_api::{DenimMessage, DenimMessages, RegularPayload, SignalMessage},
+    };
     use axum::http::{StatusCode, Uri};
     use std::str::FromStr;
 
@@ -169,28 +169,25 @@ mod test {
 
     #[test]
     fn test_unpack_messages() {
-        let msg = r#"
-        {
-            "messages":[
-                {
-                    "regularPayload": {
-                        "signalMessage": {
-                            "type": 1,
-                            "destinationDeviceId": 3,
-                            "destinationRegistrationId": 22,
-                            "content": "aGVsbG8="
-                        }
-                    },
-                    "chunks": [],
-                    "ballast": []
-                }
-            ],
-            "online": false,
-            "urgent": true,
-            "timestamp": 1730217386
+        let b = DenimMessages {
+            messages: vec![DenimMessage {
+                regular_payload: RegularPayload::SignalMessage(SignalMessage {
+                    r#type: 1,
+                    destination_device_id: 3,
+                    destination_registration_id: 22,
+                    content: "aGVsbG8=".to_string(),
+                    ..Default::default()
+                }),
+                chunks: vec![],
+                counter: None,
+                q: None,
+                ballast: vec![],
+            }],
+            online: false,
+            urgent: true,
+            timestamp: 1730217386,
         }
-        "#;
-        let b = msg.as_bytes().to_vec();
+        .encode_to_vec();
 
         let req = create_request(1, "PUT", "/v1/messages", vec![], Some(b));
 
@@ -36,6 +36,21 @@ CREATE TABLE msq_queue (
     msg         BYTEA NOT NULL
 );
 
+CREATE TABLE deniable_chunk_queue (
+    id          INTEGER PRIMARY KEY NOT NULL GENERATED ALWAYS AS IDENTITY,
+    owner       INTEGER NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+    buffer      TEXT NOT NULL,
+    chunk       BYTEA NOT NULL
+);
+
+CREATE TABLE deniable_payload_queue (
+    id          INTEGER PRIMARY KEY NOT NULL GENERATED ALWAYS AS IDENTITY,
+    owner       INTEGER NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+    buffer      TEXT NOT NULL,
+    payload     BYTEA NOT NULL,
+    expires_at  BIGINT
+);
+
 CREATE TABLE aci_signed_pre_key_store (
     id          INTEGER PRIMARY KEY NOT NULL GENERATED ALWAYS AS IDENTITY,
     owner       INTEGER NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
@@ -99,3 +114,32 @@ CREATE TABLE one_time_pq_pre_key_store (
     UNIQUE(owner, key_id)
 );
     
+
+CREATE TABLE deniable_device_keys (
+    id                                INTEGER PRIMARY KEY NOT NULL GENERATED ALWAYS AS IDENTITY,
+    owner                             INTEGER NOT NULL UNIQUE REFERENCES devices(id) ON DELETE CASCADE,
+    identity_key                      bytea NOT NULL,
+    registration_id                   TEXT NOT NULL,
+    signed_pre_key_id                 TEXT NOT NULL,
+    signed_pre_key                    bytea NOT NULL,
+    signed_pre_key_signature          bytea NOT NULL,
+    pq_last_resort_pre_key_id         TEXT NOT NULL,
+    pq_last_resort_pre_key            bytea NOT NULL,
+    pq_last_resort_pre_key_signature  bytea NOT NULL
+);
+
+CREATE TABLE deniable_one_time_ec_pre_key_store (
+    id          INTEGER PRIMARY KEY NOT NULL GENERATED ALWAYS AS IDENTITY,
+    owner       INTEGER NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+    key_id      TEXT NOT NULL,
+    public_key  bytea NOT NULL,
+    UNIQUE(owner, key_id)
+);
+
+CREATE TABLE attachments (
+    id          INTEGER PRIMARY KEY NOT NULL GENERATED ALWAYS AS IDENTITY,
+    uploader    INTEGER NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+    cdn_key     TEXT NOT NULL UNIQUE,
+    blob        bytea NOT NULL,
+    expires_at  BIGINT NOT NULL
+);
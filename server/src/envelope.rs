@@ -1,6 +1,5 @@
 use crate::account::Account;
-use base64::prelude::{Engine as _, BASE64_STANDARD};
-use common::{signalservice::Envelope, web_api::SignalMessage};
+use common::{deniable::relay::message_envelope, signalservice::Envelope, web_api::SignalMessage};
 use libsignal_core::ServiceId;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -24,25 +23,18 @@ impl ToEnvelope for SignalMessage {
         timestamp: u64,
         urgent: bool,
     ) -> Envelope {
-        Envelope {
-            r#type: Some(self.r#type),
-            source_service_id: Some(source_account.aci().service_id_string()),
-            source_device: Some(source_device_id as u32),
-            timestamp: Some(timestamp),
-            content: Some(BASE64_STANDARD.decode(&self.content).unwrap()),
-            server_guid: None,
-            server_timestamp: Some(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards")
-                    .as_millis() as u64,
-            ),
-            ephemeral: None,
-            destination_service_id: Some(destination_id.service_id_string()),
-            urgent: Some(urgent),
-            updated_pni: None,
-            story: None,
-            reporting_token: None,
-        }
+        message_envelope(
+            self,
+            source_account.aci().service_id_string(),
+            source_device_id as u32,
+            destination_id.service_id_string(),
+            timestamp,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64,
+            urgent,
+        )
+        .unwrap()
     }
 }
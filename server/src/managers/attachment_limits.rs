@@ -0,0 +1,81 @@
+use common::attachment::{blob_size, max_blob_size, padded_size, MAX_ATTACHMENT_SIZE};
+use common::deniable::quota::BufferQuota;
+use std::env::var;
+
+/// Attachment blobs are deleted after 30 days unless configured otherwise
+const DEFAULT_ATTACHMENT_TTL: u64 = 30 * 24 * 60 * 60;
+
+/// Limits on the attachment blobs stored by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttachmentLimits {
+    /// Most bytes and blobs each account may have stored at once
+    pub quota: BufferQuota,
+    /// Largest blob that is accepted
+    pub max_blob_size: usize,
+    /// Seconds a blob is kept before it is deleted
+    pub ttl: u64,
+}
+
+impl Default for AttachmentLimits {
+    fn default() -> Self {
+        Self {
+            quota: BufferQuota::default(),
+            max_blob_size: max_blob_size(),
+            ttl: DEFAULT_ATTACHMENT_TTL,
+        }
+    }
+}
+
+impl AttachmentLimits {
+    /// Largest blob an attachment of at most `max_size` bytes is uploaded as.
+    /// Attachments are never larger than [MAX_ATTACHMENT_SIZE].
+    pub fn max_blob_size_for(max_size: usize) -> Result<usize, String> {
+        if max_size == 0 {
+            return Err("Attachment size limit must be larger than 0".to_owned());
+        }
+        Ok(blob_size(padded_size(max_size.min(MAX_ATTACHMENT_SIZE))))
+    }
+
+    /// Reads the quota as `<max bytes>:<max items>`, the largest attachment in bytes and
+    /// the time to live in seconds from the environment variables `quota_key`, `max_size_key`
+    /// and `ttl_key`. Limits that are not set keep their defaults.
+    pub fn from_env(quota_key: &str, max_size_key: &str, ttl_key: &str) -> Result<Self, String> {
+        let mut limits = Self {
+            quota: BufferQuota::from_env(quota_key)?,
+            ..Default::default()
+        };
+        if let Ok(value) = var(max_size_key) {
+            let max_size: usize = value
+                .trim()
+                .parse()
+                .map_err(|err| format!("Invalid attachment size limit '{value}': {err}"))?;
+            limits.max_blob_size = Self::max_blob_size_for(max_size)?;
+        }
+        if let Ok(value) = var(ttl_key) {
+            limits.ttl = value
+                .trim()
+                .parse()
+                .map_err(|err| format!("Invalid attachment ttl '{value}': {err}"))?;
+        }
+        Ok(limits)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::attachment::is_attachment_blob_size;
+
+    #[test]
+    fn max_blob_size_fits_padded_attachments() {
+        let limit = AttachmentLimits::max_blob_size_for(1000).unwrap();
+
+        assert!(is_attachment_blob_size(limit));
+        assert_eq!(limit, blob_size(padded_size(1000)));
+        assert_eq!(
+            AttachmentLimits::max_blob_size_for(usize::MAX),
+            Ok(max_blob_size())
+        );
+        assert!(AttachmentLimits::max_blob_size_for(0).is_err());
+    }
+}
@@ -1,6 +1,11 @@
+use common::deniable::reassembly::{reassemble_after, CompletedStreams, Reassembly};
+use common::web_api::DenimChunk;
+use libsignal_core::ProtocolAddress;
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::sync::{Arc, Mutex};
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub enum Buffer {
     Sender,
     Receiver,
@@ -14,3 +19,24 @@ impl Display for Buffer {
         }
     }
 }
+
+/// Streams recently reassembled from each chunk buffer, so late duplicates of their chunks
+/// are dropped instead of waiting in the buffer
+#[derive(Debug, Clone, Default)]
+pub struct CompletedStreamsCache(Arc<Mutex<HashMap<String, CompletedStreams>>>);
+
+impl CompletedStreamsCache {
+    pub fn reassemble(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        chunks: Vec<DenimChunk>,
+    ) -> Reassembly {
+        let mut cache = self
+            .0
+            .lock()
+            .expect("Completed streams lock is not poisoned");
+        let completed = cache.entry(format!("{address}::{buffer}")).or_default();
+        reassemble_after(chunks, completed)
+    }
+}
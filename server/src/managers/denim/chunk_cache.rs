@@ -8,7 +8,7 @@ use crate::{
     storage::redis::{self, Decoder},
 };
 use anyhow::Result;
-use common::web_api::DenimChunk;
+use common::{deniable::quota::BufferQuota, web_api::DenimChunk};
 use deadpool_redis::Connection;
 use libsignal_core::ProtocolAddress;
 use std::{collections::HashMap, sync::Arc};
@@ -94,6 +94,54 @@ where
         buffer: Buffer,
         chunk: &DenimChunk,
         chunk_guid: &str,
+    ) -> Result<u64> {
+        let chunk_id = self
+            .insert_value(address, buffer, chunk, chunk_guid)
+            .await?;
+        self.update_usage(address, buffer, 1, chunk.chunk.len() as i64)
+            .await?;
+        Ok(chunk_id)
+    }
+
+    /// Insert a chunk only if the queue stays within `quota`, `None` when it does not fit
+    pub async fn insert_within_quota(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        chunk: &DenimChunk,
+        chunk_guid: &str,
+        quota: BufferQuota,
+    ) -> Result<Option<u64>> {
+        let mut connection = self.pool.get().await?;
+        let queue_usage_key = self.get_queue_usage_key(address, buffer);
+        let len = chunk.chunk.len();
+        if !redis::reserve_usage(
+            &mut connection,
+            &queue_usage_key,
+            len,
+            quota.max_items,
+            quota.max_bytes,
+        )
+        .await?
+        {
+            return Ok(None);
+        }
+
+        match self.insert_value(address, buffer, chunk, chunk_guid).await {
+            Ok(chunk_id) => Ok(Some(chunk_id)),
+            Err(err) => {
+                redis::update_usage(&mut connection, &queue_usage_key, -1, -(len as i64)).await?;
+                Err(err)
+            }
+        }
+    }
+
+    async fn insert_value(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        chunk: &DenimChunk,
+        chunk_guid: &str,
     ) -> Result<u64> {
         let connection = self.pool.get().await?;
         let queue_key: String = self.get_queue_key(address, buffer);
@@ -108,6 +156,7 @@ where
             queue_total_index_key,
             chunk_guid,
             value,
+            None,
         )
         .await;
 
@@ -115,6 +164,20 @@ where
         chunk_id
     }
 
+    /// Add to the items and bytes counted for a queue, which the quota is checked against
+    async fn update_usage(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        items: i64,
+        bytes: i64,
+    ) -> Result<()> {
+        let mut connection = self.pool.get().await?;
+        let queue_usage_key = self.get_queue_usage_key(address, buffer);
+
+        redis::update_usage(&mut connection, &queue_usage_key, items, bytes).await
+    }
+
     pub async fn remove(
         &self,
         address: &ProtocolAddress,
@@ -126,14 +189,21 @@ where
         let queue_metadata_key: String = self.get_queue_metadata_key(address, buffer);
         let queue_total_index_key: String = self.get_queue_index_key(buffer);
 
-        redis::remove(
+        let removed: Vec<DenimChunk> = redis::remove(
             connection,
             queue_key,
             queue_metadata_key,
             queue_total_index_key,
             chunk_guid,
         )
-        .await
+        .await?;
+
+        if !removed.is_empty() {
+            let bytes: usize = removed.iter().map(|chunk| chunk.chunk.len()).sum();
+            self.update_usage(address, buffer, -(removed.len() as i64), -(bytes as i64))
+                .await?;
+        }
+        Ok(removed)
     }
 
     pub async fn get_all_chunks(
@@ -211,6 +281,74 @@ where
         Ok(self.remove(receiver, Buffer::Receiver, chunk_guids).await?)
     }
 
+    /// Get chunks and their guids from a queue, also when it is locked for persistence
+    pub async fn get_chunks_to_persist(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        limit: i32,
+    ) -> Result<(Vec<DenimChunk>, Vec<String>)> {
+        let connection = self.pool.get().await?;
+        let queue_key = self.get_queue_key(address, buffer);
+        let queue_metadata_key: String = self.get_queue_metadata_key(address, buffer);
+
+        let (values, chunk_guids) =
+            redis::get_values_to_persist(connection, queue_key, queue_metadata_key, limit).await?;
+
+        Ok((DenimChunk::decode(values)?, chunk_guids))
+    }
+
+    pub async fn get_queues_to_persist(
+        &self,
+        buffer: Buffer,
+        max_time: u64,
+        limit: u8,
+    ) -> Result<Vec<String>> {
+        let connection = self.pool.get().await?;
+        let queue_total_index_key = self.get_queue_index_key(buffer);
+
+        redis::get_queues_to_persist(connection, queue_total_index_key, max_time, limit).await
+    }
+
+    /// Delete the chunks of a queue that was taken from the queue index to be persisted
+    pub async fn delete_queue(&self, address: &ProtocolAddress, buffer: Buffer) -> Result<()> {
+        let connection = self.pool.get().await?;
+        let queue_key = self.get_queue_key(address, buffer);
+        let queue_metadata_key: String = self.get_queue_metadata_key(address, buffer);
+        let queue_usage_key = self.get_queue_usage_key(address, buffer);
+
+        redis::delete_queue(connection, queue_key, queue_metadata_key, queue_usage_key).await
+    }
+
+    pub async fn lock_queue_for_persistence(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+    ) -> Result<()> {
+        let connection = self.pool.get().await?;
+        let queue_lock_key = self.get_persist_in_progress_key(address, buffer);
+
+        redis::lock_queue(connection, queue_lock_key).await
+    }
+
+    pub async fn unlock_queue_for_persistence(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+    ) -> Result<()> {
+        let connection = self.pool.get().await?;
+        let queue_lock_key = self.get_persist_in_progress_key(address, buffer);
+
+        redis::unlock_queue(connection, queue_lock_key).await
+    }
+
+    pub fn get_account_and_device_id_from_queue_key(&self, queue_key: &str) -> (String, String) {
+        let parts = queue_key.split("::").collect::<Vec<&str>>();
+        let account_id = parts[1].trim_matches('{').to_string();
+        let device_id = parts[2].trim_end_matches('}').to_string();
+        (account_id, device_id)
+    }
+
     pub async fn add_availability_listener(
         &mut self,
         address: &ProtocolAddress,
@@ -223,7 +361,7 @@ where
         remove(self.listeners.clone(), address).await;
     }
 
-    fn get_queue_key(&self, address: &ProtocolAddress, buffer: Buffer) -> String {
+    pub fn get_queue_key(&self, address: &ProtocolAddress, buffer: Buffer) -> String {
         #[cfg(not(test))]
         return format!(
             "chunk_{}_queue::{{{}::{}}}",
@@ -277,7 +415,25 @@ where
         )
     }
 
-    fn get_queue_index_key(&self, buffer: Buffer) -> String {
+    fn get_queue_usage_key(&self, address: &ProtocolAddress, buffer: Buffer) -> String {
+        #[cfg(not(test))]
+        return format!(
+            "chunk_{}_queue_usage::{{{}::{}}}",
+            buffer,
+            address.name(),
+            address.device_id()
+        );
+        #[cfg(test)]
+        format!(
+            "{}chunk_{}_queue_usage::{{{}::{}}}",
+            self.test_key,
+            buffer,
+            address.name(),
+            address.device_id()
+        )
+    }
+
+    pub fn get_queue_index_key(&self, buffer: Buffer) -> String {
         #[cfg(not(test))]
         return format!("chunk_{}_queue_index_key", buffer);
         #[cfg(test)]
@@ -1,11 +1,32 @@
-use super::{buffer::Buffer, chunk_cache::ChunkCache, payload_cache::PayloadCache};
-use crate::{availability_listener::AvailabilityListener, managers::manager::Manager};
+use super::{
+    buffer::{Buffer, CompletedStreamsCache},
+    chunk_cache::ChunkCache,
+    payload_cache::PayloadCache,
+};
+use crate::{
+    availability_listener::AvailabilityListener, managers::manager::Manager,
+    storage::database::SignalDatabase,
+};
 use anyhow::{Ok, Result};
-use bincode::serialize;
-use common::deniable::chunk::{ChunkType, Chunker};
+use common::deniable::chunk::{Chunker, DenimSizeError};
+use common::deniable::counter::stream_ids;
+use common::deniable::cover_traffic::{CoverEnvelopeSizes, CoverTrafficSchedule};
+use common::deniable::q_value::{QValuePolicy, QValueRange};
+use common::deniable::quota::{
+    ChunkAdmission, DenimQuotas, DroppedStreams, QuotaCounters, QuotaStats,
+};
+use common::deniable::reassembly::Reassembly;
+use common::deniable::relay::{cover_envelope, Carrier};
+use common::deniable::scheduler::SchedulingStrategy;
 use common::signalservice::Envelope;
 use common::web_api::{DeniablePayload, DenimChunk, DenimMessage};
 use libsignal_core::ProtocolAddress;
+use rand::rngs::OsRng;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 #[derive(Debug)]
@@ -16,6 +37,15 @@ where
     chunk_cache: ChunkCache<T>,
     payload_cache: PayloadCache<T>,
     pub chunker: Chunker,
+    q_value_policy: QValuePolicy,
+    cover_traffic: Option<CoverTrafficSchedule>,
+    cover_sizes: CoverEnvelopeSizes,
+    /// Number of incoming denim messages rejected for their size
+    rejected_messages: Arc<AtomicU64>,
+    quotas: DenimQuotas,
+    quota_counters: QuotaCounters,
+    dropped_streams: DroppedStreams,
+    completed_streams: CompletedStreamsCache,
 }
 
 impl<T> Clone for DenIMManager<T>
@@ -27,6 +57,14 @@ where
             chunk_cache: self.chunk_cache.clone(),
             payload_cache: self.payload_cache.clone(),
             chunker: self.chunker.clone(),
+            q_value_policy: self.q_value_policy,
+            cover_traffic: self.cover_traffic,
+            cover_sizes: self.cover_sizes.clone(),
+            rejected_messages: self.rejected_messages.clone(),
+            quotas: self.quotas,
+            quota_counters: self.quota_counters.clone(),
+            dropped_streams: self.dropped_streams.clone(),
+            completed_streams: self.completed_streams.clone(),
         }
     }
 }
@@ -44,14 +82,131 @@ impl<T> DenIMManager<T>
 where
     T: AvailabilityListener,
 {
-    pub fn new(chunk_cache: ChunkCache<T>, payload_cache: PayloadCache<T>, q_value: f32) -> Self {
+    pub fn new(
+        chunk_cache: ChunkCache<T>,
+        payload_cache: PayloadCache<T>,
+        q_value_policy: QValuePolicy,
+    ) -> Self {
         Self {
             chunk_cache,
             payload_cache,
-            chunker: Chunker::new(q_value),
+            chunker: Chunker::new(q_value_policy.default),
+            q_value_policy,
+            cover_traffic: None,
+            cover_sizes: CoverEnvelopeSizes::default(),
+            rejected_messages: Arc::new(AtomicU64::new(0)),
+            quotas: DenimQuotas::default(),
+            quota_counters: QuotaCounters::default(),
+            dropped_streams: DroppedStreams::default(),
+            completed_streams: CompletedStreamsCache::default(),
         }
     }
 
+    /// Pick the q value of a connection from the q values proposed by the client
+    pub fn negotiate_q_value(&self, proposal: Option<QValueRange>) -> f32 {
+        self.q_value_policy.negotiate(proposal)
+    }
+
+    /// Check that an incoming denim message has the size the q value of the sender gives it.
+    /// Messages that do not are counted, since they single out their sender as a DenIM user.
+    pub fn check_incoming_message(
+        &self,
+        sender: &ProtocolAddress,
+        message: &DenimMessage,
+        q_value: f32,
+    ) -> std::result::Result<(), DenimSizeError> {
+        Chunker::new(q_value)
+            .check_message_size(message)
+            .inspect_err(|err| {
+                let rejected = self.rejected_messages.fetch_add(1, Ordering::Relaxed) + 1;
+                eprintln!("Rejected denim message from {sender} ({rejected} in total): {err}");
+            })
+    }
+
+    #[cfg(test)]
+    pub fn rejected_messages(&self) -> u64 {
+        self.rejected_messages.load(Ordering::Relaxed)
+    }
+
+    /// Strategy for picking which payloads in outgoing payload buffers are sent first
+    pub fn set_payload_scheduling(&mut self, strategy: SchedulingStrategy) {
+        self.payload_cache.set_scheduling(strategy);
+    }
+
+    /// Schedule for server initiated cover messages, `None` disables them
+    pub fn set_cover_traffic(&mut self, cover_traffic: Option<CoverTrafficSchedule>) {
+        self.cover_traffic = cover_traffic;
+    }
+
+    pub fn cover_traffic(&self) -> Option<CoverTrafficSchedule> {
+        self.cover_traffic
+    }
+
+    /// Sizes of the content of cover envelopes, which should match those of regular envelopes
+    pub fn set_cover_sizes(&mut self, cover_sizes: CoverEnvelopeSizes) {
+        self.cover_sizes = cover_sizes;
+    }
+
+    /// Quotas of the incoming chunk buffers of senders and outgoing payload buffers of receivers
+    pub fn set_quotas(&mut self, quotas: DenimQuotas) {
+        self.quotas = quotas;
+    }
+
+    /// Deniable data dropped for exceeding a quota since the server started
+    pub fn quota_stats(&self) -> QuotaStats {
+        self.quota_counters.stats()
+    }
+
+    /// Store chunks sent by `sender` in its incoming chunk buffer as far as the sender quota
+    /// allows. Streams that do not fit are dropped silently with the chunks of them already
+    /// buffered, the sender gets the same response and no carrier changes size.
+    pub async fn accept_incoming_chunks(
+        &self,
+        sender: &ProtocolAddress,
+        chunks: Vec<DenimChunk>,
+    ) -> Result<u64> {
+        let quota = self.quotas.sender;
+        if quota.is_unbounded() {
+            return self.enqueue_incoming_chunk_buffer(sender, chunks).await;
+        }
+        let sender_name = sender.to_string();
+        let mut admission = ChunkAdmission::new(&sender_name, &self.dropped_streams);
+
+        let mut count = 0;
+        for chunk in chunks {
+            // Checking the quota and taking room in it is one step in Redis
+            if admission.admits(&chunk) {
+                if let Some(chunk_id) = self
+                    .chunk_cache
+                    .insert_within_quota(
+                        sender,
+                        Buffer::Sender,
+                        &chunk,
+                        &Uuid::new_v4().to_string(),
+                        quota,
+                    )
+                    .await?
+                {
+                    count += chunk_id;
+                    continue;
+                }
+            }
+            admission.drop_chunk(&chunk);
+        }
+
+        // Chunks of dropped streams that were buffered before can not be used
+        if admission.drops_buffered() {
+            let buffered = self.chunk_cache.dequeue_incoming_chunks(sender).await?;
+            let kept = admission.retain(buffered);
+            count = self.enqueue_incoming_chunk_buffer(sender, kept).await?;
+        }
+
+        if let Some(stats) = admission.finish(&self.quota_counters) {
+            eprintln!("Dropped deniable chunks from {sender} over the sender quota ({stats})");
+        }
+        Ok(count)
+    }
+
     /// Store chunks in incoming chunk buffer
     pub async fn enqueue_incoming_chunk_buffer(
         &self,
@@ -74,7 +229,11 @@ where
         sender: &ProtocolAddress,
     ) -> Result<Vec<DeniablePayload>> {
         let chunks = self.chunk_cache.dequeue_incoming_chunks(sender).await?;
-        let (payloads, pending_chunks) = self.create_deniable_payloads(chunks)?;
+        let (payloads, pending_chunks) = decode_payloads(self.completed_streams.reassemble(
+            sender,
+            Buffer::Sender,
+            chunks,
+        ));
 
         if !pending_chunks.is_empty() {
             eprintln!("Error: payloads created but there are still chunks left");
@@ -86,6 +245,20 @@ where
         Ok(payloads)
     }
 
+    /// Move persisted chunks of the incoming chunk buffer back into the cache
+    pub async fn restore_incoming_chunk_buffer<D: SignalDatabase>(
+        &self,
+        db: &D,
+        sender: &ProtocolAddress,
+    ) -> Result<usize> {
+        let chunks = db.pop_chunk_queue(sender, Buffer::Sender).await?;
+        let count = chunks.len();
+        if count > 0 {
+            self.enqueue_incoming_chunk_buffer(sender, chunks).await?;
+        }
+        Ok(count)
+    }
+
     /// Store chunks in outgoing chunk buffer
     pub async fn enqueue_outgoing_chunk_buffer(
         &self,
@@ -113,7 +286,11 @@ where
         receiver: &ProtocolAddress,
     ) -> Result<Vec<DeniablePayload>> {
         let chunks = self.chunk_cache.dequeue_outgoing_chunks(receiver).await?;
-        let (payloads, pending_chunks) = self.create_deniable_payloads(chunks)?;
+        let (payloads, pending_chunks) = decode_payloads(self.completed_streams.reassemble(
+            receiver,
+            Buffer::Receiver,
+            chunks,
+        ));
 
         if !pending_chunks.is_empty() {
             eprintln!("Error: payloads created but there are still chunks left");
@@ -125,108 +302,145 @@ where
         Ok(payloads)
     }
 
-    /// Store deniable payloads in outgoing payload buffer
+    /// Store deniable payloads from `sender` in outgoing payload buffer,
+    /// they are purged when `expires_at` passes before they are sent
     pub async fn enqueue_outgoing_payload_buffer(
         &self,
         receiver: &ProtocolAddress,
+        sender: &ProtocolAddress,
         payloads: Vec<DeniablePayload>,
+        expires_at: Option<u64>,
     ) -> Result<u64> {
         let mut count = 0;
         for payload in payloads {
-            count += self
+            // Checking the quota and taking room in it is one step in Redis
+            let inserted = self
                 .payload_cache
-                .insert(
+                .insert_within_quota(
                     receiver,
+                    sender,
                     Buffer::Receiver,
                     &payload,
                     &Uuid::new_v4().to_string(),
+                    expires_at,
+                    self.quotas.receiver,
                 )
-                .await?
+                .await?;
+            match inserted {
+                Some(payload_id) => count += payload_id,
+                // Payloads over the receiver quota are dropped silently, like lost chunks
+                None => {
+                    let len = bincode::serialized_size(&payload)? as usize;
+                    let stats = self.quota_counters.payloads_dropped(1, len);
+                    eprintln!(
+                        "Dropped deniable payload for {receiver} over the receiver quota ({stats})"
+                    );
+                }
+            }
         }
         Ok(count)
     }
 
-    /// Dequeue chunk data from outgoing payload buffer
+    /// Move persisted payload data and chunks of the outgoing buffers back into the cache
+    pub async fn restore_outgoing_buffers<D: SignalDatabase>(
+        &self,
+        db: &D,
+        receiver: &ProtocolAddress,
+    ) -> Result<usize> {
+        let mut count = 0;
+
+        let payloads = db.pop_payload_queue(receiver, Buffer::Receiver).await?;
+        if !payloads.is_empty() {
+            count += self
+                .payload_cache
+                .restore(receiver, Buffer::Receiver, payloads)
+                .await?;
+        }
+
+        let chunks = db.pop_chunk_queue(receiver, Buffer::Receiver).await?;
+        count += chunks.len();
+        if !chunks.is_empty() {
+            self.enqueue_outgoing_chunk_buffer(receiver, chunks).await?;
+        }
+
+        Ok(count)
+    }
+
+    /// Dequeue chunk data from outgoing payload buffer filling `bytes_amount` encoded bytes,
+    /// returns the chunks and the length of the ballast that fills the rest
     pub async fn dequeue_outgoing_payload_buffer(
         &self,
         receiver: &ProtocolAddress,
         bytes_amount: usize,
     ) -> Result<(Vec<DenimChunk>, usize)> {
-        let chunks = self
-            .payload_cache
+        self.payload_cache
             .dequeue_payload_data(receiver, Buffer::Receiver, bytes_amount)
-            .await?;
-
-        let mut denim_chunks = Vec::new();
-        for chunk in chunks.0 {
-            denim_chunks.push(DenimChunk {
-                chunk: chunk.0,
-                flags: chunk.1,
-            });
-        }
+            .await
+    }
 
-        Ok((denim_chunks, chunks.1))
+    /// Put chunks of denim messages the receiver never acknowledged back in front of
+    /// the outgoing payload buffer
+    pub async fn requeue_outgoing_chunks(
+        &self,
+        receiver: &ProtocolAddress,
+        chunks: Vec<DenimChunk>,
+    ) -> Result<usize> {
+        self.payload_cache
+            .requeue_chunks(receiver, Buffer::Receiver, chunks)
+            .await
     }
 
-    /// Wrap a regular envelope in a denim message with chunks from outgoing payload buffer
+    /// Wrap a regular envelope in a denim message with chunks from outgoing payload buffer,
+    /// using the q value and the next counter of the connection of the receiver
     pub async fn create_denim_message(
         &self,
         receiver: &ProtocolAddress,
         envelope: Envelope,
+        q_value: f32,
+        counter: i32,
     ) -> Result<DenimMessage> {
-        let regular_payload = common::web_api::RegularPayload::Envelope(envelope);
-        let regular_payload_size = serialize(&regular_payload)
-            .expect("Should serialize payload")
-            .len() as f32;
-        let free_space = self.get_free_space_in_bytes(regular_payload_size);
-        let chunks = self
-            .dequeue_outgoing_payload_buffer(receiver, free_space)
+        let carrier = Carrier::new(envelope, q_value, counter);
+        let (chunks, ballast) = self
+            .dequeue_outgoing_payload_buffer(receiver, carrier.free_space())
             .await?;
-        let q = self.chunker.q_value;
 
-        let denim_message = DenimMessage {
-            regular_payload,
-            chunks: chunks.0,
-            counter: None, //TODO find out what this is used for, is only used for server -> client
-            q: Some(q),
-            ballast: vec![0; chunks.1],
-        };
+        Ok(carrier.into_message(chunks, ballast))
+    }
 
-        Ok(denim_message)
+    /// Create a denim message with a cover envelope, used to drain the outgoing payload buffer
+    /// when the receiver has no regular messages waiting
+    pub async fn create_cover_message(
+        &self,
+        receiver: &ProtocolAddress,
+        q_value: f32,
+        counter: i32,
+    ) -> Result<DenimMessage> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let envelope = cover_envelope(receiver.name(), &self.cover_sizes, timestamp, &mut OsRng);
+
+        self.create_denim_message(receiver, envelope, q_value, counter)
+            .await
     }
 
-    /// Get amount of available chunk data
-    fn get_free_space_in_bytes(&self, regular_payload_size: f32) -> usize {
-        self.chunker.get_free_space_in_bytes(regular_payload_size)
+    /// Ids of the streams from `sender` with chunks waiting in the incoming chunk buffer
+    pub async fn incomplete_incoming_streams(&self, sender: &ProtocolAddress) -> Result<Vec<u32>> {
+        let chunks = self
+            .chunk_cache
+            .get_all_chunks(sender, Buffer::Sender)
+            .await?;
+        Ok(stream_ids(&chunks))
     }
 
     /// Create deniable payloads from chunks
     /// If there are leftover chunks, return those chunks
+    #[cfg(test)]
     fn create_deniable_payloads(
         &self,
         chunks: Vec<DenimChunk>,
     ) -> Result<(Vec<DeniablePayload>, Vec<DenimChunk>)> {
-        let mut payloads: Vec<DeniablePayload> = Vec::new();
-        let mut pending_chunks: Vec<DenimChunk> = Vec::new();
-        let mut iterator = chunks.into_iter();
-
-        while let Some(mut chunk) = iterator.next() {
-            match ChunkType::from(chunk.flags) {
-                ChunkType::Dummy => continue,
-                ChunkType::Final => {
-                    pending_chunks.sort();
-                    let mut payload_data = pending_chunks
-                        .iter()
-                        .flat_map(|d| d.chunk.clone())
-                        .collect::<Vec<u8>>();
-                    payload_data.append(&mut chunk.chunk);
-                    payloads.push(bincode::deserialize(&payload_data)?);
-                    pending_chunks.clear();
-                }
-                ChunkType::Data(_) => pending_chunks.push(chunk.clone()),
-            }
-        }
-        Ok((payloads, pending_chunks))
+        Ok(decode_payloads(common::deniable::reassembly::reassemble(
+            chunks,
+        )))
     }
 
     #[cfg(test)]
@@ -267,25 +481,58 @@ where
     }
 }
 
+/// Decode the payloads of a reassembly, returning them with the chunks still pending
+fn decode_payloads(reassembly: Reassembly) -> (Vec<DeniablePayload>, Vec<DenimChunk>) {
+    for err in reassembly.errors {
+        eprintln!("Error: dropped deniable chunks: {err}");
+    }
+
+    let mut payloads: Vec<DeniablePayload> = Vec::new();
+    for payload_data in reassembly.payloads {
+        match bincode::deserialize(&payload_data) {
+            std::result::Result::Ok(payload) => payloads.push(payload),
+            Err(err) => eprintln!("Error: could not decode deniable payload: {err}"),
+        }
+    }
+    (payloads, reassembly.pending)
+}
+
 #[cfg(test)]
 pub mod denim_manager_tests {
     use common::{
-        deniable::constants,
-        web_api::{PayloadData, SignalMessage},
+        deniable::{
+            chunk::{random_filler, ChunkType},
+            constants,
+        },
+        web_api::{ChunkHeader, PayloadData, RegularPayload, SignalMessage},
     };
-    use rand::seq::SliceRandom;
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+    use tokio::runtime::Runtime;
 
     use super::*;
     use crate::test_utils::{
-        message_cache::{generate_payload, teardown, DeniablePayloadType, MockWebSocketConnection},
+        message_cache::{
+            arbitrary_payload, generate_payload, teardown, DeniablePayloadType,
+            MockWebSocketConnection,
+        },
         user::new_account_and_address,
     };
+    use common::deniable::quota::{BufferQuota, QuotaStats};
 
     async fn init_manager() -> DenIMManager<MockWebSocketConnection> {
         DenIMManager::<MockWebSocketConnection> {
             chunk_cache: ChunkCache::connect(),
             payload_cache: PayloadCache::connect(),
             chunker: Chunker::default(),
+            q_value_policy: QValuePolicy::default(),
+            cover_traffic: None,
+            cover_sizes: CoverEnvelopeSizes::default(),
+            rejected_messages: Arc::new(AtomicU64::new(0)),
+            quotas: DenimQuotas::default(),
+            quota_counters: QuotaCounters::default(),
+            dropped_streams: DroppedStreams::default(),
+            completed_streams: CompletedStreamsCache::default(),
         }
     }
 
@@ -295,6 +542,7 @@ pub mod denim_manager_tests {
             chunks.push(DenimChunk {
                 flags: flag,
                 chunk: vec![i as u8],
+                ..Default::default()
             });
         }
         chunks
@@ -307,7 +555,7 @@ pub mod denim_manager_tests {
         let mut result = Vec::new();
 
         let (incoming_chunks, _size, mut pending_data) =
-            chunker.create_ordered_chunks(40.0, payload_data);
+            chunker.create_ordered_chunks(60.0, payload_data);
 
         result.append(&mut incoming_chunks.clone());
 
@@ -317,7 +565,7 @@ pub mod denim_manager_tests {
             }
 
             let (incoming_chunks, _size, new_pending_data) =
-                chunker.create_ordered_chunks(40.0, pending_data.clone());
+                chunker.create_ordered_chunks(60.0, pending_data.clone());
 
             pending_data = new_pending_data;
 
@@ -331,6 +579,23 @@ pub mod denim_manager_tests {
         (result, final_incoming_chunks)
     }
 
+    /// Chunks of a payload as they are sent in consecutive denim messages
+    fn ordered_payload_chunks(
+        chunker: &Chunker,
+        regular_payload_size: f32,
+        payload_data: PayloadData,
+    ) -> Vec<DenimChunk> {
+        let mut chunks = Vec::new();
+        let mut pending_data = payload_data;
+        while !pending_data.chunk.is_empty() {
+            let (new_chunks, _size, new_pending_data) =
+                chunker.create_ordered_chunks(regular_payload_size, pending_data);
+            chunks.extend(new_chunks);
+            pending_data = new_pending_data;
+        }
+        chunks
+    }
+
     fn create_deniable_payload(payload: DeniablePayload, text: &str) -> DeniablePayload {
         match payload {
             DeniablePayload::SignalMessage(_) => DeniablePayload::SignalMessage(SignalMessage {
@@ -344,6 +609,42 @@ pub mod denim_manager_tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_check_incoming_message_counts_rejections() {
+        let denim_manager = init_manager().await;
+        let (_, sender_address) = new_account_and_address();
+
+        let regular_payload = RegularPayload::SignalMessage(SignalMessage {
+            content: "A message to Bob is here written".to_string(),
+            ..Default::default()
+        });
+        let (chunks, ballast, _) = Chunker::new(0.6).create_ordered_chunks(
+            regular_payload.encoded_len() as f32,
+            PayloadData::new(Vec::new()),
+        );
+        let mut message = DenimMessage {
+            regular_payload,
+            chunks,
+            counter: None,
+            q: None,
+            ballast: random_filler(ballast),
+        };
+
+        assert!(denim_manager
+            .check_incoming_message(&sender_address, &message, 0.6)
+            .is_ok());
+        assert!(denim_manager
+            .check_incoming_message(&sender_address, &message, 1.5)
+            .is_err());
+
+        message.ballast.push(0);
+        assert!(denim_manager
+            .check_incoming_message(&sender_address, &message, 0.6)
+            .is_err());
+
+        assert_eq!(denim_manager.rejected_messages(), 2);
+    }
+
     #[tokio::test]
     async fn test_incoming_and_outgoing_buffer() {
         let denim_manager = init_manager().await;
@@ -380,6 +681,197 @@ pub mod denim_manager_tests {
         assert_eq!(result_outgoing_chunks.len(), 2);
     }
 
+    #[tokio::test]
+    async fn test_incomplete_incoming_streams() {
+        let denim_manager = init_manager().await;
+        let connection = denim_manager.chunk_cache.get_connection().await.unwrap();
+
+        let (_, sender_address) = new_account_and_address();
+        let incoming_chunks = [3, 1, 3]
+            .into_iter()
+            .enumerate()
+            .map(|(sequence, stream_id)| DenimChunk {
+                chunk: vec![sequence as u8],
+                flags: 0,
+                header: ChunkHeader::new(stream_id, sequence as u32, 10),
+            })
+            .collect();
+
+        let _ = denim_manager
+            .enqueue_incoming_chunk_buffer(&sender_address, incoming_chunks)
+            .await;
+
+        let result_streams = denim_manager
+            .incomplete_incoming_streams(&sender_address)
+            .await
+            .unwrap();
+
+        // Teardown cache
+        teardown(&denim_manager.chunk_cache.test_key, connection).await;
+
+        assert_eq!(result_streams, vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_flush_drops_late_duplicates_of_completed_streams() {
+        let denim_manager = init_manager().await;
+        let connection = denim_manager.chunk_cache.get_connection().await.unwrap();
+
+        let payload = create_deniable_payload(
+            DeniablePayload::SignalMessage(SignalMessage::default()),
+            "A message to Bob is here written",
+        );
+        let (chunks, _size, _pending_data) = denim_manager.chunker.create_ordered_chunks(
+            200.0,
+            PayloadData::new(bincode::serialize(&payload).unwrap()),
+        );
+        let (_, sender_address) = new_account_and_address();
+
+        let _ = denim_manager
+            .enqueue_incoming_chunk_buffer(&sender_address, chunks.clone())
+            .await;
+        let first_payloads = denim_manager
+            .flush_incoming_chunk_buffer(&sender_address)
+            .await
+            .unwrap();
+
+        let _ = denim_manager
+            .enqueue_incoming_chunk_buffer(&sender_address, chunks)
+            .await;
+        let duplicate_payloads = denim_manager
+            .flush_incoming_chunk_buffer(&sender_address)
+            .await
+            .unwrap();
+        let result_chunks = denim_manager
+            .get_incoming_chunks(&sender_address)
+            .await
+            .unwrap();
+
+        // Teardown cache
+        teardown(&denim_manager.chunk_cache.test_key, connection).await;
+
+        assert_eq!(first_payloads, vec![payload]);
+        assert!(duplicate_payloads.is_empty());
+        assert!(result_chunks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sender_quota_drops_whole_streams() {
+        let mut denim_manager = init_manager().await;
+        denim_manager.set_quotas(DenimQuotas {
+            sender: BufferQuota::new(1024, 3),
+            ..Default::default()
+        });
+        let connection = denim_manager.chunk_cache.get_connection().await.unwrap();
+
+        let (_, sender_address) = new_account_and_address();
+        let chunk = |stream_id: u32, sequence: u32, flags: ChunkType| DenimChunk {
+            chunk: vec![sequence as u8; 10],
+            flags: flags.into(),
+            header: ChunkHeader::new(stream_id, sequence, 30),
+        };
+
+        // Stream 2 only fits partly, so none of it is kept
+        let _ = denim_manager
+            .accept_incoming_chunks(
+                &sender_address,
+                vec![
+                    chunk(1, 0, ChunkType::Data(0)),
+                    chunk(2, 0, ChunkType::Data(0)),
+                ],
+            )
+            .await
+            .unwrap();
+        let _ = denim_manager
+            .accept_incoming_chunks(
+                &sender_address,
+                vec![
+                    chunk(1, 1, ChunkType::Data(0)),
+                    chunk(2, 1, ChunkType::Data(0)),
+                ],
+            )
+            .await
+            .unwrap();
+        let after_drop = denim_manager
+            .incomplete_incoming_streams(&sender_address)
+            .await
+            .unwrap();
+
+        // The rest of stream 2 is dropped even though it fits now
+        let _ = denim_manager
+            .accept_incoming_chunks(&sender_address, vec![chunk(2, 2, ChunkType::Final)])
+            .await
+            .unwrap();
+        let _ = denim_manager
+            .accept_incoming_chunks(&sender_address, vec![chunk(3, 0, ChunkType::Data(0))])
+            .await
+            .unwrap();
+        let result_chunks = denim_manager
+            .get_incoming_chunks(&sender_address)
+            .await
+            .unwrap();
+
+        // Teardown cache
+        teardown(&denim_manager.chunk_cache.test_key, connection).await;
+
+        assert_eq!(after_drop, vec![1]);
+        let mut result_headers = result_chunks
+            .iter()
+            .map(|chunk| (chunk.header.stream_id, chunk.header.sequence))
+            .collect::<Vec<(u32, u32)>>();
+        result_headers.sort();
+        assert_eq!(result_headers, vec![(1, 0), (1, 1), (3, 0)]);
+        assert_eq!(
+            denim_manager.quota_counters.stats(),
+            QuotaStats {
+                dropped_chunks: 3,
+                dropped_chunk_bytes: 30,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_chunks_stay_within_sender_quota() {
+        let mut denim_manager = init_manager().await;
+        denim_manager.set_quotas(DenimQuotas {
+            sender: BufferQuota::new(1024, 3),
+            ..Default::default()
+        });
+        let connection = denim_manager.chunk_cache.get_connection().await.unwrap();
+        let (_, sender_address) = new_account_and_address();
+
+        // Every request sees room for its chunk before any of them is stored
+        futures::future::join_all((0..10).map(|stream_id| {
+            let denim_manager = denim_manager.clone();
+            let sender_address = sender_address.clone();
+            async move {
+                denim_manager
+                    .accept_incoming_chunks(
+                        &sender_address,
+                        vec![DenimChunk {
+                            chunk: vec![1; 10],
+                            flags: ChunkType::Data(0).into(),
+                            header: ChunkHeader::new(stream_id, 0, 20),
+                        }],
+                    )
+                    .await
+                    .unwrap()
+            }
+        }))
+        .await;
+        let result_chunks = denim_manager
+            .get_incoming_chunks(&sender_address)
+            .await
+            .unwrap();
+
+        // Teardown cache
+        teardown(&denim_manager.chunk_cache.test_key, connection).await;
+
+        assert_eq!(result_chunks.len(), 3);
+        assert_eq!(denim_manager.quota_counters.stats().dropped_chunks, 7);
+    }
+
     #[tokio::test]
     async fn test_multiple_incoming_buffers() {
         let denim_manager = init_manager().await;
@@ -466,7 +958,7 @@ pub mod denim_manager_tests {
 
         let (incoming_chunks, _size, _pending_data) = denim_manager
             .chunker
-            .create_ordered_chunks(150.0, PayloadData::new(data));
+            .create_ordered_chunks(200.0, PayloadData::new(data));
 
         let (_, sender_address) = new_account_and_address();
 
@@ -508,11 +1000,11 @@ pub mod denim_manager_tests {
 
         let (incoming_chunks1, _size1, pending_data1) = denim_manager
             .chunker
-            .create_ordered_chunks(100.0, PayloadData::new(data));
+            .create_ordered_chunks(120.0, PayloadData::new(data));
 
         let (incoming_chunks2, _size2, pending_data2) = denim_manager
             .chunker
-            .create_ordered_chunks(100.0, pending_data1);
+            .create_ordered_chunks(120.0, pending_data1);
 
         let (_, sender_address) = new_account_and_address();
 
@@ -618,7 +1110,7 @@ pub mod denim_manager_tests {
 
         let (incoming_chunks, _size, mut pending_data) = denim_manager
             .chunker
-            .create_ordered_chunks(40.0, PayloadData::new(data));
+            .create_ordered_chunks(60.0, PayloadData::new(data));
 
         let _ = denim_manager
             .enqueue_incoming_chunk_buffer(&sender_address, incoming_chunks)
@@ -628,7 +1120,7 @@ pub mod denim_manager_tests {
         while !pending_data.chunk.is_empty() {
             let (incoming_chunks, _size, new_pending_data) = denim_manager
                 .chunker
-                .create_ordered_chunks(40.0, pending_data.clone());
+                .create_ordered_chunks(60.0, pending_data.clone());
             pending_data = new_pending_data;
             let _ = denim_manager
                 .enqueue_incoming_chunk_buffer(&sender_address, incoming_chunks)
@@ -666,7 +1158,7 @@ pub mod denim_manager_tests {
 
         let (incoming_chunks, _size, mut pending_data) = denim_manager
             .chunker
-            .create_ordered_chunks(40.0, PayloadData::new(data));
+            .create_ordered_chunks(60.0, PayloadData::new(data));
 
         // Store chunks to later insert into cache
         let mut buffer = Vec::<DenimChunk>::new();
@@ -686,7 +1178,7 @@ pub mod denim_manager_tests {
 
             let (incoming_chunks, _size, new_pending_data) = denim_manager
                 .chunker
-                .create_ordered_chunks(40.0, pending_data.clone());
+                .create_ordered_chunks(60.0, pending_data.clone());
 
             pending_data = new_pending_data;
 
@@ -802,13 +1294,20 @@ pub mod denim_manager_tests {
         let outgoing_payload3 = generate_payload(DeniablePayloadType::KeyResponse);
 
         let _ = denim_manager
-            .enqueue_outgoing_payload_buffer(&receiver_address1, vec![outgoing_payload1.clone()])
+            .enqueue_outgoing_payload_buffer(
+                &receiver_address1,
+                &receiver_address1,
+                vec![outgoing_payload1.clone()],
+                None,
+            )
             .await;
 
         let _ = denim_manager
             .enqueue_outgoing_payload_buffer(
+                &receiver_address2,
                 &receiver_address2,
                 vec![outgoing_payload2.clone(), outgoing_payload3.clone()],
+                None,
             )
             .await;
 
@@ -832,6 +1331,49 @@ pub mod denim_manager_tests {
         assert_eq!(result_outgoing_payloads2[1], outgoing_payload3);
     }
 
+    #[tokio::test]
+    async fn test_receiver_quota_drops_payloads() {
+        let mut denim_manager = init_manager().await;
+        denim_manager.set_quotas(DenimQuotas {
+            receiver: BufferQuota::new(1 << 20, 2),
+            ..Default::default()
+        });
+        let connection = denim_manager.chunk_cache.get_connection().await.unwrap();
+
+        let (_, receiver_address) = new_account_and_address();
+        let (_, sender_address) = new_account_and_address();
+        let outgoing_payloads = (0..3)
+            .map(|_| generate_payload(DeniablePayloadType::SignalMessage))
+            .collect::<Vec<DeniablePayload>>();
+
+        let count = denim_manager
+            .enqueue_outgoing_payload_buffer(
+                &receiver_address,
+                &sender_address,
+                outgoing_payloads.clone(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let result_outgoing_payloads = denim_manager
+            .get_deniable_payloads(&receiver_address)
+            .await
+            .unwrap();
+
+        // Teardown cache
+        teardown(&denim_manager.chunk_cache.test_key, connection).await;
+
+        assert_eq!(count, 2);
+        assert_eq!(result_outgoing_payloads, outgoing_payloads[..2]);
+        let stats = denim_manager.quota_counters.stats();
+        assert_eq!(stats.dropped_payloads, 1);
+        assert_eq!(
+            stats.dropped_payload_bytes,
+            bincode::serialized_size(&outgoing_payloads[2]).unwrap()
+        );
+    }
+
     // TODO: Test when DenIMChunk has all data, meaning flags = 0
 
     #[tokio::test]
@@ -847,7 +1389,12 @@ pub mod denim_manager_tests {
         );
 
         let _ = denim_manager
-            .enqueue_outgoing_payload_buffer(&receiver_address, vec![outgoing_payload.clone()])
+            .enqueue_outgoing_payload_buffer(
+                &receiver_address,
+                &receiver_address,
+                vec![outgoing_payload.clone()],
+                None,
+            )
             .await
             .unwrap();
 
@@ -891,8 +1438,10 @@ pub mod denim_manager_tests {
 
         let _ = denim_manager
             .enqueue_outgoing_payload_buffer(
+                &receiver_address,
                 &receiver_address,
                 vec![outgoing_payload1.clone(), outgoing_payload2.clone()],
+                None,
             )
             .await
             .unwrap();
@@ -945,7 +1494,12 @@ pub mod denim_manager_tests {
         );
 
         let _ = denim_manager
-            .enqueue_outgoing_payload_buffer(&receiver_address, vec![outgoing_payload1.clone()])
+            .enqueue_outgoing_payload_buffer(
+                &receiver_address,
+                &receiver_address,
+                vec![outgoing_payload1.clone()],
+                None,
+            )
             .await
             .unwrap();
 
@@ -1000,7 +1554,7 @@ pub mod denim_manager_tests {
 
         assert!(result_outgoing_payloads_buffer.is_empty());
         assert_eq!(vec![result_taken_values], outgoing_payloads);
-        assert_eq!(result_extra_dummy_data, vec![0; 4]);
+        assert_eq!(result_extra_dummy_data.len(), 4);
     }
 
     #[tokio::test]
@@ -1022,8 +1576,10 @@ pub mod denim_manager_tests {
 
         let _ = denim_manager
             .enqueue_outgoing_payload_buffer(
+                &receiver_address,
                 &receiver_address,
                 vec![outgoing_payload1.clone(), outgoing_payload2.clone()],
+                None,
             )
             .await
             .unwrap();
@@ -1057,7 +1613,7 @@ pub mod denim_manager_tests {
 
         assert!(result_outgoing_payloads_buffer.is_empty());
         assert_eq!(result_taken_values, outgoing_payloads);
-        assert_eq!(result_taken_extra_dummy_data, vec![0; 8]);
+        assert_eq!(result_taken_extra_dummy_data.len(), 8);
     }
 
     #[tokio::test]
@@ -1079,8 +1635,10 @@ pub mod denim_manager_tests {
 
         let _ = denim_manager
             .enqueue_outgoing_payload_buffer(
+                &receiver_address,
                 &receiver_address,
                 vec![outgoing_payload1.clone(), outgoing_payload2.clone()],
+                None,
             )
             .await
             .unwrap();
@@ -1116,7 +1674,7 @@ pub mod denim_manager_tests {
 
         assert!(result_outgoing_payloads_buffer.is_empty());
         assert_eq!(result_taken_values, outgoing_payloads);
-        assert_eq!(result_taken_extra_dummy_data, vec![0; result_dummy_len]);
+        assert_eq!(result_taken_extra_dummy_data.len(), result_dummy_len);
     }
 
     #[tokio::test]
@@ -1138,8 +1696,10 @@ pub mod denim_manager_tests {
 
         let _ = denim_manager
             .enqueue_outgoing_payload_buffer(
+                &receiver_address,
                 &receiver_address,
                 vec![outgoing_payload1.clone(), outgoing_payload2.clone()],
+                None,
             )
             .await
             .unwrap();
@@ -1192,8 +1752,10 @@ pub mod denim_manager_tests {
 
         let _ = denim_manager
             .enqueue_outgoing_payload_buffer(
+                &receiver_address,
                 &receiver_address,
                 vec![outgoing_payload1.clone(), outgoing_payload2.clone()],
+                None,
             )
             .await
             .unwrap();
@@ -1233,7 +1795,12 @@ pub mod denim_manager_tests {
         );
 
         let _ = denim_manager
-            .enqueue_outgoing_payload_buffer(&receiver_address, vec![outgoing_payload1.clone()])
+            .enqueue_outgoing_payload_buffer(
+                &receiver_address,
+                &receiver_address,
+                vec![outgoing_payload1.clone()],
+                None,
+            )
             .await
             .unwrap();
 
@@ -1298,6 +1865,73 @@ pub mod denim_manager_tests {
         assert_eq!(vec![result_taken_values], outgoing_payloads);
     }
 
+    #[tokio::test]
+    async fn test_take_deniable_payload_data_headers() {
+        let denim_manager = init_manager().await;
+        let connection = denim_manager.chunk_cache.get_connection().await.unwrap();
+
+        let (_, receiver_address) = new_account_and_address();
+
+        let outgoing_payload1 = create_deniable_payload(
+            DeniablePayload::SignalMessage(SignalMessage::default()),
+            "A message to Bob is here written",
+        );
+
+        let _ = denim_manager
+            .enqueue_outgoing_payload_buffer(
+                &receiver_address,
+                &receiver_address,
+                vec![outgoing_payload1.clone()],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let outgoing_payloads = denim_manager
+            .get_deniable_payloads_raw(&receiver_address)
+            .await
+            .unwrap();
+
+        let mut result_chunks = Vec::new();
+        for _ in 0..3 {
+            let (mut chunks, _) = denim_manager
+                .dequeue_outgoing_payload_buffer(
+                    &receiver_address,
+                    20 + constants::EMPTY_DENIMCHUNK_SIZE,
+                )
+                .await
+                .unwrap();
+            result_chunks.append(&mut chunks);
+        }
+
+        // Teardown cache
+        teardown(&denim_manager.chunk_cache.test_key, connection).await;
+
+        let total_length = outgoing_payloads[0].len() as u32;
+        let stream_id = result_chunks[0].header.stream_id;
+
+        assert!(result_chunks
+            .iter()
+            .all(|chunk| chunk.header.stream_id == stream_id
+                && chunk.header.total_length == total_length));
+        assert_eq!(
+            result_chunks
+                .iter()
+                .map(|chunk| chunk.header.sequence)
+                .collect::<Vec<u32>>(),
+            vec![0, 1, 2]
+        );
+
+        // Chunks can be reassembled in any order
+        result_chunks.reverse();
+        let (result_payloads, result_pending) = denim_manager
+            .create_deniable_payloads(result_chunks)
+            .unwrap();
+
+        assert_eq!(result_payloads, vec![outgoing_payload1]);
+        assert!(result_pending.is_empty());
+    }
+
     #[tokio::test]
     async fn test_take_multiple_deniable_payloads_data_inorder_with_overlap() {
         let denim_manager = init_manager().await;
@@ -1317,8 +1951,10 @@ pub mod denim_manager_tests {
 
         let _ = denim_manager
             .enqueue_outgoing_payload_buffer(
+                &receiver_address,
                 &receiver_address,
                 vec![outgoing_payload1.clone(), outgoing_payload2.clone()],
+                None,
             )
             .await
             .unwrap();
@@ -1460,4 +2096,97 @@ pub mod denim_manager_tests {
         assert!(result_outgoing_payloads_buffer.is_empty());
         assert_eq!(result_payloads[0], outgoing_payload1);
     }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn test_create_deniable_payloads_round_trip(
+            payloads in prop::collection::vec(arbitrary_payload(), 0..8),
+            q_value in 0.5_f32..5.0,
+            regular_payload_size in 100_usize..2000,
+            seed in any::<u64>(),
+        ) {
+            let denim_manager = Runtime::new().unwrap().block_on(init_manager());
+            let chunker = Chunker::new(q_value);
+            let serialized: Vec<Vec<u8>> = payloads
+                .iter()
+                .map(|payload| bincode::serialize(payload).unwrap())
+                .collect();
+
+            let mut chunks: Vec<DenimChunk> = serialized
+                .iter()
+                .enumerate()
+                .flat_map(|(stream_id, data)| {
+                    ordered_payload_chunks(
+                        &chunker,
+                        regular_payload_size as f32,
+                        PayloadData::with_stream_id(stream_id as u32, data.clone()),
+                    )
+                })
+                .collect();
+            chunks.shuffle(&mut StdRng::seed_from_u64(seed));
+
+            let (result_payloads, pending_chunks) =
+                denim_manager.create_deniable_payloads(chunks).unwrap();
+
+            // Payloads complete in the order their last chunk arrived
+            let mut expected = serialized;
+            let mut received: Vec<Vec<u8>> = result_payloads
+                .iter()
+                .map(|payload| bincode::serialize(payload).unwrap())
+                .collect();
+            expected.sort();
+            received.sort();
+            prop_assert_eq!(received, expected);
+            prop_assert!(pending_chunks.is_empty());
+        }
+
+        #[test]
+        fn test_dequeue_outgoing_payloads_round_trip(
+            payloads in prop::collection::vec(arbitrary_payload(), 1..8),
+            bytes_amount in 100_usize..2000,
+        ) {
+            let (result_payloads, pending_chunks) = Runtime::new().unwrap().block_on(async {
+                let denim_manager = init_manager().await;
+                let connection = denim_manager.chunk_cache.get_connection().await.unwrap();
+                let (_, receiver_address) = new_account_and_address();
+
+                denim_manager
+                    .enqueue_outgoing_payload_buffer(
+                        &receiver_address,
+                        &receiver_address,
+                        payloads.clone(),
+                        None,
+                    )
+                    .await
+                    .unwrap();
+
+                let mut result_payloads = Vec::new();
+                let mut pending_chunks = Vec::new();
+                loop {
+                    let (chunks, _) = denim_manager
+                        .dequeue_outgoing_payload_buffer(&receiver_address, bytes_amount)
+                        .await
+                        .unwrap();
+                    if chunks.iter().all(DenimChunk::is_dummy) {
+                        break;
+                    }
+                    let (new_payloads, new_pending_chunks) = denim_manager
+                        .create_deniable_payloads([pending_chunks, chunks].concat())
+                        .unwrap();
+                    result_payloads.extend(new_payloads);
+                    pending_chunks = new_pending_chunks;
+                }
+
+                // Teardown cache
+                teardown(&denim_manager.chunk_cache.test_key, connection).await;
+
+                (result_payloads, pending_chunks)
+            });
+
+            prop_assert_eq!(result_payloads, payloads);
+            prop_assert!(pending_chunks.is_empty());
+        }
+    }
 }
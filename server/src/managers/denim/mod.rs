@@ -1,4 +1,4 @@
-mod buffer;
+pub mod buffer;
 pub mod chunk_cache;
 pub mod denim_manager;
 pub mod payload_cache;
@@ -5,27 +5,39 @@ use crate::{
         manager::Manager,
         message::message_cache::{self, MessageCache},
     },
-    storage::redis::{self, Decoder},
+    storage::redis::{self, Decoder, EntryMetadata},
 };
 use anyhow::{Ok, Result};
 use common::{
-    deniable::{chunk::ChunkType, constants},
-    web_api::DeniablePayload,
+    deniable::{
+        constants,
+        quota::BufferQuota,
+        scheduler::{ChunkFill, PayloadClass, PayloadScheduler, QueuedPayload, SchedulingStrategy},
+    },
+    web_api::{ChunkHeader, DeniablePayload, DenimChunk, PayloadData},
 };
 use deadpool_redis::Connection;
 use libsignal_core::ProtocolAddress;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::sync::Mutex;
+use uuid::Uuid;
 
 /// Use default decoder implementation
 impl Decoder<DeniablePayload> for DeniablePayload {}
 
 type ListenerMap<T> = Arc<Mutex<HashMap<String, Arc<Mutex<T>>>>>;
+type SchedulerMap = Arc<Mutex<HashMap<String, Arc<Mutex<Box<dyn PayloadScheduler>>>>>>;
 
 #[derive(Debug)]
 pub struct PayloadCache<T> {
     pub(crate) pool: deadpool_redis::Pool,
     pub(crate) listeners: ListenerMap<T>,
+    strategy: SchedulingStrategy,
+    schedulers: SchedulerMap,
     #[cfg(test)]
     pub test_key: String,
 }
@@ -39,12 +51,16 @@ where
         return Self {
             pool: self.pool.clone(),
             listeners: self.listeners.clone(),
+            strategy: self.strategy,
+            schedulers: self.schedulers.clone(),
         };
 
         #[cfg(test)]
         Self {
             pool: self.pool.clone(),
             listeners: self.listeners.clone(),
+            strategy: self.strategy,
+            schedulers: self.schedulers.clone(),
             test_key: self.test_key.clone(),
         }
     }
@@ -68,12 +84,16 @@ where
         return Self {
             pool: cache.pool.clone(),
             listeners: cache.listeners.clone(),
+            strategy: SchedulingStrategy::default(),
+            schedulers: SchedulerMap::default(),
         };
 
         #[cfg(test)]
         Self {
             pool: cache.pool.clone(),
             listeners: cache.listeners.clone(),
+            strategy: SchedulingStrategy::default(),
+            schedulers: SchedulerMap::default(),
             test_key: cache.test_key.clone(),
         }
     }
@@ -91,26 +111,113 @@ where
         Ok(self.pool.get().await?)
     }
 
+    /// Schedule payloads with `strategy` when taking chunks from queues
+    pub fn set_scheduling(&mut self, strategy: SchedulingStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Insert a payload from `sender`, payloads are scheduled by their sender and class
     pub async fn insert(
         &self,
         address: &ProtocolAddress,
+        sender: &ProtocolAddress,
         buffer: Buffer,
         payload: &DeniablePayload,
         payload_guid: &str,
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        self.insert_within_quota(
+            address,
+            sender,
+            buffer,
+            payload,
+            payload_guid,
+            expires_at,
+            BufferQuota::default(),
+        )
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Unbounded quota rejected a payload"))
+    }
+
+    /// Insert a payload only if the queue stays within `quota`, `None` when it does not fit.
+    /// The usage the quota is checked against counts the payloads of the queue until their
+    /// last chunk is taken or they expire, also while they are persisted in the database.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn insert_within_quota(
+        &self,
+        address: &ProtocolAddress,
+        sender: &ProtocolAddress,
+        buffer: Buffer,
+        payload: &DeniablePayload,
+        payload_guid: &str,
+        expires_at: Option<u64>,
+        quota: BufferQuota,
+    ) -> Result<Option<u64>> {
+        let value = bincode::serialize(payload)?;
+        let len = value.len();
+        let mut connection = self.pool.get().await?;
+        let queue_usage_key = self.get_queue_usage_key(address, buffer);
+        if !redis::reserve_usage(
+            &mut connection,
+            &queue_usage_key,
+            len,
+            quota.max_items,
+            quota.max_bytes,
+        )
+        .await?
+        {
+            return Ok(None);
+        }
+
+        let inserted = self
+            .insert_value(
+                address,
+                sender,
+                buffer,
+                payload,
+                value,
+                payload_guid,
+                expires_at,
+            )
+            .await;
+        if inserted.is_err() {
+            redis::update_usage(&mut connection, &queue_usage_key, -1, -(len as i64)).await?;
+        }
+        inserted.map(Some)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn insert_value(
+        &self,
+        address: &ProtocolAddress,
+        sender: &ProtocolAddress,
+        buffer: Buffer,
+        payload: &DeniablePayload,
+        value: Vec<u8>,
+        payload_guid: &str,
+        expires_at: Option<u64>,
     ) -> Result<u64> {
         let connection = self.pool.get().await?;
         let queue_key: String = self.get_queue_key(address, buffer);
         let queue_metadata_key: String = self.get_queue_metadata_key(address, buffer);
         let queue_total_index_key: String = self.get_queue_index_key(buffer);
-        let value = bincode::serialize(payload)?;
+        let metadata = EntryMetadata {
+            order: 0,
+            total_length: value.len() as u32,
+            stream_id: rand::random(),
+            expires_at,
+            priority: PayloadClass::from(payload).into(),
+            group: Some(format!("{}.{}", sender.name(), sender.device_id())),
+        };
 
-        let payload_id = redis::insert(
+        let payload_id = redis::insert_with_metadata(
             connection,
             queue_key,
             queue_metadata_key,
             queue_total_index_key,
             payload_guid,
             value,
+            metadata,
         )
         .await;
 
@@ -118,6 +225,20 @@ where
         payload_id
     }
 
+    /// Add to the items and bytes counted for a queue, which the quota is checked against
+    async fn update_usage(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        items: i64,
+        bytes: i64,
+    ) -> Result<()> {
+        let mut connection = self.pool.get().await?;
+        let queue_usage_key = self.get_queue_usage_key(address, buffer);
+
+        redis::update_usage(&mut connection, &queue_usage_key, items, bytes).await
+    }
+
     pub async fn remove(
         &self,
         address: &ProtocolAddress,
@@ -139,6 +260,28 @@ where
         .await
     }
 
+    /// Remove values without decoding them, partly dequeued payloads are not valid payloads
+    pub async fn remove_raw(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        payload_guid: Vec<String>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let connection = self.pool.get().await?;
+        let queue_key: String = self.get_queue_key(address, buffer);
+        let queue_metadata_key: String = self.get_queue_metadata_key(address, buffer);
+        let queue_total_index_key: String = self.get_queue_index_key(buffer);
+
+        redis::remove::<redis::Bytes>(
+            connection,
+            queue_key,
+            queue_metadata_key,
+            queue_total_index_key,
+            payload_guid,
+        )
+        .await
+    }
+
     pub async fn get_all_payloads(
         &self,
         address: &ProtocolAddress,
@@ -189,42 +332,278 @@ where
         Ok(redis::Bytes::decode(values)?)
     }
 
+    /// Put chunks that were taken but never acknowledged in front of a queue,
+    /// they keep their headers and are sent whole before any other payload data
+    pub async fn requeue_chunks(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        chunks: Vec<DenimChunk>,
+    ) -> Result<usize> {
+        let count = chunks.len();
+        let values = chunks
+            .iter()
+            .map(bincode::serialize)
+            .collect::<Result<Vec<Vec<u8>>, _>>()?;
+
+        let connection = self.pool.get().await?;
+        redis::push_front(connection, self.get_requeue_key(address, buffer), values).await?;
+
+        if count > 0 {
+            notify_cached(self.listeners.clone(), address).await;
+        }
+        Ok(count)
+    }
+
+    /// Take chunks filling `bytes_amount` encoded bytes from a queue, requeued chunks first,
+    /// the payloads are picked by the scheduler of the queue
     pub async fn dequeue_payload_data(
         &self,
         address: &ProtocolAddress,
         buffer: Buffer,
         bytes_amount: usize,
-    ) -> Result<(Vec<(Vec<u8>, i32)>, usize)> {
+    ) -> Result<(Vec<DenimChunk>, usize)> {
         let queue_key = self.get_queue_key(address, buffer);
         let queue_lock_key = self.get_persist_in_progress_key(address, buffer);
         let queue_metadata_key: String = self.get_queue_metadata_key(address, buffer);
         let queue_total_index_key: String = self.get_queue_index_key(buffer);
 
-        let mut result = Vec::new();
-        let mut take = bytes_amount;
-        while take >= constants::EMPTY_DENIMCHUNK_SIZE {
-            take -= constants::EMPTY_DENIMCHUNK_SIZE;
+        let scheduler = self.get_scheduler(&queue_key).await;
+        let mut scheduler = scheduler.lock().await;
+
+        let connection = self.pool.get().await?;
+        let mut queue: Vec<QueuedPayload> =
+            redis::get_entries(connection, queue_key.clone(), queue_lock_key.clone())
+                .await?
+                .iter()
+                .map(|(id, metadata)| {
+                    QueuedPayload::new(*id, metadata.priority, metadata.group.clone())
+                })
+                .collect();
+
+        let requeued = self
+            .take_requeued_chunks(address, buffer, bytes_amount)
+            .await?;
+        let mut fill = ChunkFill::new(bytes_amount, requeued);
+        while let Some((index, limit)) = fill.next(scheduler.as_mut(), &queue) {
             let connection = self.pool.get().await?;
-            let (data, taken, order) = redis::dequeue_bytes(
+            let chunk = redis::dequeue_bytes(
                 connection,
                 queue_key.clone(),
                 queue_metadata_key.clone(),
                 queue_total_index_key.clone(),
                 queue_lock_key.clone(),
-                take,
+                queue[index].id,
+                limit,
             )
             .await?;
-            if taken == 0 {
-                result.push((vec![0; take], ChunkType::Dummy.into()));
-                take = 0;
-                break;
-            } else {
-                take -= taken;
-                result.push((data, order));
+            let total_length = chunk.as_ref().map(|chunk| chunk.header.total_length);
+            if fill.push(scheduler.as_mut(), &queue[index], chunk) {
+                queue.remove(index);
+                // The payload leaves the queue with its last chunk
+                let total_length = total_length.unwrap_or_default();
+                self.update_usage(address, buffer, -1, -(total_length as i64))
+                    .await?;
             }
         }
 
-        Ok((result, take))
+        // Nothing is left to schedule, so there is no turn to remember
+        if queue.is_empty() {
+            self.schedulers.lock().await.remove(&queue_key);
+        }
+
+        Ok(fill.finish())
+    }
+
+    /// Take requeued chunks in order when they fit whole in `space` encoded bytes,
+    /// leaving a rest that can be filled
+    async fn take_requeued_chunks(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        mut space: usize,
+    ) -> Result<Vec<DenimChunk>> {
+        let requeue_key = self.get_requeue_key(address, buffer);
+        let connection = self.pool.get().await?;
+        let values = redis::get_list(connection, requeue_key.clone()).await?;
+
+        let mut chunks = Vec::new();
+        for value in values {
+            let chunk: DenimChunk = bincode::deserialize(&value)?;
+            let size = constants::chunk_size(chunk.chunk.len());
+            if size > space || matches!(space - size, 1 | 2) {
+                continue;
+            }
+            let connection = self.pool.get().await?;
+            if redis::remove_from_list(connection, requeue_key.clone(), &value).await? {
+                space -= size;
+                chunks.push(chunk);
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Get payload data with their expiry and guids from a queue, also when it is locked for persistence.
+    /// Partly dequeued payloads keep their stream id, order and total length.
+    pub async fn get_payloads_to_persist(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        limit: i32,
+    ) -> Result<(Vec<(PayloadData, Option<u64>)>, Vec<String>)> {
+        let connection = self.pool.get().await?;
+        let queue_key = self.get_queue_key(address, buffer);
+        let queue_metadata_key: String = self.get_queue_metadata_key(address, buffer);
+
+        let (values, payload_guids) =
+            redis::get_values_to_persist(connection, queue_key, queue_metadata_key, limit).await?;
+
+        let payloads = redis::decode_with_metadata(values)?
+            .into_iter()
+            .map(|(data, metadata)| {
+                let payload = PayloadData {
+                    chunk: data,
+                    flags: metadata.order,
+                    header: ChunkHeader::new(
+                        metadata.stream_id,
+                        metadata.order.unsigned_abs(),
+                        metadata.total_length,
+                    ),
+                };
+                (payload, metadata.expires_at)
+            })
+            .collect();
+
+        Ok((payloads, payload_guids))
+    }
+
+    /// Put persisted payload data back into a queue, their usage was never given back.
+    /// Payloads that expired in the database before any of their data was sent are dropped.
+    pub async fn restore(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        payloads: Vec<(PayloadData, Option<u64>)>,
+    ) -> Result<usize> {
+        let queue_key: String = self.get_queue_key(address, buffer);
+        let queue_metadata_key: String = self.get_queue_metadata_key(address, buffer);
+        let queue_total_index_key: String = self.get_queue_index_key(buffer);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let mut count = 0;
+        for (payload, expires_at) in payloads {
+            if payload.flags == 0 && expires_at.is_some_and(|expires_at| expires_at <= now) {
+                self.update_usage(address, buffer, -1, -(payload.chunk.len() as i64))
+                    .await?;
+                continue;
+            }
+            let connection = self.pool.get().await?;
+            // The sender and class are not persisted, restored payloads are scheduled together
+            let metadata = EntryMetadata {
+                order: payload.flags,
+                total_length: payload.header.total_length,
+                stream_id: payload.header.stream_id,
+                expires_at,
+                ..Default::default()
+            };
+            redis::insert_with_metadata(
+                connection,
+                queue_key.clone(),
+                queue_metadata_key.clone(),
+                queue_total_index_key.clone(),
+                &Uuid::new_v4().to_string(),
+                payload.chunk,
+                metadata,
+            )
+            .await?;
+            count += 1;
+        }
+
+        if count > 0 {
+            notify_cached(self.listeners.clone(), address).await;
+        }
+        Ok(count)
+    }
+
+    /// Remove payloads that expired at `now` from all queues of a buffer,
+    /// returns the number of removed payloads
+    pub async fn purge_expired(&self, buffer: Buffer, now: u64) -> Result<usize> {
+        let connection = self.pool.get().await?;
+        let queue_total_index_key = self.get_queue_index_key(buffer);
+        let queue_keys = redis::get_queues(connection, queue_total_index_key.clone()).await?;
+
+        let mut count = 0;
+        for queue_key in queue_keys {
+            let (account_id, device_id) = self.get_account_and_device_id_from_queue_key(&queue_key);
+            let address = ProtocolAddress::new(account_id, device_id.parse::<u32>()?.into());
+
+            let connection = self.pool.get().await?;
+            let removed = redis::remove_expired(
+                connection,
+                queue_key,
+                self.get_queue_metadata_key(&address, buffer),
+                queue_total_index_key.clone(),
+                now,
+            )
+            .await?;
+            if !removed.is_empty() {
+                let bytes: usize = removed.iter().map(Vec::len).sum();
+                self.update_usage(&address, buffer, -(removed.len() as i64), -(bytes as i64))
+                    .await?;
+            }
+            count += removed.len();
+        }
+        Ok(count)
+    }
+
+    pub async fn get_queues_to_persist(
+        &self,
+        buffer: Buffer,
+        max_time: u64,
+        limit: u8,
+    ) -> Result<Vec<String>> {
+        let connection = self.pool.get().await?;
+        let queue_total_index_key = self.get_queue_index_key(buffer);
+
+        redis::get_queues_to_persist(connection, queue_total_index_key, max_time, limit).await
+    }
+
+    pub async fn lock_queue_for_persistence(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+    ) -> Result<()> {
+        let connection = self.pool.get().await?;
+        let queue_lock_key = self.get_persist_in_progress_key(address, buffer);
+
+        redis::lock_queue(connection, queue_lock_key).await
+    }
+
+    pub async fn unlock_queue_for_persistence(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+    ) -> Result<()> {
+        let connection = self.pool.get().await?;
+        let queue_lock_key = self.get_persist_in_progress_key(address, buffer);
+
+        redis::unlock_queue(connection, queue_lock_key).await
+    }
+
+    async fn get_scheduler(&self, queue_key: &str) -> Arc<Mutex<Box<dyn PayloadScheduler>>> {
+        self.schedulers
+            .lock()
+            .await
+            .entry(queue_key.to_owned())
+            .or_insert_with(|| Arc::new(Mutex::new(self.strategy.scheduler())))
+            .clone()
+    }
+
+    pub fn get_account_and_device_id_from_queue_key(&self, queue_key: &str) -> (String, String) {
+        let parts = queue_key.split("::").collect::<Vec<&str>>();
+        let account_id = parts[1].trim_matches('{').to_string();
+        let device_id = parts[2].trim_end_matches('}').to_string();
+        (account_id, device_id)
     }
 
     pub async fn add_availability_listener(
@@ -275,6 +654,42 @@ where
         )
     }
 
+    fn get_requeue_key(&self, address: &ProtocolAddress, buffer: Buffer) -> String {
+        #[cfg(not(test))]
+        return format!(
+            "payload_{}_queue_requeued::{{{}::{}}}",
+            buffer,
+            address.name(),
+            address.device_id()
+        );
+        #[cfg(test)]
+        format!(
+            "{}payload_{}_queue_requeued::{{{}::{}}}",
+            self.test_key,
+            buffer,
+            address.name(),
+            address.device_id()
+        )
+    }
+
+    fn get_queue_usage_key(&self, address: &ProtocolAddress, buffer: Buffer) -> String {
+        #[cfg(not(test))]
+        return format!(
+            "payload_{}_queue_usage::{{{}::{}}}",
+            buffer,
+            address.name(),
+            address.device_id()
+        );
+        #[cfg(test)]
+        format!(
+            "{}payload_{}_queue_usage::{{{}::{}}}",
+            self.test_key,
+            buffer,
+            address.name(),
+            address.device_id()
+        )
+    }
+
     fn get_queue_metadata_key(&self, address: &ProtocolAddress, buffer: Buffer) -> String {
         #[cfg(not(test))]
         return format!(
@@ -311,7 +726,7 @@ pub mod payload_cache_tests {
         user::new_protocol_address,
     };
     use ::redis::{cmd, Value};
-    use common::web_api::SignalMessage;
+    use common::{deniable::chunk::ChunkType, web_api::SignalMessage};
 
     #[tokio::test]
     async fn test_availability_listener_new_messages() {
@@ -320,7 +735,7 @@ pub mod payload_cache_tests {
         let uuid = generate_uuid();
         let address = new_protocol_address();
 
-        let mut payload = DeniablePayload::SignalMessage(SignalMessage::default());
+        let payload = DeniablePayload::SignalMessage(SignalMessage::default());
         let buffer = Buffer::Receiver;
 
         payload_cache
@@ -332,7 +747,7 @@ pub mod payload_cache_tests {
 
         // Continue after branches complete
         let (_, _) = tokio::join!(
-            payload_cache.insert(&address, buffer, &mut payload, &uuid),
+            payload_cache.insert(&address, &address, buffer, &payload, &uuid, None),
             notifyed
         );
 
@@ -353,11 +768,11 @@ pub mod payload_cache_tests {
         let address = new_protocol_address();
         let payload_guid = generate_uuid();
 
-        let mut payload = DeniablePayload::SignalMessage(SignalMessage::default());
+        let payload = DeniablePayload::SignalMessage(SignalMessage::default());
         let reciver = Buffer::Receiver;
 
         let payload_id = payload_cache
-            .insert(&address, reciver, &mut payload, &payload_guid)
+            .insert(&address, &address, reciver, &payload, &payload_guid, None)
             .await
             .unwrap();
 
@@ -381,30 +796,58 @@ pub mod payload_cache_tests {
         let mut connection = payload_cache.pool.get().await.unwrap();
         let address = new_protocol_address();
 
-        let mut payload1 = generate_payload(DeniablePayloadType::SignalMessage);
-        let mut payload2 = generate_payload(DeniablePayloadType::Envelope);
-        let mut payload3 = generate_payload(DeniablePayloadType::KeyRequest);
-        let mut payload4 = generate_payload(DeniablePayloadType::KeyResponse);
+        let payload1 = generate_payload(DeniablePayloadType::SignalMessage);
+        let payload2 = generate_payload(DeniablePayloadType::Envelope);
+        let payload3 = generate_payload(DeniablePayloadType::KeyRequest);
+        let payload4 = generate_payload(DeniablePayloadType::KeyResponse);
 
         let reciver = Buffer::Receiver;
 
         let payload_id1 = payload_cache
-            .insert(&address, reciver, &mut payload1, &generate_uuid())
+            .insert(
+                &address,
+                &address,
+                reciver,
+                &payload1,
+                &generate_uuid(),
+                None,
+            )
             .await
             .unwrap();
 
         let _payload_id2 = payload_cache
-            .insert(&address, reciver, &mut payload2, &generate_uuid())
+            .insert(
+                &address,
+                &address,
+                reciver,
+                &payload2,
+                &generate_uuid(),
+                None,
+            )
             .await
             .unwrap();
 
         let _payload_id3 = payload_cache
-            .insert(&address, reciver, &mut payload3, &generate_uuid())
+            .insert(
+                &address,
+                &address,
+                reciver,
+                &payload3,
+                &generate_uuid(),
+                None,
+            )
             .await
             .unwrap();
 
         let payload_id4 = payload_cache
-            .insert(&address, reciver, &mut payload4, &generate_uuid())
+            .insert(
+                &address,
+                &address,
+                reciver,
+                &payload4,
+                &generate_uuid(),
+                None,
+            )
             .await
             .unwrap();
 
@@ -433,11 +876,11 @@ pub mod payload_cache_tests {
         let address = new_protocol_address();
         let payload_guid = generate_uuid();
 
-        let mut payload = DeniablePayload::SignalMessage(SignalMessage::default());
+        let payload = DeniablePayload::SignalMessage(SignalMessage::default());
         let reciver = Buffer::Receiver;
 
         payload_cache
-            .insert(&address, reciver, &mut payload, &payload_guid)
+            .insert(&address, &address, reciver, &payload, &payload_guid, None)
             .await
             .unwrap();
 
@@ -451,4 +894,259 @@ pub mod payload_cache_tests {
         assert_eq!(removed_payloads.len(), 1);
         assert_eq!(removed_payloads[0], payload);
     }
+
+    #[tokio::test]
+    async fn test_persist_and_restore_partly_dequeued_payload() {
+        let payload_cache: PayloadCache<MockWebSocketConnection> = PayloadCache::connect();
+        let connection = payload_cache.pool.get().await.unwrap();
+        let address = new_protocol_address();
+        let payload = generate_payload(DeniablePayloadType::Envelope);
+        let buffer = Buffer::Receiver;
+
+        payload_cache
+            .insert(&address, &address, buffer, &payload, &generate_uuid(), None)
+            .await
+            .unwrap();
+
+        let (first, _) = payload_cache
+            .dequeue_payload_data(&address, buffer, constants::EMPTY_DENIMCHUNK_SIZE + 10)
+            .await
+            .unwrap();
+
+        let (persisted, guids) = payload_cache
+            .get_payloads_to_persist(&address, buffer, 100)
+            .await
+            .unwrap();
+        payload_cache
+            .remove_raw(&address, buffer, guids)
+            .await
+            .unwrap();
+        payload_cache
+            .restore(&address, buffer, persisted.clone())
+            .await
+            .unwrap();
+
+        let (rest, _) = payload_cache
+            .dequeue_payload_data(&address, buffer, 4096)
+            .await
+            .unwrap();
+
+        teardown(&payload_cache.test_key, connection).await;
+
+        assert_eq!(persisted.len(), 1);
+        assert_eq!(persisted[0].0.flags, -1);
+        assert_eq!(rest[0].header.stream_id, first[0].header.stream_id);
+        assert_eq!(rest[0].header.sequence, 1);
+        assert_eq!(rest[0].flags, i32::from(ChunkType::Final));
+
+        let data = [first[0].chunk.clone(), rest[0].chunk.clone()].concat();
+        assert_eq!(data, bincode::serialize(&payload).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_quota_counts_persisted_payloads() {
+        let payload_cache: PayloadCache<MockWebSocketConnection> = PayloadCache::connect();
+        let connection = payload_cache.pool.get().await.unwrap();
+        let address = new_protocol_address();
+        let payload = generate_payload(DeniablePayloadType::SignalMessage);
+        let buffer = Buffer::Receiver;
+        let quota = BufferQuota::new(usize::MAX, 1);
+        let insert = || async {
+            payload_cache
+                .insert_within_quota(
+                    &address,
+                    &address,
+                    buffer,
+                    &payload,
+                    &generate_uuid(),
+                    None,
+                    quota,
+                )
+                .await
+        };
+
+        let first = insert().await.unwrap();
+        let (persisted, guids) = payload_cache
+            .get_payloads_to_persist(&address, buffer, 100)
+            .await
+            .unwrap();
+        payload_cache
+            .remove_raw(&address, buffer, guids)
+            .await
+            .unwrap();
+        let while_persisted = insert().await.unwrap();
+        payload_cache
+            .restore(&address, buffer, persisted)
+            .await
+            .unwrap();
+        let while_restored = insert().await.unwrap();
+        payload_cache
+            .dequeue_payload_data(&address, buffer, 4096)
+            .await
+            .unwrap();
+        let after_sent = insert().await.unwrap();
+
+        teardown(&payload_cache.test_key, connection).await;
+
+        assert!(first.is_some());
+        assert!(while_persisted.is_none());
+        assert!(while_restored.is_none());
+        assert!(after_sent.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_requeued_chunks_are_taken_first() {
+        let payload_cache: PayloadCache<MockWebSocketConnection> = PayloadCache::connect();
+        let connection = payload_cache.pool.get().await.unwrap();
+        let address = new_protocol_address();
+        let payload = generate_payload(DeniablePayloadType::Envelope);
+        let buffer = Buffer::Receiver;
+
+        payload_cache
+            .insert(&address, &address, buffer, &payload, &generate_uuid(), None)
+            .await
+            .unwrap();
+
+        let (first, _) = payload_cache
+            .dequeue_payload_data(&address, buffer, constants::EMPTY_DENIMCHUNK_SIZE + 10)
+            .await
+            .unwrap();
+        payload_cache
+            .requeue_chunks(&address, buffer, vec![first[0].clone()])
+            .await
+            .unwrap();
+
+        // The requeued chunk does not fit whole, so it waits for more space
+        let (second, _) = payload_cache
+            .dequeue_payload_data(&address, buffer, constants::EMPTY_DENIMCHUNK_SIZE + 5)
+            .await
+            .unwrap();
+        let (rest, _) = payload_cache
+            .dequeue_payload_data(&address, buffer, 4096)
+            .await
+            .unwrap();
+
+        teardown(&payload_cache.test_key, connection).await;
+
+        assert_eq!(second[0].header.sequence, 1);
+        assert_eq!(rest[0], first[0]);
+        assert_eq!(rest[1].header.sequence, 2);
+        assert_eq!(rest[1].flags, i32::from(ChunkType::Final));
+
+        let data = [
+            first[0].chunk.clone(),
+            second[0].chunk.clone(),
+            rest[1].chunk.clone(),
+        ]
+        .concat();
+        assert_eq!(data, bincode::serialize(&payload).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_between_senders() {
+        let mut payload_cache: PayloadCache<MockWebSocketConnection> = PayloadCache::connect();
+        payload_cache.set_scheduling(SchedulingStrategy::RoundRobin);
+        let connection = payload_cache.pool.get().await.unwrap();
+        let address = new_protocol_address();
+        let buffer = Buffer::Receiver;
+
+        let first_sender = new_protocol_address();
+        let second_sender = new_protocol_address();
+        let large = generate_payload(DeniablePayloadType::Envelope);
+        let small = generate_payload(DeniablePayloadType::SignalMessage);
+        payload_cache
+            .insert(
+                &address,
+                &first_sender,
+                buffer,
+                &large,
+                &generate_uuid(),
+                None,
+            )
+            .await
+            .unwrap();
+        payload_cache
+            .insert(
+                &address,
+                &second_sender,
+                buffer,
+                &small,
+                &generate_uuid(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let mut chunks = Vec::new();
+        for _ in 0..3 {
+            let (taken, _) = payload_cache
+                .dequeue_payload_data(&address, buffer, constants::EMPTY_DENIMCHUNK_SIZE + 10)
+                .await
+                .unwrap();
+            chunks.push(taken[0].clone());
+        }
+
+        teardown(&payload_cache.test_key, connection).await;
+
+        // The second sender does not wait until the large payload is sent
+        let small = bincode::serialize(&small).unwrap();
+        assert_eq!(chunks[0].header.stream_id, chunks[2].header.stream_id);
+        assert_ne!(chunks[0].header.stream_id, chunks[1].header.stream_id);
+        assert_eq!(chunks[1].chunk, small[..chunks[1].chunk.len()]);
+        assert_eq!(chunks[2].header.sequence, 1);
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired_payloads() {
+        let payload_cache: PayloadCache<MockWebSocketConnection> = PayloadCache::connect();
+        let connection = payload_cache.pool.get().await.unwrap();
+        let address = new_protocol_address();
+        let buffer = Buffer::Receiver;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let started = generate_payload(DeniablePayloadType::Envelope);
+        let expired = generate_payload(DeniablePayloadType::SignalMessage);
+        let fresh = generate_payload(DeniablePayloadType::SignalMessage);
+        let forever = generate_payload(DeniablePayloadType::SignalMessage);
+        for (payload, expires_at) in [
+            (&started, Some(now - 1)),
+            (&expired, Some(now - 1)),
+            (&fresh, Some(now + 3600)),
+            (&forever, None),
+        ] {
+            payload_cache
+                .insert(
+                    &address,
+                    &address,
+                    buffer,
+                    payload,
+                    &generate_uuid(),
+                    expires_at,
+                )
+                .await
+                .unwrap();
+        }
+        // Part of the first payload is sent, so the receiver should get the rest
+        payload_cache
+            .dequeue_payload_data(&address, buffer, constants::EMPTY_DENIMCHUNK_SIZE + 10)
+            .await
+            .unwrap();
+
+        let purged = payload_cache.purge_expired(buffer, now).await.unwrap();
+        let (persisted, _) = payload_cache
+            .get_payloads_to_persist(&address, buffer, 100)
+            .await
+            .unwrap();
+
+        teardown(&payload_cache.test_key, connection).await;
+
+        assert_eq!(purged, 1);
+        assert_eq!(persisted.len(), 3);
+        assert_eq!(persisted[0].1, Some(now - 1));
+        assert_eq!(persisted[1].1, Some(now + 3600));
+        assert_eq!(persisted[2].1, None);
+    }
 }
@@ -2,8 +2,11 @@ use super::manager::Manager;
 use crate::{account::AuthenticatedDevice, error::ApiError, storage::database::SignalDatabase};
 use anyhow::Result;
 use axum::http::StatusCode;
-use common::web_api::{PreKeyResponse, PreKeyResponseItem, SetKeyRequest, UploadSignedPreKey};
+use common::web_api::{
+    DeniableKeyUpload, PreKeyResponse, PreKeyResponseItem, SetKeyRequest, UploadSignedPreKey,
+};
 use libsignal_core::{ProtocolAddress, ServiceId, ServiceIdKind};
+use libsignal_protocol::IdentityKey;
 use sha2::{Digest, Sha256};
 
 #[derive(Debug, Clone, Default)]
@@ -231,6 +234,115 @@ impl<T: SignalDatabase> KeyManager<T> {
         ))
     }
 
+    /// Stores the deniable identity key and prekeys uploaded by a device over the deniable channel.
+    /// The prekeys must be signed by the uploaded deniable identity key.
+    pub async fn handle_put_deniable_keys(
+        &self,
+        address: &ProtocolAddress,
+        upload: DeniableKeyUpload,
+    ) -> Result<(), ApiError> {
+        let identity_key = IdentityKey::decode(&upload.identity_key).map_err(|_| ApiError {
+            status_code: StatusCode::UNPROCESSABLE_ENTITY,
+            body: "Invalid deniable identity key".to_owned(),
+        })?;
+
+        if !verify_signed_pre_key(&identity_key, &upload.signed_pre_key)
+            || !verify_signed_pre_key(&identity_key, &upload.pq_last_resort_pre_key)
+        {
+            return Err(ApiError {
+                status_code: StatusCode::UNPROCESSABLE_ENTITY,
+                body: "Invalid signature".to_owned(),
+            });
+        }
+
+        self.db
+            .store_deniable_key_upload(&upload, address)
+            .await
+            .map_err(|_| ApiError {
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                body: "Database fault".to_owned(),
+            })
+    }
+
+    /// Answers a deniable key request from the deniable keys of the target account only.
+    /// Every device has its own deniable identity key, which is returned with its prekeys.
+    /// Devices whose prekeys are not signed by their deniable identity key are left out.
+    pub async fn handle_get_deniable_keys(
+        &self,
+        target_service_id: ServiceId,
+    ) -> Result<PreKeyResponse, ApiError> {
+        let devices = self
+            .db
+            .get_all_devices(&target_service_id)
+            .await
+            .map_err(|_| ApiError {
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                body: "Could not get all targets devices".into(),
+            })?;
+
+        let mut keys = Vec::new();
+        let mut first_identity_key = None;
+        for device in devices.iter() {
+            let address =
+                ProtocolAddress::new(target_service_id.service_id_string(), device.device_id());
+            let bundle = self
+                .db
+                .get_deniable_key_bundle(&address)
+                .await
+                .map_err(|err| ApiError {
+                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                    body: format!("Could not fetch deniable key bundle: {}", err),
+                })?;
+            let Some((identity_key, bundle)) = bundle.and_then(|bundle| {
+                let identity_key = IdentityKey::decode(&bundle.identity_key).ok()?;
+                (verify_signed_pre_key(&identity_key, &bundle.signed_pre_key)
+                    && verify_signed_pre_key(&identity_key, &bundle.pq_last_resort_pre_key))
+                .then_some((identity_key, bundle))
+            }) else {
+                continue;
+            };
+
+            let prekey = self
+                .db
+                .get_deniable_one_time_ec_pre_key(&address)
+                .await
+                .map_err(|_| ApiError {
+                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                    body: "Could not fetch deniable pre key".to_owned(),
+                })?;
+
+            keys.push(
+                PreKeyResponseItem::new(
+                    device.device_id(),
+                    bundle.registration_id,
+                    prekey,
+                    bundle.pq_last_resort_pre_key,
+                    bundle.signed_pre_key,
+                )
+                .with_identity_key(identity_key),
+            );
+            first_identity_key.get_or_insert(identity_key);
+        }
+
+        // The identity key of the response is the one of the first device, every device
+        // carries its own as well
+        let Some(identity_key) = first_identity_key else {
+            return Err(ApiError {
+                status_code: StatusCode::NOT_FOUND,
+                body: format!(
+                    "No deniable keys for service id: {}",
+                    target_service_id.service_id_string()
+                ),
+            });
+        };
+
+        Ok(PreKeyResponse::new(
+            target_service_id.service_id_string(),
+            identity_key,
+            keys,
+        ))
+    }
+
     // The Signal endpoint /v2/keys/check says that a u64 id is needed, however their ids, such as
     // KyperPreKeyID only supports u32. Here only a u32 is used and therefore only a 4 byte size
     // instead of the sugested u64.
@@ -305,6 +417,13 @@ impl<T: SignalDatabase> KeyManager<T> {
     }
 }
 
+fn verify_signed_pre_key(identity_key: &IdentityKey, prekey: &UploadSignedPreKey) -> bool {
+    identity_key
+        .public_key()
+        .verify_signature(&prekey.public_key, &prekey.signature)
+        .unwrap_or(false)
+}
+
 #[cfg(test)]
 mod key_manager_tests {
     use super::*;
@@ -315,7 +434,7 @@ mod key_manager_tests {
             new_device_pre_key_bundle, new_upload_pre_keys, new_upload_signed_pre_key,
         },
     };
-    use libsignal_protocol::{IdentityKey, KeyPair};
+    use libsignal_protocol::{IdentityKey, IdentityKeyPair, KeyPair};
     use rand::rngs::OsRng;
     use sha2::{Digest, Sha256};
 
@@ -550,6 +669,148 @@ mod key_manager_tests {
         assert_eq!(pq_pre_key, pq_pre_key_db);
         assert_eq!(pq_last_resort_pre_key, pq_last_resort_pre_key_db);
     }
+    #[tokio::test]
+    async fn put_and_get_deniable_keys_test() {
+        let database = database_connect().await;
+        let km = KeyManager::new(database.clone());
+
+        let (target, target_device, target_address) = new_account_and_device_and_address();
+        let identity_key = IdentityKeyPair::generate(&mut OsRng);
+        let upload = DeniableKeyUpload {
+            identity_key: identity_key.identity_key().serialize(),
+            registration_id: 42,
+            signed_pre_key: new_upload_signed_pre_key(Some(*identity_key.private_key())),
+            pq_last_resort_pre_key: new_upload_signed_pre_key(Some(*identity_key.private_key())),
+            pre_keys: new_upload_pre_keys(1),
+        };
+
+        database.add_account(&target).await.unwrap();
+
+        let keys_before_upload = km.handle_get_deniable_keys(target.aci().into()).await;
+        km.handle_put_deniable_keys(&target_address, upload.clone())
+            .await
+            .unwrap();
+        let keys = km
+            .handle_get_deniable_keys(target.aci().into())
+            .await
+            .unwrap();
+        let keys_without_pre_key = km
+            .handle_get_deniable_keys(target.aci().into())
+            .await
+            .unwrap();
+
+        database.delete_account(&target.aci().into()).await.unwrap();
+
+        assert!(keys_before_upload.is_err());
+        assert_eq!(
+            IdentityKey::decode(keys.identity_key()).unwrap(),
+            *identity_key.identity_key()
+        );
+
+        let device_bundle = keys.devices();
+        assert!(device_bundle.len() == 1);
+        assert_eq!(device_bundle[0].device_id(), target_device.device_id());
+        assert_eq!(device_bundle[0].registration_id(), upload.registration_id);
+        assert_eq!(
+            device_bundle[0].identity_key().as_deref(),
+            Some(&*upload.identity_key)
+        );
+        assert_eq!(
+            device_bundle[0].pre_key().clone(),
+            Some(upload.pre_keys[0].clone())
+        );
+        assert_eq!(
+            device_bundle[0].signed_pre_key().clone(),
+            upload.signed_pre_key
+        );
+        assert_eq!(
+            device_bundle[0].pq_pre_key().clone(),
+            upload.pq_last_resort_pre_key
+        );
+        assert_eq!(keys_without_pre_key.devices()[0].pre_key().clone(), None);
+    }
+
+    #[tokio::test]
+    async fn get_deniable_keys_of_devices_with_own_identity_keys_test() {
+        let database = database_connect().await;
+        let km = KeyManager::new(database.clone());
+
+        let (target, target_device, target_address) = new_account_and_device_and_address();
+        let device2 = new_device();
+        let address2 = ProtocolAddress::new(target_address.name().into(), device2.device_id());
+        let upload = |identity_key: &IdentityKeyPair| DeniableKeyUpload {
+            identity_key: identity_key.identity_key().serialize(),
+            registration_id: 42,
+            signed_pre_key: new_upload_signed_pre_key(Some(*identity_key.private_key())),
+            pq_last_resort_pre_key: new_upload_signed_pre_key(Some(*identity_key.private_key())),
+            pre_keys: new_upload_pre_keys(1),
+        };
+        let identity_key1 = IdentityKeyPair::generate(&mut OsRng);
+        let identity_key2 = IdentityKeyPair::generate(&mut OsRng);
+
+        database.add_account(&target).await.unwrap();
+        database
+            .add_device(&target.aci().into(), &device2)
+            .await
+            .unwrap();
+
+        km.handle_put_deniable_keys(&target_address, upload(&identity_key1))
+            .await
+            .unwrap();
+        km.handle_put_deniable_keys(&address2, upload(&identity_key2))
+            .await
+            .unwrap();
+        let keys = km
+            .handle_get_deniable_keys(target.aci().into())
+            .await
+            .unwrap();
+
+        database.delete_account(&target.aci().into()).await.unwrap();
+
+        let identity_key_of = |device_id| {
+            keys.devices()
+                .iter()
+                .find(|item| item.device_id() == device_id)
+                .and_then(|item| item.identity_key().as_deref())
+                .map(|identity_key| IdentityKey::decode(identity_key).unwrap())
+        };
+        assert_eq!(keys.devices().len(), 2);
+        assert_eq!(
+            identity_key_of(target_device.device_id()),
+            Some(*identity_key1.identity_key())
+        );
+        assert_eq!(
+            identity_key_of(device2.device_id()),
+            Some(*identity_key2.identity_key())
+        );
+    }
+
+    #[tokio::test]
+    async fn put_deniable_keys_rejects_invalid_signature_test() {
+        let database = database_connect().await;
+        let km = KeyManager::new(database.clone());
+
+        let (target, target_address) = new_account_and_address();
+        let identity_key = IdentityKeyPair::generate(&mut OsRng);
+        let upload = DeniableKeyUpload {
+            identity_key: identity_key.identity_key().serialize(),
+            registration_id: 42,
+            signed_pre_key: new_upload_signed_pre_key(Some(*identity_key.private_key())),
+            pq_last_resort_pre_key: new_upload_signed_pre_key(None),
+            pre_keys: new_upload_pre_keys(1),
+        };
+
+        database.add_account(&target).await.unwrap();
+
+        let res = km.handle_put_deniable_keys(&target_address, upload).await;
+        let keys = km.handle_get_deniable_keys(target.aci().into()).await;
+
+        database.delete_account(&target.aci().into()).await.unwrap();
+
+        assert!(res.is_err());
+        assert!(keys.is_err());
+    }
+
     #[tokio::test]
     async fn check_keys_test() {
         let database = database_connect().await;
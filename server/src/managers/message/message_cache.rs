@@ -107,6 +107,7 @@ where
             queue_total_index_key,
             message_guid,
             data,
+            None,
         )
         .await;
 
@@ -1,4 +1,5 @@
 pub mod account_manager;
+pub mod attachment_limits;
 mod client_presence_manager;
 pub mod denim;
 pub mod key_manager;
@@ -1,5 +1,6 @@
 use super::{
     account_manager::AccountManager,
+    attachment_limits::AttachmentLimits,
     client_presence_manager::ClientPresenceManager,
     denim::denim_manager::DenIMManager,
     key_manager::KeyManager,
@@ -11,7 +12,7 @@ use super::{
 use crate::test_utils::websocket::{MockDB, MockSocket};
 use crate::{storage::database::SignalDatabase, storage::postgres::PostgresDatabase};
 use axum::extract::ws::Message;
-use common::websocket::wsstream::WSStream;
+use common::{deniable::q_value::QValuePolicy, websocket::wsstream::WSStream};
 use std::fmt::Debug;
 
 #[derive(Debug)]
@@ -28,6 +29,9 @@ where
     pub client_presence_manager: ClientPresenceManager<WebSocketConnection<U, T>>,
     pub message_cache: MessageCache<WebSocketConnection<U, T>>,
     pub denim_manager: DenIMManager<WebSocketConnection<U, T>>,
+    pub attachment_limits: AttachmentLimits,
+    /// Token operators give to read server statistics, `None` keeps them hidden
+    pub admin_token: Option<String>,
 }
 
 impl<T, U> Manager for SignalServerState<T, U>
@@ -55,6 +59,8 @@ where
             client_presence_manager: self.client_presence_manager.clone(),
             message_cache: self.message_cache.clone(),
             denim_manager: self.denim_manager.clone(),
+            attachment_limits: self.attachment_limits,
+            admin_token: self.admin_token.clone(),
         }
     }
 }
@@ -63,11 +69,11 @@ impl<U> SignalServerState<PostgresDatabase, U>
 where
     U: WSStream<Message, axum::Error> + Debug,
 {
-    pub async fn new(q_value: f32) -> Self {
-        SignalServerState::connect("DATABASE_URL", q_value).await
+    pub async fn new(q_value_policy: QValuePolicy) -> Self {
+        SignalServerState::connect("DATABASE_URL", q_value_policy).await
     }
 
-    pub async fn connect(connection_str: &str, q_value: f32) -> Self {
+    pub async fn connect(connection_str: &str, q_value_policy: QValuePolicy) -> Self {
         let db = PostgresDatabase::connect(connection_str.to_string()).await;
         let cache = MessageCache::connect();
         Self {
@@ -78,7 +84,13 @@ where
             message_manager: MessagesManager::new(db, cache.clone()),
             client_presence_manager: ClientPresenceManager::connect(),
             message_cache: cache.clone(),
-            denim_manager: DenIMManager::new(cache.clone().into(), cache.clone().into(), q_value),
+            denim_manager: DenIMManager::new(
+                cache.clone().into(),
+                cache.clone().into(),
+                q_value_policy,
+            ),
+            attachment_limits: AttachmentLimits::default(),
+            admin_token: None,
         }
     }
 }
@@ -104,7 +116,13 @@ impl SignalServerState<MockDB, MockSocket> {
             message_manager: MessagesManager::new(db, cache.clone()),
             client_presence_manager: ClientPresenceManager::connect(),
             message_cache: cache.clone(),
-            denim_manager: DenIMManager::new(cache.clone().into(), cache.clone().into(), 0.6),
+            denim_manager: DenIMManager::new(
+                cache.clone().into(),
+                cache.clone().into(),
+                QValuePolicy::default(),
+            ),
+            attachment_limits: AttachmentLimits::default(),
+            admin_token: None,
         }
     }
 }
@@ -2,6 +2,7 @@ use crate::{
     account::AuthenticatedDevice,
     availability_listener::AvailabilityListener,
     managers::{client_presence_manager::DisplacedPresenceListener, state::SignalServerState},
+    server::response::SendMessageResponse,
     signal_server::{handle_keepalive, handle_put_messages},
     storage::database::SignalDatabase,
 };
@@ -10,11 +11,16 @@ use axum::{
     extract::ws::{CloseFrame, Message},
     http::{StatusCode, Uri},
 };
-use bincode::serialize;
+use common::deniable::counter::{
+    parse_retransmit_header, retransmit_header, CarrierCounter, CarrierOrder, CarrierWindow,
+    MissingCarriers,
+};
+use common::deniable::cover_traffic::CoverTrafficSchedule;
 use common::signalservice::{
     web_socket_message, Envelope, WebSocketMessage, WebSocketRequestMessage,
     WebSocketResponseMessage,
 };
+use common::web_api::{DenimChunk, RegularPayload};
 use common::websocket::connection_state::ConnectionState;
 use common::websocket::net_helper::{
     create_request, create_response, current_millis, generate_req_id, unpack_messages,
@@ -24,7 +30,7 @@ use common::websocket::wsstream::WSStream;
 use futures_util::{stream::SplitSink, SinkExt};
 use libsignal_core::{ProtocolAddress, ServiceId, ServiceIdKind};
 use prost::Message as PMessage;
-use std::{collections::HashMap, fmt::Debug, net::SocketAddr, sync::Arc, time::SystemTimeError};
+use std::{collections::HashMap, fmt::Debug, net::SocketAddr, sync::Arc};
 use tokio::sync::Mutex;
 
 #[derive(Debug)]
@@ -39,6 +45,13 @@ pub struct WebSocketConnection<W: WSStream<Message, Error> + Debug, DB: SignalDa
     socket_address: SocketAddr,
     ws: ConnectionState<W, Message>,
     pending_requests: HashMap<u64, String>,
+    pending_cover_requests: HashMap<u64, String>,
+    /// Chunks carried by envelopes the client has not acknowledged yet, by server guid,
+    /// with the counter of the denim message that carried them
+    in_flight_chunks: HashMap<String, (i32, Vec<DenimChunk>)>,
+    q_value: f32,
+    outgoing_counter: CarrierCounter,
+    incoming_carriers: CarrierWindow,
     state: SignalServerState<DB, W>,
 }
 
@@ -49,6 +62,7 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
         identity: UserIdentity,
         socket_addr: SocketAddr,
         ws: SplitSink<W, Message>,
+        q_value: f32,
         state: SignalServerState<DB, W>,
     ) -> Self {
         Self {
@@ -56,10 +70,20 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
             socket_address: socket_addr,
             ws: ConnectionState::Active(ws),
             pending_requests: HashMap::new(),
+            pending_cover_requests: HashMap::new(),
+            in_flight_chunks: HashMap::new(),
+            q_value,
+            outgoing_counter: CarrierCounter::default(),
+            incoming_carriers: CarrierWindow::default(),
             state,
         }
     }
 
+    /// The q value negotiated for this connection
+    pub fn q_value(&self) -> f32 {
+        self.q_value
+    }
+
     pub fn socket_address(&self) -> SocketAddr {
         self.socket_address
     }
@@ -72,10 +96,7 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
     }
 
     pub async fn send_message(&mut self, message: Envelope) -> Result<(), String> {
-        let msg = self
-            .create_message(message.clone())
-            .await
-            .map_err(|_| "Time went backwards".to_string())?;
+        let msg = self.create_message(message.clone()).await?;
         match self.send(Message::Binary(msg.encode_to_vec())).await {
             Ok(_) => {
                 self.state
@@ -92,7 +113,57 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
         }
     }
 
+    /// Send a cover envelope carrying chunks from the outgoing payload buffer
+    pub async fn send_cover_message(&mut self) -> Result<(), String> {
+        let id = generate_req_id();
+        let mut outgoing_counter = self.outgoing_counter.clone();
+        let counter = outgoing_counter.next_counter();
+        let denim_message = self
+            .state
+            .denim_manager
+            .create_cover_message(&self.protocol_address(), self.q_value, counter)
+            .await
+            .map_err(|err| format!("Failed to create cover message: {err}"))?;
+        self.outgoing_counter = outgoing_counter;
+        let RegularPayload::Envelope(envelope) = &denim_message.regular_payload else {
+            return Err("Cover message does not carry an envelope".to_string());
+        };
+        let server_guid = envelope.server_guid().to_owned();
+
+        let msg = create_request(
+            id,
+            "PUT",
+            "/api/v1/message",
+            vec![
+                "X-Signal-Key: false".to_string(),
+                format!(
+                    "X-Signal-Timestamp: {}",
+                    current_millis().map_err(|_| "Time went backwards".to_string())?
+                ),
+            ],
+            Some(denim_message.encode_to_vec()),
+        );
+        self.hold_in_flight(server_guid.clone(), counter, &denim_message.chunks);
+        self.pending_cover_requests.insert(id, server_guid);
+        self.send(Message::Binary(msg.encode_to_vec()))
+            .await
+            .map_err(|err| format!("{}", err))
+    }
+
+    pub fn cover_traffic(&self) -> Option<CoverTrafficSchedule> {
+        self.state.denim_manager.cover_traffic()
+    }
+
     pub async fn send_messages(&mut self, cached_only: bool) -> bool {
+        if !cached_only {
+            let _ = self
+                .state
+                .denim_manager
+                .restore_outgoing_buffers(&self.state.db, &self.protocol_address())
+                .await
+                .map_err(|e| println!("Failed to restore deniable buffers: {}", e));
+        }
+
         let Ok(envelopes) = self
             .state
             .message_manager
@@ -114,22 +185,25 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
         true
     }
 
-    async fn create_message(
-        &mut self,
-        mut message: Envelope,
-    ) -> Result<WebSocketMessage, SystemTimeError> {
+    /// Wrap an envelope in a denim message. When that fails, the envelope stays queued
+    /// and no chunks are held in flight or counted as sent.
+    async fn create_message(&mut self, mut message: Envelope) -> Result<WebSocketMessage, String> {
         let id = generate_req_id();
         let receiver = self.protocol_address();
+        let timestamp = current_millis().map_err(|_| "Time went backwards".to_string())?;
 
         message.ephemeral = None; // was false
         message.story = Some(false); // TODO: needs to handled in handle_request instead
 
+        let mut outgoing_counter = self.outgoing_counter.clone();
+        let counter = outgoing_counter.next_counter();
         let denim_message = self
             .state
             .denim_manager
-            .create_denim_message(&receiver, message.clone())
+            .create_denim_message(&receiver, message.clone(), self.q_value, counter)
             .await
-            .expect("Failed to create denim message: {e}");
+            .map_err(|err| format!("Failed to create denim message: {err}"))?;
+        self.outgoing_counter = outgoing_counter;
 
         let msg = create_request(
             id,
@@ -137,15 +211,76 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
             "/api/v1/message",
             vec![
                 "X-Signal-Key: false".to_string(),
-                format!("X-Signal-Timestamp: {}", current_millis()?),
+                format!("X-Signal-Timestamp: {timestamp}"),
             ],
-            Some(serialize(&denim_message).unwrap()),
+            Some(denim_message.encode_to_vec()),
         );
-        self.pending_requests
-            .insert(id, message.server_guid.expect("This is always some"));
+        let server_guid = message.server_guid.expect("This is always some");
+        self.hold_in_flight(server_guid.clone(), counter, &denim_message.chunks);
+        self.pending_requests.insert(id, server_guid);
         Ok(msg)
     }
 
+    /// Chunks taken from the outgoing payload buffer stay in flight until the envelope
+    /// carrying them is acknowledged
+    fn hold_in_flight(&mut self, server_guid: String, counter: i32, chunks: &[DenimChunk]) {
+        let chunks: Vec<DenimChunk> = chunks
+            .iter()
+            .filter(|chunk| !chunk.is_dummy())
+            .cloned()
+            .collect();
+        if !chunks.is_empty() {
+            self.in_flight_chunks.insert(server_guid, (counter, chunks));
+        }
+    }
+
+    /// Put the in flight chunks of envelopes back in front of the outgoing payload buffer
+    async fn requeue_in_flight(&mut self, server_guids: Vec<String>) {
+        let chunks: Vec<DenimChunk> = server_guids
+            .iter()
+            .filter_map(|server_guid| self.in_flight_chunks.remove(server_guid))
+            .flat_map(|(_, chunks)| chunks)
+            .collect();
+        self.requeue_chunks(chunks).await;
+    }
+
+    /// Put the in flight chunks of the denim messages the client is missing back in front of
+    /// the outgoing payload buffer, as they did not arrive
+    async fn retransmit_in_flight(&mut self, missing: &[MissingCarriers]) {
+        let server_guids = self
+            .in_flight_chunks
+            .iter()
+            .filter(|(_, (counter, _))| missing.iter().any(|missing| missing.contains(*counter)))
+            .map(|(server_guid, _)| server_guid.clone())
+            .collect();
+        self.requeue_in_flight(server_guids).await;
+    }
+
+    async fn requeue_chunks(&self, mut chunks: Vec<DenimChunk>) {
+        if chunks.is_empty() {
+            return;
+        }
+        chunks.sort_by_key(|chunk| (chunk.header.stream_id, chunk.header.sequence));
+
+        if let Err(err) = self
+            .state
+            .denim_manager
+            .requeue_outgoing_chunks(&self.protocol_address(), chunks)
+            .await
+        {
+            println!("Failed to requeue deniable chunks: {err}");
+        }
+    }
+
+    /// Chunks of an acknowledged envelope arrived, those of a rejected one are sent again
+    async fn settle_in_flight(&mut self, server_guid: String, acknowledged: bool) {
+        if acknowledged {
+            self.in_flight_chunks.remove(&server_guid);
+        } else {
+            self.requeue_in_flight(vec![server_guid]).await;
+        }
+    }
+
     pub async fn close(&mut self) {
         if let ConnectionState::Active(ref mut socket) = self.ws {
             if let Err(e) = socket.close().await {
@@ -153,6 +288,9 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
             }
         }
         self.ws = ConnectionState::Closed;
+
+        let server_guids = self.in_flight_chunks.keys().cloned().collect();
+        self.requeue_in_flight(server_guids).await;
     }
 
     pub async fn close_reason(&mut self, code: u16, reason: &str) -> Result<(), String> {
@@ -245,10 +383,36 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
             };
         }
 
+        let payload = unpack_messages(request_msq.body.clone())?;
+        let carriers = payload
+            .messages
+            .iter()
+            .map(|message| self.incoming_carriers.observe(message.counter))
+            .collect::<Vec<CarrierOrder>>();
+        let is_repeated = !carriers.is_empty()
+            && carriers
+                .iter()
+                .all(|order| *order == CarrierOrder::Duplicate);
+        let missing = payload
+            .messages
+            .iter()
+            .zip(&carriers)
+            .filter_map(|(message, order)| order.missing_before(message.counter))
+            .collect::<Vec<MissingCarriers>>();
+
         let res = match &self.identity {
             UserIdentity::ProtocolAddress(_) => {
                 todo!("We do not support protocol addresses yet!")
             }
+            // A repeated request was handled when it first arrived, so it is only acknowledged
+            UserIdentity::AuthenticatedDevice(_) if is_repeated => {
+                eprintln!(
+                    "Ignored {} repeated denim messages from {}",
+                    carriers.len(),
+                    self.protocol_address()
+                );
+                Ok(SendMessageResponse { needs_sync: false })
+            }
             UserIdentity::AuthenticatedDevice(authenticated_device) => {
                 handle_put_messages(
                     &self.state,
@@ -264,28 +428,35 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
                         .as_str(),
                     )
                     .ok_or("Could not parse uri to service id")?,
-                    unpack_messages(request_msq.body.clone())?,
+                    payload,
+                    self.q_value,
                 )
                 .await
             }
         };
         match res {
-            Ok(res) => self
-                .send(Message::Binary(
+            Ok(res) => {
+                // The client sends the chunks of the missing denim messages again
+                let headers = if missing.is_empty() {
+                    vec![]
+                } else {
+                    vec![retransmit_header(&missing)]
+                };
+                self.send(Message::Binary(
                     create_response(
                         msq_id,
                         StatusCode::OK,
-                        vec![],
+                        headers,
                         Some(serde_json::to_string(&res).unwrap().as_bytes().to_vec()),
                     )?
                     .encode_to_vec(),
                 ))
                 .await
-                .map_err(|err| err.to_string()),
-            Err(_) => self
+                .map_err(|err| err.to_string())
+            }
+            Err(err) => self
                 .send(Message::Binary(
-                    create_response(msq_id, StatusCode::INTERNAL_SERVER_ERROR, vec![], None)?
-                        .encode_to_vec(),
+                    create_response(msq_id, err.status_code, vec![], None)?.encode_to_vec(),
                 ))
                 .await
                 .map_err(|err| err.to_string()),
@@ -301,6 +472,22 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
         //
         // TODO This should be fixed, since the current implementation is wrong
 
+        let missing = parse_retransmit_header(&response_msq.headers);
+        if !missing.is_empty() {
+            self.retransmit_in_flight(&missing).await;
+        }
+
+        let acknowledged = response_msq.status.is_none_or(|status| status < 400);
+
+        // Cover envelopes are not stored, so there is nothing to delete
+        if let Some(server_guid) = self
+            .pending_cover_requests
+            .remove(&response_msq.id.ok_or("Response message was not present")?)
+        {
+            self.settle_in_flight(server_guid, acknowledged).await;
+            return Ok(());
+        }
+
         if !self
             .pending_requests
             .contains_key(&response_msq.id.ok_or("Response message was not present")?)
@@ -320,10 +507,12 @@ impl<W: WSStream<Message, Error> + Debug + Send + 'static, DB: SignalDatabase>
             .map(|_| ())
             .map_err(|err| err.to_string())?;
 
-        self.pending_requests
+        let server_guid = self
+            .pending_requests
             .remove(&response_msq.id.ok_or("Request id was not present")?)
-            .ok_or("Could not remove pending requests".to_string())
-            .map(|_| ())
+            .ok_or("Could not remove pending requests".to_string())?;
+        self.settle_in_flight(server_guid, acknowledged).await;
+        Ok(())
     }
 }
 
@@ -377,18 +566,24 @@ pub(crate) mod test {
         storage::database::SignalDatabase,
         storage::postgres::PostgresDatabase,
         test_utils::{
-            message_cache::teardown,
+            message_cache::{generate_payload, teardown, DeniablePayloadType},
             user::new_authenticated_device,
             websocket::{MockDB, MockSocket},
         },
     };
     use axum::{extract::ws::Message, http::StatusCode, Error};
     use base64::prelude::{Engine as _, BASE64_STANDARD};
-    use bincode::deserialize;
     use common::websocket::net_helper::{create_request, create_response};
     use common::{
-        signalservice::{Envelope, WebSocketMessage},
-        web_api::{DenimMessage, RegularPayload},
+        deniable::{
+            chunk::{random_filler, Chunker},
+            counter::{retransmit_header, CarrierOrder, CarrierWindow},
+            q_value::QValuePolicy,
+        },
+        signalservice::{envelope, Envelope, WebSocketMessage},
+        web_api::{
+            DenimChunk, DenimMessage, DenimMessages, PayloadData, RegularPayload, SignalMessage,
+        },
     };
     use futures_util::{stream::SplitStream, StreamExt};
     use libsignal_core::Aci;
@@ -399,6 +594,37 @@ pub(crate) mod test {
     use tokio::sync::mpsc::{Receiver, Sender};
     use tokio::time::sleep;
 
+    fn make_denim_messages(
+        destination_device_id: u32,
+        destination_registration_id: u32,
+    ) -> Vec<u8> {
+        let regular_payload = RegularPayload::SignalMessage(SignalMessage {
+            r#type: 1,
+            destination_device_id,
+            destination_registration_id,
+            content: "aGVsbG8=".to_string(),
+            ..Default::default()
+        });
+        // The q value of the connection decides how much space the chunks and ballast take
+        let (chunks, ballast, _) = Chunker::new(0.6).create_ordered_chunks(
+            regular_payload.encoded_len() as f32,
+            PayloadData::new(Vec::new()),
+        );
+        DenimMessages {
+            messages: vec![DenimMessage {
+                regular_payload,
+                chunks,
+                counter: None,
+                q: None,
+                ballast: random_filler(ballast),
+            }],
+            online: false,
+            urgent: true,
+            timestamp: 1730217386,
+        }
+        .encode_to_vec()
+    }
+
     fn make_envelope() -> Envelope {
         Envelope {
             ephemeral: None,
@@ -426,6 +652,7 @@ pub(crate) mod test {
             UserIdentity::AuthenticatedDevice(Box::new(auth_device)),
             who,
             msender,
+            0.6,
             state,
         );
 
@@ -520,41 +747,198 @@ pub(crate) mod test {
         assert!(req.headers.len() == 2);
         assert!(req.headers[0] == "X-Signal-Key: false");
         assert!(req.headers[1].starts_with("X-Signal-Timestamp:"));
-        let denim_msg: DenimMessage = deserialize(&req.body.unwrap()).unwrap();
+        let denim_msg = DenimMessage::decode(&req.body.unwrap()).unwrap();
         let RegularPayload::Envelope(signal_msg) = denim_msg.regular_payload else {
             panic!("No envelope received")
         };
         assert!(signal_msg.encode_to_vec() == env.encode_to_vec());
     }
 
+    #[tokio::test]
+    async fn test_send_cover_message() {
+        let state = SignalServerState::<MockDB, MockSocket>::new();
+        let (mut client, _sender, mut receiver, _stream) =
+            create_connection("127.0.0.1:4042", state).await;
+        client.send_cover_message().await.unwrap();
+
+        let msg = match receiver.recv().await {
+            Some(Message::Binary(x)) => WebSocketMessage::decode(Bytes::from(x))
+                .expect("unexpected error in decode websocket message"),
+            _ => panic!("Did not receive cover message"),
+        };
+
+        assert!(client.pending_requests.is_empty());
+        assert_eq!(client.pending_cover_requests.len(), 1);
+        let req = msg.request.unwrap();
+        assert!(req.path.unwrap() == "/api/v1/message");
+        assert!(req.headers.len() == 2);
+        let denim_msg = DenimMessage::decode(&req.body.unwrap()).unwrap();
+        let RegularPayload::Envelope(envelope) = denim_msg.regular_payload else {
+            panic!("No envelope received")
+        };
+        assert_eq!(envelope.r#type(), envelope::Type::Unknown);
+        assert!(denim_msg.q.is_some());
+
+        client
+            .on_receive(create_response(req.id.unwrap(), StatusCode::OK, vec![], None).unwrap())
+            .await
+            .unwrap();
+        assert!(client.pending_cover_requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_requeue_chunks_of_unacknowledged_envelopes() {
+        let state = SignalServerState::<MockDB, MockSocket>::new();
+        let (mut client, _sender, mut receiver, _stream) =
+            create_connection("127.0.0.1:4042", state.clone()).await;
+        let address = client.protocol_address();
+        state
+            .denim_manager
+            .enqueue_outgoing_payload_buffer(
+                &address,
+                &address,
+                vec![generate_payload(DeniablePayloadType::Envelope)],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            client.send_cover_message().await.unwrap();
+            let Some(Message::Binary(msg)) = receiver.recv().await else {
+                panic!("Did not receive cover message");
+            };
+            let req = WebSocketMessage::decode(Bytes::from(msg))
+                .unwrap()
+                .request
+                .unwrap();
+            let denim_msg = DenimMessage::decode(req.body()).unwrap();
+            assert_eq!(client.in_flight_chunks.len(), 1);
+
+            // The client rejects the cover messages, so their chunks are sent again
+            client
+                .on_receive(
+                    create_response(req.id(), StatusCode::UNPROCESSABLE_ENTITY, vec![], None)
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert!(client.in_flight_chunks.is_empty());
+            sent.push(denim_msg.chunks[0].clone());
+        }
+
+        // Chunks of the last cover message were never acknowledged
+        client.send_cover_message().await.unwrap();
+        client.close().await;
+        let (requeued, _) = state
+            .denim_manager
+            .dequeue_outgoing_payload_buffer(&address, 4096)
+            .await
+            .unwrap();
+
+        teardown(
+            &state.message_cache.test_key,
+            state.message_cache.get_connection().await.unwrap(),
+        )
+        .await;
+
+        assert!(!sent[0].is_dummy());
+        assert_eq!(sent[0], sent[1]);
+        assert_eq!(requeued[0], sent[0]);
+        assert!(client.in_flight_chunks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retransmit_chunks_of_missing_denim_messages() {
+        let state = SignalServerState::<MockDB, MockSocket>::new();
+        let (mut client, _sender, mut receiver, _stream) =
+            create_connection("127.0.0.1:4042", state.clone()).await;
+        let address = client.protocol_address();
+        state
+            .denim_manager
+            .enqueue_outgoing_payload_buffer(
+                &address,
+                &address,
+                vec![generate_payload(DeniablePayloadType::Envelope)],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            client.send_cover_message().await.unwrap();
+            let Some(Message::Binary(msg)) = receiver.recv().await else {
+                panic!("Did not receive cover message");
+            };
+            let req = WebSocketMessage::decode(Bytes::from(msg))
+                .unwrap()
+                .request
+                .unwrap();
+            sent.push((req.id(), DenimMessage::decode(req.body()).unwrap()));
+        }
+
+        // The first denim message goes missing, so the client reports its counter
+        let (id, received) = &sent[1];
+        let mut window = CarrierWindow::default();
+        let order = window.observe(received.counter);
+        assert_eq!(order, CarrierOrder::Gap { missing: 1 });
+        let missing = order.missing_before(received.counter).unwrap();
+        assert!(missing.contains(sent[0].1.counter.unwrap()));
+        let missing_chunks: Vec<DenimChunk> = sent[0]
+            .1
+            .chunks
+            .iter()
+            .filter(|chunk| !chunk.is_dummy())
+            .cloned()
+            .collect();
+        let headers = vec![retransmit_header(&[missing])];
+        client
+            .on_receive(create_response(*id, StatusCode::OK, headers, None).unwrap())
+            .await
+            .unwrap();
+        let (requeued, _) = state
+            .denim_manager
+            .dequeue_outgoing_payload_buffer(&address, 4096)
+            .await
+            .unwrap();
+
+        teardown(
+            &state.message_cache.test_key,
+            state.message_cache.get_connection().await.unwrap(),
+        )
+        .await;
+
+        assert!(!missing_chunks.is_empty());
+        assert_eq!(requeued[..missing_chunks.len()], missing_chunks[..]);
+    }
+
+    #[tokio::test]
+    async fn test_denim_message_uses_negotiated_q_value() {
+        let state = SignalServerState::<MockDB, MockSocket>::new();
+        let (mut client, _sender, mut receiver, _stream) =
+            create_connection("127.0.0.1:4042", state).await;
+        client.q_value = 1.5;
+        client.send_cover_message().await.unwrap();
+
+        let msg = match receiver.recv().await {
+            Some(Message::Binary(x)) => WebSocketMessage::decode(Bytes::from(x))
+                .expect("unexpected error in decode websocket message"),
+            _ => panic!("Did not receive cover message"),
+        };
+
+        let denim_msg = DenimMessage::decode(&msg.request.unwrap().body.unwrap()).unwrap();
+        assert_eq!(client.q_value(), 1.5);
+        assert_eq!(denim_msg.q, Some(1.5));
+    }
+
     #[tokio::test]
     async fn test_on_receive_request() {
         let state = SignalServerState::<MockDB, MockSocket>::new();
         let (mut client, _sender, _receiver, _stream) =
             create_connection("127.0.0.1:4042", state).await;
-        let msg = r#"
-        {
-            "messages":[
-                {
-                    "regularPayload": {
-                        "signalMessage": {
-                            "type": 1,
-                            "destinationDeviceId": 3,
-                            "destinationRegistrationId": 22,
-                            "content": "aGVsbG8="
-                        }
-                    },
-                    "chunks": [],
-                    "ballast": []
-                }
-            ],
-            "online": false,
-            "urgent": true,
-            "timestamp": 1730217386
-        }
-        "#
-        .as_bytes()
-        .to_vec();
+        let msg = make_denim_messages(3, 22);
 
         client
             .on_receive(create_request(
@@ -568,6 +952,80 @@ pub(crate) mod test {
             .unwrap();
     }
 
+    #[tokio::test]
+    async fn test_on_receive_request_with_wrong_size() {
+        let state = SignalServerState::<MockDB, MockSocket>::new();
+        let (mut client, _sender, mut receiver, _stream) =
+            create_connection("127.0.0.1:4042", state).await;
+        let mut msg = DenimMessages::decode(&make_denim_messages(3, 22)).unwrap();
+        msg.messages[0].ballast.push(0);
+
+        client
+            .on_receive(create_request(
+                1,
+                "PUT",
+                &format!("/v1/messages/{}", client.protocol_address().name()),
+                vec![],
+                Some(msg.encode_to_vec()),
+            ))
+            .await
+            .unwrap();
+
+        let Some(Message::Binary(response)) = receiver.recv().await else {
+            panic!("Expected binary response");
+        };
+        let response = WebSocketMessage::decode(Bytes::from(response))
+            .unwrap()
+            .response
+            .unwrap();
+        assert_eq!(
+            response.status.unwrap(),
+            StatusCode::UNPROCESSABLE_ENTITY.as_u16() as u32
+        );
+    }
+
+    #[tokio::test]
+    async fn test_on_receive_repeated_request() {
+        let state = SignalServerState::<MockDB, MockSocket>::new();
+        let (mut client, _sender, mut receiver, _stream) =
+            create_connection("127.0.0.1:4042", state).await;
+        let mut msg = DenimMessages::decode(&make_denim_messages(3, 22)).unwrap();
+        msg.messages[0].ballast.push(0);
+        msg.messages[0].counter = Some(0);
+
+        let mut statuses = Vec::new();
+        for id in [1, 2] {
+            client
+                .on_receive(create_request(
+                    id,
+                    "PUT",
+                    &format!("/v1/messages/{}", client.protocol_address().name()),
+                    vec![],
+                    Some(msg.encode_to_vec()),
+                ))
+                .await
+                .unwrap();
+
+            let Some(Message::Binary(response)) = receiver.recv().await else {
+                panic!("Expected binary response");
+            };
+            let response = WebSocketMessage::decode(Bytes::from(response))
+                .unwrap()
+                .response
+                .unwrap();
+            statuses.push(response.status.unwrap());
+        }
+
+        // The repeated request is acknowledged without being checked again
+        assert_eq!(
+            statuses,
+            vec![
+                StatusCode::UNPROCESSABLE_ENTITY.as_u16() as u32,
+                StatusCode::OK.as_u16() as u32
+            ]
+        );
+    }
+
     #[ignore = "This in currently not testable"]
     #[tokio::test]
     async fn test_on_receive_response() {
@@ -581,9 +1039,11 @@ pub(crate) mod test {
     }
     #[tokio::test]
     async fn test_alice_sends_msg_to_bob() {
-        let mut state =
-            SignalServerState::<PostgresDatabase, MockSocket>::connect("DATABASE_URL_TEST", 0.6)
-                .await;
+        let mut state = SignalServerState::<PostgresDatabase, MockSocket>::connect(
+            "DATABASE_URL_TEST",
+            QValuePolicy::default(),
+        )
+        .await;
         let (alice, alice_sender, mut alice_receiver, alice_mreceiver) =
             create_connection("127.0.0.1:4042", state.clone()).await;
         let (bob, _alice_sender, mut bob_receiver, bob_mreceiver) =
@@ -619,33 +1079,7 @@ pub(crate) mod test {
             .add_message_availability_listener(&bob_address, ws_bob.clone())
             .await;
 
-        let sending_msg = format!(
-            r#"
-            {{
-                "messages":[
-                    {{
-                        "regularPayload": {{
-                            "signalMessage": {{
-                                "type": 1,
-                                "destinationDeviceId": {},
-                                "destinationRegistrationId": {},
-                                "content": "aGVsbG8="
-                            }}
-                        }},
-                        "chunks": [],
-                        "ballast": []
-                    }}
-                ],
-                "online": false,
-                "urgent": true,
-                "timestamp": 1730217386
-            }}
-            "#,
-            bob_address.device_id(),
-            reg_id,
-        )
-        .as_bytes()
-        .to_vec();
+        let sending_msg = make_denim_messages(bob_address.device_id().into(), reg_id);
 
         alice_sender
             .send(Ok(Message::Binary(
@@ -706,7 +1140,7 @@ pub(crate) mod test {
             _ => panic!("Expected binary message"),
         };
 
-        let denim_msg: DenimMessage = deserialize(&message.request.unwrap().body.unwrap()).unwrap();
+        let denim_msg = DenimMessage::decode(&message.request.unwrap().body.unwrap()).unwrap();
         let RegularPayload::Envelope(signal_msg) = denim_msg.regular_payload else {
             panic!("No envelope received")
         };
@@ -770,7 +1204,7 @@ pub(crate) mod test {
         assert!(req.headers.len() == 2);
         assert!(req.headers[0] == "X-Signal-Key: false");
         assert!(req.headers[1].starts_with("X-Signal-Timestamp:"));
-        let denim_msg: DenimMessage = deserialize(&req.body.unwrap()).unwrap();
+        let denim_msg = DenimMessage::decode(&req.body.unwrap()).unwrap();
         let RegularPayload::Envelope(signal_msg) = denim_msg.regular_payload else {
             panic!("No envelope received")
         };
@@ -1,6 +1,7 @@
 use super::connection::{ClientConnection, ConnectionMap, WebSocketConnection};
 use crate::storage::database::SignalDatabase;
 use axum::extract::ws::Message;
+use common::deniable::cover_traffic::{CoverTrafficSchedule, CoverTrafficScheduler};
 use common::signalservice::WebSocketMessage;
 use common::websocket::wsstream::WSStream;
 use futures_util::stream::{SplitStream, StreamExt};
@@ -74,10 +75,15 @@ where
         mut receiver: SplitStream<T>,
     ) {
         let address = connection.protocol_address();
+        let cover_traffic = connection.cover_traffic();
         let connection = Arc::new(Mutex::new(connection));
         self.register_new_connection(address.clone(), connection.clone())
             .await;
 
+        if let Some(schedule) = cover_traffic {
+            tokio::spawn(Self::send_cover_messages(connection.clone(), schedule));
+        }
+
         tokio::spawn({
             let mut self_clone = self.clone();
 
@@ -131,6 +137,25 @@ where
         });
     }
 
+    /// Send cover envelopes on the given schedule until the connection is closed
+    async fn send_cover_messages(
+        connection: ClientConnection<T, U>,
+        schedule: CoverTrafficSchedule,
+    ) {
+        let mut scheduler = CoverTrafficScheduler::new(schedule);
+        loop {
+            scheduler.tick().await;
+
+            let mut connection = connection.lock().await;
+            if !connection.is_active() {
+                break;
+            }
+            if let Err(err) = connection.send_cover_message().await {
+                println!("WebSocketManager cover ERROR: {}", err);
+            }
+        }
+    }
+
     /// Keep new connection open
     pub async fn register_new_connection(
         &mut self,
@@ -173,6 +198,7 @@ mod test {
         test_utils::websocket::{MockDB, MockSocket},
     };
     use axum::extract::ws::Message;
+    use common::web_api::{DenimMessage, DenimMessages, RegularPayload, SignalMessage};
     use common::websocket::net_helper;
     use prost::Message as PMessage;
 
@@ -317,27 +343,24 @@ mod test {
     #[ignore = "not implemented"]
     #[tokio::test]
     async fn test_binary_decode_ok() {
-        let msg = r#"
-        {
-            "messages":[
-                {
-                    "regularPayload": {
-                        "signalMessage": {
-                            "type": 1,
-                            "destinationDeviceId": 3,
-                            "destinationRegistrationId": 22,
-                            "content": "aGVsbG8="
-                        }
-                    },
-                    "chunks": [],
-                    "ballast": 0
-                }
-            ],
-            "online": false,
-            "urgent": true,
-            "timestamp": 1730217386
-        }
-        "#;
+        let msg = DenimMessages {
+            messages: vec![DenimMessage {
+                regular_payload: RegularPayload::SignalMessage(SignalMessage {
+                    r#type: 1,
+                    destination_device_id: 3,
+                    destination_registration_id: 22,
+                    content: "aGVsbG8=".to_string(),
+                    ..Default::default()
+                }),
+                chunks: vec![],
+                counter: None,
+                q: None,
+                ballast: vec![],
+            }],
+            online: false,
+            urgent: true,
+            timestamp: 1730217386,
+        };
 
         let state = SignalServerState::<MockDB, MockSocket>::new();
         let (ws, sender, receiver, mreceiver) =
@@ -356,7 +379,7 @@ mod test {
             "PUT",
             "v1/messages/aaa?story=false",
             vec![],
-            Some(msg.as_bytes().to_vec()),
+            Some(msg.encode_to_vec()),
         );
 
         sender
@@ -0,0 +1,288 @@
+use super::persister::{Persister, RunFlag};
+use crate::{
+    availability_listener::AvailabilityListener,
+    managers::{
+        denim::{buffer::Buffer, chunk_cache::ChunkCache},
+        manager::{self, Manager},
+    },
+    storage::database::SignalDatabase,
+};
+use anyhow::Result;
+use libsignal_core::ProtocolAddress;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const QUEUE_BATCH_LIMIT: u8 = 100;
+const CHUNK_BATCH_LIMIT: u8 = 100;
+const PERSIST_DELAY: u64 = 600;
+
+#[derive(Debug)]
+pub struct ChunkPersister<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener + 'static,
+{
+    run_flag: RunFlag,
+    chunk_cache: ChunkCache<U>,
+    db: T,
+}
+
+impl<T, U> Clone for ChunkPersister<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener,
+{
+    fn clone(&self) -> Self {
+        Self {
+            run_flag: self.run_flag.clone(),
+            chunk_cache: self.chunk_cache.clone(),
+            db: self.db.clone(),
+        }
+    }
+}
+
+/*
+ * Takes the chunk queues from the cache that is >10 minutes old,
+ * removes them from the cache and puts them into the database
+*/
+impl<T, U> Persister<T, U> for ChunkPersister<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener + 'static,
+{
+    type Manager = Vec<Box<dyn Manager>>;
+
+    fn listen(db: T, managers: Self::Manager) -> ChunkPersister<T, U> {
+        let chunk_cache = manager::get::<ChunkCache<U>>(&managers).unwrap().clone();
+
+        let mut chunk_persister = ChunkPersister {
+            run_flag: Arc::new(AtomicBool::new(true)),
+            chunk_cache,
+            db,
+        };
+
+        let chunk_persister_clone = chunk_persister.clone();
+
+        tokio::spawn(async move {
+            while chunk_persister.run_flag.load(Ordering::Relaxed) {
+                let _ = chunk_persister.persist().await;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+        chunk_persister_clone
+    }
+
+    fn get_run_flag(&self) -> &RunFlag {
+        &self.run_flag
+    }
+
+    // Finds the chunk queues where the oldest chunk is >10 minutes old.
+    async fn persist(&mut self) -> Result<()> {
+        for buffer in [Buffer::Sender, Buffer::Receiver] {
+            let mut queues_to_persist;
+
+            while {
+                let time_in_secs: u64 = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Failed to get time")
+                    .as_secs();
+
+                queues_to_persist = self
+                    .chunk_cache
+                    .get_queues_to_persist(buffer, time_in_secs - PERSIST_DELAY, QUEUE_BATCH_LIMIT)
+                    .await?;
+
+                !queues_to_persist.is_empty()
+            } {
+                for queue_key in &queues_to_persist {
+                    let (account_id, device_id) = self
+                        .chunk_cache
+                        .get_account_and_device_id_from_queue_key(queue_key);
+
+                    let protocol_address =
+                        ProtocolAddress::new(account_id, device_id.parse::<u32>()?.into());
+
+                    // Chunks of deleted devices are dropped with the device, the queue is
+                    // already out of the queue index so nothing else would remove it
+                    if self.db.get_device(&protocol_address).await.is_err() {
+                        self.chunk_cache
+                            .delete_queue(&protocol_address, buffer)
+                            .await?;
+                        continue;
+                    }
+
+                    self.persist_queue(&protocol_address, buffer).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T, U> ChunkPersister<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener,
+{
+    /// Moves all chunks of a queue into the database.
+    async fn persist_queue(&mut self, address: &ProtocolAddress, buffer: Buffer) -> Result<()> {
+        self.chunk_cache
+            .lock_queue_for_persistence(address, buffer)
+            .await?;
+
+        loop {
+            let (chunks, chunk_guids) = self
+                .chunk_cache
+                .get_chunks_to_persist(address, buffer, CHUNK_BATCH_LIMIT as i32)
+                .await?;
+
+            if chunks.is_empty() {
+                break;
+            }
+
+            self.db.push_chunk_queue(address, buffer, chunks).await?;
+            self.chunk_cache
+                .remove(address, buffer, chunk_guids)
+                .await?;
+        }
+
+        self.chunk_cache
+            .unlock_queue_for_persistence(address, buffer)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod chunk_persister_tests {
+    use super::ChunkPersister;
+    use crate::{
+        managers::denim::{buffer::Buffer, chunk_cache::ChunkCache},
+        persisters::persister::Persister,
+        storage::{database::SignalDatabase, postgres::PostgresDatabase},
+        test_utils::{
+            database::database_connect,
+            message_cache::{generate_uuid, teardown, MockWebSocketConnection},
+            user::new_account_and_address,
+        },
+    };
+    use common::web_api::{ChunkHeader, DenimChunk};
+    use redis::cmd;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn test_chunk_persister_late_chunks() {
+        let _ = dotenv::dotenv();
+        let db = database_connect().await;
+        let cache: ChunkCache<MockWebSocketConnection> = ChunkCache::connect();
+        let mut connection = cache.get_connection().await.unwrap();
+        let (account, address) = new_account_and_address();
+        let buffer = Buffer::Sender;
+
+        db.add_account(&account).await.unwrap();
+
+        let chunks = (0..3)
+            .map(|sequence| DenimChunk {
+                chunk: vec![sequence as u8; 8],
+                flags: -(sequence as i32),
+                header: ChunkHeader::new(1, sequence, 32),
+            })
+            .collect::<Vec<DenimChunk>>();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 660;
+        cmd("ZADD")
+            .arg(cache.get_queue_index_key(buffer))
+            .arg("NX")
+            .arg(timestamp)
+            .arg(cache.get_queue_key(&address, buffer))
+            .query_async::<()>(&mut connection)
+            .await
+            .unwrap();
+
+        for chunk in &chunks {
+            cache
+                .insert(&address, buffer, chunk, &generate_uuid())
+                .await
+                .unwrap();
+        }
+
+        let chunk_persister: ChunkPersister<PostgresDatabase, MockWebSocketConnection> =
+            ChunkPersister::listen(db.clone(), vec![Box::new(cache.clone())]);
+
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        chunk_persister.stop().await;
+
+        let cached = cache.get_all_chunks(&address, buffer).await.unwrap();
+        let persisted = db.pop_chunk_queue(&address, buffer).await.unwrap();
+
+        teardown(&cache.test_key, connection).await;
+        db.delete_account(&account.aci().into()).await.unwrap();
+
+        assert!(cached.is_empty());
+        assert_eq!(persisted, chunks);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_persister_drops_chunks_of_deleted_devices() {
+        let _ = dotenv::dotenv();
+        let db = database_connect().await;
+        let cache: ChunkCache<MockWebSocketConnection> = ChunkCache::connect();
+        let mut connection = cache.get_connection().await.unwrap();
+        let (_, address) = new_account_and_address();
+        let buffer = Buffer::Receiver;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 660;
+        cmd("ZADD")
+            .arg(cache.get_queue_index_key(buffer))
+            .arg("NX")
+            .arg(timestamp)
+            .arg(cache.get_queue_key(&address, buffer))
+            .query_async::<()>(&mut connection)
+            .await
+            .unwrap();
+
+        let chunk = DenimChunk {
+            chunk: vec![1; 8],
+            flags: 2,
+            header: ChunkHeader::new(1, 0, 8),
+        };
+        cache
+            .insert(&address, buffer, &chunk, &generate_uuid())
+            .await
+            .unwrap();
+
+        let chunk_persister: ChunkPersister<PostgresDatabase, MockWebSocketConnection> =
+            ChunkPersister::listen(db.clone(), vec![Box::new(cache.clone())]);
+
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+
+        chunk_persister.stop().await;
+
+        let cached = cache.get_all_chunks(&address, buffer).await.unwrap();
+        let queue_exists = cmd("EXISTS")
+            .arg(cache.get_queue_key(&address, buffer))
+            .query_async::<bool>(&mut connection)
+            .await
+            .unwrap();
+
+        teardown(&cache.test_key, connection).await;
+
+        assert!(cached.is_empty());
+        assert!(!queue_exists);
+    }
+}
@@ -1,2 +1,5 @@
+pub mod chunk_persister;
 pub mod message_persister;
+pub mod payload_persister;
+pub mod payload_sweeper;
 pub mod persister;
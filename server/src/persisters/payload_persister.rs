@@ -0,0 +1,158 @@
+use super::persister::{Persister, RunFlag};
+use crate::{
+    availability_listener::AvailabilityListener,
+    managers::{
+        denim::{buffer::Buffer, payload_cache::PayloadCache},
+        manager::{self, Manager},
+    },
+    storage::database::SignalDatabase,
+};
+use anyhow::Result;
+use libsignal_core::ProtocolAddress;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const QUEUE_BATCH_LIMIT: u8 = 100;
+const PAYLOAD_BATCH_LIMIT: u8 = 100;
+const PERSIST_DELAY: u64 = 600;
+
+#[derive(Debug)]
+pub struct PayloadPersister<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener + 'static,
+{
+    run_flag: RunFlag,
+    payload_cache: PayloadCache<U>,
+    db: T,
+}
+
+impl<T, U> Clone for PayloadPersister<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener,
+{
+    fn clone(&self) -> Self {
+        Self {
+            run_flag: self.run_flag.clone(),
+            payload_cache: self.payload_cache.clone(),
+            db: self.db.clone(),
+        }
+    }
+}
+
+/*
+ * Takes the payload queues from the cache that is >10 minutes old,
+ * removes them from the cache and puts them into the database
+*/
+impl<T, U> Persister<T, U> for PayloadPersister<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener + 'static,
+{
+    type Manager = Vec<Box<dyn Manager>>;
+
+    fn listen(db: T, managers: Self::Manager) -> PayloadPersister<T, U> {
+        let payload_cache = manager::get::<PayloadCache<U>>(&managers).unwrap().clone();
+
+        let mut payload_persister = PayloadPersister {
+            run_flag: Arc::new(AtomicBool::new(true)),
+            payload_cache,
+            db,
+        };
+
+        let payload_persister_clone = payload_persister.clone();
+
+        tokio::spawn(async move {
+            while payload_persister.run_flag.load(Ordering::Relaxed) {
+                let _ = payload_persister.persist().await;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+        payload_persister_clone
+    }
+
+    fn get_run_flag(&self) -> &RunFlag {
+        &self.run_flag
+    }
+
+    // Finds the payload queues where the oldest payload is >10 minutes old.
+    async fn persist(&mut self) -> Result<()> {
+        for buffer in [Buffer::Sender, Buffer::Receiver] {
+            let mut queues_to_persist;
+
+            while {
+                let time_in_secs: u64 = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Failed to get time")
+                    .as_secs();
+
+                queues_to_persist = self
+                    .payload_cache
+                    .get_queues_to_persist(buffer, time_in_secs - PERSIST_DELAY, QUEUE_BATCH_LIMIT)
+                    .await?;
+
+                !queues_to_persist.is_empty()
+            } {
+                for queue_key in &queues_to_persist {
+                    let (account_id, device_id) = self
+                        .payload_cache
+                        .get_account_and_device_id_from_queue_key(queue_key);
+
+                    let protocol_address =
+                        ProtocolAddress::new(account_id, device_id.parse::<u32>()?.into());
+
+                    // Payloads of deleted devices are dropped with the device
+                    if self.db.get_device(&protocol_address).await.is_err() {
+                        continue;
+                    }
+
+                    self.persist_queue(&protocol_address, buffer).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T, U> PayloadPersister<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener,
+{
+    /// Moves all payload data of a queue into the database.
+    async fn persist_queue(&mut self, address: &ProtocolAddress, buffer: Buffer) -> Result<()> {
+        self.payload_cache
+            .lock_queue_for_persistence(address, buffer)
+            .await?;
+
+        loop {
+            let (payloads, payload_guids) = self
+                .payload_cache
+                .get_payloads_to_persist(address, buffer, PAYLOAD_BATCH_LIMIT as i32)
+                .await?;
+
+            if payloads.is_empty() {
+                break;
+            }
+
+            self.db
+                .push_payload_queue(address, buffer, payloads)
+                .await?;
+            self.payload_cache
+                .remove_raw(address, buffer, payload_guids)
+                .await?;
+        }
+
+        self.payload_cache
+            .unlock_queue_for_persistence(address, buffer)
+            .await?;
+        Ok(())
+    }
+}
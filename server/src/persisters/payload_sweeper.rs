@@ -0,0 +1,93 @@
+use super::persister::{Persister, RunFlag};
+use crate::{
+    availability_listener::AvailabilityListener,
+    managers::{
+        denim::{buffer::Buffer, payload_cache::PayloadCache},
+        manager::{self, Manager},
+    },
+    storage::database::SignalDatabase,
+};
+use anyhow::Result;
+use std::{
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const SWEEP_INTERVAL: u64 = 60;
+
+#[derive(Debug)]
+pub struct PayloadSweeper<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener + 'static,
+{
+    run_flag: RunFlag,
+    payload_cache: PayloadCache<U>,
+    db: PhantomData<T>,
+}
+
+impl<T, U> Clone for PayloadSweeper<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener,
+{
+    fn clone(&self) -> Self {
+        Self {
+            run_flag: self.run_flag.clone(),
+            payload_cache: self.payload_cache.clone(),
+            db: PhantomData,
+        }
+    }
+}
+
+/*
+ * Purges expired deniable payloads from the receiver queues in the cache,
+ * persisted payloads are dropped when they are restored after they expired
+*/
+impl<T, U> Persister<T, U> for PayloadSweeper<T, U>
+where
+    T: SignalDatabase,
+    U: AvailabilityListener + 'static,
+{
+    type Manager = Vec<Box<dyn Manager>>;
+
+    fn listen(_: T, managers: Self::Manager) -> PayloadSweeper<T, U> {
+        let payload_cache = manager::get::<PayloadCache<U>>(&managers).unwrap().clone();
+
+        let mut payload_sweeper = PayloadSweeper {
+            run_flag: Arc::new(AtomicBool::new(true)),
+            payload_cache,
+            db: PhantomData,
+        };
+
+        let payload_sweeper_clone = payload_sweeper.clone();
+
+        tokio::spawn(async move {
+            while payload_sweeper.run_flag.load(Ordering::Relaxed) {
+                if let Err(err) = payload_sweeper.persist().await {
+                    eprintln!("Error: could not purge expired deniable payloads: {err}");
+                }
+                tokio::time::sleep(Duration::from_secs(SWEEP_INTERVAL)).await;
+            }
+        });
+        payload_sweeper_clone
+    }
+
+    fn get_run_flag(&self) -> &RunFlag {
+        &self.run_flag
+    }
+
+    async fn persist(&mut self) -> Result<()> {
+        let time_in_secs: u64 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        self.payload_cache
+            .purge_expired(Buffer::Receiver, time_in_secs)
+            .await?;
+
+        Ok(())
+    }
+}
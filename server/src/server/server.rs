@@ -7,13 +7,18 @@ use crate::{
     envelope::ToEnvelope,
     error::ApiError,
     managers::{
+        attachment_limits::AttachmentLimits,
+        denim::{chunk_cache::ChunkCache, payload_cache::PayloadCache},
         state::SignalServerState,
         websocket::{
             connection::{UserIdentity, WebSocketConnection},
             signal_websocket::SignalWebSocket,
         },
     },
-    persisters::{message_persister::MessagePersister, persister::Persister},
+    persisters::{
+        chunk_persister::ChunkPersister, message_persister::MessagePersister,
+        payload_persister::PayloadPersister, payload_sweeper::PayloadSweeper, persister::Persister,
+    },
     storage::database::SignalDatabase,
     storage::postgres::PostgresDatabase,
     validators::{
@@ -21,13 +26,14 @@ use crate::{
         pre_key_signature_validator::PreKeySignatureValidator,
     },
 };
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use axum::{
+    body::Bytes,
     debug_handler,
     extract::{
         connect_info::ConnectInfo,
         ws::{Message, WebSocketUpgrade},
-        Host, Path, Query, Request, State,
+        DefaultBodyLimit, Host, Path, Query, Request, State,
     },
     handler::HandlerWithoutStateExt,
     http::{
@@ -42,21 +48,27 @@ use axum::{
 use axum_extra::{headers, TypedHeader};
 use axum_server::tls_rustls::RustlsConfig;
 use base64::prelude::{Engine as _, BASE64_URL_SAFE, BASE64_URL_SAFE_NO_PAD};
+use common::attachment::is_attachment_blob_size;
 use common::deniable::chunk::ChunkType;
+use common::deniable::cover_traffic::{CoverEnvelopeSizes, CoverTrafficSchedule};
+use common::deniable::q_value::{QValuePolicy, QValueRange, Q_VALUE_HEADER};
+use common::deniable::quota::{DenimQuotas, QuotaStats};
+use common::deniable::scheduler::SchedulingStrategy;
+use common::signalservice::envelope;
 use common::web_api::{
-    authorization::BasicAuthorizationHeader, DenimMessages, DeviceCapabilityType,
-    DevicePreKeyBundle, LinkDeviceRequest, PreKeyCount, PreKeyResponse, RegistrationRequest,
-    RegistrationResponse, RegularPayload, SetKeyRequest, SignalMessage,
+    authorization::BasicAuthorizationHeader, AttachmentUploadResponse, DenimMessages,
+    DeviceCapabilityType, DevicePreKeyBundle, LinkDeviceRequest, PreKeyCount, PreKeyResponse,
+    RegistrationRequest, RegistrationResponse, RegularPayload, SetKeyRequest, SignalMessage,
 };
-use common::web_api::{DeniablePayload, DenimChunk};
+use common::web_api::{DeniablePayload, DenimChunk, IdentifierResponse};
 use common::websocket::wsstream::WSStream;
 use futures_util::StreamExt;
-use headers::authorization::Basic;
+use headers::authorization::{Basic, Bearer};
 use headers::Authorization;
 use hmac::{Hmac, Mac};
 use libsignal_core::{ProtocolAddress, ServiceId, ServiceIdKind};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::{
     env,
     fmt::Debug,
@@ -66,12 +78,14 @@ use std::{
 };
 use tower_http::compression::CompressionLayer;
 use tower_http::cors::CorsLayer;
+use uuid::Uuid;
 
 pub async fn handle_put_messages<T: SignalDatabase, U: WSStream<Message, axum::Error> + Debug>(
     state: &SignalServerState<T, U>,
     authenticated_device: &AuthenticatedDevice,
     destination_identifier: &ServiceId,
     payload: DenimMessages,
+    q_value: f32,
 ) -> Result<SendMessageResponse, ApiError> {
     if *destination_identifier == authenticated_device.account().pni() {
         return Err(ApiError {
@@ -80,6 +94,17 @@ pub async fn handle_put_messages<T: SignalDatabase, U: WSStream<Message, axum::E
         });
     }
 
+    let sender = authenticated_device.get_protocol_address(ServiceIdKind::Aci);
+    for message in &payload.messages {
+        state
+            .denim_manager
+            .check_incoming_message(&sender, message, q_value)
+            .map_err(|err| ApiError {
+                status_code: StatusCode::UNPROCESSABLE_ENTITY,
+                body: err.to_string(),
+            })?;
+    }
+
     let is_sync_message = *destination_identifier == authenticated_device.account().aci();
     let destination: Account = if is_sync_message {
         authenticated_device.account().clone()
@@ -157,18 +182,12 @@ pub async fn handle_put_messages<T: SignalDatabase, U: WSStream<Message, axum::E
             })?;
     }
 
-    handle_receiving_chunks(
-        state,
-        authenticated_device,
-        &destination,
-        chunks,
-        payload.timestamp,
-    )
-    .await
-    .map_err(|e| ApiError {
-        status_code: StatusCode::INTERNAL_SERVER_ERROR,
-        body: format!("internal error: {e}"),
-    })?;
+    handle_receiving_chunks(state, authenticated_device, chunks, payload.timestamp)
+        .await
+        .map_err(|e| ApiError {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            body: format!("internal error: {e}"),
+        })?;
 
     let needs_sync = !is_sync_message && authenticated_device.account().devices().len() > 1;
     Ok(SendMessageResponse { needs_sync })
@@ -180,7 +199,6 @@ pub async fn handle_receiving_chunks<
 >(
     state: &SignalServerState<T, U>,
     authenticated_device: &AuthenticatedDevice,
-    destination: &Account,
     chunks: Vec<DenimChunk>,
     payload_timestamp: u64,
 ) -> Result<()> {
@@ -193,17 +211,11 @@ pub async fn handle_receiving_chunks<
         return Ok(());
     };
 
-    let receiver_device_id = destination
-        .devices()
-        .first()
-        .ok_or_else(|| anyhow!("Error"))?
-        .device_id();
-
     let sender = authenticated_device.get_protocol_address(ServiceIdKind::Aci);
 
     let _ = state
         .denim_manager
-        .enqueue_incoming_chunk_buffer(&sender, chunks.clone())
+        .accept_incoming_chunks(&sender, chunks.clone())
         .await?;
 
     let has_final_chunk = chunks
@@ -214,47 +226,87 @@ pub async fn handle_receiving_chunks<
         return Ok(());
     }
 
+    state
+        .denim_manager
+        .restore_incoming_chunk_buffer(&state.db, &sender)
+        .await?;
+
     let deniable_payloads = state
         .denim_manager
         .flush_incoming_chunk_buffer(&sender)
         .await?;
 
-    // Hold deniable payloads before storing in cache
-    let mut account_payloads_map = HashMap::new();
+    // Hold deniable payloads for each receiving device and expiry before storing in cache
+    let mut device_payloads_map: HashMap<(ProtocolAddress, Option<u64>), Vec<DeniablePayload>> =
+        HashMap::new();
 
     for deniable_payload in deniable_payloads {
         match deniable_payload {
             DeniablePayload::KeyRequest(pre_key_request) => {
-                let receiver_service_id =
+                let Some(receiver_service_id) =
                     ServiceId::parse_from_service_id_string(&pre_key_request.service_id)
-                        .ok_or_else(|| anyhow!("Failed to get service id"))?;
-
-                let pre_key_response = state
+                else {
+                    eprintln!(
+                        "Key request for invalid service id {} dropped.",
+                        pre_key_request.service_id
+                    );
+                    continue;
+                };
+
+                let pre_key_response = match state
                     .key_manager
-                    .handle_get_keys_id_device_id(
-                        &state.db,
-                        &authenticated_device,
-                        receiver_service_id,
-                        receiver_device_id.to_string(),
-                    )
+                    .handle_get_deniable_keys(receiver_service_id)
                     .await
-                    .expect("Failed to create pre key response: {e}");
-
-                let sender_account = authenticated_device.account();
+                {
+                    Ok(pre_key_response) => pre_key_response,
+                    Err(err) => {
+                        eprintln!("Key request dropped: {err}.");
+                        continue;
+                    }
+                };
+
+                // Only the requesting device is waiting for the keys
                 let payload = DeniablePayload::KeyResponse(pre_key_response);
-                account_payloads_map
-                    .entry(sender_account.clone())
-                    .or_insert_with(Vec::new)
+                device_payloads_map
+                    .entry((sender.clone(), None))
+                    .or_default()
+                    .push(payload);
+            }
+            DeniablePayload::IdentifierRequest(identifier_request) => {
+                // The phone number is looked up together with the keys, so no regular
+                // request ties the sender to the contact. Accounts without deniable keys
+                // can not be reached deniably and are answered like unknown ones.
+                let keys = match state
+                    .account_manager
+                    .get_account_from_phonenumber_without_devices(&identifier_request.phone_number)
+                    .await
+                {
+                    Ok(receiver_account) => state
+                        .key_manager
+                        .handle_get_deniable_keys(receiver_account.aci().into())
+                        .await
+                        .ok(),
+                    Err(_) => None,
+                };
+
+                let payload = DeniablePayload::IdentifierResponse(IdentifierResponse {
+                    phone_number: identifier_request.phone_number,
+                    keys,
+                });
+                device_payloads_map
+                    .entry((sender.clone(), None))
+                    .or_default()
                     .push(payload);
             }
             DeniablePayload::SignalMessage(signal_message) => {
-                let receiver_service_id = ServiceId::parse_from_service_id_string(
-                    &signal_message
-                        .clone()
-                        .destination_service_id
-                        .expect("Failed to get destination ACI"),
-                )
-                .expect("Failed to parse string to ServiceId");
+                let Some(receiver_service_id) = signal_message
+                    .destination_service_id
+                    .as_deref()
+                    .and_then(ServiceId::parse_from_service_id_string)
+                else {
+                    eprintln!("Deniable message without valid destination dropped.");
+                    continue;
+                };
                 let sender_account = authenticated_device.account();
                 let sender_device_id = u32::from(authenticated_device.device().device_id()) as u8;
 
@@ -266,33 +318,174 @@ pub async fn handle_receiving_chunks<
                     false,
                 );
 
-                let receiver_account = state
+                let Ok(receiver_account) = state
                     .account_manager
                     .get_account(&receiver_service_id)
-                    .await?;
+                    .await
+                else {
+                    eprintln!(
+                        "Deniable message for unknown account {} dropped.",
+                        receiver_service_id.service_id_string()
+                    );
+                    continue;
+                };
+                let Some(receiver) = deniable_message_receiver(&receiver_account, &signal_message)
+                else {
+                    eprintln!(
+                        "Deniable message for unknown device {} dropped.",
+                        signal_message.destination_device_id
+                    );
+                    continue;
+                };
                 let payload = DeniablePayload::Envelope(envelope);
-                account_payloads_map
-                    .entry(receiver_account)
-                    .or_insert_with(Vec::new)
+                device_payloads_map
+                    .entry((receiver, signal_message.expires_at))
+                    .or_default()
                     .push(payload);
             }
+            DeniablePayload::GroupMessage(group_message) => {
+                // The group only exists between its members, the message is delivered
+                // to each member like a deniable message of its own
+                let signal_message = SignalMessage {
+                    r#type: envelope::Type::KeyExchange.into(),
+                    content: group_message.content,
+                    ..Default::default()
+                };
+                let sender_account = authenticated_device.account();
+                let sender_device_id = u32::from(authenticated_device.device().device_id()) as u8;
+                let members = group_message
+                    .members
+                    .iter()
+                    .filter_map(|member| ServiceId::parse_from_service_id_string(member))
+                    .collect::<HashSet<ServiceId>>();
+
+                for member_service_id in members {
+                    let Ok(member_account) =
+                        state.account_manager.get_account(&member_service_id).await
+                    else {
+                        eprintln!(
+                            "Deniable group message for unknown account {} dropped.",
+                            member_service_id.service_id_string()
+                        );
+                        continue;
+                    };
+                    let envelope = signal_message.to_envelope(
+                        &member_service_id,
+                        sender_account,
+                        sender_device_id,
+                        payload_timestamp,
+                        false,
+                    );
+                    for receiver in deniable_group_receivers(&member_account, &sender) {
+                        device_payloads_map
+                            .entry((receiver, group_message.expires_at))
+                            .or_default()
+                            .push(DeniablePayload::Envelope(envelope.clone()));
+                    }
+                }
+            }
+            DeniablePayload::SyncMessage(sync_message) => {
+                // Sync messages are delivered to the other devices of the sending account,
+                // like the regular sync messages sent to the own ACI
+                let sender_account = authenticated_device.account();
+                let sender_device_id = u32::from(authenticated_device.device().device_id()) as u8;
+                let Some(receivers) =
+                    deniable_sync_receivers(sender_account, &sender, &sync_message.messages)
+                else {
+                    eprintln!("Deniable sync message for mismatched devices dropped.");
+                    continue;
+                };
+                let own_service_id = ServiceId::from(sender_account.aci());
+                for (receiver, signal_message) in receivers.into_iter().zip(&sync_message.messages)
+                {
+                    let envelope = signal_message.to_envelope(
+                        &own_service_id,
+                        sender_account,
+                        sender_device_id,
+                        payload_timestamp,
+                        false,
+                    );
+                    device_payloads_map
+                        .entry((receiver, sync_message.expires_at))
+                        .or_default()
+                        .push(DeniablePayload::Envelope(envelope));
+                }
+            }
+            DeniablePayload::KeyUpload(key_upload) => {
+                if let Err(err) = state
+                    .key_manager
+                    .handle_put_deniable_keys(&sender, key_upload)
+                    .await
+                {
+                    eprintln!("Deniable key upload dropped: {err}.");
+                }
+            }
             payload => eprintln!("Payload not supported: {:?}.", payload),
         }
     }
 
-    for (account, payloads) in account_payloads_map {
-        for device in account.devices() {
-            let address = account.get_protocol_address(ServiceIdKind::Aci, device.device_id());
-            let _ = state
-                .denim_manager
-                .enqueue_outgoing_payload_buffer(&address, payloads.clone())
-                .await?;
-        }
+    for ((address, expires_at), payloads) in device_payloads_map {
+        let _ = state
+            .denim_manager
+            .enqueue_outgoing_payload_buffer(&address, &sender, payloads, expires_at)
+            .await?;
     }
 
     Ok(())
 }
 
+/// Address of the device a deniable message is encrypted for,
+/// `None` when the account has no such device
+fn deniable_message_receiver(
+    account: &Account,
+    signal_message: &SignalMessage,
+) -> Option<ProtocolAddress> {
+    account
+        .devices()
+        .iter()
+        .map(|device| device.device_id())
+        .find(|device_id| u32::from(*device_id) == signal_message.destination_device_id)
+        .map(|device_id| account.get_protocol_address(ServiceIdKind::Aci, device_id))
+}
+
+/// Addresses of the devices of a deniable group member, leaving out the sending device
+fn deniable_group_receivers(account: &Account, sender: &ProtocolAddress) -> Vec<ProtocolAddress> {
+    account
+        .devices()
+        .iter()
+        .map(|device| account.get_protocol_address(ServiceIdKind::Aci, device.device_id()))
+        .filter(|address| address != sender)
+        .collect()
+}
+
+/// Addresses of the other devices of the sending account, one for each sync message.
+/// `None` unless the messages cover exactly those devices, once each.
+fn deniable_sync_receivers(
+    account: &Account,
+    sender: &ProtocolAddress,
+    messages: &[SignalMessage],
+) -> Option<Vec<ProtocolAddress>> {
+    let message_device_ids: Vec<u32> = messages
+        .iter()
+        .map(|message| message.destination_device_id)
+        .collect();
+    if message_device_ids.iter().collect::<HashSet<_>>().len() != message_device_ids.len() {
+        return None;
+    }
+    DestinationDeviceValidator::validate_complete_device_list(
+        account,
+        &message_device_ids,
+        &[u32::from(sender.device_id())],
+    )
+    .ok()?;
+    DestinationDeviceValidator::validate_registration_id_from_messages(account, messages, false)
+        .ok()?;
+    messages
+        .iter()
+        .map(|message| deniable_message_receiver(account, message))
+        .collect()
+}
+
 pub async fn handle_keepalive<T: SignalDatabase, U: WSStream<Message, axum::Error> + Debug>(
     state: &SignalServerState<T, U>,
     authenticated_device: &AuthenticatedDevice,
@@ -561,6 +754,96 @@ async fn handle_post_link_device<T: SignalDatabase, U: WSStream<Message, axum::E
     })
 }
 
+async fn handle_put_attachment<T: SignalDatabase, U: WSStream<Message, axum::Error> + Debug>(
+    state: &SignalServerState<T, U>,
+    authenticated_device: &AuthenticatedDevice,
+    blob: &[u8],
+) -> Result<AttachmentUploadResponse, ApiError> {
+    let limits = state.attachment_limits;
+    if blob.len() > limits.max_blob_size {
+        return Err(ApiError {
+            status_code: StatusCode::PAYLOAD_TOO_LARGE,
+            body: format!("Attachments can be at most {} bytes", limits.max_blob_size),
+        });
+    }
+    // Every attachment is padded and encrypted the same way, so deniable attachments
+    // can not be told apart from regular ones. Blobs of other sizes would stand out.
+    if !is_attachment_blob_size(blob.len()) {
+        return Err(ApiError {
+            status_code: StatusCode::BAD_REQUEST,
+            body: "Attachment is not padded".to_owned(),
+        });
+    }
+
+    // Expired blobs are deleted here, so they never count towards a quota
+    let now = (time_now()? / 1000) as u64;
+    state
+        .db
+        .delete_expired_attachments(now)
+        .await
+        .map_err(|err| ApiError {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            body: format!("Could not delete expired attachments: {err}"),
+        })?;
+
+    let cdn_key = Uuid::new_v4().to_string();
+    let stored = state
+        .db
+        .store_attachment(
+            &authenticated_device.account().aci(),
+            &cdn_key,
+            blob,
+            now + limits.ttl,
+            &limits.quota,
+        )
+        .await
+        .map_err(|err| ApiError {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            body: format!("Could not store attachment: {err}"),
+        })?;
+    if !stored {
+        return Err(ApiError {
+            status_code: StatusCode::TOO_MANY_REQUESTS,
+            body: "Attachment quota exceeded".to_owned(),
+        });
+    }
+    Ok(AttachmentUploadResponse { cdn_key })
+}
+
+async fn handle_get_attachment<T: SignalDatabase, U: WSStream<Message, axum::Error> + Debug>(
+    state: &SignalServerState<T, U>,
+    cdn_key: &str,
+) -> Result<Vec<u8>, ApiError> {
+    // Blobs are kept until they expire, every device of every receiver downloads them
+    let now = (time_now()? / 1000) as u64;
+    state
+        .db
+        .get_attachment(cdn_key, now)
+        .await
+        .map_err(|err| ApiError {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            body: format!("Could not get attachment: {err}"),
+        })?
+        .ok_or_else(|| ApiError {
+            status_code: StatusCode::NOT_FOUND,
+            body: "Attachment not found".to_owned(),
+        })
+}
+
+/// Deniable data dropped over the quotas, only for operators with the admin token
+fn handle_get_denim_quota<T: SignalDatabase, U: WSStream<Message, axum::Error> + Debug>(
+    state: &SignalServerState<T, U>,
+    token: Option<&str>,
+) -> Result<QuotaStats, ApiError> {
+    if state.admin_token.is_none() || state.admin_token.as_deref() != token {
+        return Err(ApiError {
+            status_code: StatusCode::UNAUTHORIZED,
+            body: "".to_owned(),
+        });
+    }
+    Ok(state.denim_manager.quota_stats())
+}
+
 async fn handle_delete_account<T: SignalDatabase, U: WSStream<Message, axum::Error> + Debug>(
     state: SignalServerState<T, U>,
     authenticated_device: AuthenticatedDevice,
@@ -671,14 +954,21 @@ async fn put_messages_endpoint(
     State(state): State<SignalServerState<PostgresDatabase, SignalWebSocket>>,
     authenticated_device: AuthenticatedDevice,
     Path(destination_identifier): Path<String>,
-    Json(payload): Json<DenimMessages>,
+    body: Bytes,
 ) -> Result<SendMessageResponse, ApiError> {
     let destination_identifier = parse_service_id(destination_identifier)?;
+    let payload = DenimMessages::decode(&body).map_err(|err| ApiError {
+        status_code: StatusCode::BAD_REQUEST,
+        body: err,
+    })?;
+    // Messages sent outside a websocket connection use the default q value
+    let q_value = state.denim_manager.negotiate_q_value(None);
     handle_put_messages(
         &state,
         &authenticated_device,
         &destination_identifier,
         payload,
+        q_value,
     )
     .await
 }
@@ -832,6 +1122,28 @@ async fn post_link_device_endpoint(
     handle_post_link_device(state, basic, link_device_request).await
 }
 
+/// Handler for the PUT v1/attachments endpoint.
+#[debug_handler]
+async fn put_attachment_endpoint(
+    State(state): State<SignalServerState<PostgresDatabase, SignalWebSocket>>,
+    authenticated_device: AuthenticatedDevice,
+    body: Bytes,
+) -> Result<Json<AttachmentUploadResponse>, ApiError> {
+    handle_put_attachment(&state, &authenticated_device, &body)
+        .await
+        .map(Json)
+}
+
+/// Handler for the GET v1/attachments/{cdn_key} endpoint.
+#[debug_handler]
+async fn get_attachment_endpoint(
+    State(state): State<SignalServerState<PostgresDatabase, SignalWebSocket>>,
+    _authenticated_device: AuthenticatedDevice,
+    Path(cdn_key): Path<String>,
+) -> Result<Vec<u8>, ApiError> {
+    handle_get_attachment(&state, &cdn_key).await
+}
+
 /// Websocket upgrade handler '/v1/websocket'
 #[debug_handler]
 async fn create_websocket_endpoint(
@@ -839,13 +1151,26 @@ async fn create_websocket_endpoint(
     authenticated_device: AuthenticatedDevice,
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
+    headers: HeaderMap,
     ConnectInfo(socket_addr): ConnectInfo<SocketAddr>,
-) -> impl IntoResponse {
+) -> Response {
     let user_agent = match user_agent {
         Some(TypedHeader(user_agent)) => user_agent.to_string(),
         None => "Unknown browser".to_string(),
     };
-    let q_value = state.denim_manager.chunker.q_value.to_string();
+
+    // Clients may propose a q value or a range of q values
+    let proposal = match headers.get(Q_VALUE_HEADER).map(|value| {
+        value
+            .to_str()
+            .map_err(|err| err.to_string())
+            .and_then(QValueRange::parse)
+    }) {
+        None => None,
+        Some(Ok(proposal)) => Some(proposal),
+        Some(Err(err)) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let q_value = state.denim_manager.negotiate_q_value(proposal);
 
     println!("`{user_agent}` at {socket_addr} connected.");
 
@@ -860,6 +1185,7 @@ async fn create_websocket_endpoint(
                 UserIdentity::AuthenticatedDevice(authenticated_device.into()),
                 socket_addr,
                 sender,
+                q_value,
                 state.clone(),
             );
 
@@ -888,11 +1214,23 @@ async fn create_websocket_endpoint(
                 .await;
         }
     });
-    res.headers_mut()
-        .append("q-value", HeaderValue::from_str(&q_value).unwrap());
+    res.headers_mut().append(
+        Q_VALUE_HEADER,
+        HeaderValue::from_str(&q_value.to_string()).unwrap(),
+    );
     res
 }
 
+/// Handler for the GET v1/denim/quota endpoint.
+#[debug_handler]
+async fn get_denim_quota_endpoint(
+    State(state): State<SignalServerState<PostgresDatabase, SignalWebSocket>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<QuotaStats>, ApiError> {
+    let token = bearer.as_ref().map(|TypedHeader(bearer)| bearer.token());
+    handle_get_denim_quota(&state, token).map(Json)
+}
+
 #[debug_handler]
 pub async fn get_keepalive(
     State(state): State<SignalServerState<PostgresDatabase, SignalWebSocket>>,
@@ -950,8 +1288,24 @@ pub async fn start_server(use_tls: bool) -> Result<(), Box<dyn std::error::Error
         ]);
 
     dotenv::dotenv()?;
-    let q_value = env::var("Q_VALUE").unwrap_or("0.6".to_owned()).parse()?;
-    let state = SignalServerState::<PostgresDatabase, SignalWebSocket>::new(q_value).await;
+    let q_value_policy = QValuePolicy::from_env("Q_VALUE", "Q_VALUE_RANGE")?;
+    let cover_traffic = CoverTrafficSchedule::from_env("DENIM_COVER_TRAFFIC")?;
+    let cover_sizes = CoverEnvelopeSizes::from_env("DENIM_COVER_SIZES")?;
+    let payload_scheduling = SchedulingStrategy::from_env("DENIM_PAYLOAD_SCHEDULING")?;
+    let quotas = DenimQuotas::from_env("DENIM_SENDER_QUOTA", "DENIM_RECEIVER_QUOTA")?;
+    let attachment_limits =
+        AttachmentLimits::from_env("ATTACHMENT_QUOTA", "ATTACHMENT_MAX_SIZE", "ATTACHMENT_TTL")?;
+    let admin_token = env::var("ADMIN_TOKEN").ok();
+    let mut state =
+        SignalServerState::<PostgresDatabase, SignalWebSocket>::new(q_value_policy).await;
+    state.denim_manager.set_cover_traffic(cover_traffic);
+    state.denim_manager.set_cover_sizes(cover_sizes);
+    state
+        .denim_manager
+        .set_payload_scheduling(payload_scheduling);
+    state.denim_manager.set_quotas(quotas);
+    state.attachment_limits = attachment_limits;
+    state.admin_token = admin_token;
 
     let message_persister = MessagePersister::<
         PostgresDatabase,
@@ -965,10 +1319,45 @@ pub async fn start_server(use_tls: bool) -> Result<(), Box<dyn std::error::Error
         ],
     );
 
+    let chunk_persister = ChunkPersister::<
+        PostgresDatabase,
+        WebSocketConnection<SignalWebSocket, PostgresDatabase>,
+    >::listen(
+        state.db.clone(),
+        vec![Box::new(ChunkCache::from(state.message_cache.clone()))],
+    );
+
+    let payload_persister = PayloadPersister::<
+        PostgresDatabase,
+        WebSocketConnection<SignalWebSocket, PostgresDatabase>,
+    >::listen(
+        state.db.clone(),
+        vec![Box::new(PayloadCache::from(state.message_cache.clone()))],
+    );
+
+    let payload_sweeper = PayloadSweeper::<
+        PostgresDatabase,
+        WebSocketConnection<SignalWebSocket, PostgresDatabase>,
+    >::listen(
+        state.db.clone(),
+        vec![Box::new(PayloadCache::from(state.message_cache.clone()))],
+    );
+
+    // Routes carrying DenIM messages are never compressed,
+    // compressed sizes would reveal whether a message carried deniable chunks
+    let denim_routes = Router::new()
+        .route("/v1/messages/:destination", put(put_messages_endpoint))
+        .route("/v1/websocket", any(create_websocket_endpoint));
+
+    // Attachment blobs are encrypted, so compressing them gains nothing
+    let attachment_routes = Router::new()
+        .route("/v1/attachments", put(put_attachment_endpoint))
+        .route("/v1/attachments/:cdn_key", get(get_attachment_endpoint))
+        .layer(DefaultBodyLimit::max(attachment_limits.max_blob_size));
+
     let app = Router::new()
         .route("/", get(|| async { "Hello from Signal Server" }))
         .route("/v1/identifier/:phone_number", get(get_identifier_endpoint))
-        .route("/v1/messages/:destination", put(put_messages_endpoint))
         .route("/v1/registration", post(post_registration_endpoint))
         .route(
             "/v2/keys/:identifier/:device_id",
@@ -981,10 +1370,12 @@ pub async fn start_server(use_tls: bool) -> Result<(), Box<dyn std::error::Error
         .route("/v1/devices/provisioning/code", get(get_link_device_token))
         .route("/v1/devices/link", post(post_link_device_endpoint))
         .route("/v1/devices/:device_id", delete(delete_device_endpoint))
-        .route("/v1/websocket", any(create_websocket_endpoint))
         .route("/v1/keepalive", get(get_keepalive))
-        .with_state(state)
+        .route("/v1/denim/quota", get(get_denim_quota_endpoint))
         .layer(CompressionLayer::new().gzip(true))
+        .merge(denim_routes)
+        .merge(attachment_routes)
+        .with_state(state)
         .layer(cors)
         .layer(from_fn(signal_time_middleware));
 
@@ -1014,6 +1405,9 @@ pub async fn start_server(use_tls: bool) -> Result<(), Box<dyn std::error::Error
     }
 
     message_persister.stop().await;
+    chunk_persister.stop().await;
+    payload_persister.stop().await;
+    payload_sweeper.stop().await;
 
     Ok(())
 }
@@ -1043,6 +1437,213 @@ fn get_kind(identity_string: String) -> Result<ServiceIdKind, ApiError> {
 
 #[cfg(test)]
 mod server_tests {
+    use super::{
+        deniable_group_receivers, deniable_message_receiver, deniable_sync_receivers,
+        handle_get_attachment, handle_get_denim_quota, handle_put_attachment,
+    };
+    use crate::{
+        managers::{attachment_limits::AttachmentLimits, state::SignalServerState},
+        storage::{database::SignalDatabase, postgres::PostgresDatabase},
+        test_utils::{
+            user::{new_account, new_authenticated_device, new_device},
+            websocket::MockSocket,
+        },
+    };
+    use axum::http::StatusCode;
+    use common::{
+        attachment::encrypt_attachment,
+        deniable::{q_value::QValuePolicy, quota::BufferQuota},
+        web_api::SignalMessage,
+    };
+    use libsignal_core::ServiceIdKind;
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    async fn connect_state() -> SignalServerState<PostgresDatabase, MockSocket> {
+        SignalServerState::<PostgresDatabase, MockSocket>::connect(
+            "DATABASE_URL_TEST",
+            QValuePolicy::default(),
+        )
+        .await
+    }
+
+    #[test]
+    fn deniable_message_receiver_routes_to_destination_device() {
+        let mut account = new_account();
+        let second_device = new_device();
+        account.add_device(second_device.clone()).unwrap();
+
+        let message = SignalMessage {
+            destination_device_id: second_device.device_id().into(),
+            ..Default::default()
+        };
+        let unknown_device_message = SignalMessage {
+            destination_device_id: account.get_next_device_id(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            deniable_message_receiver(&account, &message),
+            Some(account.get_protocol_address(ServiceIdKind::Aci, second_device.device_id()))
+        );
+        assert_eq!(
+            deniable_message_receiver(&account, &unknown_device_message),
+            None
+        );
+    }
+
+    #[test]
+    fn deniable_group_receivers_leave_out_sending_device() {
+        let mut account = new_account();
+        let second_device = new_device();
+        account.add_device(second_device.clone()).unwrap();
+        let first_address =
+            account.get_protocol_address(ServiceIdKind::Aci, account.devices()[0].device_id());
+        let second_address =
+            account.get_protocol_address(ServiceIdKind::Aci, second_device.device_id());
+        let other_address =
+            new_account().get_protocol_address(ServiceIdKind::Aci, second_device.device_id());
+
+        assert_eq!(
+            deniable_group_receivers(&account, &first_address),
+            vec![second_address.clone()]
+        );
+        assert_eq!(
+            deniable_group_receivers(&account, &other_address),
+            vec![first_address, second_address]
+        );
+    }
+
+    #[test]
+    fn deniable_sync_receivers_cover_other_own_devices() {
+        let mut account = new_account();
+        let second_device = new_device();
+        let third_device = new_device();
+        account.add_device(second_device.clone()).unwrap();
+        account.add_device(third_device.clone()).unwrap();
+        let sender =
+            account.get_protocol_address(ServiceIdKind::Aci, account.devices()[0].device_id());
+        let message_for = |device_id: u32| SignalMessage {
+            destination_device_id: device_id,
+            ..Default::default()
+        };
+        let second = message_for(second_device.device_id().into());
+        let third = message_for(third_device.device_id().into());
+
+        assert_eq!(
+            deniable_sync_receivers(&account, &sender, &[third.clone(), second.clone()]),
+            Some(vec![
+                account.get_protocol_address(ServiceIdKind::Aci, third_device.device_id()),
+                account.get_protocol_address(ServiceIdKind::Aci, second_device.device_id()),
+            ])
+        );
+        assert_eq!(
+            deniable_sync_receivers(&account, &sender, std::slice::from_ref(&second)),
+            None
+        );
+        assert_eq!(
+            deniable_sync_receivers(
+                &account,
+                &sender,
+                &[
+                    second.clone(),
+                    third.clone(),
+                    message_for(sender.device_id().into())
+                ]
+            ),
+            None
+        );
+        assert_eq!(
+            deniable_sync_receivers(&account, &sender, &[second.clone(), second, third]),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_put_attachment_stores_blob_for_download() {
+        let state = connect_state().await;
+        let uploader = new_authenticated_device();
+        let blob = encrypt_attachment(b"attachment", &mut OsRng).blob;
+        state.db.add_account(uploader.account()).await.unwrap();
+
+        let upload = handle_put_attachment(&state, &uploader, &blob).await;
+        let download = match &upload {
+            Ok(upload) => Some(handle_get_attachment(&state, &upload.cdn_key).await),
+            Err(_) => None,
+        };
+        state
+            .db
+            .delete_account(&uploader.account().aci().into())
+            .await
+            .unwrap();
+
+        assert!(upload.is_ok());
+        assert_eq!(download.unwrap().unwrap(), blob);
+    }
+
+    #[tokio::test]
+    async fn handle_get_denim_quota_needs_admin_token() {
+        let mut state = connect_state().await;
+
+        let disabled = handle_get_denim_quota(&state, None);
+        state.admin_token = Some("secret".to_owned());
+        let missing = handle_get_denim_quota(&state, None);
+        let wrong = handle_get_denim_quota(&state, Some("guess"));
+        let stats = handle_get_denim_quota(&state, Some("secret"));
+
+        assert_eq!(disabled.unwrap_err().status_code, StatusCode::UNAUTHORIZED);
+        assert_eq!(missing.unwrap_err().status_code, StatusCode::UNAUTHORIZED);
+        assert_eq!(wrong.unwrap_err().status_code, StatusCode::UNAUTHORIZED);
+        assert_eq!(stats.unwrap(), Default::default());
+    }
+
+    #[tokio::test]
+    async fn handle_get_attachment_of_unknown_key_is_not_found() {
+        let state = connect_state().await;
+
+        let download = handle_get_attachment(&state, &Uuid::new_v4().to_string()).await;
+
+        assert_eq!(download.unwrap_err().status_code, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn handle_put_attachment_rejects_oversized_blob() {
+        let mut state = connect_state().await;
+        state.attachment_limits.max_blob_size = AttachmentLimits::max_blob_size_for(1024).unwrap();
+        let uploader = new_authenticated_device();
+        let blob = encrypt_attachment(&[0; 4096], &mut OsRng).blob;
+
+        let upload = handle_put_attachment(&state, &uploader, &blob).await;
+
+        assert_eq!(
+            upload.unwrap_err().status_code,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_put_attachment_enforces_quota() {
+        let mut state = connect_state().await;
+        state.attachment_limits.quota = BufferQuota::new(1024 * 1024, 1);
+        let uploader = new_authenticated_device();
+        let blob = encrypt_attachment(b"attachment", &mut OsRng).blob;
+        state.db.add_account(uploader.account()).await.unwrap();
+
+        let first = handle_put_attachment(&state, &uploader, &blob).await;
+        let second = handle_put_attachment(&state, &uploader, &blob).await;
+        state
+            .db
+            .delete_account(&uploader.account().aci().into())
+            .await
+            .unwrap();
+
+        assert!(first.is_ok());
+        assert_eq!(
+            second.unwrap_err().status_code,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
     #[ignore = "Not implemented"]
     #[tokio::test]
     async fn handle_register_account_registers_account() {
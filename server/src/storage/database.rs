@@ -1,8 +1,15 @@
-use crate::account::{Account, Device};
+use crate::{
+    account::{Account, Device},
+    managers::denim::buffer::Buffer,
+};
 use anyhow::Result;
 use axum::async_trait;
+use common::deniable::quota::BufferQuota;
 use common::signalservice::Envelope;
-use common::web_api::{DeviceCapabilityType, DevicePreKeyBundle, UploadPreKey, UploadSignedPreKey};
+use common::web_api::{
+    DeniableDevicePreKeyBundle, DeniableKeyUpload, DenimChunk, DeviceCapabilityType,
+    DevicePreKeyBundle, PayloadData, UploadPreKey, UploadSignedPreKey,
+};
 use libsignal_core::{Aci, Pni, ProtocolAddress, ServiceId};
 
 /// Represents a database connection that can store objects related to the signal protocol.
@@ -118,6 +125,46 @@ pub trait SignalDatabase: Clone + Send + Sync + 'static {
 
     async fn get_one_time_pq_pre_key(&self, owner: &ProtocolAddress) -> Result<UploadSignedPreKey>;
 
+    /// Store the deniable identity key of the account and the deniable prekeys of the device
+    /// that corrosponds to the given [ProtocolAddress], replacing the ones uploaded before.
+    async fn store_deniable_key_upload(
+        &self,
+        upload: &DeniableKeyUpload,
+        owner: &ProtocolAddress,
+    ) -> Result<()>;
+
+    /// Get the deniable prekeys of the device that corrosponds to the given [ProtocolAddress],
+    /// `None` if it never uploaded any.
+    async fn get_deniable_key_bundle(
+        &self,
+        address: &ProtocolAddress,
+    ) -> Result<Option<DeniableDevicePreKeyBundle>>;
+
+    /// Get a deniable one time prekey of the device that is associated with the given
+    /// [ProtocolAddress].
+    async fn get_deniable_one_time_ec_pre_key(
+        &self,
+        owner: &ProtocolAddress,
+    ) -> Result<Option<UploadPreKey>>;
+
+    /// Store an encrypted attachment blob under `cdn_key` until `expires_at`, if the blobs
+    /// the uploader has stored leave room for it within `quota`. Returns whether it was stored.
+    async fn store_attachment(
+        &self,
+        uploader: &Aci,
+        cdn_key: &str,
+        blob: &[u8],
+        expires_at: u64,
+        quota: &BufferQuota,
+    ) -> Result<bool>;
+
+    /// Get the attachment blob stored under `cdn_key`, `None` if there is none
+    /// or it expired before `now`.
+    async fn get_attachment(&self, cdn_key: &str, now: u64) -> Result<Option<Vec<u8>>>;
+
+    /// Delete the attachment blobs that expired before `now`.
+    async fn delete_expired_attachments(&self, now: u64) -> Result<()>;
+
     /// Get number of messages for associated [ProtocolAddress]
     async fn count_messages(&self, address: &ProtocolAddress) -> Result<u32>;
 
@@ -126,4 +173,34 @@ pub trait SignalDatabase: Clone + Send + Sync + 'static {
 
     /// Delete and get all messages for associated [ProtocolAddress]
     async fn delete_messages(&self, address: &ProtocolAddress) -> Result<Vec<Envelope>>;
+
+    /// Store chunks from a DenIM chunk buffer for associated [ProtocolAddress]
+    async fn push_chunk_queue(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        chunks: Vec<DenimChunk>,
+    ) -> Result<()>;
+
+    /// Delete and get all chunks of a DenIM chunk buffer in insertion order
+    async fn pop_chunk_queue(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+    ) -> Result<Vec<DenimChunk>>;
+
+    /// Store payload data and its expiry from a DenIM payload buffer for associated [ProtocolAddress]
+    async fn push_payload_queue(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        payloads: Vec<(PayloadData, Option<u64>)>,
+    ) -> Result<()>;
+
+    /// Delete and get all payload data and its expiry of a DenIM payload buffer in insertion order
+    async fn pop_payload_queue(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+    ) -> Result<Vec<(PayloadData, Option<u64>)>>;
 }
@@ -1,12 +1,17 @@
 use crate::{
     account::{Account, Device},
+    managers::denim::buffer::Buffer,
     storage::database::SignalDatabase,
 };
 use anyhow::{anyhow, bail, Result};
 use axum::async_trait;
 use common::{
+    deniable::quota::BufferQuota,
     signalservice::Envelope,
-    web_api::{DeviceCapabilityType, DevicePreKeyBundle, UploadPreKey, UploadSignedPreKey},
+    web_api::{
+        DeniableDevicePreKeyBundle, DeniableKeyUpload, DenimChunk, DeviceCapabilityType,
+        DevicePreKeyBundle, PayloadData, UploadPreKey, UploadSignedPreKey,
+    },
 };
 use libsignal_core::{Aci, Pni, ProtocolAddress, ServiceId};
 use libsignal_protocol::{IdentityKey, PublicKey};
@@ -867,6 +872,278 @@ impl SignalDatabase for PostgresDatabase {
         .map_err(|err| err.into())
     }
 
+    async fn store_deniable_key_upload(
+        &self,
+        upload: &DeniableKeyUpload,
+        owner: &ProtocolAddress,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO deniable_device_keys (owner, identity_key, registration_id, signed_pre_key_id, signed_pre_key, signed_pre_key_signature, pq_last_resort_pre_key_id, pq_last_resort_pre_key, pq_last_resort_pre_key_signature)
+            SELECT id, 
+                   $10, 
+                   $3, 
+                   $4, 
+                   $5, 
+                   $6, 
+                   $7, 
+                   $8, 
+                   $9
+            FROM devices
+            WHERE owner = 
+                    (SELECT id
+                     FROM accounts
+                     WHERE aci = $1 
+                        OR pni = $1)
+              AND devices.device_id = $2
+            ON CONFLICT (owner) DO UPDATE
+            SET identity_key = EXCLUDED.identity_key,
+                registration_id = EXCLUDED.registration_id,
+                signed_pre_key_id = EXCLUDED.signed_pre_key_id,
+                signed_pre_key = EXCLUDED.signed_pre_key,
+                signed_pre_key_signature = EXCLUDED.signed_pre_key_signature,
+                pq_last_resort_pre_key_id = EXCLUDED.pq_last_resort_pre_key_id,
+                pq_last_resort_pre_key = EXCLUDED.pq_last_resort_pre_key,
+                pq_last_resort_pre_key_signature = EXCLUDED.pq_last_resort_pre_key_signature
+            "#,
+            owner.name(),
+            owner.device_id().to_string(),
+            upload.registration_id.to_string(),
+            upload.signed_pre_key.key_id.to_string(),
+            &*upload.signed_pre_key.public_key,
+            &*upload.signed_pre_key.signature,
+            upload.pq_last_resort_pre_key.key_id.to_string(),
+            &*upload.pq_last_resort_pre_key.public_key,
+            &*upload.pq_last_resort_pre_key.signature,
+            &*upload.identity_key,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE 
+            FROM deniable_one_time_ec_pre_key_store
+            WHERE owner = 
+                (SELECT id
+                 FROM devices
+                 WHERE owner = 
+                         (SELECT id
+                         FROM accounts
+                         WHERE aci = $1 
+                            OR pni = $1)
+                   AND devices.device_id = $2)
+            "#,
+            owner.name(),
+            owner.device_id().to_string(),
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        for otpk in upload.pre_keys.iter() {
+            sqlx::query!(
+                r#"
+                INSERT INTO deniable_one_time_ec_pre_key_store (owner, key_id, public_key)
+                SELECT id, 
+                       $3, 
+                       $4
+                FROM devices
+                WHERE owner = 
+                        (SELECT id
+                         FROM accounts
+                         WHERE aci = $1 
+                            OR pni = $1)
+                  AND devices.device_id = $2
+                "#,
+                owner.name(),
+                owner.device_id().to_string(),
+                otpk.key_id.to_string(),
+                &*otpk.public_key,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await.map_err(|err| err.into())
+    }
+
+    async fn get_deniable_key_bundle(
+        &self,
+        address: &ProtocolAddress,
+    ) -> Result<Option<DeniableDevicePreKeyBundle>> {
+        sqlx::query!(
+            r#"
+            SELECT deniable_device_keys.identity_key,
+                   deniable_device_keys.registration_id,
+                   deniable_device_keys.signed_pre_key_id,
+                   deniable_device_keys.signed_pre_key,
+                   deniable_device_keys.signed_pre_key_signature,
+                   deniable_device_keys.pq_last_resort_pre_key_id,
+                   deniable_device_keys.pq_last_resort_pre_key,
+                   deniable_device_keys.pq_last_resort_pre_key_signature
+            FROM deniable_device_keys
+            INNER JOIN devices ON devices.id = deniable_device_keys.owner
+            WHERE devices.owner = 
+                    (SELECT id
+                    FROM accounts
+                    WHERE aci = $1 
+                       OR pni = $1)
+              AND devices.device_id = $2
+            "#,
+            address.name(),
+            address.device_id().to_string(),
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| {
+            row.map(|row| DeniableDevicePreKeyBundle {
+                identity_key: row.identity_key.into(),
+                registration_id: row.registration_id.parse().unwrap(),
+                signed_pre_key: UploadSignedPreKey {
+                    key_id: row.signed_pre_key_id.parse().unwrap(),
+                    public_key: row.signed_pre_key.into(),
+                    signature: row.signed_pre_key_signature.into(),
+                },
+                pq_last_resort_pre_key: UploadSignedPreKey {
+                    key_id: row.pq_last_resort_pre_key_id.parse().unwrap(),
+                    public_key: row.pq_last_resort_pre_key.into(),
+                    signature: row.pq_last_resort_pre_key_signature.into(),
+                },
+            })
+        })
+        .map_err(|err| err.into())
+    }
+
+    async fn get_deniable_one_time_ec_pre_key(
+        &self,
+        owner: &ProtocolAddress,
+    ) -> Result<Option<UploadPreKey>> {
+        sqlx::query!(
+            r#"
+            WITH key AS
+                (DELETE 
+                 FROM deniable_one_time_ec_pre_key_store
+                 WHERE id IN 
+                    (SELECT deniable_one_time_ec_pre_key_store.id
+                     FROM deniable_one_time_ec_pre_key_store
+                     INNER JOIN devices on devices.id = deniable_one_time_ec_pre_key_store.owner
+                     WHERE devices.owner =
+                            (SELECT id
+                             FROM accounts
+                             WHERE aci = $1 
+                                OR pni = $1)
+                       AND devices.device_id = $2
+                     LIMIT 1) RETURNING key_id, 
+                                        public_key)
+            SELECT key_id, 
+                   public_key
+            FROM key
+            "#,
+            owner.name(),
+            owner.device_id().to_string()
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| {
+            row.map(|row| UploadPreKey {
+                key_id: row.key_id.parse().unwrap(),
+                public_key: row.public_key.into(),
+            })
+        })
+        .map_err(|err| err.into())
+    }
+
+    async fn store_attachment(
+        &self,
+        uploader: &Aci,
+        cdn_key: &str,
+        blob: &[u8],
+        expires_at: u64,
+        quota: &BufferQuota,
+    ) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        // Locking the account row keeps concurrent uploads from both fitting the same room
+        let owner = sqlx::query!(
+            r#"
+            SELECT id
+            FROM accounts
+            WHERE aci = $1
+            FOR UPDATE
+            "#,
+            uploader.service_id_string()
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .id;
+
+        let usage = sqlx::query!(
+            r#"
+            SELECT COUNT(*) AS "items!",
+                   COALESCE(SUM(LENGTH(blob)), 0)::BIGINT AS "bytes!"
+            FROM attachments
+            WHERE uploader = $1
+            "#,
+            owner
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if !quota.admits(usage.items as usize, usage.bytes as usize, blob.len()) {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r#"
+            INSERT INTO attachments (uploader, cdn_key, blob, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            owner,
+            cdn_key,
+            blob,
+            expires_at as i64
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn get_attachment(&self, cdn_key: &str, now: u64) -> Result<Option<Vec<u8>>> {
+        sqlx::query!(
+            r#"
+            SELECT blob
+            FROM attachments
+            WHERE cdn_key = $1
+              AND expires_at > $2
+            "#,
+            cdn_key,
+            now as i64
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| row.map(|row| row.blob))
+        .map_err(|err| err.into())
+    }
+
+    async fn delete_expired_attachments(&self, now: u64) -> Result<()> {
+        sqlx::query!(
+            r#"
+            DELETE
+            FROM attachments
+            WHERE expires_at <= $1
+            "#,
+            now as i64
+        )
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|err| err.into())
+    }
+
     async fn count_messages(&self, address: &ProtocolAddress) -> Result<u32> {
         let result = sqlx::query!(
             r#"
@@ -938,6 +1215,152 @@ impl SignalDatabase for PostgresDatabase {
             Ok(acc)
         })
     }
+
+    async fn push_chunk_queue(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        chunks: Vec<DenimChunk>,
+    ) -> Result<()> {
+        for chunk in chunks {
+            let data = bincode::serialize(&chunk)?;
+            sqlx::query!(
+                r#"
+                INSERT INTO deniable_chunk_queue (owner, buffer, chunk)
+                SELECT id, 
+                       $1, 
+                       $2
+                FROM devices
+                WHERE owner = 
+                        (SELECT id
+                         FROM accounts
+                         WHERE aci = $3 
+                            OR pni = $3)
+                  AND device_id = $4
+                "#,
+                buffer.to_string(),
+                data,
+                address.name(),
+                address.device_id().to_string()
+            )
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| anyhow!("{}", err))?;
+        }
+        Ok(())
+    }
+
+    async fn pop_chunk_queue(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+    ) -> Result<Vec<DenimChunk>> {
+        let mut rows = sqlx::query!(
+            r#"
+            DELETE 
+            FROM deniable_chunk_queue USING devices
+            WHERE devices.id = deniable_chunk_queue.owner
+              AND devices.owner =
+                    (SELECT id
+                     FROM accounts
+                     WHERE aci = $1 
+                        OR pni = $1)
+              AND devices.device_id = $2
+              AND deniable_chunk_queue.buffer = $3
+            RETURNING deniable_chunk_queue.id,
+                      deniable_chunk_queue.chunk
+            "#,
+            address.name(),
+            address.device_id().to_string(),
+            buffer.to_string()
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.sort_by_key(|row| row.id);
+        rows.iter()
+            .try_fold(vec![], |mut acc, row| -> Result<Vec<DenimChunk>> {
+                acc.push(bincode::deserialize(&row.chunk)?);
+                Ok(acc)
+            })
+    }
+
+    async fn push_payload_queue(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+        payloads: Vec<(PayloadData, Option<u64>)>,
+    ) -> Result<()> {
+        for (payload, expires_at) in payloads {
+            let data = bincode::serialize(&payload)?;
+            sqlx::query!(
+                r#"
+                INSERT INTO deniable_payload_queue (owner, buffer, payload, expires_at)
+                SELECT id, 
+                       $1, 
+                       $2,
+                       $5
+                FROM devices
+                WHERE owner = 
+                        (SELECT id
+                         FROM accounts
+                         WHERE aci = $3 
+                            OR pni = $3)
+                  AND device_id = $4
+                "#,
+                buffer.to_string(),
+                data,
+                address.name(),
+                address.device_id().to_string(),
+                expires_at.map(|expires_at| expires_at as i64)
+            )
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| anyhow!("{}", err))?;
+        }
+        Ok(())
+    }
+
+    async fn pop_payload_queue(
+        &self,
+        address: &ProtocolAddress,
+        buffer: Buffer,
+    ) -> Result<Vec<(PayloadData, Option<u64>)>> {
+        let mut rows = sqlx::query!(
+            r#"
+            DELETE 
+            FROM deniable_payload_queue USING devices
+            WHERE devices.id = deniable_payload_queue.owner
+              AND devices.owner =
+                    (SELECT id
+                     FROM accounts
+                     WHERE aci = $1 
+                        OR pni = $1)
+              AND devices.device_id = $2
+              AND deniable_payload_queue.buffer = $3
+            RETURNING deniable_payload_queue.id,
+                      deniable_payload_queue.payload,
+                      deniable_payload_queue.expires_at
+            "#,
+            address.name(),
+            address.device_id().to_string(),
+            buffer.to_string()
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.sort_by_key(|row| row.id);
+        rows.iter().try_fold(
+            vec![],
+            |mut acc, row| -> Result<Vec<(PayloadData, Option<u64>)>> {
+                let expires_at = row.expires_at.map(|expires_at| expires_at as u64);
+                acc.push((bincode::deserialize(&row.payload)?, expires_at));
+                Ok(acc)
+            },
+        )
+    }
 }
 
 async fn store_aci_signed_pre_key(
@@ -1096,7 +1519,11 @@ async fn store_pq_pni_signed_pre_key(
 
 #[cfg(test)]
 mod db_tests {
-    use common::signalservice::Envelope;
+    use common::{
+        deniable::quota::BufferQuota,
+        signalservice::Envelope,
+        web_api::{DeniableDevicePreKeyBundle, DeniableKeyUpload},
+    };
     use libsignal_core::{Aci, Pni, ProtocolAddress};
     use uuid::Uuid;
 
@@ -1107,7 +1534,10 @@ mod db_tests {
                 database_connect, get_aci_signed_pre_key, get_pni_signed_pre_key,
                 get_pq_aci_signed_pre_key, get_pq_pni_signed_pre_key,
             },
-            key::{new_device_pre_key_bundle, new_upload_pre_keys, new_upload_signed_pre_key},
+            key::{
+                new_device_pre_key_bundle, new_identity_key, new_upload_pre_keys,
+                new_upload_signed_pre_key,
+            },
             user::{new_account, new_account_and_address, new_account_and_device, new_device},
         },
     };
@@ -1428,6 +1858,101 @@ mod db_tests {
         assert_eq!(key_bundle, retrieved_key_bundle);
     }
 
+    #[tokio::test]
+    async fn test_store_and_get_deniable_key_upload() {
+        let db = database_connect().await;
+        let (account, address) = new_account_and_address();
+        let identity_key = new_identity_key();
+        let upload = DeniableKeyUpload {
+            identity_key: identity_key.serialize(),
+            registration_id: 7,
+            signed_pre_key: new_upload_signed_pre_key(None),
+            pq_last_resort_pre_key: new_upload_signed_pre_key(None),
+            pre_keys: new_upload_pre_keys(1),
+        };
+
+        db.add_account(&account).await.unwrap();
+        let missing_bundle = db.get_deniable_key_bundle(&address).await.unwrap();
+        db.store_deniable_key_upload(&upload, &address)
+            .await
+            .unwrap();
+        let retrieved_bundle = db.get_deniable_key_bundle(&address).await.unwrap();
+        let retrieved_pre_key = db.get_deniable_one_time_ec_pre_key(&address).await.unwrap();
+        let regular_pre_key = db.get_one_time_ec_pre_key(&address).await.unwrap();
+        db.delete_account(&account.aci().into()).await.unwrap();
+
+        assert_eq!(missing_bundle, None);
+        assert_eq!(
+            retrieved_bundle,
+            Some(DeniableDevicePreKeyBundle {
+                identity_key: upload.identity_key,
+                registration_id: upload.registration_id,
+                signed_pre_key: upload.signed_pre_key,
+                pq_last_resort_pre_key: upload.pq_last_resort_pre_key,
+            })
+        );
+        assert_eq!(retrieved_pre_key, Some(upload.pre_keys[0].clone()));
+        assert_eq!(regular_pre_key, None);
+    }
+
+    #[tokio::test]
+    async fn test_store_and_get_attachment() {
+        let db = database_connect().await;
+        let account = new_account();
+        let cdn_key = Uuid::new_v4().to_string();
+        let blob = vec![7; 592];
+
+        db.add_account(&account).await.unwrap();
+        let missing_blob = db.get_attachment(&cdn_key, 100).await.unwrap();
+        let stored = db
+            .store_attachment(
+                &account.aci(),
+                &cdn_key,
+                &blob,
+                200,
+                &BufferQuota::default(),
+            )
+            .await
+            .unwrap();
+        let retrieved_blob = db.get_attachment(&cdn_key, 100).await.unwrap();
+        let expired_blob = db.get_attachment(&cdn_key, 200).await.unwrap();
+        db.delete_account(&account.aci().into()).await.unwrap();
+
+        assert_eq!(missing_blob, None);
+        assert!(stored);
+        assert_eq!(retrieved_blob, Some(blob));
+        assert_eq!(expired_blob, None);
+    }
+
+    #[tokio::test]
+    async fn test_store_attachment_within_quota() {
+        let db = database_connect().await;
+        let account = new_account();
+        let quota = BufferQuota::new(1024, 2);
+        let blob = vec![7; 592];
+
+        db.add_account(&account).await.unwrap();
+        let mut stored = vec![];
+        for expires_at in [100, 200, 200] {
+            let cdn_key = Uuid::new_v4().to_string();
+            stored.push(
+                db.store_attachment(&account.aci(), &cdn_key, &blob, expires_at, &quota)
+                    .await
+                    .unwrap(),
+            );
+        }
+        db.delete_expired_attachments(100).await.unwrap();
+        let cdn_key = Uuid::new_v4().to_string();
+        let stored_after_expiry = db
+            .store_attachment(&account.aci(), &cdn_key, &blob, 200, &quota)
+            .await
+            .unwrap();
+        db.delete_account(&account.aci().into()).await.unwrap();
+
+        assert_eq!(stored, vec![true, false, false]);
+        assert!(stored_after_expiry);
+    }
+
     #[tokio::test]
     async fn test_get_one_time_ec_pre_key_count() {
         let db = database_connect().await;
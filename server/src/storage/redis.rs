@@ -1,7 +1,10 @@
 use anyhow::{anyhow, Ok, Result};
 use base64::{prelude::BASE64_STANDARD, Engine as _};
 use bon::vec;
-use common::deniable::chunk::ChunkType;
+use common::{
+    deniable::{chunk::ChunkType, constants},
+    web_api::{ChunkHeader, DenimChunk},
+};
 use deadpool_redis::Connection;
 use redis::{cmd, FromRedisValue, Value};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -85,26 +88,112 @@ fn get_field_metadata(value: &Value) -> Result<u64> {
         .map_err(|e| anyhow::Error::from(e))
 }
 
-fn get_order_metadata(value: &Value) -> Result<i64> {
-    to_string(value)?
-        .split(":")
-        .nth(2)
-        .ok_or_else(|| anyhow!("Failed"))?
-        .parse()
-        .map_err(|e| anyhow::Error::from(e))
+/// Metadata stored next to a value, used when parts of the value are taken out
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EntryMetadata {
+    pub order: i32,
+    pub total_length: u32,
+    pub stream_id: u32,
+    /// Seconds since epoch after which the value may be purged
+    pub expires_at: Option<u64>,
+    /// Values with a higher priority may be taken first
+    pub priority: u8,
+    /// Values in the same group are scheduled together, e.g. values from one sender
+    pub group: Option<String>,
 }
 
-fn create_payload_entry(id: u64, value: Vec<u8>, order: i32) -> String {
-    format!("{}:{}:{}", id, BASE64_STANDARD.encode(&value), order)
+fn get_entry_metadata(value: &Value) -> Result<EntryMetadata> {
+    let entry = to_string(value)?;
+    let mut fields = entry.split(":").skip(2);
+    let mut next = || fields.next().ok_or_else(|| anyhow!("Failed"));
+
+    let order = next()?.parse()?;
+    let total_length = next()?.parse()?;
+    let stream_id = next()?.parse()?;
+    // Entries written before values could expire or be scheduled lack the last fields
+    let expires_at = match fields.next() {
+        Some(field) => Some(field.parse()?).filter(|&expires_at: &u64| expires_at != 0),
+        None => None,
+    };
+    let priority = match fields.next() {
+        Some(field) => field.parse()?,
+        None => 0,
+    };
+    // The group is the last field and may contain the delimiter
+    let group = fields.collect::<Vec<&str>>().join(":");
+
+    Ok(EntryMetadata {
+        order,
+        total_length,
+        stream_id,
+        expires_at,
+        priority,
+        group: (!group.is_empty()).then_some(group),
+    })
+}
+
+fn create_payload_entry(id: u64, value: Vec<u8>, metadata: &EntryMetadata) -> String {
+    format!(
+        "{}:{}:{}:{}:{}:{}:{}:{}",
+        id,
+        BASE64_STANDARD.encode(&value),
+        metadata.order,
+        metadata.total_length,
+        metadata.stream_id,
+        metadata.expires_at.unwrap_or_default(),
+        metadata.priority,
+        metadata.group.as_deref().unwrap_or_default()
+    )
+}
+
+/// Decode values together with their metadata
+pub fn decode_with_metadata(values: Vec<Value>) -> Result<Vec<(Vec<u8>, EntryMetadata)>> {
+    let metadata = values
+        .iter()
+        .map(get_entry_metadata)
+        .collect::<Result<Vec<EntryMetadata>>>()?;
+
+    Ok(Bytes::decode(values)?.into_iter().zip(metadata).collect())
 }
 
 pub async fn insert(
+    connection: Connection,
+    queue_key: String,
+    queue_metadata_key: String,
+    queue_total_index_key: String,
+    field_guid: &str,
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+) -> Result<u64> {
+    let metadata = EntryMetadata {
+        order: 0,
+        total_length: value.len() as u32,
+        stream_id: rand::random(),
+        expires_at,
+        ..Default::default()
+    };
+
+    insert_with_metadata(
+        connection,
+        queue_key,
+        queue_metadata_key,
+        queue_total_index_key,
+        field_guid,
+        value,
+        metadata,
+    )
+    .await
+}
+
+/// Insert a value with existing metadata, used to restore partly taken values
+pub async fn insert_with_metadata(
     mut connection: Connection,
     queue_key: String,
     queue_metadata_key: String,
     queue_total_index_key: String,
     field_guid: &str,
     value: Vec<u8>,
+    metadata: EntryMetadata,
 ) -> Result<u64> {
     let message_guid_exists = cmd("HEXISTS")
         .arg(&queue_metadata_key)
@@ -130,7 +219,7 @@ pub async fn insert(
         .query_async::<u64>(&mut connection)
         .await?;
 
-    let value_unique = format!("{}:{}:{}", value_id, BASE64_STANDARD.encode(&value), 0);
+    let value_unique = create_payload_entry(value_id, value, &metadata);
 
     #[rustfmt::skip]
     cmd("ZADD")
@@ -304,46 +393,356 @@ pub async fn get_values(
         .query_async::<Vec<Value>>(&mut connection)
         .await?;
 
+    let field_guids = get_field_guids(&mut connection, &queue_metadata_key, &values).await?;
+
+    Ok((values, field_guids))
+}
+
+/// Get the first values of a queue even when it is locked, used by persisters
+pub async fn get_values_to_persist(
+    mut connection: Connection,
+    queue_key: String,
+    queue_metadata_key: String,
+    limit: i32,
+) -> Result<(Vec<Value>, Vec<String>)> {
+    let values = cmd("ZRANGE")
+        .arg(&queue_key)
+        .arg(0)
+        .arg(limit)
+        .query_async::<Vec<Value>>(&mut connection)
+        .await?;
+
+    let field_guids = get_field_guids(&mut connection, &queue_metadata_key, &values).await?;
+
+    Ok((values, field_guids))
+}
+
+async fn get_field_guids(
+    connection: &mut Connection,
+    queue_metadata_key: &str,
+    values: &[Value],
+) -> Result<Vec<String>> {
     let field_ids = values
-        .clone()
-        .into_iter()
-        .map(|v| get_field_metadata(&v).map(|v| v.to_string()))
+        .iter()
+        .map(|v| get_field_metadata(v).map(|v| v.to_string()))
         .collect::<Result<Vec<String>>>()?;
 
     let mut field_guids = Vec::new();
     for field_id in &field_ids {
         let field_guid: Option<String> = cmd("HGET")
-            .arg(format! {"{}:rev", &queue_metadata_key})
-            .arg(&field_id)
-            .query_async(&mut connection)
+            .arg(format! {"{}:rev", queue_metadata_key})
+            .arg(field_id)
+            .query_async(connection)
             .await?;
         field_guids.push(field_guid.unwrap_or_default());
     }
 
-    Ok((values, field_guids))
+    Ok(field_guids)
+}
+
+/// Get queues in a queue index that are older than `max_time` and remove them from the index
+pub async fn get_queues_to_persist(
+    mut connection: Connection,
+    queue_total_index_key: String,
+    max_time: u64,
+    limit: u8,
+) -> Result<Vec<String>> {
+    let results = cmd("ZRANGE")
+        .arg(&queue_total_index_key)
+        .arg(0)
+        .arg(max_time)
+        .arg("BYSCORE")
+        .arg("LIMIT")
+        .arg(0)
+        .arg(limit)
+        .query_async::<Vec<String>>(&mut connection)
+        .await?;
+
+    if !results.is_empty() {
+        cmd("ZREM")
+            .arg(&queue_total_index_key)
+            .arg(&results)
+            .query_async::<()>(&mut connection)
+            .await?;
+    }
+    Ok(results)
+}
+
+/// Get all queues in a queue index
+pub async fn get_queues(
+    mut connection: Connection,
+    queue_total_index_key: String,
+) -> Result<Vec<String>> {
+    let results = cmd("ZRANGE")
+        .arg(&queue_total_index_key)
+        .arg(0)
+        .arg(-1)
+        .query_async::<Vec<String>>(&mut connection)
+        .await?;
+
+    Ok(results)
+}
+
+/// Remove values that expired at `now` and were not partly taken yet, returns the removed values.
+/// A receiver that got some chunks of a value still gets the rest.
+pub async fn remove_expired(
+    mut connection: Connection,
+    queue_key: String,
+    queue_metadata_key: String,
+    queue_total_index_key: String,
+    now: u64,
+) -> Result<Vec<Bytes>> {
+    let values = cmd("ZRANGE")
+        .arg(&queue_key)
+        .arg(0)
+        .arg(-1)
+        .query_async::<Vec<Value>>(&mut connection)
+        .await?;
+
+    let mut expired = Vec::new();
+    for value in values {
+        let metadata = get_entry_metadata(&value)?;
+        if metadata.order == 0
+            && metadata
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now)
+        {
+            expired.push(value);
+        }
+    }
+    if expired.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let field_guids = get_field_guids(&mut connection, &queue_metadata_key, &expired).await?;
+    remove::<Bytes>(
+        connection,
+        queue_key,
+        queue_metadata_key,
+        queue_total_index_key,
+        field_guids,
+    )
+    .await
+}
+
+/// Delete a queue with its metadata and usage, after it was removed from its queue index
+pub async fn delete_queue(
+    mut connection: Connection,
+    queue_key: String,
+    queue_metadata_key: String,
+    queue_usage_key: String,
+) -> Result<()> {
+    cmd("DEL")
+        .arg(&queue_key)
+        .arg(&queue_metadata_key)
+        .arg(format!("{}:rev", &queue_metadata_key))
+        .arg(&queue_usage_key)
+        .query_async::<()>(&mut connection)
+        .await?;
+
+    Ok(())
+}
+
+/// Reserve room for a value of `len` bytes in the usage of a queue, if the queue stays within
+/// `max_items` items and `max_bytes` bytes. The check and the reservation are one atomic step,
+/// so concurrent inserts can not both take the last room.
+pub async fn reserve_usage(
+    connection: &mut Connection,
+    queue_usage_key: &str,
+    len: usize,
+    max_items: Option<usize>,
+    max_bytes: Option<usize>,
+) -> Result<bool> {
+    let script = redis::Script::new(
+        r#"
+        local items = tonumber(redis.call('HGET', KEYS[1], 'items') or '0')
+        local bytes = tonumber(redis.call('HGET', KEYS[1], 'bytes') or '0')
+        local len = tonumber(ARGV[1])
+        local max_items = tonumber(ARGV[2])
+        local max_bytes = tonumber(ARGV[3])
+        if (max_items >= 0 and items + 1 > max_items)
+            or (max_bytes >= 0 and bytes + len > max_bytes) then
+            return 0
+        end
+        redis.call('HINCRBY', KEYS[1], 'items', 1)
+        redis.call('HINCRBY', KEYS[1], 'bytes', len)
+        return 1
+        "#,
+    );
+    let limit = |limit: Option<usize>| limit.map_or(-1, |limit| limit as i64);
+    let reserved: bool = script
+        .key(queue_usage_key)
+        .arg(len)
+        .arg(limit(max_items))
+        .arg(limit(max_bytes))
+        .invoke_async(connection)
+        .await?;
+
+    Ok(reserved)
+}
+
+/// Add to the items and bytes counted in the usage of a queue
+pub async fn update_usage(
+    connection: &mut Connection,
+    queue_usage_key: &str,
+    items: i64,
+    bytes: i64,
+) -> Result<()> {
+    redis::pipe()
+        .atomic()
+        .cmd("HINCRBY")
+        .arg(queue_usage_key)
+        .arg("items")
+        .arg(items)
+        .cmd("HINCRBY")
+        .arg(queue_usage_key)
+        .arg("bytes")
+        .arg(bytes)
+        .query_async::<()>(connection)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn lock_queue(mut connection: Connection, queue_lock_key: String) -> Result<()> {
+    cmd("SETEX")
+        .arg(&queue_lock_key)
+        .arg(30)
+        .arg("1")
+        .query_async::<()>(&mut connection)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn unlock_queue(mut connection: Connection, queue_lock_key: String) -> Result<()> {
+    cmd("DEL")
+        .arg(&queue_lock_key)
+        .query_async::<()>(&mut connection)
+        .await?;
+
+    Ok(())
+}
+
+/// Push values to the front of a list, keeping their order
+pub async fn push_front(
+    mut connection: Connection,
+    list_key: String,
+    values: Vec<Vec<u8>>,
+) -> Result<()> {
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    let mut push = cmd("LPUSH");
+    push.arg(&list_key);
+    for value in values.iter().rev() {
+        push.arg(value);
+    }
+    push.query_async::<()>(&mut connection).await?;
+
+    #[rustfmt::skip]
+    cmd("EXPIRE")
+        .arg(&list_key)            // key (list)
+        .arg(2678400)              // expire in 31 days
+        .query_async::<()>(&mut connection)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_list(mut connection: Connection, list_key: String) -> Result<Vec<Vec<u8>>> {
+    Ok(cmd("LRANGE")
+        .arg(&list_key)
+        .arg(0)
+        .arg(-1)
+        .query_async::<Vec<Vec<u8>>>(&mut connection)
+        .await?)
+}
+
+/// Remove the first occurrence of a value from a list, returns whether it was there
+pub async fn remove_from_list(
+    mut connection: Connection,
+    list_key: String,
+    value: &[u8],
+) -> Result<bool> {
+    let removed = cmd("LREM")
+        .arg(&list_key)
+        .arg(1)
+        .arg(value)
+        .query_async::<u64>(&mut connection)
+        .await?;
+
+    Ok(removed == 1)
+}
+
+/// Get the ids and metadata of the first values of a queue, empty when the queue is locked
+pub async fn get_entries(
+    mut connection: Connection,
+    queue_key: String,
+    queue_lock_key: String,
+) -> Result<Vec<(u64, EntryMetadata)>> {
+    let locked = cmd("GET")
+        .arg(&queue_lock_key)
+        .query_async::<Option<String>>(&mut connection)
+        .await?;
+
+    if locked.is_some() {
+        return Ok(Vec::new());
+    }
+
+    let values = cmd("ZRANGE")
+        .arg(&queue_key)
+        .arg(0)
+        .arg(PAGE_SIZE - 1)
+        .query_async::<Vec<Value>>(&mut connection)
+        .await?;
+
+    values
+        .iter()
+        .map(|value| Ok((get_field_metadata(value)?, get_entry_metadata(value)?)))
+        .collect()
 }
 
-/// Take part of redis value out and remove
+/// Take part of the redis value with id `field_id` out and remove, the chunk fits in `space` encoded bytes
+/// Returns `None` when nothing could be taken
 pub async fn dequeue_bytes(
     mut connection: Connection,
     queue_key: String,
     queue_metadata_key: String,
     queue_total_index_key: String,
     queue_lock_key: String,
-    bytes_amount: usize,
-) -> Result<(Vec<u8>, usize, i32)> {
-    // Return early when buffer is empty
-    let first = match get_first(&mut connection, &queue_key, &queue_lock_key).await {
+    field_id: u64,
+    space: usize,
+) -> Result<Option<DenimChunk>> {
+    if space < constants::EMPTY_DENIMCHUNK_SIZE {
+        return Ok(None);
+    }
+
+    // Return early when the value is gone
+    let first = match get_value(&mut connection, &queue_key, &queue_lock_key, field_id).await {
         anyhow::Result::Ok(value) => value,
-        Err(_) => return Ok((Vec::new(), 0, ChunkType::Dummy.into())),
+        Err(_) => return Ok(None),
     };
 
     let field_id = get_field_metadata(&first)?;
+    let metadata = get_entry_metadata(&first)?;
+    let header = ChunkHeader::new(
+        metadata.stream_id,
+        metadata.order.unsigned_abs(),
+        metadata.total_length,
+    );
     let mut value = Bytes::decode(vec![first.clone()])?
         .first()
         .ok_or_else(|| anyhow!("Failed to decode value."))?
         .clone();
 
+    // Take as much as fits in the space while leaving a rest that can be filled
+    let bytes_amount = match constants::chunk_data_len(space, value.len()) {
+        Some(bytes_amount) if bytes_amount > 0 => bytes_amount,
+        _ => return Ok(None),
+    };
+
     // Get some data from first value and remove
     if bytes_amount < value.len() {
         let rest = value.split_off(bytes_amount);
@@ -351,7 +750,11 @@ pub async fn dequeue_bytes(
         if !updated {
             return Err(anyhow!("Failed to update value."));
         }
-        return Ok((value.clone(), value.len(), ChunkType::Data(order).into()));
+        Ok(Some(DenimChunk {
+            chunk: value,
+            flags: ChunkType::Data(order).into(),
+            header,
+        }))
     // Get whole of first value and remove
     } else {
         // Get guid for proper removal
@@ -371,16 +774,21 @@ pub async fn dequeue_bytes(
             )
             .await?;
             let value = removed.into_iter().flatten().collect::<Vec<u8>>();
-            return Ok((value.clone(), value.len(), ChunkType::Final.into()));
+            return Ok(Some(DenimChunk {
+                chunk: value,
+                flags: ChunkType::Final.into(),
+                header,
+            }));
         }
-        return Err(anyhow!("Failed to take values: field guid not found."));
+        Err(anyhow!("Failed to take values: field guid not found."))
     }
 }
 
-async fn get_first(
+async fn get_value(
     connection: &mut Connection,
     queue_key: &str,
     queue_lock_key: &str,
+    field_id: u64,
 ) -> Result<Value> {
     let locked = cmd("GET")
         .arg(&queue_lock_key)
@@ -388,18 +796,17 @@ async fn get_first(
         .await?;
 
     if locked.is_some() {
-        return Err(anyhow!("Failed to get first value: queue is locked."));
+        return Err(anyhow!("Failed to get value: queue is locked."));
     }
 
-    // Get value at index 0
-    let value = cmd("ZRANGE")
+    let value = cmd("ZRANGEBYSCORE")
         .arg(queue_key)
-        .arg(0)
-        .arg(0)
+        .arg(field_id)
+        .arg(field_id)
         .query_async::<Vec<Value>>(connection)
         .await?
-        .get(0)
-        .ok_or_else(|| anyhow!("Failed to get first value: empty value."))?
+        .first()
+        .ok_or_else(|| anyhow!("Failed to get value: empty value."))?
         .clone();
 
     Ok(value)
@@ -436,8 +843,15 @@ async fn update_value(
     }
 
     // Advance order by 1 and set new entry
-    let order = get_order_metadata(value)?;
-    let new_entry = create_payload_entry(field_id, new_value, (order - 1) as i32);
+    let metadata = get_entry_metadata(value)?;
+    let new_entry = create_payload_entry(
+        field_id,
+        new_value,
+        &EntryMetadata {
+            order: metadata.order - 1,
+            ..metadata
+        },
+    );
     let added = cmd("ZADD")
         .arg(queue_key)
         .arg(field_id)
@@ -452,5 +866,5 @@ async fn update_value(
         .then_some(true)
         .ok_or_else(|| anyhow!("Update failed!"))?;
 
-    Ok((updated, order as i32))
+    Ok((updated, metadata.order))
 }
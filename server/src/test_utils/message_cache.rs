@@ -3,13 +3,19 @@ use std::sync::Arc;
 use crate::availability_listener::AvailabilityListener;
 use common::{
     signalservice::Envelope,
-    web_api::{DeniablePayload, DenimChunk, PreKeyRequest, PreKeyResponse, SignalMessage},
+    web_api::{
+        DeniableGroupMessage, DeniableKeyUpload, DeniablePayload, DeniableSyncMessage, DenimChunk,
+        IdentifierRequest, IdentifierResponse, PreKeyRequest, PreKeyResponse, SignalMessage,
+    },
 };
+use proptest::{prelude::*, strategy::LazyJust};
 use redis::cmd;
 use tokio::sync::{Mutex, Notify};
 use uuid::Uuid;
 
-use super::key::{new_identity_key, new_pre_key_response_itmes};
+use super::key::{
+    new_identity_key, new_pre_key_response_itmes, new_upload_pre_keys, new_upload_signed_pre_key,
+};
 
 pub fn generate_uuid() -> String {
     Uuid::new_v4().to_string()
@@ -82,6 +88,103 @@ pub fn generate_payload(payload_type: DeniablePayloadType) -> DeniablePayload {
     }
 }
 
+/// Deniable payloads of every type with arbitrary content
+pub fn arbitrary_payload() -> impl Strategy<Value = DeniablePayload> {
+    prop_oneof![
+        (
+            any::<i32>(),
+            any::<u32>(),
+            proptest::option::of(".*"),
+            any::<u32>(),
+            ".*",
+            proptest::option::of(any::<u64>()),
+        )
+            .prop_map(
+                |(
+                    r#type,
+                    destination_device_id,
+                    destination_service_id,
+                    destination_registration_id,
+                    content,
+                    expires_at,
+                )| {
+                    DeniablePayload::SignalMessage(SignalMessage {
+                        r#type,
+                        destination_device_id,
+                        destination_service_id,
+                        destination_registration_id,
+                        content,
+                        expires_at,
+                    })
+                }
+            ),
+        (prop::collection::vec(any::<u8>(), 0..2000), any::<u64>()).prop_map(
+            |(content, timestamp)| {
+                DeniablePayload::Envelope(Envelope {
+                    content: Some(content),
+                    timestamp: Some(timestamp),
+                    ..generate_envelope(&generate_uuid())
+                })
+            }
+        ),
+        ".*".prop_map(|service_id| DeniablePayload::KeyRequest(PreKeyRequest { service_id })),
+        LazyJust::new(|| generate_payload(DeniablePayloadType::KeyResponse)),
+        ".*".prop_map(
+            |phone_number| DeniablePayload::IdentifierRequest(IdentifierRequest { phone_number })
+        ),
+        (".*", any::<bool>()).prop_map(|(phone_number, found)| {
+            DeniablePayload::IdentifierResponse(IdentifierResponse {
+                phone_number,
+                keys: found.then(|| {
+                    PreKeyResponse::new(
+                        "1".to_string(),
+                        new_identity_key(),
+                        new_pre_key_response_itmes(),
+                    )
+                }),
+            })
+        }),
+        (any::<u32>(), 0..16_u32).prop_map(|(registration_id, pre_key_count)| {
+            DeniablePayload::KeyUpload(DeniableKeyUpload {
+                identity_key: new_identity_key().serialize(),
+                registration_id,
+                signed_pre_key: new_upload_signed_pre_key(None),
+                pq_last_resort_pre_key: new_upload_signed_pre_key(None),
+                pre_keys: new_upload_pre_keys(pre_key_count),
+            })
+        }),
+        (
+            prop::collection::vec(".*", 0..8),
+            ".*",
+            proptest::option::of(any::<u64>()),
+        )
+            .prop_map(|(members, content, expires_at)| {
+                DeniablePayload::GroupMessage(DeniableGroupMessage {
+                    members,
+                    content,
+                    expires_at,
+                })
+            }),
+        (
+            prop::collection::vec((any::<u32>(), ".*"), 0..4),
+            proptest::option::of(any::<u64>()),
+        )
+            .prop_map(|(messages, expires_at)| {
+                DeniablePayload::SyncMessage(DeniableSyncMessage {
+                    messages: messages
+                        .into_iter()
+                        .map(|(destination_device_id, content)| SignalMessage {
+                            destination_device_id,
+                            content,
+                            ..Default::default()
+                        })
+                        .collect(),
+                    expires_at,
+                })
+            }),
+    ]
+}
+
 pub struct MockWebSocketConnection {
     pub evoked_notify: Arc<Notify>,
     pub evoked_handle_new_messages: Arc<Mutex<bool>>,
@@ -1,13 +1,18 @@
 use crate::{
     account::{Account, Device},
+    managers::denim::buffer::Buffer,
     storage::database::SignalDatabase,
 };
 use anyhow::Result;
 use axum::{async_trait, extract::ws::Message, Error};
 use common::websocket::wsstream::WSStream;
 use common::{
+    deniable::quota::BufferQuota,
     signalservice::Envelope,
-    web_api::{DeviceCapabilityType, DevicePreKeyBundle, UploadPreKey, UploadSignedPreKey},
+    web_api::{
+        DeniableDevicePreKeyBundle, DeniableKeyUpload, DenimChunk, DeviceCapabilityType,
+        DevicePreKeyBundle, PayloadData, UploadPreKey, UploadSignedPreKey,
+    },
 };
 use futures_util::{stream::Stream, Sink};
 use libsignal_core::{Aci, Pni, ProtocolAddress, ServiceId};
@@ -133,6 +138,47 @@ impl SignalDatabase for MockDB {
         todo!()
     }
 
+    async fn store_deniable_key_upload(
+        &self,
+        _: &DeniableKeyUpload,
+        _: &ProtocolAddress,
+    ) -> Result<()> {
+        todo!()
+    }
+
+    async fn get_deniable_key_bundle(
+        &self,
+        _: &ProtocolAddress,
+    ) -> Result<Option<DeniableDevicePreKeyBundle>> {
+        todo!()
+    }
+
+    async fn get_deniable_one_time_ec_pre_key(
+        &self,
+        _: &ProtocolAddress,
+    ) -> Result<Option<UploadPreKey>> {
+        todo!()
+    }
+
+    async fn store_attachment(
+        &self,
+        _: &Aci,
+        _: &str,
+        _: &[u8],
+        _: u64,
+        _: &BufferQuota,
+    ) -> Result<bool> {
+        todo!()
+    }
+
+    async fn get_attachment(&self, _: &str, _: u64) -> Result<Option<Vec<u8>>> {
+        todo!()
+    }
+
+    async fn delete_expired_attachments(&self, _: u64) -> Result<()> {
+        todo!()
+    }
+
     async fn count_messages(&self, _: &ProtocolAddress) -> Result<u32> {
         todo!()
     }
@@ -144,6 +190,36 @@ impl SignalDatabase for MockDB {
     async fn delete_messages(&self, _: &ProtocolAddress) -> Result<Vec<Envelope>> {
         Ok(Vec::new())
     }
+
+    async fn push_chunk_queue(
+        &self,
+        _: &ProtocolAddress,
+        _: Buffer,
+        _: Vec<DenimChunk>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn pop_chunk_queue(&self, _: &ProtocolAddress, _: Buffer) -> Result<Vec<DenimChunk>> {
+        Ok(Vec::new())
+    }
+
+    async fn push_payload_queue(
+        &self,
+        _: &ProtocolAddress,
+        _: Buffer,
+        _: Vec<(PayloadData, Option<u64>)>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn pop_payload_queue(
+        &self,
+        _: &ProtocolAddress,
+        _: Buffer,
+    ) -> Result<Vec<(PayloadData, Option<u64>)>> {
+        Ok(Vec::new())
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,186 @@
+use common::{
+    deniable::{
+        chunk::ChunkType,
+        constants,
+        scheduler::{ChunkFill, PayloadClass, PayloadScheduler, QueuedPayload, SchedulingStrategy},
+    },
+    web_api::{ChunkHeader, DeniablePayload, DenimChunk},
+};
+
+/// Payload data waiting to be chunked, with the metadata the payload cache keeps for it
+#[derive(Debug)]
+struct Entry {
+    queued: QueuedPayload,
+    data: Vec<u8>,
+    order: i32,
+    stream_id: u32,
+    total_length: u32,
+}
+
+/// Outgoing payload buffer of a receiver kept in memory in place of the payload cache of the
+/// server. Its chunks are filled with [ChunkFill] and picked by the scheduler like there.
+#[derive(Debug)]
+pub struct PayloadBuffer {
+    entries: Vec<Entry>,
+    strategy: SchedulingStrategy,
+    scheduler: Box<dyn PayloadScheduler>,
+    next_id: u64,
+}
+
+impl PayloadBuffer {
+    pub fn new(strategy: SchedulingStrategy) -> Self {
+        Self {
+            entries: Vec::new(),
+            strategy,
+            scheduler: strategy.scheduler(),
+            next_id: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes of payload data that are not chunked yet
+    pub fn bytes(&self) -> usize {
+        self.entries.iter().map(|entry| entry.data.len()).sum()
+    }
+
+    /// Insert a payload from `sender`, payloads are scheduled by their sender and class
+    pub fn insert(&mut self, sender: &str, payload: &DeniablePayload) {
+        let data = bincode::serialize(payload).expect("Can serialize payload");
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(Entry {
+            queued: QueuedPayload::new(
+                id,
+                PayloadClass::from(payload).into(),
+                Some(sender.to_owned()),
+            ),
+            order: 0,
+            stream_id: id as u32,
+            total_length: data.len() as u32,
+            data,
+        });
+    }
+
+    /// Take chunks filling `bytes_amount` encoded bytes, the payloads are picked by the
+    /// scheduler. Returns the chunks and the length of the ballast that fills the rest.
+    pub fn dequeue(&mut self, bytes_amount: usize) -> (Vec<DenimChunk>, usize) {
+        let mut queue: Vec<QueuedPayload> = self
+            .entries
+            .iter()
+            .map(|entry| entry.queued.clone())
+            .collect();
+
+        let mut fill = ChunkFill::new(bytes_amount, Vec::new());
+        while let Some((index, limit)) = fill.next(self.scheduler.as_mut(), &queue) {
+            let chunk = self.take(index, limit);
+            if fill.push(self.scheduler.as_mut(), &queue[index], chunk) {
+                queue.remove(index);
+                self.entries.remove(index);
+            }
+        }
+
+        // Nothing is left to schedule, so there is no turn to remember
+        if self.entries.is_empty() {
+            self.scheduler = self.strategy.scheduler();
+        }
+
+        fill.finish()
+    }
+
+    /// Take as much data of the entry at `index` as fits in `space` encoded bytes,
+    /// `None` when nothing could be taken
+    fn take(&mut self, index: usize, space: usize) -> Option<DenimChunk> {
+        let entry = &mut self.entries[index];
+        let bytes_amount = constants::chunk_data_len(space, entry.data.len())
+            .filter(|bytes_amount| *bytes_amount > 0)?;
+        let header = ChunkHeader::new(
+            entry.stream_id,
+            entry.order.unsigned_abs(),
+            entry.total_length,
+        );
+
+        if bytes_amount < entry.data.len() {
+            let rest = entry.data.split_off(bytes_amount);
+            let chunk = std::mem::replace(&mut entry.data, rest);
+            let flags = ChunkType::Data(entry.order).into();
+            entry.order -= 1;
+            Some(DenimChunk {
+                chunk,
+                flags,
+                header,
+            })
+        } else {
+            Some(DenimChunk {
+                chunk: std::mem::take(&mut entry.data),
+                flags: ChunkType::Final.into(),
+                header,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::{deniable::reassembly::reassemble, web_api::SignalMessage};
+
+    fn payload(len: usize) -> DeniablePayload {
+        DeniablePayload::SignalMessage(SignalMessage {
+            content: "a".repeat(len),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn dequeue_fills_space_exactly() {
+        let mut buffer = PayloadBuffer::new(SchedulingStrategy::Fifo);
+        buffer.insert("alice", &payload(500));
+
+        for space in [40, 100, 333] {
+            let (chunks, ballast) = buffer.dequeue(space);
+            let size = chunks
+                .iter()
+                .map(|chunk| constants::chunk_size(chunk.chunk.len()))
+                .sum::<usize>()
+                + constants::ballast_size(ballast);
+            assert_eq!(size, space);
+        }
+    }
+
+    #[test]
+    fn dequeued_chunks_reassemble() {
+        let mut buffer = PayloadBuffer::new(SchedulingStrategy::RoundRobin);
+        let payloads = [payload(300), payload(50), payload(700)];
+        buffer.insert("alice", &payloads[0]);
+        buffer.insert("bob", &payloads[1]);
+        buffer.insert("alice", &payloads[2]);
+
+        let mut chunks = Vec::new();
+        while !buffer.is_empty() {
+            chunks.extend(
+                buffer
+                    .dequeue(200)
+                    .0
+                    .into_iter()
+                    .filter(|chunk| !chunk.is_dummy()),
+            );
+        }
+        let reassembled: Vec<DeniablePayload> = reassemble(chunks)
+            .payloads
+            .iter()
+            .map(|data| bincode::deserialize(data).unwrap())
+            .collect();
+
+        assert_eq!(reassembled.len(), payloads.len());
+        for payload in payloads {
+            assert!(reassembled.contains(&payload));
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use common::{
+    deniable::{
+        chunk::{random_filler, Chunker, OutgoingMessage},
+        reassembly::{reassemble_after, CompletedStreams},
+    },
+    web_api::{DeniablePayload, DenimChunk, DenimMessage, RegularPayload},
+};
+use std::collections::VecDeque;
+
+/// A client in the simulation, it chunks its deniable payloads into the denim messages it
+/// sends and reassembles the deniable payloads in the denim messages it receives
+#[derive(Debug)]
+pub struct SimulatedClient {
+    chunker: Chunker,
+    /// Outgoing deniable payloads in the order they are sent
+    outgoing: VecDeque<OutgoingMessage>,
+    next_id: u32,
+    /// Chunks of incomplete incoming payloads
+    incoming: Vec<DenimChunk>,
+    completed: CompletedStreams,
+}
+
+impl SimulatedClient {
+    pub fn new(q_value: f32) -> Self {
+        Self {
+            chunker: Chunker::new(q_value),
+            outgoing: VecDeque::new(),
+            next_id: 0,
+            incoming: Vec::new(),
+            completed: CompletedStreams::default(),
+        }
+    }
+
+    /// Queue a deniable payload to be sent along the following denim messages
+    pub fn queue_deniable_payload(&mut self, payload: &DeniablePayload) {
+        let data = bincode::serialize(payload).expect("Can serialize payload");
+        self.next_id += 1;
+        let total_length = data.len() as u32;
+        self.outgoing
+            .push_back((self.next_id, data, 0, total_length));
+    }
+
+    /// Bytes of deniable payloads waiting to be sent
+    pub fn pending_bytes(&self) -> usize {
+        self.outgoing.iter().map(|message| message.1.len()).sum()
+    }
+
+    /// Wrap a regular payload in a denim message filled with chunks of the queued payloads
+    pub fn create_denim_message(&mut self, regular_payload: RegularPayload) -> DenimMessage {
+        let regular_payload_size = regular_payload.encoded_len() as f32;
+        let (chunks, ballast) = self
+            .chunker
+            .create_queued_chunks(regular_payload_size, &mut self.outgoing);
+
+        DenimMessage {
+            regular_payload,
+            chunks,
+            counter: None,
+            q: None,
+            ballast: random_filler(ballast),
+        }
+    }
+
+    /// Reassemble the deniable payloads completed by the chunks of a received denim message
+    pub fn receive(&mut self, message: &DenimMessage) -> Vec<DeniablePayload> {
+        let mut chunks = std::mem::take(&mut self.incoming);
+        chunks.extend(
+            message
+                .chunks
+                .iter()
+                .filter(|chunk| !chunk.is_dummy())
+                .cloned(),
+        );
+        let reassembly = reassemble_after(chunks, &mut self.completed);
+        self.incoming = reassembly.pending;
+        reassembly
+            .payloads
+            .iter()
+            .filter_map(|data| bincode::deserialize(data).ok())
+            .collect()
+    }
+}
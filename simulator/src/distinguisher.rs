@@ -0,0 +1,302 @@
+use crate::observation::{Direction, Observation, SimulationResult};
+use std::collections::BTreeMap;
+
+/// An adversary that only sees the observations and guesses which users are deniable
+pub trait Distinguisher {
+    fn name(&self) -> String;
+
+    /// Score of every user, a higher score means more likely deniable
+    fn scores(&self, result: &SimulationResult) -> BTreeMap<String, f64>;
+}
+
+/// How well a distinguisher separates deniable users from the other users
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evaluation {
+    pub name: String,
+    /// Area under the ROC curve of the scores, 0.5 is guessing
+    pub auc: f64,
+    /// Advantage over guessing, `|2 * auc - 1|`
+    pub advantage: f64,
+}
+
+pub fn evaluate(distinguisher: &dyn Distinguisher, result: &SimulationResult) -> Evaluation {
+    let scores = distinguisher.scores(result);
+    let (deniable, regular): (Vec<_>, Vec<_>) = result
+        .users
+        .iter()
+        .map(|user| {
+            (
+                result.is_deniable(user),
+                scores.get(user).copied().unwrap_or(0.0),
+            )
+        })
+        .partition(|(deniable, _)| *deniable);
+
+    // Mann-Whitney estimate of the probability that a deniable user scores higher
+    let auc = if deniable.is_empty() || regular.is_empty() {
+        0.5
+    } else {
+        let wins: f64 = deniable
+            .iter()
+            .flat_map(|(_, d)| regular.iter().map(move |(_, r)| (d, r)))
+            .map(|(d, r)| match d.partial_cmp(r) {
+                Some(std::cmp::Ordering::Greater) => 1.0,
+                Some(std::cmp::Ordering::Equal) => 0.5,
+                _ => 0.0,
+            })
+            .sum();
+        wins / (deniable.len() * regular.len()) as f64
+    };
+
+    Evaluation {
+        name: distinguisher.name(),
+        auc,
+        advantage: (2.0 * auc - 1.0).abs(),
+    }
+}
+
+/// Total variation distance between the message size histogram of a user and the histogram
+/// of all users
+#[derive(Debug, Clone, Copy)]
+pub struct SizeHistogram {
+    pub bucket_size: usize,
+}
+
+impl SizeHistogram {
+    fn histogram<'a>(&self, observations: impl Iterator<Item = &'a Observation>) -> Vec<f64> {
+        let mut histogram = Vec::new();
+        let mut count = 0;
+        for observation in observations {
+            let bucket = observation.size / self.bucket_size.max(1);
+            if histogram.len() <= bucket {
+                histogram.resize(bucket + 1, 0.0);
+            }
+            histogram[bucket] += 1.0;
+            count += 1;
+        }
+        histogram.iter_mut().for_each(|bin| *bin /= count as f64);
+        histogram
+    }
+}
+
+impl Distinguisher for SizeHistogram {
+    fn name(&self) -> String {
+        format!("size histogram ({} B buckets)", self.bucket_size)
+    }
+
+    fn scores(&self, result: &SimulationResult) -> BTreeMap<String, f64> {
+        let population = self.histogram(result.observations.iter());
+        result
+            .users
+            .iter()
+            .map(|user| {
+                let histogram = self.histogram(result.observations_of(user));
+                let len = histogram.len().max(population.len());
+                let distance = (0..len)
+                    .map(|bucket| {
+                        let p = histogram.get(bucket).copied().unwrap_or(0.0);
+                        let q = population.get(bucket).copied().unwrap_or(0.0);
+                        (p - q).abs()
+                    })
+                    .sum::<f64>()
+                    / 2.0;
+                (user.clone(), distance)
+            })
+            .collect()
+    }
+}
+
+/// Highest correlation between the messages a user sends and the messages another user
+/// receives, counted per window
+#[derive(Debug, Clone, Copy)]
+pub struct TimingCorrelation {
+    /// Window length in milliseconds
+    pub window: u64,
+}
+
+impl TimingCorrelation {
+    fn counts(&self, result: &SimulationResult, user: &str, direction: Direction) -> Vec<f64> {
+        let window = self.window.max(1);
+        let end = result
+            .observations
+            .iter()
+            .map(|o| o.time)
+            .max()
+            .unwrap_or(0);
+        let mut counts = vec![0.0; (end / window) as usize + 1];
+        for observation in result.observations_of(user) {
+            if observation.direction == direction {
+                counts[(observation.time / window) as usize] += 1.0;
+            }
+        }
+        counts
+    }
+}
+
+impl Distinguisher for TimingCorrelation {
+    fn name(&self) -> String {
+        format!("timing correlation ({} ms windows)", self.window)
+    }
+
+    fn scores(&self, result: &SimulationResult) -> BTreeMap<String, f64> {
+        let downstream: Vec<(&String, Vec<f64>)> = result
+            .users
+            .iter()
+            .map(|user| (user, self.counts(result, user, Direction::Downstream)))
+            .collect();
+        result
+            .users
+            .iter()
+            .map(|user| {
+                let upstream = self.counts(result, user, Direction::Upstream);
+                let correlation = downstream
+                    .iter()
+                    .filter(|(other, _)| *other != user)
+                    .map(|(_, counts)| pearson(&upstream, counts))
+                    .fold(0.0, f64::max);
+                (user.clone(), correlation)
+            })
+            .collect()
+    }
+}
+
+/// Pearson correlation, 0 when either series is constant
+fn pearson(xs: &[f64], ys: &[f64]) -> f64 {
+    let n = xs.len().min(ys.len()) as f64;
+    if n == 0.0 {
+        return 0.0;
+    }
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for (x, y) in xs.iter().zip(ys) {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+    if var_x == 0.0 || var_y == 0.0 {
+        0.0
+    } else {
+        cov / (var_x * var_y).sqrt()
+    }
+}
+
+/// Distinguisher scoring every user by a feature of its observations, to plug in a classifier
+pub struct Classifier<F> {
+    name: String,
+    feature: F,
+}
+
+impl<F: Fn(&[Observation]) -> f64> Classifier<F> {
+    pub fn new(name: impl Into<String>, feature: F) -> Self {
+        Self {
+            name: name.into(),
+            feature,
+        }
+    }
+}
+
+impl<F: Fn(&[Observation]) -> f64> Distinguisher for Classifier<F> {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn scores(&self, result: &SimulationResult) -> BTreeMap<String, f64> {
+        result
+            .users
+            .iter()
+            .map(|user| {
+                let observations: Vec<Observation> =
+                    result.observations_of(user).cloned().collect();
+                (user.clone(), (self.feature)(&observations))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::observation::GroundTruth;
+
+    fn observation(time: u64, user: &str, direction: Direction, size: usize) -> Observation {
+        Observation {
+            time,
+            user: user.to_owned(),
+            direction,
+            size,
+            truth: GroundTruth {
+                cover: false,
+                regular_size: size,
+                deniable_bytes: 0,
+            },
+        }
+    }
+
+    fn result(observations: Vec<Observation>) -> SimulationResult {
+        SimulationResult {
+            users: ["alice", "bob", "carol", "dave"].map(String::from).to_vec(),
+            deniable_users: ["alice", "bob"].map(String::from).to_vec(),
+            observations,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn feature_separating_deniable_users_has_full_advantage() {
+        let result = result(vec![
+            observation(0, "alice", Direction::Upstream, 900),
+            observation(0, "bob", Direction::Upstream, 800),
+            observation(0, "carol", Direction::Upstream, 100),
+            observation(0, "dave", Direction::Upstream, 200),
+        ]);
+        let largest = Classifier::new("largest", |observations: &[Observation]| {
+            observations.iter().map(|o| o.size).max().unwrap_or(0) as f64
+        });
+        let smallest = Classifier::new("smallest", |observations: &[Observation]| {
+            -(observations.iter().map(|o| o.size).max().unwrap_or(0) as f64)
+        });
+
+        assert_eq!(evaluate(&largest, &result).auc, 1.0);
+        assert_eq!(evaluate(&smallest, &result).auc, 0.0);
+        assert_eq!(evaluate(&smallest, &result).advantage, 1.0);
+    }
+
+    #[test]
+    fn identical_users_give_no_advantage() {
+        let result = result(
+            ["alice", "bob", "carol", "dave"]
+                .iter()
+                .flat_map(|user| {
+                    [
+                        observation(10, user, Direction::Upstream, 300),
+                        observation(20, user, Direction::Downstream, 400),
+                    ]
+                })
+                .collect(),
+        );
+
+        let histogram = evaluate(&SizeHistogram { bucket_size: 50 }, &result);
+        let timing = evaluate(&TimingCorrelation { window: 5 }, &result);
+
+        assert_eq!(histogram.advantage, 0.0);
+        assert_eq!(timing.advantage, 0.0);
+    }
+
+    #[test]
+    fn timing_correlation_links_sender_to_receiver() {
+        let mut observations = Vec::new();
+        for time in [0, 300, 700] {
+            observations.push(observation(time, "alice", Direction::Upstream, 300));
+            observations.push(observation(time + 50, "bob", Direction::Downstream, 300));
+        }
+        for time in [100, 500] {
+            observations.push(observation(time, "carol", Direction::Upstream, 300));
+            observations.push(observation(time + 550, "dave", Direction::Downstream, 300));
+        }
+        let scores = TimingCorrelation { window: 100 }.scores(&result(observations));
+
+        assert!(scores["alice"] > scores["carol"]);
+        assert_eq!(scores["bob"], 0.0);
+    }
+}
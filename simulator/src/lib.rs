@@ -0,0 +1,10 @@
+//! Offline simulation of DenIM traffic, to measure how well an observer of the network can
+//! tell which users send deniable messages.
+
+pub mod buffer;
+pub mod client;
+pub mod distinguisher;
+pub mod observation;
+pub mod server;
+pub mod simulation;
+pub mod trace;
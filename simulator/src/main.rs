@@ -0,0 +1,169 @@
+use common::deniable::{
+    cover_traffic::{CoverEnvelopeSizes, CoverTrafficSchedule},
+    quota::{BufferQuota, DenimQuotas},
+    scheduler::SchedulingStrategy,
+};
+use simulator::{
+    distinguisher::{evaluate, Classifier, Distinguisher, SizeHistogram, TimingCorrelation},
+    observation::{Direction, Observation},
+    simulation::{Simulation, SimulationConfig},
+    trace::{Trace, TrafficModel},
+};
+use std::{collections::HashMap, env, error::Error, fs, path::Path, str::FromStr};
+
+const USAGE: &str = "Usage: simulator [key=value ...]
+  seed=<u64>              seed of the simulation and the synthetic trace
+  q=<f32>                 q value of clients and the server
+  scheduling=<strategy>   fifo, round-robin, priority or drr[:<quantum>]
+  cover=<schedule>        cover traffic of clients, poisson:<rate> or fixed:<ms>
+  server-cover=<schedule> cover traffic of the server
+  cover-sizes=<weights>   block weights of the server cover envelopes, e.g. 80,15,5
+  sender-quota=<b>:<n>    most bytes and chunks buffered per sender by the server
+  receiver-quota=<b>:<n>  most bytes and payloads buffered per receiver by the server
+  latency=<ms>            latency between clients and the server
+  duration=<ms>           length of the synthetic trace and the simulation
+  trace=<path>            recorded trace, a JSON array of trace events
+  users=<n>               users of the synthetic trace
+  deniable-users=<n>      users of the synthetic trace that send deniable messages
+  rate=<per second>       regular messages sent by each user
+  deniable-rate=<per sec> deniable messages sent by each deniable user
+  observations=<path>     write the observations and the ground truth as JSON";
+
+fn parse<T: FromStr>(args: &HashMap<String, String>, key: &str) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    args.get(key)
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|err| format!("Invalid {key} '{value}': {err}"))
+        })
+        .transpose()
+}
+
+/// Mean size of the messages a user sends
+fn mean_upstream_size(observations: &[Observation]) -> f64 {
+    let sizes: Vec<usize> = observations
+        .iter()
+        .filter(|observation| observation.direction == Direction::Upstream)
+        .map(|observation| observation.size)
+        .collect();
+    if sizes.is_empty() {
+        0.0
+    } else {
+        sizes.iter().sum::<usize>() as f64 / sizes.len() as f64
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = HashMap::new();
+    for arg in env::args().skip(1) {
+        if arg == "help" {
+            println!("{USAGE}");
+            return Ok(());
+        }
+        match arg.split_once('=') {
+            Some((key, value)) => args.insert(key.to_owned(), value.to_owned()),
+            None => return Err(format!("Invalid argument '{arg}'\n{USAGE}").into()),
+        };
+    }
+
+    let defaults = SimulationConfig::default();
+    let model = TrafficModel::default();
+    let model = TrafficModel {
+        users: parse(&args, "users")?.unwrap_or(model.users),
+        deniable_users: parse(&args, "deniable-users")?.unwrap_or(model.deniable_users),
+        duration: parse(&args, "duration")?.unwrap_or(model.duration),
+        regular_rate: parse(&args, "rate")?.unwrap_or(model.regular_rate),
+        deniable_rate: parse(&args, "deniable-rate")?.unwrap_or(model.deniable_rate),
+        ..model
+    };
+    let config = SimulationConfig {
+        q_value: parse(&args, "q")?.unwrap_or(defaults.q_value),
+        scheduling: args
+            .get("scheduling")
+            .map(|value| SchedulingStrategy::parse(value))
+            .transpose()?
+            .unwrap_or(defaults.scheduling),
+        quotas: DenimQuotas {
+            sender: args
+                .get("sender-quota")
+                .map(|value| BufferQuota::parse(value))
+                .transpose()?
+                .unwrap_or(defaults.quotas.sender),
+            receiver: args
+                .get("receiver-quota")
+                .map(|value| BufferQuota::parse(value))
+                .transpose()?
+                .unwrap_or(defaults.quotas.receiver),
+        },
+        client_cover: args
+            .get("cover")
+            .map(|value| CoverTrafficSchedule::parse(value))
+            .transpose()?,
+        server_cover: args
+            .get("server-cover")
+            .map(|value| CoverTrafficSchedule::parse(value))
+            .transpose()?,
+        cover_sizes: args
+            .get("cover-sizes")
+            .map(|value| CoverEnvelopeSizes::parse(value))
+            .transpose()?
+            .unwrap_or(defaults.cover_sizes),
+        latency: parse(&args, "latency")?.unwrap_or(defaults.latency),
+        duration: parse(&args, "duration")?,
+        seed: parse(&args, "seed")?.unwrap_or(defaults.seed),
+    };
+    let trace = match args.get("trace") {
+        Some(path) => Trace::load(Path::new(path))?,
+        None => Trace::synthetic(&model, config.seed),
+    };
+
+    println!(
+        "Simulating {} messages between {} users, {} of them deniable",
+        trace.events.len(),
+        trace.users().len(),
+        trace.deniable_users().len()
+    );
+    let result = Simulation::new(config, trace).run();
+
+    let distinguishers: Vec<Box<dyn Distinguisher>> = vec![
+        Box::new(SizeHistogram { bucket_size: 16 }),
+        Box::new(TimingCorrelation { window: 1000 }),
+        Box::new(Classifier::new("mean upstream size", mean_upstream_size)),
+    ];
+    for distinguisher in &distinguishers {
+        let evaluation = evaluate(distinguisher.as_ref(), &result);
+        println!(
+            "{}: AUC {:.3}, advantage {:.3}",
+            evaluation.name, evaluation.auc, evaluation.advantage
+        );
+    }
+
+    let delivered = result
+        .deliveries
+        .iter()
+        .filter(|delivery| delivery.delivered.is_some())
+        .count();
+    println!(
+        "Delivered {delivered} of {} deniable messages",
+        result.deliveries.len()
+    );
+    if let Some(latency) = result.mean_deniable_latency() {
+        println!("Mean deniable latency: {latency:.0} ms");
+    }
+    if result.dropped_chunks > 0 || result.dropped_payloads > 0 {
+        println!(
+            "Dropped {} chunks and {} payloads over the quotas",
+            result.dropped_chunks, result.dropped_payloads
+        );
+    }
+
+    if let Some(path) = args.get("observations") {
+        fs::write(path, serde_json::to_string_pretty(&result)?)?;
+        println!("Wrote observations to {path}");
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+
+/// Direction of a denim message between a client and the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    Upstream,
+    Downstream,
+}
+
+/// A denim message as a network observer sees it, with the ground truth kept from the observer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Observation {
+    /// Milliseconds since the start of the simulation
+    pub time: u64,
+    /// Client sending or receiving the message
+    pub user: String,
+    pub direction: Direction,
+    /// Encoded size of the denim message
+    pub size: usize,
+    pub truth: GroundTruth,
+}
+
+/// What a denim message carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroundTruth {
+    /// Cover messages carry no regular message
+    pub cover: bool,
+    /// Encoded size of the regular payload
+    pub regular_size: usize,
+    /// Bytes of deniable payload data in the chunks, dummy chunks left out
+    pub deniable_bytes: usize,
+}
+
+/// A deniable message and when it arrived, `None` if it never did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeniableDelivery {
+    pub sender: String,
+    pub receiver: String,
+    pub sent: u64,
+    pub delivered: Option<u64>,
+}
+
+impl DeniableDelivery {
+    pub fn latency(&self) -> Option<u64> {
+        self.delivered.map(|delivered| delivered - self.sent)
+    }
+}
+
+/// Observables of a simulation together with which users sent or received deniable messages
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulationResult {
+    pub users: Vec<String>,
+    pub deniable_users: Vec<String>,
+    pub observations: Vec<Observation>,
+    pub deliveries: Vec<DeniableDelivery>,
+    /// Deniable chunks and payloads the server dropped for exceeding a quota
+    pub dropped_chunks: u64,
+    pub dropped_payloads: u64,
+}
+
+impl SimulationResult {
+    /// Observations of `user` in the order they were made
+    pub fn observations_of<'a>(&'a self, user: &'a str) -> impl Iterator<Item = &'a Observation> {
+        self.observations
+            .iter()
+            .filter(move |observation| observation.user == user)
+    }
+
+    pub fn is_deniable(&self, user: &str) -> bool {
+        self.deniable_users.iter().any(|deniable| deniable == user)
+    }
+
+    /// Mean latency of the delivered deniable messages in milliseconds
+    pub fn mean_deniable_latency(&self) -> Option<f64> {
+        let latencies: Vec<u64> = self
+            .deliveries
+            .iter()
+            .filter_map(DeniableDelivery::latency)
+            .collect();
+        (!latencies.is_empty())
+            .then(|| latencies.iter().sum::<u64>() as f64 / latencies.len() as f64)
+    }
+}
@@ -0,0 +1,231 @@
+use crate::buffer::PayloadBuffer;
+use common::{
+    deniable::{
+        counter::CarrierCounter,
+        cover_traffic::CoverEnvelopeSizes,
+        quota::{ChunkAdmission, DenimQuotas, DroppedStreams, QuotaCounters, QuotaStats},
+        reassembly::{reassemble_after, CompletedStreams},
+        relay::{cover_envelope, message_envelope, Carrier},
+        scheduler::SchedulingStrategy,
+    },
+    signalservice::Envelope,
+    web_api::{DeniablePayload, DenimChunk, DenimMessage},
+};
+use rand::{CryptoRng, Rng};
+use std::collections::BTreeMap;
+
+/// The server in the simulation. Deniable messages are reassembled per sender and queued
+/// for their receiver, whose denim messages carry them in chunks. Chunks, envelopes and
+/// denim messages are handled by the same functions as in the server, only the buffers are
+/// kept in memory.
+#[derive(Debug)]
+pub struct SimulatedServer {
+    q_value: f32,
+    strategy: SchedulingStrategy,
+    quotas: DenimQuotas,
+    cover_sizes: CoverEnvelopeSizes,
+    quota_counters: QuotaCounters,
+    dropped_streams: DroppedStreams,
+    /// Chunks of incomplete payloads of each sender
+    incoming: BTreeMap<String, Vec<DenimChunk>>,
+    completed: BTreeMap<String, CompletedStreams>,
+    /// Outgoing payload buffer of each receiver
+    outgoing: BTreeMap<String, PayloadBuffer>,
+    /// Counters of the denim messages to each receiver
+    counters: BTreeMap<String, CarrierCounter>,
+}
+
+impl SimulatedServer {
+    pub fn new(
+        q_value: f32,
+        strategy: SchedulingStrategy,
+        quotas: DenimQuotas,
+        cover_sizes: CoverEnvelopeSizes,
+    ) -> Self {
+        Self {
+            q_value,
+            strategy,
+            quotas,
+            cover_sizes,
+            quota_counters: QuotaCounters::default(),
+            dropped_streams: DroppedStreams::default(),
+            incoming: BTreeMap::new(),
+            completed: BTreeMap::new(),
+            outgoing: BTreeMap::new(),
+            counters: BTreeMap::new(),
+        }
+    }
+
+    /// Handle the chunks of a denim message from device `sender_device` of `sender`.
+    /// Completed deniable messages are turned into envelopes for their receivers.
+    pub fn receive(
+        &mut self,
+        sender: &str,
+        sender_device: u32,
+        message: &DenimMessage,
+        timestamp: u64,
+    ) {
+        let mut buffered = self.incoming.remove(sender).unwrap_or_default();
+        self.accept_chunks(sender, &mut buffered, &message.chunks);
+        let completed = self.completed.entry(sender.to_owned()).or_default();
+        let reassembly = reassemble_after(buffered, completed);
+        if !reassembly.pending.is_empty() {
+            self.incoming.insert(sender.to_owned(), reassembly.pending);
+        }
+
+        for data in reassembly.payloads {
+            let Ok(DeniablePayload::SignalMessage(signal_message)) = bincode::deserialize(&data)
+            else {
+                continue;
+            };
+            let Some(receiver) = signal_message.destination_service_id.clone() else {
+                continue;
+            };
+            let Some(envelope) = message_envelope(
+                &signal_message,
+                sender.to_owned(),
+                sender_device,
+                receiver.clone(),
+                timestamp,
+                timestamp,
+                false,
+            ) else {
+                continue;
+            };
+            self.enqueue(&receiver, sender, DeniablePayload::Envelope(envelope));
+        }
+    }
+
+    /// Buffer the chunks from `sender` as far as the sender quota allows, like the server does
+    fn accept_chunks(
+        &mut self,
+        sender: &str,
+        buffered: &mut Vec<DenimChunk>,
+        chunks: &[DenimChunk],
+    ) {
+        let quota = self.quotas.sender;
+        let mut admission = ChunkAdmission::new(sender, &self.dropped_streams);
+        for chunk in chunks.iter().filter(|chunk| !chunk.is_dummy()) {
+            let bytes = buffered.iter().map(|chunk| chunk.chunk.len()).sum();
+            if admission.admits(chunk) && quota.admits(buffered.len(), bytes, chunk.chunk.len()) {
+                buffered.push(chunk.clone());
+            } else {
+                admission.drop_chunk(chunk);
+            }
+        }
+        if admission.drops_buffered() {
+            *buffered = admission.retain(std::mem::take(buffered));
+        }
+        admission.finish(&self.quota_counters);
+    }
+
+    /// Queue a payload from `sender` for `receiver`, unless it is over the receiver quota
+    fn enqueue(&mut self, receiver: &str, sender: &str, payload: DeniablePayload) {
+        let buffer = self
+            .outgoing
+            .entry(receiver.to_owned())
+            .or_insert_with(|| PayloadBuffer::new(self.strategy));
+        let len = bincode::serialized_size(&payload).expect("Can size payload") as usize;
+        if !self
+            .quotas
+            .receiver
+            .admits(buffer.len(), buffer.bytes(), len)
+        {
+            self.quota_counters.payloads_dropped(1, len);
+            return;
+        }
+        buffer.insert(sender, &payload);
+    }
+
+    /// Wrap an envelope for `receiver` in a denim message filled with chunks from its
+    /// outgoing payload buffer
+    pub fn create_denim_message(&mut self, receiver: &str, envelope: Envelope) -> DenimMessage {
+        let counter = self
+            .counters
+            .entry(receiver.to_owned())
+            .or_default()
+            .next_counter();
+        let carrier = Carrier::new(envelope, self.q_value, counter);
+        let (chunks, ballast) = self
+            .outgoing
+            .entry(receiver.to_owned())
+            .or_insert_with(|| PayloadBuffer::new(self.strategy))
+            .dequeue(carrier.free_space());
+
+        carrier.into_message(chunks, ballast)
+    }
+
+    /// Create a denim message with a cover envelope, which only carries chunks from the
+    /// outgoing payload buffer of `receiver`
+    pub fn create_cover_message<R: Rng + CryptoRng>(
+        &mut self,
+        receiver: &str,
+        timestamp: u64,
+        rng: &mut R,
+    ) -> DenimMessage {
+        let envelope = cover_envelope(receiver, &self.cover_sizes, timestamp, rng);
+        self.create_denim_message(receiver, envelope)
+    }
+
+    /// Deniable payloads waiting for `receiver`
+    pub fn pending_payloads(&self, receiver: &str) -> usize {
+        self.outgoing.get(receiver).map_or(0, PayloadBuffer::len)
+    }
+
+    /// Deniable data dropped for exceeding a quota
+    pub fn quota_stats(&self) -> QuotaStats {
+        self.quota_counters.stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::deniable::quota::BufferQuota;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn server(quotas: DenimQuotas) -> SimulatedServer {
+        SimulatedServer::new(
+            0.6,
+            SchedulingStrategy::Fifo,
+            quotas,
+            CoverEnvelopeSizes::default(),
+        )
+    }
+
+    #[test]
+    fn counters_start_at_zero() {
+        let mut server = server(Default::default());
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let counters: Vec<Option<i32>> = (0..3)
+            .map(|_| server.create_cover_message("alice", 0, &mut rng).counter)
+            .collect();
+
+        assert_eq!(counters, [Some(0), Some(1), Some(2)]);
+        assert_eq!(
+            server.create_cover_message("bob", 0, &mut rng).counter,
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn payloads_over_receiver_quota_are_dropped() {
+        let quotas = DenimQuotas {
+            receiver: BufferQuota::new(usize::MAX, 1),
+            ..Default::default()
+        };
+        let mut server = server(quotas);
+
+        for _ in 0..3 {
+            server.enqueue(
+                "bob",
+                "alice",
+                DeniablePayload::Envelope(Envelope::default()),
+            );
+        }
+
+        assert_eq!(server.pending_payloads("bob"), 1);
+        assert_eq!(server.quota_stats().dropped_payloads, 2);
+    }
+}
@@ -0,0 +1,446 @@
+use crate::{
+    client::SimulatedClient,
+    observation::{DeniableDelivery, Direction, GroundTruth, Observation, SimulationResult},
+    server::SimulatedServer,
+    trace::Trace,
+};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use common::{
+    deniable::{
+        cover_traffic::{CoverEnvelopeSizes, CoverTrafficSchedule, CoverTrafficScheduler},
+        quota::DenimQuotas,
+        relay::message_envelope,
+        scheduler::SchedulingStrategy,
+    },
+    signalservice::envelope,
+    web_api::{DeniablePayload, DenimMessage, RegularPayload, SignalMessage},
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, RngCore, SeedableRng};
+use std::collections::{BTreeMap, VecDeque};
+
+/// Cover content size when the trace has no messages to take the size from
+const DEFAULT_COVER_CONTENT_SIZE: usize = 230;
+/// Users of the simulation have a single device, the primary one
+const DEVICE_ID: u32 = 1;
+
+/// Parameters of a simulation
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationConfig {
+    pub q_value: f32,
+    pub scheduling: SchedulingStrategy,
+    /// Quotas of the buffers of the server, unbounded by default
+    pub quotas: DenimQuotas,
+    /// Cover messages sent by every client to a random other user
+    pub client_cover: Option<CoverTrafficSchedule>,
+    /// Cover messages sent by the server to every client
+    pub server_cover: Option<CoverTrafficSchedule>,
+    /// Sizes of the content of the cover envelopes of the server
+    pub cover_sizes: CoverEnvelopeSizes,
+    /// Milliseconds between a client and the server in either direction
+    pub latency: u64,
+    /// Milliseconds after which no more messages are sent, the end of the trace by default
+    pub duration: Option<u64>,
+    pub seed: u64,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            q_value: 0.6,
+            scheduling: SchedulingStrategy::Fifo,
+            quotas: DenimQuotas::default(),
+            client_cover: None,
+            server_cover: None,
+            cover_sizes: CoverEnvelopeSizes::default(),
+            latency: 50,
+            duration: None,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Event {
+    /// The message at this index of the trace is sent
+    Send(usize),
+    ClientCover(String),
+    ServerCover(String),
+    ServerReceive {
+        sender: String,
+        message: DenimMessage,
+        cover: bool,
+    },
+    ClientReceive {
+        receiver: String,
+        message: DenimMessage,
+        cover: bool,
+    },
+}
+
+/// Replays a trace through simulated clients and a simulated server, recording every denim
+/// message as an observation
+pub struct Simulation {
+    config: SimulationConfig,
+    trace: Trace,
+    end: u64,
+    rng: StdRng,
+    clients: BTreeMap<String, SimulatedClient>,
+    server: SimulatedServer,
+    events: BTreeMap<(u64, u64), Event>,
+    next_event: u64,
+    client_cover: BTreeMap<String, CoverTrafficScheduler>,
+    server_cover: BTreeMap<String, CoverTrafficScheduler>,
+    /// Deliveries waiting for their deniable message, by sender and receiver
+    in_flight: BTreeMap<(String, String), VecDeque<usize>>,
+    result: SimulationResult,
+}
+
+impl Simulation {
+    pub fn new(config: SimulationConfig, trace: Trace) -> Self {
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let users = trace.users();
+        let clients = users
+            .iter()
+            .map(|user| (user.clone(), SimulatedClient::new(config.q_value)))
+            .collect();
+        let mut cover_schedulers = |schedule: Option<CoverTrafficSchedule>| {
+            schedule.map_or_else(BTreeMap::new, |schedule| {
+                users
+                    .iter()
+                    .map(|user| {
+                        let rng = StdRng::seed_from_u64(rng.gen());
+                        (user.clone(), CoverTrafficScheduler::with_rng(schedule, rng))
+                    })
+                    .collect()
+            })
+        };
+        let client_cover = cover_schedulers(config.client_cover);
+        let server_cover = cover_schedulers(config.server_cover);
+
+        Self {
+            end: config.duration.unwrap_or_else(|| trace.end()),
+            server: SimulatedServer::new(
+                config.q_value,
+                config.scheduling,
+                config.quotas,
+                config.cover_sizes.clone(),
+            ),
+            result: SimulationResult {
+                users: users.clone(),
+                deniable_users: trace.deniable_users(),
+                ..Default::default()
+            },
+            config,
+            trace,
+            rng,
+            clients,
+            events: BTreeMap::new(),
+            next_event: 0,
+            client_cover,
+            server_cover,
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    /// Run the simulation until every sent message has arrived
+    pub fn run(mut self) -> SimulationResult {
+        for index in 0..self.trace.events.len() {
+            self.schedule(self.trace.events[index].time, Event::Send(index));
+        }
+        for user in self.client_cover.keys().cloned().collect::<Vec<_>>() {
+            self.schedule_cover(0, Event::ClientCover(user));
+        }
+        for user in self.server_cover.keys().cloned().collect::<Vec<_>>() {
+            self.schedule_cover(0, Event::ServerCover(user));
+        }
+
+        while let Some(((time, _), event)) = self.events.pop_first() {
+            match event {
+                Event::Send(index) => self.send(time, index),
+                Event::ClientCover(user) => {
+                    self.send_client_cover(time, &user);
+                    self.schedule_cover(time, Event::ClientCover(user));
+                }
+                Event::ServerCover(user) => {
+                    let message = self.server.create_cover_message(&user, time, &mut self.rng);
+                    self.schedule(
+                        time + self.config.latency,
+                        Event::ClientReceive {
+                            receiver: user.clone(),
+                            message,
+                            cover: true,
+                        },
+                    );
+                    self.schedule_cover(time, Event::ServerCover(user));
+                }
+                Event::ServerReceive {
+                    sender,
+                    message,
+                    cover,
+                } => self.server_receive(time, &sender, message, cover),
+                Event::ClientReceive {
+                    receiver,
+                    message,
+                    cover,
+                } => self.client_receive(time, &receiver, &message, cover),
+            }
+        }
+        let stats = self.server.quota_stats();
+        self.result.dropped_chunks = stats.dropped_chunks;
+        self.result.dropped_payloads = stats.dropped_payloads;
+        self.result
+    }
+
+    fn schedule(&mut self, time: u64, event: Event) {
+        self.events.insert((time, self.next_event), event);
+        self.next_event += 1;
+    }
+
+    /// Schedule the next cover message of a client or the server, unless it is after the end
+    fn schedule_cover(&mut self, time: u64, event: Event) {
+        let scheduler = match &event {
+            Event::ClientCover(user) => self.client_cover.get_mut(user),
+            Event::ServerCover(user) => self.server_cover.get_mut(user),
+            _ => None,
+        };
+        let Some(scheduler) = scheduler else {
+            return;
+        };
+        let time = time + scheduler.next_delay().as_millis() as u64;
+        if time <= self.end {
+            self.schedule(time, event);
+        }
+    }
+
+    fn send(&mut self, time: u64, index: usize) {
+        if time > self.end {
+            return;
+        }
+        let event = self.trace.events[index].clone();
+        let mut content = vec![0; event.size];
+        self.rng.fill_bytes(&mut content);
+        let signal_message = self.signal_message(&event.receiver, &content);
+
+        if event.deniable {
+            self.in_flight
+                .entry((event.sender.clone(), event.receiver.clone()))
+                .or_default()
+                .push_back(self.result.deliveries.len());
+            self.result.deliveries.push(DeniableDelivery {
+                sender: event.sender.clone(),
+                receiver: event.receiver,
+                sent: time,
+                delivered: None,
+            });
+            self.client(&event.sender)
+                .queue_deniable_payload(&DeniablePayload::SignalMessage(signal_message));
+        } else {
+            self.send_regular(time, &event.sender, signal_message, false);
+        }
+    }
+
+    /// Cover messages of clients are regular messages, so they look like any other message
+    /// to the server
+    fn send_client_cover(&mut self, time: u64, user: &str) {
+        let receivers: Vec<String> = self
+            .result
+            .users
+            .iter()
+            .filter(|receiver| *receiver != user)
+            .cloned()
+            .collect();
+        let Some(receiver) = receivers.choose(&mut self.rng).cloned() else {
+            return;
+        };
+        let mut content = vec![0; self.cover_content_size()];
+        self.rng.fill_bytes(&mut content);
+        let signal_message = self.signal_message(&receiver, &content);
+        self.send_regular(time, user, signal_message, true);
+    }
+
+    fn send_regular(
+        &mut self,
+        time: u64,
+        sender: &str,
+        signal_message: SignalMessage,
+        cover: bool,
+    ) {
+        let message = self
+            .client(sender)
+            .create_denim_message(RegularPayload::SignalMessage(signal_message));
+        self.observe(time, sender, Direction::Upstream, &message, cover);
+        self.schedule(
+            time + self.config.latency,
+            Event::ServerReceive {
+                sender: sender.to_owned(),
+                message,
+                cover,
+            },
+        );
+    }
+
+    fn server_receive(&mut self, time: u64, sender: &str, message: DenimMessage, cover: bool) {
+        self.server.receive(sender, DEVICE_ID, &message, time);
+        let RegularPayload::SignalMessage(signal_message) = message.regular_payload else {
+            return;
+        };
+        let Some(receiver) = signal_message.destination_service_id.clone() else {
+            return;
+        };
+        let Some(envelope) = message_envelope(
+            &signal_message,
+            sender.to_owned(),
+            DEVICE_ID,
+            receiver.clone(),
+            time,
+            time,
+            false,
+        ) else {
+            return;
+        };
+        let message = self.server.create_denim_message(&receiver, envelope);
+        self.schedule(
+            time + self.config.latency,
+            Event::ClientReceive {
+                receiver,
+                message,
+                cover,
+            },
+        );
+    }
+
+    fn client_receive(&mut self, time: u64, receiver: &str, message: &DenimMessage, cover: bool) {
+        self.observe(time, receiver, Direction::Downstream, message, cover);
+        for payload in self.client(receiver).receive(message) {
+            let DeniablePayload::Envelope(envelope) = payload else {
+                continue;
+            };
+            let Some(sender) = envelope.source_service_id else {
+                continue;
+            };
+            if let Some(index) = self
+                .in_flight
+                .get_mut(&(sender, receiver.to_owned()))
+                .and_then(VecDeque::pop_front)
+            {
+                self.result.deliveries[index].delivered = Some(time);
+            }
+        }
+    }
+
+    fn observe(
+        &mut self,
+        time: u64,
+        user: &str,
+        direction: Direction,
+        message: &DenimMessage,
+        cover: bool,
+    ) {
+        self.result.observations.push(Observation {
+            time,
+            user: user.to_owned(),
+            direction,
+            size: message.encode_to_vec().len(),
+            truth: GroundTruth {
+                cover,
+                regular_size: message.regular_payload.encoded_len(),
+                deniable_bytes: message
+                    .chunks
+                    .iter()
+                    .filter(|chunk| !chunk.is_dummy())
+                    .map(|chunk| chunk.chunk.len())
+                    .sum(),
+            },
+        });
+    }
+
+    fn signal_message(&self, receiver: &str, content: &[u8]) -> SignalMessage {
+        let registration_id = self
+            .result
+            .users
+            .iter()
+            .position(|user| user == receiver)
+            .map_or(0, |index| index as u32 + 1);
+        SignalMessage {
+            r#type: envelope::Type::Ciphertext.into(),
+            destination_device_id: DEVICE_ID,
+            destination_service_id: Some(receiver.to_owned()),
+            destination_registration_id: registration_id,
+            content: BASE64_STANDARD.encode(content),
+            expires_at: None,
+        }
+    }
+
+    /// Cover content is as large as a random message of the trace
+    fn cover_content_size(&mut self) -> usize {
+        self.trace
+            .events
+            .choose(&mut self.rng)
+            .map_or(DEFAULT_COVER_CONTENT_SIZE, |event| event.size)
+    }
+
+    fn client(&mut self, user: &str) -> &mut SimulatedClient {
+        let q_value = self.config.q_value;
+        self.clients
+            .entry(user.to_owned())
+            .or_insert_with(|| SimulatedClient::new(q_value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::TrafficModel;
+
+    fn trace() -> Trace {
+        Trace::synthetic(
+            &TrafficModel {
+                users: 4,
+                deniable_users: 2,
+                duration: 120_000,
+                regular_rate: 0.5,
+                deniable_rate: 0.1,
+                ..Default::default()
+            },
+            3,
+        )
+    }
+
+    #[test]
+    fn same_seed_gives_same_observations() {
+        let config = SimulationConfig {
+            client_cover: Some(CoverTrafficSchedule::Poisson { rate: 0.2 }),
+            server_cover: Some(CoverTrafficSchedule::Poisson { rate: 0.2 }),
+            seed: 11,
+            ..Default::default()
+        };
+
+        let first = Simulation::new(config.clone(), trace()).run();
+        let second = Simulation::new(config, trace()).run();
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn deniable_messages_are_delivered() {
+        let config = SimulationConfig {
+            client_cover: Some(CoverTrafficSchedule::Poisson { rate: 1.0 }),
+            server_cover: Some(CoverTrafficSchedule::Poisson { rate: 1.0 }),
+            duration: Some(300_000),
+            ..Default::default()
+        };
+
+        let result = Simulation::new(config, trace()).run();
+
+        assert!(!result.deliveries.is_empty());
+        assert!(result
+            .deliveries
+            .iter()
+            .all(|delivery| delivery.delivered.is_some()));
+        assert!(result
+            .observations
+            .iter()
+            .filter(|observation| observation.direction == Direction::Upstream)
+            .all(|observation| result.users.contains(&observation.user)));
+    }
+}
@@ -0,0 +1,198 @@
+use common::deniable::cover_traffic::{CoverTrafficSchedule, CoverTrafficScheduler};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// A message sent by a user of the simulation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceEvent {
+    /// Milliseconds since the start of the simulation
+    pub time: u64,
+    pub sender: String,
+    pub receiver: String,
+    /// Size of the message content in bytes
+    pub size: usize,
+    /// Deniable messages are sent in chunks along the regular messages of the sender
+    pub deniable: bool,
+}
+
+/// Messages sent in a simulation, ordered by time
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trace {
+    pub events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    pub fn new(mut events: Vec<TraceEvent>) -> Self {
+        events.sort_by_key(|event| event.time);
+        Self { events }
+    }
+
+    /// Read a recorded trace, a JSON array of trace events
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read trace {}: {err}", path.display()))?;
+        let events: Vec<TraceEvent> = serde_json::from_str(&json)
+            .map_err(|err| format!("Invalid trace {}: {err}", path.display()))?;
+        Ok(Self::new(events))
+    }
+
+    /// Generate a trace from `model`, the same seed gives the same trace
+    pub fn synthetic(model: &TrafficModel, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let users = model.users();
+        let deniable_users = &users[..model.deniable_users.min(users.len())];
+
+        let mut events = Vec::new();
+        for sender in &users {
+            events.extend(model.messages(sender, &users, model.regular_rate, false, &mut rng));
+        }
+        for sender in deniable_users {
+            events.extend(model.messages(
+                sender,
+                deniable_users,
+                model.deniable_rate,
+                true,
+                &mut rng,
+            ));
+        }
+        Self::new(events)
+    }
+
+    /// Users sending or receiving a message
+    pub fn users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self
+            .events
+            .iter()
+            .flat_map(|event| [event.sender.clone(), event.receiver.clone()])
+            .collect();
+        users.sort();
+        users.dedup();
+        users
+    }
+
+    /// Users sending or receiving a deniable message
+    pub fn deniable_users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self
+            .events
+            .iter()
+            .filter(|event| event.deniable)
+            .flat_map(|event| [event.sender.clone(), event.receiver.clone()])
+            .collect();
+        users.sort();
+        users.dedup();
+        users
+    }
+
+    /// Time of the last message
+    pub fn end(&self) -> u64 {
+        self.events.last().map_or(0, |event| event.time)
+    }
+}
+
+/// Synthetic traffic where every user sends regular messages to random users,
+/// and the first `deniable_users` users also send deniable messages to each other
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrafficModel {
+    pub users: usize,
+    pub deniable_users: usize,
+    /// Milliseconds of traffic to generate
+    pub duration: u64,
+    /// Regular messages per second sent by each user, with exponentially distributed gaps
+    pub regular_rate: f64,
+    /// Deniable messages per second sent by each deniable user
+    pub deniable_rate: f64,
+    /// Smallest and largest message content size in bytes
+    pub message_size: (usize, usize),
+}
+
+impl Default for TrafficModel {
+    fn default() -> Self {
+        Self {
+            users: 10,
+            deniable_users: 2,
+            duration: 600_000,
+            regular_rate: 0.05,
+            deniable_rate: 0.02,
+            message_size: (50, 500),
+        }
+    }
+}
+
+impl TrafficModel {
+    fn users(&self) -> Vec<String> {
+        (0..self.users)
+            .map(|user| format!("user-{user:03}"))
+            .collect()
+    }
+
+    /// Messages from `sender` to random other users in `receivers`
+    fn messages(
+        &self,
+        sender: &str,
+        receivers: &[String],
+        rate: f64,
+        deniable: bool,
+        rng: &mut StdRng,
+    ) -> Vec<TraceEvent> {
+        let receivers: Vec<&String> = receivers
+            .iter()
+            .filter(|receiver| *receiver != sender)
+            .collect();
+        if receivers.is_empty() || !(rate.is_finite() && rate > 0.0) {
+            return Vec::new();
+        }
+        let mut gaps = CoverTrafficScheduler::with_rng(
+            CoverTrafficSchedule::Poisson { rate },
+            StdRng::seed_from_u64(rng.gen()),
+        );
+
+        let mut events = Vec::new();
+        let mut time = gaps.next_delay().as_millis() as u64;
+        while time < self.duration {
+            let receiver = receivers.choose(rng).expect("There are receivers");
+            events.push(TraceEvent {
+                time,
+                sender: sender.to_owned(),
+                receiver: (*receiver).clone(),
+                size: rng.gen_range(self.message_size.0..=self.message_size.1),
+                deniable,
+            });
+            time += gaps.next_delay().as_millis() as u64;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn synthetic_trace_is_deterministic() {
+        let model = TrafficModel::default();
+
+        assert_eq!(Trace::synthetic(&model, 7), Trace::synthetic(&model, 7));
+        assert_ne!(Trace::synthetic(&model, 7), Trace::synthetic(&model, 8));
+    }
+
+    #[test]
+    fn only_deniable_users_send_deniable_messages() {
+        let model = TrafficModel {
+            users: 5,
+            deniable_users: 2,
+            deniable_rate: 1.0,
+            ..Default::default()
+        };
+        let trace = Trace::synthetic(&model, 1);
+
+        assert_eq!(trace.deniable_users(), ["user-000", "user-001"]);
+        assert_eq!(trace.users().len(), 5);
+        assert!(trace
+            .events
+            .windows(2)
+            .all(|pair| pair[0].time <= pair[1].time));
+        assert!(trace.end() < model.duration);
+    }
+}